@@ -313,6 +313,69 @@ fn btree_benchmarks(c: &mut Criterion) {
     group.finish();
 }
 
+// ============================================================================
+// Leaf Item Parsing Benchmarks
+// ============================================================================
+
+/// Benchmark `TreeNode::items()` against indexed access on a real leaf
+fn leaf_item_benchmarks(c: &mut Criterion) {
+    use btrf_mount_windows::core::checksum::compute_node_checksum;
+    use btrf_mount_windows::core::Checksum;
+    use btrf_mount_windows::core::tree::{
+        BtrfsKey, TreeNode, ITEM_SIZE, KEY_SIZE, NODE_HEADER_SIZE,
+    };
+
+    let mut group = c.benchmark_group("leaf_items");
+
+    // 16 KiB leaf with 400 items carrying 8 bytes of data each
+    const NODE_SIZE: usize = 16384;
+    const NUM_ITEMS: usize = 400;
+
+    let mut data = vec![0u8; NODE_SIZE];
+    data[0x60..0x64].copy_from_slice(&(NUM_ITEMS as u32).to_le_bytes()); // nritems
+    data[0x64] = 0; // level
+
+    let mut data_end = NODE_SIZE - NODE_HEADER_SIZE;
+    for i in 0..NUM_ITEMS {
+        data_end -= 8;
+        let off = NODE_HEADER_SIZE + i * ITEM_SIZE;
+        data[off..off + 8].copy_from_slice(&(256 + i as u64).to_le_bytes()); // objectid
+        data[off + 8] = 0x01; // INODE_ITEM
+        data[off + KEY_SIZE..off + KEY_SIZE + 4].copy_from_slice(&(data_end as u32).to_le_bytes());
+        data[off + KEY_SIZE + 4..off + ITEM_SIZE].copy_from_slice(&8u32.to_le_bytes());
+    }
+    let csum = compute_node_checksum(&data);
+    data[0..4].copy_from_slice(&csum.to_le_bytes());
+
+    let node = TreeNode::parse(data, Checksum::Crc32c).unwrap();
+    let target = BtrfsKey::new(256 + 300, 0x01, 0);
+
+    group.bench_function("items_vec_400", |b| {
+        b.iter(|| black_box(node.items().unwrap()))
+    });
+
+    group.bench_function("item_at_all_400", |b| {
+        b.iter(|| {
+            for i in 0..node.item_count() {
+                black_box(node.item_at(i).unwrap());
+            }
+        })
+    });
+
+    group.bench_function("lookup_linear_400", |b| {
+        b.iter(|| {
+            let items = node.items().unwrap();
+            black_box(items.into_iter().find(|item| item.key == black_box(target)))
+        })
+    });
+
+    group.bench_function("lookup_binary_400", |b| {
+        b.iter(|| black_box(node.find_item(&black_box(target)).unwrap()))
+    });
+
+    group.finish();
+}
+
 // ============================================================================
 // Inode Benchmarks
 // ============================================================================
@@ -545,6 +608,8 @@ fn memory_benchmarks(c: &mut Criterion) {
 
 /// Benchmark BTRFS name hashing (used for directory lookups)
 fn name_hash_benchmarks(c: &mut Criterion) {
+    use btrf_mount_windows::core::inode::btrfs_name_hash;
+
     let mut group = c.benchmark_group("name_hash");
 
     let short_name = "a.txt";
@@ -552,21 +617,21 @@ fn name_hash_benchmarks(c: &mut Criterion) {
     let long_name = "this_is_a_very_long_filename_that_someone_might_actually_use_in_practice.tar.gz";
 
     group.bench_function("short_name", |b| {
-        b.iter(|| crc32c::crc32c(black_box(short_name.as_bytes())) as u64)
+        b.iter(|| btrfs_name_hash(black_box(short_name)))
     });
 
     group.bench_function("medium_name", |b| {
-        b.iter(|| crc32c::crc32c(black_box(medium_name.as_bytes())) as u64)
+        b.iter(|| btrfs_name_hash(black_box(medium_name)))
     });
 
     group.bench_function("long_name", |b| {
-        b.iter(|| crc32c::crc32c(black_box(long_name.as_bytes())) as u64)
+        b.iter(|| btrfs_name_hash(black_box(long_name)))
     });
 
     // Unicode name
     let unicode_name = "файл_документ.txt";
     group.bench_function("unicode_name", |b| {
-        b.iter(|| crc32c::crc32c(black_box(unicode_name.as_bytes())) as u64)
+        b.iter(|| btrfs_name_hash(black_box(unicode_name)))
     });
 
     group.finish();
@@ -819,6 +884,7 @@ criterion_group!(
         superblock_benchmarks,
         checksum_benchmarks,
         btree_benchmarks,
+        leaf_item_benchmarks,
         inode_benchmarks,
         extent_benchmarks,
         chunk_benchmarks,
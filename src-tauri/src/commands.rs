@@ -1,21 +1,35 @@
 //! Tauri IPC commands for BTRFS operations
 
-use btrf_mount_windows::{blockdev, BtrfsFilesystem, BtrfsMount, MountOptions};
+use btrf_mount_windows::core::{ScrubOptions, SuperblockCopy};
+use btrf_mount_windows::fuse::{reader, CancelToken};
+use btrf_mount_windows::{blockdev, BtrfsError, BtrfsFilesystem, BtrfsMount, MountOptions};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use tauri::ipc::{Channel, InvokeResponseBody};
 use tauri::State;
 
+/// A mounted volume and the source it was opened from
+pub struct ActiveMount {
+    /// Device or image path
+    pub source: String,
+    /// The mount
+    pub mount: BtrfsMount,
+}
+
 /// Application state
 pub struct AppState {
-    /// Active mounts
-    pub mounts: Mutex<HashMap<String, BtrfsMount>>,
+    /// Active mounts, keyed by mount point
+    pub mounts: Mutex<HashMap<String, ActiveMount>>,
+    /// Cancellation tokens of running file streams, keyed by stream ID
+    pub streams: Mutex<HashMap<String, CancelToken>>,
 }
 
 impl Default for AppState {
     fn default() -> Self {
         Self {
             mounts: Mutex::new(HashMap::new()),
+            streams: Mutex::new(HashMap::new()),
         }
     }
 }
@@ -27,6 +41,7 @@ pub struct DeviceInfo {
     pub size: u64,
     pub sector_size: u32,
     pub model: Option<String>,
+    pub serial: Option<String>,
     pub is_btrfs: bool,
 }
 
@@ -39,6 +54,16 @@ pub struct VolumeInfo {
     pub bytes_used: u64,
     pub num_devices: u64,
     pub generation: u64,
+    /// Creation time in seconds since the Unix epoch, if it could be read
+    pub created_at: Option<i64>,
+    /// Superblock copy the volume was read from: "primary", "mirror1" or "mirror2"
+    pub superblock_copy: String,
+    /// Whether changes fsynced after the last commit were left in the log
+    pub unreplayed_log: bool,
+    /// Devices of the volume that were not opened
+    pub missing_devices: u64,
+    /// Whether the volume was opened from a mirror, incomplete or truncated
+    pub degraded: bool,
 }
 
 /// Subvolume information
@@ -50,6 +75,68 @@ pub struct SubvolumeInfo {
     pub path: String,
     pub generation: u64,
     pub flags: u64,
+    pub is_snapshot: bool,
+}
+
+/// Result of verifying one file against the checksum tree
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileVerifyInfo {
+    pub ino: u64,
+    pub checksummed: bool,
+    pub bytes_checked: u64,
+    /// File offsets of blocks whose data does not match its checksum
+    pub corrupt_offsets: Vec<u64>,
+    /// File offsets of blocks with no checksum
+    pub missing_offsets: Vec<u64>,
+}
+
+/// Result of a scrub
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScrubInfo {
+    pub metadata_nodes: u64,
+    /// Logical addresses of tree nodes that failed verification
+    pub metadata_errors: Vec<u64>,
+    pub data_blocks: u64,
+    /// Logical addresses of data blocks that failed verification
+    pub data_errors: Vec<u64>,
+}
+
+/// Result of checking a volume's structure, as counts of each kind of problem
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IntegrityInfo {
+    pub trees_checked: u64,
+    pub nodes_checked: u64,
+    pub data_extents_checked: u64,
+    pub bad_checksums: u64,
+    pub unmapped: u64,
+    pub structure: u64,
+    pub orphans: u64,
+    /// Every problem found, described in words
+    pub errors: Vec<String>,
+}
+
+/// Space usage of one device of a volume
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceUsageInfo {
+    pub devid: u64,
+    pub total_bytes: u64,
+    pub bytes_used: u64,
+    pub data: u64,
+    pub metadata: u64,
+    pub system: u64,
+    pub unallocated: u64,
+}
+
+/// Where the next piece of a file sent by `read_file_stream` belongs
+///
+/// The piece's bytes follow as a raw message on the same channel, so file
+/// data is never encoded as JSON.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileChunkHeader {
+    /// File offset of the first byte
+    pub offset: u64,
+    /// Size of the whole file
+    pub size: u64,
 }
 
 /// Mount information
@@ -85,6 +172,7 @@ pub async fn list_devices() -> Result<Vec<DeviceInfo>, String> {
                         size: drive.size,
                         sector_size: drive.sector_size,
                         model: drive.model,
+                        serial: drive.serial,
                         is_btrfs: false, // Will be detected separately
                     });
                 }
@@ -119,6 +207,28 @@ pub async fn mount_volume(
     state: State<'_, AppState>,
     request: MountRequest,
 ) -> Result<MountInfo, String> {
+    // Two writers would corrupt the volume; a reader may just see stale data
+    let holders = blockdev::device_holders(&request.source);
+    if holders.is_in_use() {
+        let users = if holders.mounted_volumes.is_empty() {
+            "it is open in another program".to_string()
+        } else {
+            let letters: Vec<String> = holders
+                .mounted_volumes
+                .iter()
+                .map(|letter| format!("{}:", letter))
+                .collect();
+            format!("Windows has mounted {} from it", letters.join(", "))
+        };
+        if !request.read_only {
+            return Err(format!(
+                "{} is in use: {}. Mount it read-only or release it first",
+                request.source, users
+            ));
+        }
+        tracing::warn!("Mounting {} read-only while in use: {}", request.source, users);
+    }
+
     // Open device
     let device = blockdev::open(&request.source, request.read_only).map_err(|e| e.to_string())?;
 
@@ -140,7 +250,13 @@ pub async fn mount_volume(
 
     // Store mount
     let mut mounts = state.mounts.lock().unwrap();
-    mounts.insert(mount_point.clone(), mount);
+    mounts.insert(
+        mount_point.clone(),
+        ActiveMount {
+            source: request.source.clone(),
+            mount,
+        },
+    );
 
     Ok(MountInfo {
         source: request.source,
@@ -157,13 +273,78 @@ pub async fn unmount_volume(
 ) -> Result<(), String> {
     let mut mounts = state.mounts.lock().unwrap();
 
-    if let Some(mut mount) = mounts.remove(&mount_point) {
-        mount.unmount().map_err(|e| e.to_string())?;
+    if let Some(mut active) = mounts.remove(&mount_point) {
+        active.mount.unmount().map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
+
+/// Flushes and unmounts a volume, then ejects its device for safe removal
+#[tauri::command]
+pub async fn eject_volume(
+    state: State<'_, AppState>,
+    mount_point: String,
+) -> Result<(), String> {
+    let mut mounts = state.mounts.lock().unwrap();
+
+    let mut active = mounts
+        .remove(&mount_point)
+        .ok_or_else(|| format!("{} is not mounted", mount_point))?;
+    if let Err(e) = active.mount.eject() {
+        // Still mounted if the flush failed; keep tracking it
+        if active.mount.is_mounted() {
+            mounts.insert(mount_point, active);
+        }
+        return Err(e.to_string());
     }
 
     Ok(())
 }
 
+/// Remounts a volume read-only or read-write
+///
+/// The same source is reopened with the new mode and mounted at the same
+/// mount point with the same subvolume.
+#[tauri::command]
+pub async fn remount_volume(
+    state: State<'_, AppState>,
+    mount_point: String,
+    read_only: bool,
+) -> Result<MountInfo, String> {
+    let mut mounts = state.mounts.lock().unwrap();
+
+    let active = mounts
+        .get_mut(&mount_point)
+        .ok_or_else(|| format!("{} is not mounted", mount_point))?;
+
+    if active.mount.options().read_only != read_only {
+        let device = blockdev::open(&active.source, read_only).map_err(|e| {
+            if read_only {
+                e.to_string()
+            } else {
+                format!("{} cannot be opened read-write: {}", active.source, e)
+            }
+        })?;
+
+        let fs = BtrfsFilesystem::open(Arc::from(device), read_only).map_err(|e| e.to_string())?;
+
+        active
+            .mount
+            .remount(Arc::new(fs), read_only)
+            .map_err(|e| match e {
+                BtrfsError::ReadOnly => format!("{} is read-only", active.source),
+                e => e.to_string(),
+            })?;
+    }
+
+    Ok(MountInfo {
+        source: active.source.clone(),
+        mount_point,
+        read_only,
+    })
+}
+
 /// Lists subvolumes in a mounted volume
 #[tauri::command]
 pub async fn list_subvolumes(source: String) -> Result<Vec<SubvolumeInfo>, String> {
@@ -182,6 +363,241 @@ pub async fn list_subvolumes(source: String) -> Result<Vec<SubvolumeInfo>, Strin
             path: s.path,
             generation: s.generation,
             flags: s.flags,
+            is_snapshot: s.is_snapshot(),
+        })
+        .collect())
+}
+
+/// Lists the snapshots of a subvolume
+#[tauri::command]
+pub async fn list_snapshots(source: String, subvol_id: u64) -> Result<Vec<SubvolumeInfo>, String> {
+    let device = blockdev::open(&source, true).map_err(|e| e.to_string())?;
+
+    let fs = BtrfsFilesystem::open(Arc::from(device), true).map_err(|e| e.to_string())?;
+
+    let snapshots = fs.snapshots_of(subvol_id).map_err(|e| e.to_string())?;
+
+    Ok(snapshots
+        .into_iter()
+        .map(|s| SubvolumeInfo {
+            id: s.id,
+            parent_id: s.parent_id,
+            name: s.name,
+            path: s.path,
+            generation: s.generation,
+            flags: s.flags,
+            is_snapshot: s.is_snapshot(),
+        })
+        .collect())
+}
+
+/// Snapshots subvolume `subvol_id` as `name` next to it
+///
+/// The volume is opened read-write, so it must not be mounted.
+#[tauri::command]
+pub async fn create_snapshot(
+    state: State<'_, AppState>,
+    source: String,
+    subvol_id: u64,
+    name: String,
+    readonly: bool,
+) -> Result<SubvolumeInfo, String> {
+    let mounts = state.mounts.lock().unwrap();
+    if mounts.values().any(|active| active.source == source) {
+        return Err(format!("{} is mounted; unmount it first", source));
+    }
+
+    let device = blockdev::open(&source, false)
+        .map_err(|e| format!("{} cannot be opened read-write: {}", source, e))?;
+
+    let mut fs = BtrfsFilesystem::open(Arc::from(device), false).map_err(|e| e.to_string())?;
+
+    let s = fs
+        .create_snapshot(subvol_id, &name, readonly)
+        .map_err(|e| e.to_string())?;
+
+    Ok(SubvolumeInfo {
+        id: s.id,
+        parent_id: s.parent_id,
+        name: s.name,
+        path: s.path,
+        generation: s.generation,
+        flags: s.flags,
+        is_snapshot: s.is_snapshot(),
+    })
+}
+
+/// Verifies one file's data against the checksum tree
+#[tauri::command]
+pub async fn verify_file(
+    source: String,
+    tree_id: u64,
+    path: String,
+) -> Result<FileVerifyInfo, String> {
+    let device = blockdev::open(&source, true).map_err(|e| e.to_string())?;
+
+    let fs = BtrfsFilesystem::open(Arc::from(device), true).map_err(|e| e.to_string())?;
+
+    let report = fs.verify_file(tree_id, &path).map_err(|e| e.to_string())?;
+
+    Ok(FileVerifyInfo {
+        ino: report.ino,
+        checksummed: report.checksummed,
+        bytes_checked: report.bytes_checked,
+        corrupt_offsets: report.corrupt_offsets,
+        missing_offsets: report.missing_offsets,
+    })
+}
+
+/// Hashes the contents of a file or directory tree with SHA-256
+///
+/// Returns the hash as lowercase hex, for comparing a path across volumes.
+#[tauri::command]
+pub async fn hash_path(source: String, tree_id: u64, path: String) -> Result<String, String> {
+    let device = blockdev::open(&source, true).map_err(|e| e.to_string())?;
+
+    let fs = BtrfsFilesystem::open(Arc::from(device), true).map_err(|e| e.to_string())?;
+
+    let hash = fs.content_hash(tree_id, &path).map_err(|e| e.to_string())?;
+
+    Ok(hash.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Bytes sent per chunk by `read_file_stream`
+const STREAM_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Streams a file's contents to the GUI through `on_chunk`
+///
+/// Chunks are sent as they are read, so large files can be previewed with
+/// progress: each as a [`FileChunkHeader`] followed by its raw bytes. The
+/// stream can be stopped with `cancel_file_stream` using the caller-chosen
+/// `stream_id`. Returns the number of bytes sent.
+#[tauri::command]
+pub async fn read_file_stream(
+    state: State<'_, AppState>,
+    stream_id: String,
+    source: String,
+    tree_id: u64,
+    path: String,
+    offset: u64,
+    on_chunk: Channel<InvokeResponseBody>,
+) -> Result<u64, String> {
+    let cancel = CancelToken::new();
+    {
+        let mut streams = state.streams.lock().unwrap();
+        if streams.contains_key(&stream_id) {
+            return Err(format!("Stream {} is already running", stream_id));
+        }
+        streams.insert(stream_id.clone(), cancel.clone());
+    }
+
+    let result = tokio::task::spawn_blocking(move || {
+        let device = blockdev::open(&source, true).map_err(|e| e.to_string())?;
+
+        let fs = BtrfsFilesystem::open(Arc::from(device), true).map_err(|e| e.to_string())?;
+
+        let send = |chunk: reader::StreamChunk<'_>| {
+            let header = FileChunkHeader {
+                offset: chunk.offset,
+                size: chunk.size,
+            };
+            let header = serde_json::to_string(&header).map_err(std::io::Error::other)?;
+            on_chunk
+                .send(InvokeResponseBody::Json(header))
+                .and_then(|()| on_chunk.send(InvokeResponseBody::Raw(chunk.data.to_vec())))
+                .map_err(|e| BtrfsError::Io(std::io::Error::other(e.to_string())))
+        };
+        reader::read_file_stream(
+            &fs,
+            tree_id,
+            &path,
+            offset,
+            STREAM_CHUNK_SIZE,
+            &cancel,
+            send,
+        )
+        .map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| e.to_string());
+
+    state.streams.lock().unwrap().remove(&stream_id);
+    result?
+}
+
+/// Stops a running `read_file_stream`; does nothing if it already finished
+#[tauri::command]
+pub async fn cancel_file_stream(
+    state: State<'_, AppState>,
+    stream_id: String,
+) -> Result<(), String> {
+    if let Some(cancel) = state.streams.lock().unwrap().get(&stream_id) {
+        cancel.cancel();
+    }
+    Ok(())
+}
+
+/// Scrubs a volume, verifying data blocks, tree nodes, or both
+#[tauri::command]
+pub async fn scrub_volume(source: String, data: bool, metadata: bool) -> Result<ScrubInfo, String> {
+    let device = blockdev::open(&source, true).map_err(|e| e.to_string())?;
+
+    let fs = BtrfsFilesystem::open(Arc::from(device), true).map_err(|e| e.to_string())?;
+
+    let report = fs
+        .scrub(ScrubOptions { data, metadata })
+        .map_err(|e| e.to_string())?;
+
+    Ok(ScrubInfo {
+        metadata_nodes: report.metadata_nodes,
+        metadata_errors: report.metadata_errors,
+        data_blocks: report.data_blocks,
+        data_errors: report.data_errors,
+    })
+}
+
+/// Checks the structure of a volume's trees, for use before mounting a
+/// damaged volume
+#[tauri::command]
+pub async fn check_volume(source: String) -> Result<IntegrityInfo, String> {
+    let device = blockdev::open(&source, true).map_err(|e| e.to_string())?;
+
+    let fs = BtrfsFilesystem::open(Arc::from(device), true).map_err(|e| e.to_string())?;
+
+    let report = fs.check_integrity();
+    let counts = report.counts();
+
+    Ok(IntegrityInfo {
+        trees_checked: report.trees_checked,
+        nodes_checked: report.nodes_checked,
+        data_extents_checked: report.data_extents_checked,
+        bad_checksums: counts.bad_checksums,
+        unmapped: counts.unmapped,
+        structure: counts.structure,
+        orphans: counts.orphans,
+        errors: report.errors.iter().map(|e| format!("{:?}", e)).collect(),
+    })
+}
+
+/// Gets the size, allocation and unallocated space of each device of a
+/// volume
+#[tauri::command]
+pub async fn get_device_usage(source: String) -> Result<Vec<DeviceUsageInfo>, String> {
+    let device = blockdev::open(&source, true).map_err(|e| e.to_string())?;
+
+    let fs = BtrfsFilesystem::open(Arc::from(device), true).map_err(|e| e.to_string())?;
+
+    let usage = fs.device_usage().map_err(|e| e.to_string())?;
+    Ok(usage
+        .into_iter()
+        .map(|device| DeviceUsageInfo {
+            devid: device.devid,
+            total_bytes: device.total_bytes,
+            bytes_used: device.bytes_used,
+            data: device.data,
+            metadata: device.metadata,
+            system: device.system,
+            unallocated: device.unallocated,
         })
         .collect())
 }
@@ -192,6 +608,7 @@ pub async fn get_volume_info(source: String) -> Result<VolumeInfo, String> {
     let device = blockdev::open(&source, true).map_err(|e| e.to_string())?;
 
     let fs = BtrfsFilesystem::open(Arc::from(device), true).map_err(|e| e.to_string())?;
+    let diagnostics = fs.mount_diagnostics();
 
     Ok(VolumeInfo {
         uuid: fs.uuid().to_string(),
@@ -200,6 +617,16 @@ pub async fn get_volume_info(source: String) -> Result<VolumeInfo, String> {
         bytes_used: fs.bytes_used(),
         num_devices: fs.superblock().num_devices(),
         generation: fs.superblock().generation(),
+        created_at: fs.created_at().ok().map(|t| t.sec),
+        superblock_copy: match diagnostics.superblock_copy {
+            SuperblockCopy::Primary => "primary",
+            SuperblockCopy::Mirror1 => "mirror1",
+            SuperblockCopy::Mirror2 => "mirror2",
+        }
+        .to_string(),
+        unreplayed_log: diagnostics.unreplayed_log,
+        missing_devices: diagnostics.missing_devices,
+        degraded: diagnostics.is_degraded(),
     })
 }
 
@@ -211,9 +638,9 @@ pub async fn list_mounts(state: State<'_, AppState>) -> Result<Vec<MountInfo>, S
     Ok(mounts
         .values()
         .map(|m| MountInfo {
-            source: String::new(), // TODO: Store source in mount
-            mount_point: m.mount_point().to_string(),
-            read_only: m.filesystem().is_read_only(),
+            source: m.source.clone(),
+            mount_point: m.mount.mount_point().to_string(),
+            read_only: m.mount.filesystem().is_read_only(),
         })
         .collect())
 }
@@ -351,6 +778,20 @@ pub async fn install_library_update(app: tauri::AppHandle) -> Result<(), String>
         .install_library(&lib_data, &manifest.version, &platform_info.sha256)
         .map_err(|e| format!("Failed to install library: {}", e))?;
     
+    // Put the previous library back if the new one doesn't load
+    if let Err(e) = updater.verify_installed() {
+        return match updater.rollback() {
+            Ok(restored) => Err(format!(
+                "Installed library failed to load ({}); restored version {}",
+                e, restored.version
+            )),
+            Err(rollback_err) => Err(format!(
+                "Installed library failed to load ({}) and could not be rolled back: {}",
+                e, rollback_err
+            )),
+        };
+    }
+    
     let _ = app.emit("library-update-progress", serde_json::json!({
         "stage": "complete",
         "progress": 100
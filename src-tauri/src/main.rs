@@ -46,7 +46,18 @@ fn main() {
             commands::detect_btrfs,
             commands::mount_volume,
             commands::unmount_volume,
+            commands::eject_volume,
+            commands::remount_volume,
             commands::list_subvolumes,
+            commands::list_snapshots,
+            commands::create_snapshot,
+            commands::verify_file,
+            commands::hash_path,
+            commands::read_file_stream,
+            commands::cancel_file_stream,
+            commands::scrub_volume,
+            commands::check_volume,
+            commands::get_device_usage,
             commands::get_volume_info,
             commands::list_mounts,
             commands::get_library_version,
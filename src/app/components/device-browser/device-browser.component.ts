@@ -53,6 +53,9 @@ import { TauriService, DeviceInfo, VolumeInfo } from '../../services/tauri.servi
                     @if (device.model) {
                       <div class="text-sm text-gray-400 dark:text-gray-500">{{ device.model }}</div>
                     }
+                    @if (device.serial) {
+                      <div class="text-xs text-gray-400 dark:text-gray-500">S/N {{ device.serial }}</div>
+                    }
                   </div>
                   <div class="flex items-center gap-2">
                     @if (device.is_btrfs) {
@@ -102,8 +105,26 @@ import { TauriService, DeviceInfo, VolumeInfo } from '../../services/tauri.servi
               <div class="text-sm text-gray-500 dark:text-gray-400">Generation</div>
               <div class="text-gray-900 dark:text-white">{{ volumeInfo()?.generation }}</div>
             </div>
+            <div>
+              <div class="text-sm text-gray-500 dark:text-gray-400">Superblock</div>
+              <div class="text-gray-900 dark:text-white">{{ volumeInfo()?.superblock_copy }}</div>
+            </div>
           </div>
 
+          @if (volumeInfo()?.degraded || volumeInfo()?.unreplayed_log) {
+            <div class="mt-4 text-sm text-yellow-700 dark:text-yellow-400">
+              @if (volumeInfo()?.degraded) {
+                <div>Opened degraded</div>
+              }
+              @if (volumeInfo()?.missing_devices) {
+                <div>{{ volumeInfo()?.missing_devices }} device(s) missing</div>
+              }
+              @if (volumeInfo()?.unreplayed_log) {
+                <div>Changes in the tree log are not shown</div>
+              }
+            </div>
+          }
+
           <!-- Usage bar -->
           <div class="mt-4">
             <div class="h-2 bg-gray-200 dark:bg-gray-700 rounded-full overflow-hidden">
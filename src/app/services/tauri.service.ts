@@ -1,11 +1,12 @@
 import { Injectable, signal } from '@angular/core';
-import { invoke } from '@tauri-apps/api/core';
+import { Channel, invoke } from '@tauri-apps/api/core';
 
 export interface DeviceInfo {
   path: string;
   size: number;
   sector_size: number;
   model: string | null;
+  serial: string | null;
   is_btrfs: boolean;
 }
 
@@ -16,6 +17,56 @@ export interface VolumeInfo {
   bytes_used: number;
   num_devices: number;
   generation: number;
+  created_at: number | null;
+  superblock_copy: 'primary' | 'mirror1' | 'mirror2';
+  unreplayed_log: boolean;
+  missing_devices: number;
+  degraded: boolean;
+}
+
+export interface FileVerifyInfo {
+  ino: number;
+  checksummed: boolean;
+  bytes_checked: number;
+  corrupt_offsets: number[];
+  missing_offsets: number[];
+}
+
+export interface IntegrityInfo {
+  trees_checked: number;
+  nodes_checked: number;
+  data_extents_checked: number;
+  bad_checksums: number;
+  unmapped: number;
+  structure: number;
+  orphans: number;
+  errors: string[];
+}
+
+export interface DeviceUsageInfo {
+  devid: number;
+  total_bytes: number;
+  bytes_used: number;
+  data: number;
+  metadata: number;
+  system: number;
+  unallocated: number;
+}
+
+export interface FileChunkHeader {
+  offset: number;
+  size: number;
+}
+
+export interface FileChunk extends FileChunkHeader {
+  data: Uint8Array;
+}
+
+export interface ScrubInfo {
+  metadata_nodes: number;
+  metadata_errors: number[];
+  data_blocks: number;
+  data_errors: number[];
 }
 
 export interface SubvolumeInfo {
@@ -25,6 +76,7 @@ export interface SubvolumeInfo {
   path: string;
   generation: number;
   flags: number;
+  is_snapshot: boolean;
 }
 
 export interface MountInfo {
@@ -95,6 +147,32 @@ export class TauriService {
     }
   }
 
+  async ejectVolume(mountPoint: string): Promise<void> {
+    this.isLoading.set(true);
+    this.error.set(null);
+    try {
+      await invoke('eject_volume', { mountPoint });
+    } catch (e) {
+      this.error.set(String(e));
+      throw e;
+    } finally {
+      this.isLoading.set(false);
+    }
+  }
+
+  async remountVolume(mountPoint: string, readOnly: boolean): Promise<MountInfo> {
+    this.isLoading.set(true);
+    this.error.set(null);
+    try {
+      return await invoke<MountInfo>('remount_volume', { mountPoint, readOnly });
+    } catch (e) {
+      this.error.set(String(e));
+      throw e;
+    } finally {
+      this.isLoading.set(false);
+    }
+  }
+
   async listSubvolumes(source: string): Promise<SubvolumeInfo[]> {
     this.isLoading.set(true);
     this.error.set(null);
@@ -108,6 +186,141 @@ export class TauriService {
     }
   }
 
+  async listSnapshots(source: string, subvolId: number): Promise<SubvolumeInfo[]> {
+    this.isLoading.set(true);
+    this.error.set(null);
+    try {
+      return await invoke<SubvolumeInfo[]>('list_snapshots', { source, subvolId });
+    } catch (e) {
+      this.error.set(String(e));
+      throw e;
+    } finally {
+      this.isLoading.set(false);
+    }
+  }
+
+  async createSnapshot(
+    source: string,
+    subvolId: number,
+    name: string,
+    readonly: boolean
+  ): Promise<SubvolumeInfo> {
+    this.isLoading.set(true);
+    this.error.set(null);
+    try {
+      return await invoke<SubvolumeInfo>('create_snapshot', { source, subvolId, name, readonly });
+    } catch (e) {
+      this.error.set(String(e));
+      throw e;
+    } finally {
+      this.isLoading.set(false);
+    }
+  }
+
+  async verifyFile(source: string, treeId: number, path: string): Promise<FileVerifyInfo> {
+    this.isLoading.set(true);
+    this.error.set(null);
+    try {
+      return await invoke<FileVerifyInfo>('verify_file', { source, treeId, path });
+    } catch (e) {
+      this.error.set(String(e));
+      throw e;
+    } finally {
+      this.isLoading.set(false);
+    }
+  }
+
+  async hashPath(source: string, treeId: number, path: string): Promise<string> {
+    this.isLoading.set(true);
+    this.error.set(null);
+    try {
+      return await invoke<string>('hash_path', { source, treeId, path });
+    } catch (e) {
+      this.error.set(String(e));
+      throw e;
+    } finally {
+      this.isLoading.set(false);
+    }
+  }
+
+  async readFileStream(
+    streamId: string,
+    source: string,
+    treeId: number,
+    path: string,
+    onChunk: (chunk: FileChunk) => void,
+    offset = 0
+  ): Promise<number> {
+    this.error.set(null);
+    // Each chunk arrives as its header, then its bytes as a raw message
+    const channel = new Channel<FileChunkHeader | ArrayBuffer>();
+    let header: FileChunkHeader | null = null;
+    channel.onmessage = (message) => {
+      if (!(message instanceof ArrayBuffer)) {
+        header = message;
+      } else if (header) {
+        onChunk({ ...header, data: new Uint8Array(message) });
+        header = null;
+      }
+    };
+    try {
+      return await invoke<number>('read_file_stream', {
+        streamId,
+        source,
+        treeId,
+        path,
+        offset,
+        onChunk: channel,
+      });
+    } catch (e) {
+      this.error.set(String(e));
+      throw e;
+    }
+  }
+
+  async cancelFileStream(streamId: string): Promise<void> {
+    await invoke('cancel_file_stream', { streamId });
+  }
+
+  async scrubVolume(source: string, data = true, metadata = true): Promise<ScrubInfo> {
+    this.isLoading.set(true);
+    this.error.set(null);
+    try {
+      return await invoke<ScrubInfo>('scrub_volume', { source, data, metadata });
+    } catch (e) {
+      this.error.set(String(e));
+      throw e;
+    } finally {
+      this.isLoading.set(false);
+    }
+  }
+
+  async checkVolume(source: string): Promise<IntegrityInfo> {
+    this.isLoading.set(true);
+    this.error.set(null);
+    try {
+      return await invoke<IntegrityInfo>('check_volume', { source });
+    } catch (e) {
+      this.error.set(String(e));
+      throw e;
+    } finally {
+      this.isLoading.set(false);
+    }
+  }
+
+  async getDeviceUsage(source: string): Promise<DeviceUsageInfo[]> {
+    this.isLoading.set(true);
+    this.error.set(null);
+    try {
+      return await invoke<DeviceUsageInfo[]>('get_device_usage', { source });
+    } catch (e) {
+      this.error.set(String(e));
+      throw e;
+    } finally {
+      this.isLoading.set(false);
+    }
+  }
+
   async getVolumeInfo(source: string): Promise<VolumeInfo> {
     this.isLoading.set(true);
     this.error.set(null);
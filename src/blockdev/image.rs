@@ -3,19 +3,176 @@
 //! Provides access to BTRFS filesystem images stored in regular files.
 
 use super::{BlockDevice, BlockDeviceError, Result};
-use memmap2::{MmapMut, MmapOptions};
+use memmap2::{Mmap, MmapMut, MmapOptions};
+use std::collections::BTreeMap;
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::Path;
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
 
 /// Default sector size for image files
 const DEFAULT_SECTOR_SIZE: u32 = 512;
 
+/// Default size of the regions mapped by [`ImageFile::open_windowed`]
+pub const DEFAULT_MMAP_WINDOW: u64 = 64 * 1024 * 1024;
+
+/// Mapping offsets must be multiples of the Windows allocation granularity
+const MMAP_WINDOW_ALIGN: u64 = 64 * 1024;
+
+/// Size of the pages buffered by the write-back cache
+const PAGE_SIZE: u64 = 4096;
+
+/// Dirty pages held before writes are pushed to the file
+const MAX_DIRTY_PAGES: usize = 1024;
+
+/// Maps `len` bytes of a file starting at `offset`
+type MapFn = fn(&File, u64, usize) -> std::io::Result<Mmap>;
+
+fn map_region(file: &File, offset: u64, len: usize) -> std::io::Result<Mmap> {
+    unsafe { MmapOptions::new().offset(offset).len(len).map(file) }
+}
+
+/// Fixed-size regions of an image mapped on demand
+///
+/// Only the most recently used region stays mapped, so address space use is
+/// bounded by the window size regardless of the image size.
+struct MmapWindows {
+    window: u64,
+    file_size: u64,
+    current: Option<(u64, Mmap)>,
+    map: MapFn,
+    /// Set once a mapping fails; all further reads use file I/O
+    failed: bool,
+}
+
+impl MmapWindows {
+    /// Copies `buf.len()` bytes at `offset` from mapped regions
+    ///
+    /// Returns false, with `buf` possibly partly filled, if a region could
+    /// not be mapped.
+    fn read(&mut self, file: &File, offset: u64, buf: &mut [u8]) -> bool {
+        let mut done = 0;
+        while done < buf.len() && !self.failed {
+            let pos = offset + done as u64;
+            let start = pos - pos % self.window;
+
+            if self.current.as_ref().is_none_or(|(s, _)| *s != start) {
+                // Unmap the old region before mapping the next one
+                self.current = None;
+                let len = self.window.min(self.file_size - start) as usize;
+                match (self.map)(file, start, len) {
+                    Ok(map) => self.current = Some((start, map)),
+                    Err(_) => self.failed = true,
+                }
+            }
+
+            if let Some((start, map)) = &self.current {
+                let from = (pos - start) as usize;
+                let n = (map.len() - from).min(buf.len() - done);
+                buf[done..done + n].copy_from_slice(&map[from..from + n]);
+                done += n;
+            }
+        }
+        !self.failed
+    }
+}
+
+/// The image file and the pages written to it but not yet flushed
+struct FileState {
+    file: File,
+    /// Dirty pages by page index; the page at the end of the image is
+    /// short if the size isn't a multiple of the page size
+    dirty: BTreeMap<u64, Vec<u8>>,
+}
+
+impl FileState {
+    /// Reads from the file, ignoring dirty pages
+    ///
+    /// A single read may return short, so this keeps going until `buf` is
+    /// filled. Anything past the end of the file reads as zeros, the same as
+    /// a hole.
+    fn read_file(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.file.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        buf[filled..].fill(0);
+        Ok(())
+    }
+
+    /// Copies dirty pages overlapping `offset..offset + buf.len()` into `buf`
+    fn overlay(&self, offset: u64, buf: &mut [u8]) {
+        let end = offset + buf.len() as u64;
+        let first = offset / PAGE_SIZE;
+        for (&index, page) in self.dirty.range(first..end.div_ceil(PAGE_SIZE)) {
+            let page_start = index * PAGE_SIZE;
+            let start = page_start.max(offset);
+            let stop = (page_start + page.len() as u64).min(end);
+            if start < stop {
+                buf[(start - offset) as usize..(stop - offset) as usize].copy_from_slice(
+                    &page[(start - page_start) as usize..(stop - page_start) as usize],
+                );
+            }
+        }
+    }
+
+    /// Buffers a write of `buf` at `offset`, which must end within `size`
+    ///
+    /// Pages the write only partly covers are read in first, so the rest of
+    /// the page keeps its contents when written back.
+    fn write(&mut self, offset: u64, buf: &[u8], size: u64) -> Result<()> {
+        let end = offset + buf.len() as u64;
+        for index in offset / PAGE_SIZE..end.div_ceil(PAGE_SIZE) {
+            let page_start = index * PAGE_SIZE;
+            let page_len = PAGE_SIZE.min(size - page_start);
+            let start = page_start.max(offset);
+            let stop = (page_start + page_len).min(end);
+
+            if !self.dirty.contains_key(&index) {
+                let mut page = vec![0u8; page_len as usize];
+                if stop - start < page_len {
+                    self.read_file(page_start, &mut page)?;
+                }
+                self.dirty.insert(index, page);
+            }
+            let page = self.dirty.get_mut(&index).expect("page was just inserted");
+            page[(start - page_start) as usize..(stop - page_start) as usize]
+                .copy_from_slice(&buf[(start - offset) as usize..(stop - offset) as usize]);
+        }
+
+        if self.dirty.len() > MAX_DIRTY_PAGES {
+            self.write_back()?;
+        }
+        Ok(())
+    }
+
+    /// Writes every dirty page to the file
+    fn write_back(&mut self) -> Result<()> {
+        for (index, page) in std::mem::take(&mut self.dirty) {
+            self.file.seek(SeekFrom::Start(index * PAGE_SIZE))?;
+            self.file.write_all(&page)?;
+        }
+        Ok(())
+    }
+}
+
 /// An image file backed block device
+///
+/// Writable images are written through a shared mapping when the whole
+/// file can be mapped. Otherwise writes are buffered in a page cache and
+/// reach the file on [`BlockDevice::flush_device`], when enough pages are
+/// dirty, or when the image is dropped.
 pub struct ImageFile {
-    file: RwLock<File>,
-    mmap: Option<MmapMut>,
+    file: RwLock<FileState>,
+    mmap: Option<RwLock<MmapMut>>,
+    windows: Option<Mutex<MmapWindows>>,
     size: u64,
     read_only: bool,
     use_mmap: bool,
@@ -24,6 +181,15 @@ pub struct ImageFile {
 impl ImageFile {
     /// Opens an image file
     pub fn open<P: AsRef<Path>>(path: P, read_only: bool) -> Result<Self> {
+        Self::open_with(path, read_only, true)
+    }
+
+    /// Opens an image file, mapping it only if `use_mmap` is set
+    ///
+    /// Without mmap every read and write goes through file I/O and the
+    /// page cache, which behaves the same on sparse files and network
+    /// shares where mapping may not. Read-only images are never mapped.
+    pub fn open_with<P: AsRef<Path>>(path: P, read_only: bool, use_mmap: bool) -> Result<Self> {
         let file = OpenOptions::new()
             .read(true)
             .write(!read_only)
@@ -33,7 +199,7 @@ impl ImageFile {
         let size = metadata.len();
 
         // Try to memory map for better performance on large files
-        let (mmap, use_mmap) = if size > 0 && !read_only {
+        let (mmap, use_mmap) = if use_mmap && size > 0 && !read_only {
             match unsafe { MmapOptions::new().map_mut(&file) } {
                 Ok(m) => (Some(m), true),
                 Err(_) => (None, false),
@@ -43,14 +209,54 @@ impl ImageFile {
         };
 
         Ok(Self {
-            file: RwLock::new(file),
-            mmap,
+            file: RwLock::new(FileState {
+                file,
+                dirty: BTreeMap::new(),
+            }),
+            mmap: mmap.map(RwLock::new),
+            windows: None,
             size,
             read_only,
             use_mmap,
         })
     }
 
+    /// Opens an image file, mapping `window`-sized regions on demand
+    ///
+    /// Unlike [`ImageFile::open`], the whole file is never mapped at once,
+    /// so images larger than the free address space (as on 32-bit Windows)
+    /// still benefit from mmap. The window is rounded up to a multiple of
+    /// 64 KiB. If a region can't be mapped, reads fall back to file I/O.
+    pub fn open_windowed<P: AsRef<Path>>(path: P, read_only: bool, window: u64) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(path.as_ref())?;
+        let size = file.metadata()?.len();
+
+        let windows = (size > 0).then(|| {
+            Mutex::new(MmapWindows {
+                window: window.max(1).next_multiple_of(MMAP_WINDOW_ALIGN),
+                file_size: size,
+                current: None,
+                map: map_region,
+                failed: false,
+            })
+        });
+
+        Ok(Self {
+            file: RwLock::new(FileState {
+                file,
+                dirty: BTreeMap::new(),
+            }),
+            mmap: None,
+            windows,
+            size,
+            read_only,
+            use_mmap: false,
+        })
+    }
+
     /// Creates a new image file with the specified size
     pub fn create<P: AsRef<Path>>(path: P, size: u64) -> Result<Self> {
         let file = OpenOptions::new()
@@ -73,8 +279,12 @@ impl ImageFile {
         };
 
         Ok(Self {
-            file: RwLock::new(file),
-            mmap,
+            file: RwLock::new(FileState {
+                file,
+                dirty: BTreeMap::new(),
+            }),
+            mmap: mmap.map(RwLock::new),
+            windows: None,
             size,
             read_only: false,
             use_mmap,
@@ -105,18 +315,28 @@ impl BlockDevice for ImageFile {
 
         let bytes_to_read = std::cmp::min(buf.len() as u64, self.size - offset) as usize;
 
-        if self.use_mmap {
-            if let Some(ref mmap) = self.mmap {
-                let src = &mmap[offset as usize..offset as usize + bytes_to_read];
-                buf[..bytes_to_read].copy_from_slice(src);
+        let buf = &mut buf[..bytes_to_read];
+
+        if let Some(mmap) = self.mmap.as_ref().filter(|_| self.use_mmap) {
+            let mmap = mmap.read().unwrap();
+            buf.copy_from_slice(&mmap[offset as usize..offset as usize + bytes_to_read]);
+            return Ok(bytes_to_read);
+        }
+
+        // Unflushed writes take precedence over what the file holds
+        if let Some(windows) = &self.windows {
+            let state = self.file.read().unwrap();
+            if windows.lock().unwrap().read(&state.file, offset, buf) {
+                state.overlay(offset, buf);
                 return Ok(bytes_to_read);
             }
         }
 
-        let mut file = self.file.write().unwrap();
-        file.seek(SeekFrom::Start(offset))?;
-        let n = file.read(&mut buf[..bytes_to_read])?;
-        Ok(n)
+        let mut state = self.file.write().unwrap();
+        state.read_file(offset, buf)?;
+        state.overlay(offset, buf);
+
+        Ok(bytes_to_read)
     }
 
     fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize> {
@@ -133,20 +353,38 @@ impl BlockDevice for ImageFile {
 
         let bytes_to_write = std::cmp::min(buf.len() as u64, self.size - offset) as usize;
 
-        // For mmap writes, we need mutable access - but mmap is not behind RwLock
-        // So we fall back to file I/O for writes when using shared references
-        let mut file = self.file.write().unwrap();
-        file.seek(SeekFrom::Start(offset))?;
-        let n = file.write(&buf[..bytes_to_write])?;
-        Ok(n)
+        let buf = &buf[..bytes_to_write];
+
+        if let Some(mmap) = self.mmap.as_ref().filter(|_| self.use_mmap) {
+            let mut mmap = mmap.write().unwrap();
+            mmap[offset as usize..offset as usize + bytes_to_write].copy_from_slice(buf);
+            return Ok(bytes_to_write);
+        }
+
+        self.file.write().unwrap().write(offset, buf, self.size)?;
+        Ok(bytes_to_write)
     }
 
     fn flush_device(&self) -> Result<()> {
-        self.file.write().unwrap().flush()?;
+        if let Some(mmap) = &self.mmap {
+            mmap.read().unwrap().flush()?;
+        }
+
+        let mut state = self.file.write().unwrap();
+        state.write_back()?;
+        state.file.flush()?;
         Ok(())
     }
 }
 
+impl Drop for ImageFile {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush_device() {
+            tracing::warn!("Failed to write back image file: {}", e);
+        }
+    }
+}
+
 // ImageFile is Send + Sync because all mutable state is behind RwLock
 unsafe impl Send for ImageFile {}
 unsafe impl Sync for ImageFile {}
@@ -195,4 +433,161 @@ mod tests {
         let result = img.write_at(0, b"test");
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_sparse_image_reads_zeros() {
+        let temp = NamedTempFile::new().unwrap();
+        let path = temp.path();
+
+        // Only the first block is ever written
+        let size = 64 * 1024 * 1024;
+        let img = ImageFile::create(path, size).unwrap();
+        img.write_at(0, b"header").unwrap();
+        img.flush_device().unwrap();
+
+        let hole = size / 2;
+        let mut buf = vec![0xFFu8; 64 * 1024];
+
+        // mmap path
+        assert!(img.use_mmap);
+        assert_eq!(img.read_at(hole, &mut buf).unwrap(), buf.len());
+        assert!(buf.iter().all(|&b| b == 0));
+
+        // File I/O path (read-only opens are not mapped)
+        let ro = ImageFile::open(path, true).unwrap();
+        assert!(!ro.use_mmap);
+        buf.fill(0xFF);
+        assert_eq!(ro.read_at(hole, &mut buf).unwrap(), buf.len());
+        assert!(buf.iter().all(|&b| b == 0));
+
+        // Reading the holes must not have allocated them
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let allocated = std::fs::metadata(path).unwrap().blocks() * 512;
+            assert!(allocated < size / 2, "allocated {} bytes", allocated);
+        }
+    }
+
+    fn patterned_image(len: usize) -> (NamedTempFile, Vec<u8>) {
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let temp = NamedTempFile::new().unwrap();
+        std::fs::write(temp.path(), &data).unwrap();
+        (temp, data)
+    }
+
+    #[test]
+    fn test_windowed_reads() {
+        let (temp, data) = patterned_image(256 * 1024 + 100);
+        let img = ImageFile::open_windowed(temp.path(), true, 1).unwrap();
+
+        // Rounded up to the mapping granularity
+        let window = img.windows.as_ref().unwrap().lock().unwrap().window;
+        assert_eq!(window, MMAP_WINDOW_ALIGN);
+
+        // Within one window, across a window boundary, and at the short tail
+        for (offset, len) in [(100, 1000), (60 * 1024, 10 * 1024), (256 * 1024, 100)] {
+            let mut buf = vec![0u8; len];
+            assert_eq!(img.read_at(offset as u64, &mut buf).unwrap(), len);
+            assert_eq!(buf, data[offset..offset + len]);
+        }
+        assert!(!img.windows.as_ref().unwrap().lock().unwrap().failed);
+    }
+
+    #[test]
+    fn test_open_without_mmap() {
+        let len = 3 * PAGE_SIZE as usize + 100;
+        let (temp, mut expected) = patterned_image(len);
+        let mapped = ImageFile::open(temp.path(), false).unwrap();
+        let unmapped = ImageFile::open_with(temp.path(), false, false).unwrap();
+        assert!(mapped.use_mmap);
+        assert!(!unmapped.use_mmap);
+        assert!(unmapped.mmap.is_none());
+
+        for (offset, size) in [(0, 100), (4000, 200), (len - 50, 50), (len - 10, 100)] {
+            let mut a = vec![0u8; size];
+            let mut b = vec![0u8; size];
+            let read = mapped.read_at(offset as u64, &mut a).unwrap();
+            assert_eq!(unmapped.read_at(offset as u64, &mut b).unwrap(), read);
+            assert_eq!(a, b);
+            assert_eq!(a[..read], expected[offset..offset + read]);
+        }
+
+        // Writes go through the page cache and land on flush
+        unmapped.write_at(5000, b"unmapped").unwrap();
+        unmapped.flush_device().unwrap();
+        expected[5000..5008].copy_from_slice(b"unmapped");
+        assert_eq!(std::fs::read(temp.path()).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_mmap_writes() {
+        let temp = NamedTempFile::new().unwrap();
+        let img = ImageFile::create(temp.path(), 64 * 1024).unwrap();
+        assert!(img.use_mmap);
+
+        img.write_at(1000, b"mapped").unwrap();
+        img.flush_device().unwrap();
+        assert_eq!(&std::fs::read(temp.path()).unwrap()[1000..1006], b"mapped");
+    }
+
+    #[test]
+    fn test_buffered_writes_at_tail() {
+        // The last page is short
+        let len = 3 * PAGE_SIZE as usize + 100;
+        let (temp, mut expected) = patterned_image(len);
+        let img = ImageFile::open_windowed(temp.path(), false, MMAP_WINDOW_ALIGN).unwrap();
+
+        // Part of one page, and a write running past the end of the image
+        assert_eq!(img.write_at(5000, &[0xAA; 10]).unwrap(), 10);
+        assert_eq!(img.write_at(len as u64 - 150, &[0xBB; 200]).unwrap(), 150);
+        expected[5000..5010].fill(0xAA);
+        expected[len - 150..].fill(0xBB);
+
+        // Reads see the writes before they reach the file
+        let mut buf = vec![0u8; len];
+        img.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, expected);
+        assert_ne!(std::fs::read(temp.path()).unwrap(), expected);
+
+        // Only the written bytes change, and the file doesn't grow
+        img.flush_device().unwrap();
+        assert_eq!(std::fs::read(temp.path()).unwrap(), expected);
+        assert!(img.file.read().unwrap().dirty.is_empty());
+
+        // Dropping the image writes back what is still dirty
+        img.write_at(0, b"dropped").unwrap();
+        drop(img);
+        assert_eq!(&std::fs::read(temp.path()).unwrap()[..7], b"dropped");
+    }
+
+    #[test]
+    fn test_windowed_map_failure_falls_back() {
+        let (temp, data) = patterned_image(200 * 1024);
+        let img = ImageFile::open_windowed(temp.path(), true, MMAP_WINDOW_ALIGN).unwrap();
+
+        // The first window maps; mapping the second one fails
+        fn fail_past_first(file: &File, offset: u64, len: usize) -> std::io::Result<Mmap> {
+            if offset > 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::OutOfMemory,
+                    "address space exhausted",
+                ));
+            }
+            map_region(file, offset, len)
+        }
+        img.windows.as_ref().unwrap().lock().unwrap().map = fail_past_first;
+
+        let mut buf = vec![0u8; 32 * 1024];
+        img.read_at(1000, &mut buf).unwrap();
+        assert_eq!(buf, data[1000..1000 + buf.len()]);
+
+        // Spans into the unmappable window, so it is re-read from the file
+        img.read_at(50 * 1024, &mut buf).unwrap();
+        assert_eq!(buf, data[50 * 1024..50 * 1024 + buf.len()]);
+        assert!(img.windows.as_ref().unwrap().lock().unwrap().failed);
+
+        img.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, data[..buf.len()]);
+    }
 }
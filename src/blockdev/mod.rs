@@ -4,13 +4,24 @@
 //! including physical disks and image files.
 
 pub mod image;
+pub mod multi;
+pub mod partition;
 pub mod physical;
+pub mod usage;
+pub mod vhd;
+pub mod vhdx;
 
 use std::io::{Read, Seek, Write};
+use std::sync::Arc;
 use thiserror::Error;
 
 pub use image::ImageFile;
-pub use physical::{DriveInfo, PhysicalDisk};
+pub use multi::MultiDevice;
+pub use partition::{Partition, PartitionDevice};
+pub use physical::{AlignedBuffer, DeviceIds, DriveInfo, PhysicalDisk, RetryPolicy};
+pub use usage::{device_holders, is_device_in_use, DeviceHolders};
+pub use vhd::VhdFile;
+pub use vhdx::VhdxFile;
 
 /// Errors that can occur during block device operations
 #[derive(Error, Debug)]
@@ -35,6 +46,29 @@ pub enum BlockDeviceError {
 
     #[error("Windows API error: {0}")]
     WindowsError(String),
+
+    #[error("Transient device error: {0}")]
+    Transient(String),
+
+    #[error("Invalid disk image: {0}")]
+    InvalidImage(String),
+}
+
+impl BlockDeviceError {
+    /// Returns true if the operation may succeed when retried
+    ///
+    /// Covers device hiccups such as timeouts and bus resets, as opposed to
+    /// bad offsets or missing devices, which fail the same way every time.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Transient(_) => true,
+            Self::Io(e) => matches!(
+                e.kind(),
+                std::io::ErrorKind::Interrupted | std::io::ErrorKind::TimedOut
+            ),
+            _ => false,
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, BlockDeviceError>;
@@ -47,6 +81,14 @@ pub trait BlockDevice: Send + Sync {
     /// Returns the sector size of the device
     fn sector_size(&self) -> u32;
 
+    /// Returns the transfer size the device handles most efficiently
+    ///
+    /// Bulk reads should be sized to a multiple of this. Defaults to the
+    /// sector size.
+    fn optimal_io_size(&self) -> u32 {
+        self.sector_size()
+    }
+
     /// Returns true if the device is read-only
     fn is_read_only(&self) -> bool;
 
@@ -58,17 +100,82 @@ pub trait BlockDevice: Send + Sync {
 
     /// Flushes any buffered data to the device
     fn flush_device(&self) -> Result<()>;
+
+    /// Asks the device to eject its media so it can be unplugged safely
+    ///
+    /// Callers flush first. Devices without removable media, such as image
+    /// files, do nothing.
+    fn eject(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns the member device with BTRFS devid `devid`
+    ///
+    /// Only volumes spanning several devices have members; a single device
+    /// returns `None` and serves its own devid directly.
+    fn member(&self, devid: u64) -> Option<Arc<dyn BlockDevice>> {
+        let _ = devid;
+        None
+    }
 }
 
 /// Opens a block device from the given path
 ///
-/// Automatically detects whether the path refers to a physical disk
-/// or an image file.
+/// Automatically detects whether the path refers to a physical disk,
+/// a VHD or VHDX image (by extension) or a raw image file. A physical disk
+/// path with a partition suffix, as in `\\.\PhysicalDrive1p2`, opens that
+/// partition of the disk.
 pub fn open(path: &str, read_only: bool) -> Result<Box<dyn BlockDevice>> {
+    open_with(path, read_only, OpenOptions::default())
+}
+
+/// How [`open_with`] accesses a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenOptions {
+    /// Whether raw image files may be memory mapped; without it they are
+    /// always read and written with file I/O
+    pub use_mmap: bool,
+    /// How physical disks reissue requests that fail with transient errors
+    pub retry: RetryPolicy,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self {
+            use_mmap: true,
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+/// Opens a block device from the given path, like [`open`], with `options`
+pub fn open_with(
+    path: &str,
+    read_only: bool,
+    options: OpenOptions,
+) -> Result<Box<dyn BlockDevice>> {
+    let extension = std::path::Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+
     if path.starts_with("\\\\.\\PhysicalDrive") || path.starts_with("//./PhysicalDrive") {
-        Ok(Box::new(PhysicalDisk::open(path, read_only)?))
+        let (disk, index) = partition::split_partition_spec(path);
+        let disk = PhysicalDisk::open(disk, read_only)?.with_retry_policy(options.retry);
+        match index {
+            Some(index) => Ok(Box::new(PartitionDevice::open(Box::new(disk), index)?)),
+            None => Ok(Box::new(disk)),
+        }
+    } else if extension.as_deref() == Some("vhd") {
+        Ok(Box::new(VhdFile::open(path, read_only)?))
+    } else if extension.as_deref() == Some("vhdx") {
+        Ok(Box::new(VhdxFile::open(path, read_only)?))
     } else {
-        Ok(Box::new(ImageFile::open(path, read_only)?))
+        Ok(Box::new(ImageFile::open_with(
+            path,
+            read_only,
+            options.use_mmap,
+        )?))
     }
 }
 
@@ -87,6 +194,27 @@ pub fn list_physical_drives() -> Result<Vec<DriveInfo>> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_open_with_mmap_disabled() {
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        let temp = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(temp.path(), &data).unwrap();
+        let path = temp.path().to_str().unwrap();
+
+        let no_mmap = OpenOptions {
+            use_mmap: false,
+            ..Default::default()
+        };
+        for device in [
+            open(path, false).unwrap(),
+            open_with(path, false, no_mmap).unwrap(),
+        ] {
+            let mut buf = vec![0u8; 10_000];
+            assert_eq!(device.read_at(30_000, &mut buf).unwrap(), buf.len());
+            assert_eq!(buf, data[30_000..40_000]);
+        }
+    }
+
     #[test]
     fn test_block_device_error_display() {
         let err = BlockDeviceError::NotFound("test".to_string());
@@ -110,6 +238,9 @@ mod tests {
 
         let err = BlockDeviceError::WindowsError("test".to_string());
         assert!(format!("{}", err).contains("test"));
+
+        let err = BlockDeviceError::InvalidImage("bad footer".to_string());
+        assert_eq!(format!("{}", err), "Invalid disk image: bad footer");
     }
 
     #[test]
@@ -127,6 +258,30 @@ mod tests {
         assert!(device.is_read_only());
     }
 
+    #[test]
+    fn test_open_dispatches_on_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["disk.VHD", "disk.vhdx"] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, vec![0u8; 1024 * 1024]).unwrap();
+
+            // A raw image with a disk image extension is parsed, not passed through
+            let result = open(path.to_str().unwrap(), true);
+            assert!(matches!(result, Err(BlockDeviceError::InvalidImage(_))));
+        }
+    }
+
+    #[test]
+    fn test_optimal_io_size_defaults_to_sector_size() {
+        use tempfile::NamedTempFile;
+
+        let temp = NamedTempFile::new().unwrap();
+        std::fs::write(temp.path(), vec![0u8; 1024]).unwrap();
+
+        let device = open(temp.path().to_str().unwrap(), true).unwrap();
+        assert_eq!(device.optimal_io_size(), device.sector_size());
+    }
+
     #[test]
     fn test_open_nonexistent_file() {
         let result = open("/nonexistent/path/to/file.img", true);
@@ -0,0 +1,121 @@
+//! Multi-device volumes
+//!
+//! A BTRFS filesystem can span several devices, each identified by the
+//! devid in its superblock's device item. Chunk stripes name the device
+//! they live on, so reads are routed to the member with that devid.
+
+use super::{BlockDevice, BlockDeviceError, Result};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Several block devices making up one filesystem, keyed by devid
+///
+/// As a [`BlockDevice`] it reads and writes its primary member, the one
+/// with the lowest devid, so the superblock is read from there; stripes on
+/// other devices are reached through [`BlockDevice::member`].
+pub struct MultiDevice {
+    members: BTreeMap<u64, Arc<dyn BlockDevice>>,
+}
+
+impl MultiDevice {
+    /// Creates a volume from `(devid, device)` pairs
+    pub fn new(members: impl IntoIterator<Item = (u64, Arc<dyn BlockDevice>)>) -> Result<Self> {
+        let mut map = BTreeMap::new();
+        for (devid, device) in members {
+            if map.insert(devid, device).is_some() {
+                return Err(BlockDeviceError::Io(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("device {} was given more than once", devid),
+                )));
+            }
+        }
+        if map.is_empty() {
+            return Err(BlockDeviceError::NotFound("no member devices".to_string()));
+        }
+        Ok(Self { members: map })
+    }
+
+    /// Returns the devids of the members, in ascending order
+    pub fn devids(&self) -> Vec<u64> {
+        self.members.keys().copied().collect()
+    }
+
+    fn primary(&self) -> &Arc<dyn BlockDevice> {
+        self.members.values().next().expect("at least one member")
+    }
+}
+
+impl BlockDevice for MultiDevice {
+    fn size(&self) -> u64 {
+        self.primary().size()
+    }
+
+    fn sector_size(&self) -> u32 {
+        self.primary().sector_size()
+    }
+
+    fn optimal_io_size(&self) -> u32 {
+        self.primary().optimal_io_size()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.members.values().any(|device| device.is_read_only())
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        self.primary().read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize> {
+        self.primary().write_at(offset, buf)
+    }
+
+    fn flush_device(&self) -> Result<()> {
+        for device in self.members.values() {
+            device.flush_device()?;
+        }
+        Ok(())
+    }
+
+    fn eject(&self) -> Result<()> {
+        for device in self.members.values() {
+            device.eject()?;
+        }
+        Ok(())
+    }
+
+    fn member(&self, devid: u64) -> Option<Arc<dyn BlockDevice>> {
+        self.members.get(&devid).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::MemDevice;
+
+    fn mem(fill: u8) -> Arc<dyn BlockDevice> {
+        Arc::new(MemDevice::new(vec![fill; 4096]))
+    }
+
+    #[test]
+    fn test_routes_to_members() {
+        let multi = MultiDevice::new([(3, mem(3)), (1, mem(1))]).unwrap();
+        assert_eq!(multi.devids(), [1, 3]);
+
+        // Plain reads go to the lowest devid
+        let mut buf = [0u8; 4];
+        multi.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, [1; 4]);
+
+        multi.member(3).unwrap().read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, [3; 4]);
+        assert!(multi.member(2).is_none());
+    }
+
+    #[test]
+    fn test_rejects_bad_member_lists() {
+        assert!(MultiDevice::new([(1, mem(0)), (1, mem(0))]).is_err());
+        assert!(MultiDevice::new(Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,437 @@
+//! Partitions of a partitioned disk
+//!
+//! Reads the GPT or MBR partition table of a disk so a single partition can
+//! be opened as a block device of its own. Only the four primary MBR
+//! entries are listed; logical partitions inside an extended partition are
+//! not. GPT header and table checksums are not verified, as the BTRFS magic
+//! check on opening catches a table that points at the wrong place.
+
+use super::{BlockDevice, BlockDeviceError, Result};
+use crate::core::{BTRFS_MAGIC, SUPERBLOCK_OFFSET};
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Size of the MBR and of the smallest logical block
+const MBR_SIZE: usize = 512;
+
+/// Offset of the four MBR partition entries
+const MBR_TABLE_OFFSET: usize = 446;
+
+/// MBR partition type of the protective entry covering a GPT disk
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+
+/// Size of the GPT header fields read here
+const GPT_HEADER_SIZE: usize = 92;
+
+/// Smallest valid GPT partition entry
+const GPT_ENTRY_MIN_SIZE: usize = 128;
+
+/// Upper bound on GPT entries, well above the usual 128
+const GPT_MAX_ENTRIES: u32 = 1024;
+
+/// Offset of the magic within the superblock
+const SUPERBLOCK_MAGIC_OFFSET: u64 = 0x40;
+
+/// A partition table entry, in bytes from the start of the disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Partition {
+    /// 1-based partition number: the MBR slot or GPT entry index
+    pub index: u32,
+    /// Offset of the first byte
+    pub start: u64,
+    /// Length in bytes
+    pub size: u64,
+}
+
+/// Reads exactly `buf.len()` bytes at `offset`
+fn read_exact_at(device: &dyn BlockDevice, offset: u64, buf: &mut [u8]) -> Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match device.read_at(offset + filled as u64, &mut buf[filled..])? {
+            0 => return Err(BlockDeviceError::ReadBeyondEnd),
+            n => filled += n,
+        }
+    }
+    Ok(())
+}
+
+/// Lists the partitions of a disk
+///
+/// Returns an empty list for a disk without a partition table.
+pub fn partitions(device: &dyn BlockDevice) -> Result<Vec<Partition>> {
+    if device.size() < MBR_SIZE as u64 {
+        return Ok(Vec::new());
+    }
+    let mut mbr = [0u8; MBR_SIZE];
+    read_exact_at(device, 0, &mut mbr)?;
+    if mbr[510..512] != [0x55, 0xAA] {
+        return Ok(Vec::new());
+    }
+
+    let entries = parse_mbr(&mbr);
+    if entries
+        .iter()
+        .any(|&(kind, _, _)| kind == MBR_TYPE_GPT_PROTECTIVE)
+    {
+        // The logical block size is usually the sector size, but images of
+        // 4Kn disks are often presented with 512-byte sectors
+        let mut block_sizes = vec![device.sector_size() as u64, 512, 4096];
+        block_sizes.dedup();
+        for block_size in block_sizes {
+            if let Some(partitions) = read_gpt(device, block_size)? {
+                return Ok(partitions);
+            }
+        }
+        return Err(BlockDeviceError::InvalidImage(
+            "protective MBR without a GPT header".to_string(),
+        ));
+    }
+
+    // MBR entries count 512-byte sectors regardless of the device
+    Ok(entries
+        .iter()
+        .zip(1..)
+        .filter(|&(&(kind, _, sectors), _)| kind != 0 && sectors != 0)
+        .map(|(&(_, first, sectors), index)| Partition {
+            index,
+            start: first as u64 * MBR_SIZE as u64,
+            size: sectors as u64 * MBR_SIZE as u64,
+        })
+        .collect())
+}
+
+/// Returns the type, first sector and sector count of each MBR entry
+fn parse_mbr(mbr: &[u8]) -> Vec<(u8, u32, u32)> {
+    (0..4)
+        .map(|slot| {
+            let entry = &mbr[MBR_TABLE_OFFSET + slot * 16..][..16];
+            (
+                entry[4],
+                LittleEndian::read_u32(&entry[8..12]),
+                LittleEndian::read_u32(&entry[12..16]),
+            )
+        })
+        .collect()
+}
+
+/// Reads the GPT assuming `block_size`-byte logical blocks
+///
+/// Returns `None` if there is no GPT header at LBA 1 for that block size.
+fn read_gpt(device: &dyn BlockDevice, block_size: u64) -> Result<Option<Vec<Partition>>> {
+    if device.size() < block_size * 2 {
+        return Ok(None);
+    }
+    let mut header = [0u8; GPT_HEADER_SIZE];
+    read_exact_at(device, block_size, &mut header)?;
+    if &header[..8] != b"EFI PART" {
+        return Ok(None);
+    }
+
+    let table_lba = LittleEndian::read_u64(&header[72..80]);
+    let count = LittleEndian::read_u32(&header[80..84]);
+    let entry_size = LittleEndian::read_u32(&header[84..88]) as usize;
+    if count > GPT_MAX_ENTRIES || entry_size < GPT_ENTRY_MIN_SIZE {
+        return Err(BlockDeviceError::InvalidImage(format!(
+            "GPT header has {} entries of {} bytes",
+            count, entry_size
+        )));
+    }
+
+    let mut table = vec![0u8; count as usize * entry_size];
+    let table_offset = table_lba
+        .checked_mul(block_size)
+        .filter(|&offset| offset.saturating_add(table.len() as u64) <= device.size())
+        .ok_or_else(|| {
+            BlockDeviceError::InvalidImage("GPT partition table is past the end".to_string())
+        })?;
+    read_exact_at(device, table_offset, &mut table)?;
+
+    let mut partitions = Vec::new();
+    for (entry, index) in table.chunks_exact(entry_size).zip(1..) {
+        // An all-zero type GUID marks an unused entry
+        if entry[..16].iter().all(|&b| b == 0) {
+            continue;
+        }
+        let first = LittleEndian::read_u64(&entry[32..40]);
+        let last = LittleEndian::read_u64(&entry[40..48]);
+        let (Some(start), Some(end)) = (
+            first.checked_mul(block_size),
+            last.checked_add(1)
+                .and_then(|end| end.checked_mul(block_size)),
+        ) else {
+            continue;
+        };
+        if end > start {
+            partitions.push(Partition {
+                index,
+                start,
+                size: end - start,
+            });
+        }
+    }
+    Ok(Some(partitions))
+}
+
+/// Splits a `\\.\PhysicalDrive1p2`-style path into the disk path and the
+/// partition number
+///
+/// Paths without a partition suffix are returned unchanged with `None`.
+pub fn split_partition_spec(path: &str) -> (&str, Option<u32>) {
+    let Some(drive) = path.rfind("PhysicalDrive") else {
+        return (path, None);
+    };
+    let tail = &path[drive + "PhysicalDrive".len()..];
+    let digits = tail
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(tail.len());
+    if digits == 0 {
+        return (path, None);
+    }
+
+    let split = path.len() - tail.len() + digits;
+    match path[split..].strip_prefix('p').map(str::parse::<u32>) {
+        Some(Ok(index)) if index > 0 => (&path[..split], Some(index)),
+        _ => (path, None),
+    }
+}
+
+/// One partition of a disk, presented as a device starting at offset 0
+pub struct PartitionDevice {
+    disk: Box<dyn BlockDevice>,
+    partition: Partition,
+}
+
+impl PartitionDevice {
+    /// Opens partition `index` of `disk`
+    ///
+    /// Fails unless the partition lies within the disk and holds a BTRFS
+    /// superblock magic.
+    pub fn open(disk: Box<dyn BlockDevice>, index: u32) -> Result<Self> {
+        let partition = partitions(disk.as_ref())?
+            .into_iter()
+            .find(|p| p.index == index)
+            .ok_or_else(|| BlockDeviceError::NotFound(format!("partition {}", index)))?;
+        if partition.start.saturating_add(partition.size) > disk.size() {
+            return Err(BlockDeviceError::InvalidImage(format!(
+                "partition {} extends past the end of the disk",
+                index
+            )));
+        }
+
+        let device = Self { disk, partition };
+        let mut magic = [0u8; 8];
+        let magic_offset = SUPERBLOCK_OFFSET + SUPERBLOCK_MAGIC_OFFSET;
+        if magic_offset + magic.len() as u64 > device.partition.size
+            || read_exact_at(&device, magic_offset, &mut magic).is_err()
+            || magic != BTRFS_MAGIC
+        {
+            return Err(BlockDeviceError::InvalidImage(format!(
+                "partition {} does not contain a BTRFS filesystem",
+                index
+            )));
+        }
+        Ok(device)
+    }
+
+    /// Returns the partition table entry being accessed
+    pub fn partition(&self) -> Partition {
+        self.partition
+    }
+
+    /// Returns the number of bytes from `offset` that fit in the partition
+    fn clamp(&self, offset: u64, len: usize) -> Result<usize> {
+        if offset >= self.partition.size {
+            return Err(BlockDeviceError::InvalidOffset {
+                offset,
+                size: self.partition.size,
+            });
+        }
+        Ok(len.min((self.partition.size - offset) as usize))
+    }
+}
+
+impl BlockDevice for PartitionDevice {
+    fn size(&self) -> u64 {
+        self.partition.size
+    }
+
+    fn sector_size(&self) -> u32 {
+        self.disk.sector_size()
+    }
+
+    fn optimal_io_size(&self) -> u32 {
+        self.disk.optimal_io_size()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.disk.is_read_only()
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let len = self.clamp(offset, buf.len())?;
+        self.disk
+            .read_at(self.partition.start + offset, &mut buf[..len])
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize> {
+        let len = self.clamp(offset, buf.len())?;
+        self.disk
+            .write_at(self.partition.start + offset, &buf[..len])
+    }
+
+    fn flush_device(&self) -> Result<()> {
+        self.disk.flush_device()
+    }
+
+    fn eject(&self) -> Result<()> {
+        self.disk.eject()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::MemDevice;
+
+    /// Size of the test disks (4 MiB)
+    const DISK_SIZE: usize = 4 * 1024 * 1024;
+
+    /// Writes the BTRFS magic into a filesystem starting at `start`
+    fn put_magic(disk: &mut [u8], start: u64) {
+        let offset = (start + SUPERBLOCK_OFFSET + SUPERBLOCK_MAGIC_OFFSET) as usize;
+        disk[offset..offset + 8].copy_from_slice(&BTRFS_MAGIC);
+    }
+
+    /// Writes an MBR with `(type, first sector, sector count)` entries
+    fn put_mbr(disk: &mut [u8], entries: &[(u8, u32, u32)]) {
+        for (slot, &(kind, first, sectors)) in entries.iter().enumerate() {
+            let entry = &mut disk[MBR_TABLE_OFFSET + slot * 16..][..16];
+            entry[4] = kind;
+            LittleEndian::write_u32(&mut entry[8..12], first);
+            LittleEndian::write_u32(&mut entry[12..16], sectors);
+        }
+        disk[510] = 0x55;
+        disk[511] = 0xAA;
+    }
+
+    /// Builds a 512-byte-block GPT disk with `(first, last)` LBA entries,
+    /// leaving the first entry slot unused
+    fn gpt_disk(entries: &[(u64, u64)]) -> Vec<u8> {
+        let mut disk = vec![0u8; DISK_SIZE];
+        put_mbr(&mut disk, &[(MBR_TYPE_GPT_PROTECTIVE, 1, u32::MAX)]);
+
+        let header = &mut disk[512..512 + GPT_HEADER_SIZE];
+        header[..8].copy_from_slice(b"EFI PART");
+        LittleEndian::write_u64(&mut header[72..80], 2);
+        LittleEndian::write_u32(&mut header[80..84], 128);
+        LittleEndian::write_u32(&mut header[84..88], 128);
+
+        for (slot, &(first, last)) in entries.iter().enumerate() {
+            let entry = &mut disk[1024 + (slot + 1) * 128..][..128];
+            entry[..16].fill(0xAF);
+            LittleEndian::write_u64(&mut entry[32..40], first);
+            LittleEndian::write_u64(&mut entry[40..48], last);
+        }
+        disk
+    }
+
+    #[test]
+    fn test_gpt_partitions() {
+        // A 1 MiB partition, then a 2 MiB one holding BTRFS
+        let mut disk = gpt_disk(&[(2048, 4095), (4096, 8191)]);
+        put_magic(&mut disk, 4096 * 512);
+        disk[4096 * 512] = 0x5A;
+        let disk = Box::new(MemDevice::new(disk));
+
+        assert_eq!(
+            partitions(disk.as_ref()).unwrap(),
+            [
+                Partition {
+                    index: 2,
+                    start: 0x100000,
+                    size: 0x100000,
+                },
+                Partition {
+                    index: 3,
+                    start: 0x200000,
+                    size: 0x200000,
+                },
+            ]
+        );
+
+        let device = PartitionDevice::open(disk, 3).unwrap();
+        assert_eq!(device.size(), 0x200000);
+        let mut buf = [0u8; 1];
+        device.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, [0x5A]);
+
+        // Writes land inside the partition and stop at its end
+        assert_eq!(device.write_at(0x200000 - 2, &[1, 2, 3]).unwrap(), 2);
+        assert!(matches!(
+            device.read_at(0x200000, &mut buf),
+            Err(BlockDeviceError::InvalidOffset { .. })
+        ));
+    }
+
+    #[test]
+    fn test_mbr_partitions() {
+        let mut disk = vec![0u8; DISK_SIZE];
+        put_mbr(
+            &mut disk,
+            &[(0x83, 2048, 2048), (0, 0, 0), (0x83, 4096, 4096)],
+        );
+        put_magic(&mut disk, 2048 * 512);
+        let disk = Box::new(MemDevice::new(disk));
+
+        let found = partitions(disk.as_ref()).unwrap();
+        assert_eq!(found.iter().map(|p| p.index).collect::<Vec<_>>(), [1, 3]);
+
+        let device = PartitionDevice::open(disk, 1).unwrap();
+        assert_eq!(device.partition().start, 0x100000);
+    }
+
+    #[test]
+    fn test_open_rejects_bad_partitions() {
+        let open =
+            |disk: Vec<u8>, index| PartitionDevice::open(Box::new(MemDevice::new(disk)), index);
+
+        // No BTRFS magic
+        let disk = gpt_disk(&[(2048, 4095)]);
+        assert!(matches!(
+            open(disk.clone(), 2),
+            Err(BlockDeviceError::InvalidImage(_))
+        ));
+        // No such partition
+        assert!(matches!(open(disk, 1), Err(BlockDeviceError::NotFound(_))));
+        // Past the end of the disk
+        let disk = gpt_disk(&[(4096, 16383)]);
+        assert!(matches!(
+            open(disk, 2),
+            Err(BlockDeviceError::InvalidImage(_))
+        ));
+        // Unpartitioned
+        assert!(partitions(&MemDevice::new(vec![0u8; DISK_SIZE]))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_split_partition_spec() {
+        assert_eq!(
+            split_partition_spec("\\\\.\\PhysicalDrive1p2"),
+            ("\\\\.\\PhysicalDrive1", Some(2))
+        );
+        assert_eq!(
+            split_partition_spec("//./PhysicalDrive12p3"),
+            ("//./PhysicalDrive12", Some(3))
+        );
+        for path in [
+            "\\\\.\\PhysicalDrive1",
+            "\\\\.\\PhysicalDrive1p0",
+            "\\\\.\\PhysicalDrive1px",
+            "\\\\.\\PhysicalDrivep2",
+            "disk.img",
+        ] {
+            assert_eq!(split_partition_spec(path), (path, None));
+        }
+    }
+}
@@ -2,8 +2,12 @@
 //!
 //! Provides raw access to physical drives using Windows APIs.
 
-use super::{BlockDevice, BlockDeviceError, Result};
+use super::{BlockDevice, BlockDeviceError, PartitionDevice, Result};
+use std::alloc::{self, Layout};
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
 #[cfg(windows)]
 use windows::{
@@ -14,7 +18,12 @@ use windows::{
             CreateFileW, FlushFileBuffers, ReadFile, SetFilePointerEx, WriteFile,
             FILE_ATTRIBUTE_NORMAL, FILE_BEGIN, FILE_SHARE_READ, FILE_SHARE_WRITE, OPEN_EXISTING,
         },
-        System::Ioctl::{DISK_GEOMETRY, IOCTL_DISK_GET_DRIVE_GEOMETRY, IOCTL_DISK_GET_LENGTH_INFO},
+        System::Ioctl::{
+            PropertyStandardQuery, StorageAccessAlignmentProperty, StorageDeviceProperty,
+            DISK_GEOMETRY, FSCTL_DISMOUNT_VOLUME, FSCTL_LOCK_VOLUME, IOCTL_DISK_GET_DRIVE_GEOMETRY,
+            IOCTL_DISK_GET_LENGTH_INFO, IOCTL_STORAGE_EJECT_MEDIA, IOCTL_STORAGE_QUERY_PROPERTY,
+            STORAGE_PROPERTY_QUERY,
+        },
         System::IO::DeviceIoControl,
     },
 };
@@ -32,8 +41,210 @@ pub struct DriveInfo {
     pub sector_size: u32,
     /// Model name if available
     pub model: Option<String>,
+    /// Serial number if available
+    pub serial: Option<String>,
+}
+
+/// Size of the fixed part of a STORAGE_DEVICE_DESCRIPTOR
+const DEVICE_DESCRIPTOR_SIZE: usize = 36;
+
+/// Identification strings from a STORAGE_DEVICE_DESCRIPTOR
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceIds {
+    /// Vendor and product ID, e.g. "Samsung SSD 870 EVO 1TB"
+    pub model: Option<String>,
+    /// Serial number
+    pub serial: Option<String>,
+}
+
+/// Extracts the model and serial number from a raw
+/// STORAGE_DEVICE_DESCRIPTOR
+///
+/// The strings are NUL-terminated at offsets given in the descriptor, zero
+/// when absent, and are often padded with spaces. Returns `None` if the
+/// descriptor is truncated.
+pub fn parse_device_descriptor(data: &[u8]) -> Option<DeviceIds> {
+    if data.len() < DEVICE_DESCRIPTOR_SIZE {
+        return None;
+    }
+    let field = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+    let string = |offset: usize| {
+        let start = field(offset) as usize;
+        if start == 0 || start >= data.len() {
+            return None;
+        }
+        let bytes = &data[start..];
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        let text = String::from_utf8_lossy(&bytes[..end]).trim().to_string();
+        (!text.is_empty()).then_some(text)
+    };
+
+    // Version, Size, DeviceType..CommandQueueing, VendorIdOffset,
+    // ProductIdOffset, ProductRevisionOffset, SerialNumberOffset, ...
+    let model = match (string(12), string(16)) {
+        (Some(vendor), Some(product)) => Some(format!("{} {}", vendor, product)),
+        (vendor, product) => vendor.or(product),
+    };
+    Some(DeviceIds {
+        model,
+        serial: string(24),
+    })
+}
+
+/// Size of a STORAGE_ACCESS_ALIGNMENT_DESCRIPTOR
+const ALIGNMENT_DESCRIPTOR_SIZE: usize = 28;
+
+/// Extracts the physical sector size from a raw
+/// STORAGE_ACCESS_ALIGNMENT_DESCRIPTOR
+///
+/// Returns `None` if the descriptor is truncated or the size is not a
+/// power of two, as some USB bridges report garbage here.
+pub fn parse_alignment_descriptor(data: &[u8]) -> Option<u32> {
+    if data.len() < ALIGNMENT_DESCRIPTOR_SIZE {
+        return None;
+    }
+    let field = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+
+    // Version, Size, BytesPerCacheLine, BytesOffsetForCacheAlignment,
+    // BytesPerLogicalSector, BytesPerPhysicalSector, ...
+    if (field(4) as usize) < ALIGNMENT_DESCRIPTOR_SIZE {
+        return None;
+    }
+    let physical = field(20).max(field(16));
+    physical.is_power_of_two().then_some(physical)
+}
+
+/// Win32 error codes a flaky drive or bridge can return for a request that
+/// succeeds when reissued
+///
+/// ERROR_DEVICE_NOT_CONNECTED (1167) is left out: an unplugged device does
+/// not come back under the same handle, so retrying only delays the error.
+const TRANSIENT_WIN32_ERRORS: [u32; 6] = [
+    21,   // ERROR_NOT_READY
+    23,   // ERROR_CRC
+    31,   // ERROR_GEN_FAILURE
+    121,  // ERROR_SEM_TIMEOUT
+    170,  // ERROR_BUSY
+    1117, // ERROR_IO_DEVICE
+];
+
+/// Returns true if a Win32 error code is worth retrying
+pub fn is_transient_win32_error(code: u32) -> bool {
+    TRANSIENT_WIN32_ERRORS.contains(&code)
+}
+
+/// Maps a failed Windows call to a block device error, marking errors that
+/// may clear up on retry as transient
+#[cfg(windows)]
+fn windows_error(e: windows::core::Error) -> BlockDeviceError {
+    // HRESULT_FROM_WIN32 puts the Win32 code in the low word of facility 7
+    let hresult = e.code().0 as u32;
+    if hresult & 0xFFFF_0000 == 0x8007_0000 && is_transient_win32_error(hresult & 0xFFFF) {
+        BlockDeviceError::Transient(e.to_string())
+    } else {
+        BlockDeviceError::WindowsError(e.to_string())
+    }
+}
+
+/// How often to reissue a disk request that failed with a transient error
+///
+/// The default makes a single attempt, so errors are reported immediately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total attempts, including the first
+    pub attempts: u32,
+    /// Delay before the first retry, doubled for each one after
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 1,
+            backoff: Duration::ZERO,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Creates a policy making up to `attempts` tries
+    pub fn new(attempts: u32, backoff: Duration) -> Self {
+        Self { attempts, backoff }
+    }
+
+    /// Runs `op` until it succeeds, fails with a permanent error, or the
+    /// attempts run out
+    pub fn run<T>(&self, mut op: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut delay = self.backoff;
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Err(e) if e.is_transient() && attempt < self.attempts => {
+                    tracing::warn!(
+                        "Attempt {} of {} failed, retrying: {}",
+                        attempt,
+                        self.attempts,
+                        e
+                    );
+                    std::thread::sleep(delay);
+                    delay = delay.saturating_mul(2);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// A zeroed heap buffer whose start address is aligned to a sector boundary
+///
+/// Handles opened with `FILE_FLAG_NO_BUFFERING` require the memory buffer,
+/// not just the offset and length, to be sector-aligned.
+pub struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+    layout: Layout,
 }
 
+impl AlignedBuffer {
+    /// Allocates `len` zeroed bytes aligned to `align`, which must be a power of two
+    pub fn new(len: usize, align: usize) -> Self {
+        let layout =
+            Layout::from_size_align(len.max(1), align).expect("sector size must be a power of two");
+        // Safety: the layout has a non-zero size
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        Self { ptr, len, layout }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // Safety: ptr points to at least len initialized bytes owned by self
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // Safety: as for deref, and &mut self guarantees exclusive access
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // Safety: allocated in new() with the same layout
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+// Safety: AlignedBuffer owns its allocation exclusively
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
 /// A physical disk device
 pub struct PhysicalDisk {
     #[cfg(windows)]
@@ -43,8 +254,10 @@ pub struct PhysicalDisk {
     path: String,
     size: u64,
     sector_size: u32,
+    optimal_io_size: u32,
     read_only: bool,
     position: AtomicU64,
+    retry: RetryPolicy,
 }
 
 impl PhysicalDisk {
@@ -79,14 +292,19 @@ impl PhysicalDisk {
         }
 
         let (size, sector_size) = Self::get_disk_geometry(handle)?;
+        let optimal_io_size = Self::query_alignment(handle)
+            .filter(|&io_size| io_size >= sector_size)
+            .unwrap_or(sector_size);
 
         Ok(Self {
             handle,
             path: path.to_string(),
             size,
             sector_size,
+            optimal_io_size,
             read_only,
             position: AtomicU64::new(0),
+            retry: RetryPolicy::default(),
         })
     }
 
@@ -98,6 +316,12 @@ impl PhysicalDisk {
         )))
     }
 
+    /// Opens partition `index` of a physical disk, numbered from 1 in the
+    /// order of its GPT or MBR entries
+    pub fn open_partition(path: &str, index: u32, read_only: bool) -> Result<PartitionDevice> {
+        PartitionDevice::open(Box::new(Self::open(path, read_only)?), index)
+    }
+
     #[cfg(windows)]
     fn get_disk_geometry(handle: HANDLE) -> Result<(u64, u32)> {
         // Get disk length
@@ -138,6 +362,77 @@ impl PhysicalDisk {
         Ok((length_info as u64, geometry.BytesPerSector))
     }
 
+    /// Queries the physical sector size, which may exceed the logical one
+    #[cfg(windows)]
+    fn query_alignment(handle: HANDLE) -> Option<u32> {
+        let query = STORAGE_PROPERTY_QUERY {
+            PropertyId: StorageAccessAlignmentProperty,
+            QueryType: PropertyStandardQuery,
+            AdditionalParameters: [0],
+        };
+        let mut descriptor = [0u8; ALIGNMENT_DESCRIPTOR_SIZE];
+        let mut bytes_returned: u32 = 0;
+
+        unsafe {
+            DeviceIoControl(
+                handle,
+                IOCTL_STORAGE_QUERY_PROPERTY,
+                Some(&query as *const _ as *const _),
+                std::mem::size_of::<STORAGE_PROPERTY_QUERY>() as u32,
+                Some(descriptor.as_mut_ptr() as *mut _),
+                descriptor.len() as u32,
+                Some(&mut bytes_returned),
+                None,
+            )
+        }
+        .ok()?;
+
+        parse_alignment_descriptor(&descriptor[..bytes_returned as usize])
+    }
+
+    /// Queries the model and serial number the drive reports
+    #[cfg(windows)]
+    fn query_device_ids(handle: HANDLE) -> Option<DeviceIds> {
+        let query = STORAGE_PROPERTY_QUERY {
+            PropertyId: StorageDeviceProperty,
+            QueryType: PropertyStandardQuery,
+            AdditionalParameters: [0],
+        };
+        // Room for the descriptor and its strings; longer ones are cut off
+        let mut descriptor = [0u8; 1024];
+        let mut bytes_returned: u32 = 0;
+
+        unsafe {
+            DeviceIoControl(
+                handle,
+                IOCTL_STORAGE_QUERY_PROPERTY,
+                Some(&query as *const _ as *const _),
+                std::mem::size_of::<STORAGE_PROPERTY_QUERY>() as u32,
+                Some(descriptor.as_mut_ptr() as *mut _),
+                descriptor.len() as u32,
+                Some(&mut bytes_returned),
+                None,
+            )
+        }
+        .ok()?;
+
+        parse_device_descriptor(&descriptor[..bytes_returned as usize])
+    }
+
+    /// Returns the model and serial number the drive reports
+    ///
+    /// Empty when the drive doesn't answer the query.
+    #[cfg(windows)]
+    pub fn device_ids(&self) -> DeviceIds {
+        Self::query_device_ids(self.handle).unwrap_or_default()
+    }
+
+    /// Retries reads and writes that fail with transient errors
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
     /// Returns the path of the disk
     pub fn path(&self) -> &str {
         &self.path
@@ -162,6 +457,10 @@ impl BlockDevice for PhysicalDisk {
         self.sector_size
     }
 
+    fn optimal_io_size(&self) -> u32 {
+        self.optimal_io_size
+    }
+
     fn is_read_only(&self) -> bool {
         self.read_only
     }
@@ -175,20 +474,39 @@ impl BlockDevice for PhysicalDisk {
             });
         }
 
-        let mut new_pos: i64 = 0;
-        unsafe {
-            SetFilePointerEx(self.handle, offset as i64, Some(&mut new_pos), FILE_BEGIN)
-                .map_err(|e| BlockDeviceError::WindowsError(e.to_string()))?;
-        }
-
-        let mut bytes_read: u32 = 0;
-        unsafe {
-            ReadFile(self.handle, Some(buf), Some(&mut bytes_read), None)
-                .map_err(|e| BlockDeviceError::WindowsError(e.to_string()))?;
-        }
-
-        self.position.store(offset + bytes_read as u64, Ordering::SeqCst);
-        Ok(bytes_read as usize)
+        // Raw disks only accept whole, sector-aligned reads into
+        // sector-aligned memory, so read the covering sectors and copy out
+        let sector = self.sector_size as u64;
+        let start = offset - offset % sector;
+        let end = (offset + buf.len() as u64)
+            .next_multiple_of(sector)
+            .min(self.size);
+        let mut scratch = AlignedBuffer::new((end - start) as usize, self.sector_size as usize);
+
+        // Seek and read together so a retry starts from the right place
+        let bytes_read = self.retry.run(|| {
+            let mut new_pos: i64 = 0;
+            let mut bytes_read: u32 = 0;
+            unsafe {
+                SetFilePointerEx(self.handle, start as i64, Some(&mut new_pos), FILE_BEGIN)
+                    .map_err(windows_error)?;
+                ReadFile(
+                    self.handle,
+                    Some(&mut scratch[..]),
+                    Some(&mut bytes_read),
+                    None,
+                )
+                .map_err(windows_error)?;
+            }
+            Ok(bytes_read)
+        })?;
+
+        let skip = (offset - start) as usize;
+        let n = (bytes_read as usize).saturating_sub(skip).min(buf.len());
+        buf[..n].copy_from_slice(&scratch[skip..skip + n]);
+
+        self.position.store(offset + n as u64, Ordering::SeqCst);
+        Ok(n)
     }
 
     #[cfg(not(windows))]
@@ -212,17 +530,17 @@ impl BlockDevice for PhysicalDisk {
             });
         }
 
-        let mut new_pos: i64 = 0;
-        unsafe {
-            SetFilePointerEx(self.handle, offset as i64, Some(&mut new_pos), FILE_BEGIN)
-                .map_err(|e| BlockDeviceError::WindowsError(e.to_string()))?;
-        }
-
-        let mut bytes_written: u32 = 0;
-        unsafe {
-            WriteFile(self.handle, Some(buf), Some(&mut bytes_written), None)
-                .map_err(|e| BlockDeviceError::WindowsError(e.to_string()))?;
-        }
+        let bytes_written = self.retry.run(|| {
+            let mut new_pos: i64 = 0;
+            let mut bytes_written: u32 = 0;
+            unsafe {
+                SetFilePointerEx(self.handle, offset as i64, Some(&mut new_pos), FILE_BEGIN)
+                    .map_err(windows_error)?;
+                WriteFile(self.handle, Some(buf), Some(&mut bytes_written), None)
+                    .map_err(windows_error)?;
+            }
+            Ok(bytes_written)
+        })?;
 
         self.position
             .store(offset + bytes_written as u64, Ordering::SeqCst);
@@ -250,6 +568,35 @@ impl BlockDevice for PhysicalDisk {
     fn flush_device(&self) -> Result<()> {
         Ok(())
     }
+
+    #[cfg(windows)]
+    fn eject(&self) -> Result<()> {
+        // Like Windows' own safe removal: take the volume exclusively and
+        // dismount it, so no cached writes are pending when the media goes
+        for code in [
+            FSCTL_LOCK_VOLUME,
+            FSCTL_DISMOUNT_VOLUME,
+            IOCTL_STORAGE_EJECT_MEDIA,
+        ] {
+            let mut bytes_returned: u32 = 0;
+
+            unsafe {
+                DeviceIoControl(
+                    self.handle,
+                    code,
+                    None,
+                    0,
+                    None,
+                    0,
+                    Some(&mut bytes_returned),
+                    None,
+                )
+            }
+            .map_err(|e| BlockDeviceError::WindowsError(e.to_string()))?;
+        }
+
+        Ok(())
+    }
 }
 
 /// Lists all physical drives on the system
@@ -260,12 +607,14 @@ pub fn list_drives() -> Result<Vec<DriveInfo>> {
     for i in 0..32 {
         let path = format!("\\\\.\\PhysicalDrive{}", i);
         if let Ok(disk) = PhysicalDisk::open(&path, true) {
+            let ids = disk.device_ids();
             drives.push(DriveInfo {
                 path,
                 number: i,
                 size: disk.size,
                 sector_size: disk.sector_size,
-                model: None, // Would need additional WMI queries
+                model: ids.model,
+                serial: ids.serial,
             });
         }
     }
@@ -281,3 +630,151 @@ pub fn list_drives() -> Result<Vec<DriveInfo>> {
 // Safety: PhysicalDisk handle operations are thread-safe on Windows
 unsafe impl Send for PhysicalDisk {}
 unsafe impl Sync for PhysicalDisk {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a descriptor with the given logical and physical sector sizes
+    fn alignment_descriptor(logical: u32, physical: u32) -> Vec<u8> {
+        [1, 28, 64, 0, logical, physical, 0]
+            .iter()
+            .flat_map(|field: &u32| field.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_alignment_descriptor() {
+        // (logical, physical, expected); 512e drives report 512 over 4K
+        for (logical, physical, expected) in [
+            (512, 4096, Some(4096)),
+            (4096, 4096, Some(4096)),
+            (512, 0, Some(512)),
+            (512, 3000, None),
+        ] {
+            let descriptor = alignment_descriptor(logical, physical);
+            assert_eq!(parse_alignment_descriptor(&descriptor), expected);
+        }
+
+        let truncated = alignment_descriptor(512, 4096);
+        assert_eq!(parse_alignment_descriptor(&truncated[..20]), None);
+    }
+
+    /// Builds a descriptor with the strings appended after the fixed part
+    fn device_descriptor(vendor: &str, product: &str, serial: &str) -> Vec<u8> {
+        let mut data = vec![0u8; DEVICE_DESCRIPTOR_SIZE];
+        for (field, text) in [(12, vendor), (16, product), (24, serial)] {
+            if text.is_empty() {
+                continue;
+            }
+            let offset = data.len() as u32;
+            data[field..field + 4].copy_from_slice(&offset.to_le_bytes());
+            data.extend_from_slice(text.as_bytes());
+            data.push(0);
+        }
+        let size = data.len() as u32;
+        data[4..8].copy_from_slice(&size.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_parse_device_descriptor() {
+        let ids = |vendor, product, serial| {
+            parse_device_descriptor(&device_descriptor(vendor, product, serial)).unwrap()
+        };
+
+        // Space padding, as ATA and SCSI identify data has, is trimmed
+        assert_eq!(
+            ids("WDC     ", "WD40EFRX-68N32N0 ", "  WD-WCC7K1234567"),
+            DeviceIds {
+                model: Some("WDC WD40EFRX-68N32N0".to_string()),
+                serial: Some("WD-WCC7K1234567".to_string()),
+            }
+        );
+        // NVMe drives usually report no vendor
+        assert_eq!(
+            ids("", "Samsung SSD 980 PRO 1TB", ""),
+            DeviceIds {
+                model: Some("Samsung SSD 980 PRO 1TB".to_string()),
+                serial: None,
+            }
+        );
+        assert_eq!(ids("", "", "   "), DeviceIds::default());
+
+        // Offsets past the end of a short read are ignored
+        let mut data = device_descriptor("", "Disk", "");
+        data[24..28].copy_from_slice(&4096u32.to_le_bytes());
+        assert_eq!(parse_device_descriptor(&data).unwrap().serial, None);
+        assert_eq!(parse_device_descriptor(&data[..20]), None);
+    }
+
+    /// Runs an operation under `policy` that fails transiently `failures`
+    /// times, then succeeds, returning its result and the calls made
+    fn run_flaky(policy: RetryPolicy, failures: u32) -> (Result<()>, u32) {
+        let mut calls = 0;
+        let result = policy.run(|| {
+            calls += 1;
+            if calls <= failures {
+                Err(BlockDeviceError::Transient("device busy".to_string()))
+            } else {
+                Ok(())
+            }
+        });
+        (result, calls)
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let policy = RetryPolicy::new(4, Duration::from_millis(1));
+
+        // Three failures fit within four attempts
+        let (result, calls) = run_flaky(policy, 3);
+        assert!(result.is_ok());
+        assert_eq!(calls, 4);
+
+        // Four do not, and the last error is reported
+        let (result, calls) = run_flaky(policy, 4);
+        assert!(result.unwrap_err().is_transient());
+        assert_eq!(calls, 4);
+
+        // Off by default
+        let (result, calls) = run_flaky(RetryPolicy::default(), 1);
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+
+        // Permanent errors are not retried
+        let mut calls = 0;
+        let result: Result<()> = policy.run(|| {
+            calls += 1;
+            Err(BlockDeviceError::ReadBeyondEnd)
+        });
+        assert!(matches!(result, Err(BlockDeviceError::ReadBeyondEnd)));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_is_transient_win32_error() {
+        assert!(is_transient_win32_error(121)); // ERROR_SEM_TIMEOUT
+        assert!(is_transient_win32_error(1117)); // ERROR_IO_DEVICE
+        assert!(!is_transient_win32_error(5)); // ERROR_ACCESS_DENIED
+        assert!(!is_transient_win32_error(87)); // ERROR_INVALID_PARAMETER
+        assert!(!is_transient_win32_error(1167)); // ERROR_DEVICE_NOT_CONNECTED
+    }
+
+    #[test]
+    fn test_aligned_buffer_is_sector_aligned() {
+        for align in [512usize, 4096] {
+            assert!(AlignedBuffer::new(0, align).is_empty());
+
+            for len in [1usize, 512, 4096, 65536 + 17] {
+                let mut buf = AlignedBuffer::new(len, align);
+                assert_eq!(buf.as_ptr() as usize % align, 0);
+                assert_eq!(buf.len(), len);
+                assert!(buf.iter().all(|&b| b == 0));
+
+                buf[len - 1] = 0xAB;
+                assert_eq!(buf[len - 1], 0xAB);
+            }
+        }
+    }
+}
@@ -0,0 +1,441 @@
+//! Detection of devices that are already in use
+//!
+//! Mounting a disk that Windows or another instance is also using risks
+//! two writers on one filesystem. The checks here ask Windows which
+//! volumes it has mounted from which disks, and whether anything else
+//! holds the device open.
+
+use super::partition::{split_partition_spec, Partition};
+
+#[cfg(windows)]
+use super::{partition::partitions, PhysicalDisk};
+#[cfg(windows)]
+use windows::{
+    core::PCWSTR,
+    Win32::{
+        Foundation::{CloseHandle, ERROR_MORE_DATA, ERROR_SHARING_VIOLATION, HANDLE},
+        Storage::FileSystem::{
+            CreateFileW, GetLogicalDrives, FILE_ATTRIBUTE_NORMAL, FILE_GENERIC_READ,
+            FILE_SHARE_NONE, FILE_SHARE_READ, FILE_SHARE_WRITE,
+            IOCTL_VOLUME_GET_VOLUME_DISK_EXTENTS, OPEN_EXISTING,
+        },
+        System::Ioctl::FSCTL_IS_VOLUME_MOUNTED,
+        System::IO::DeviceIoControl,
+    },
+};
+
+/// A stretch of a physical drive that a volume lies on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskExtent {
+    /// Number of the physical drive
+    pub disk: u32,
+    /// Offset of the first byte on the drive
+    pub start: u64,
+    /// Length in bytes
+    pub length: u64,
+}
+
+impl DiskExtent {
+    /// Returns true if the extent is on `disk` and overlaps the `size`
+    /// bytes at `start`
+    fn overlaps(&self, disk: u32, start: u64, size: u64) -> bool {
+        self.disk == disk
+            && self.start < start.saturating_add(size)
+            && start < self.start.saturating_add(self.length)
+    }
+}
+
+/// A volume Windows has mounted, and where on disk it lies
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountedVolume {
+    /// Drive letter of the volume
+    pub letter: char,
+    /// Stretches of physical drives the volume occupies
+    pub extents: Vec<DiskExtent>,
+}
+
+/// What else is using a device
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceHolders {
+    /// Drive letters of volumes Windows has mounted from the device
+    pub mounted_volumes: Vec<char>,
+    /// Whether another handle holds the device open, as another instance
+    /// of this program would
+    pub locked: bool,
+}
+
+impl DeviceHolders {
+    /// Works out how `path` is in use from the volumes Windows has mounted
+    /// and whether the device could be opened exclusively
+    ///
+    /// A physical drive is in use when any mounted volume lies on it, and a
+    /// partition of one when a volume overlaps `partition`, its table
+    /// entry. Without the entry the whole drive is checked. A volume path
+    /// such as `\\.\E:` is in use when that volume is mounted. Image files
+    /// have no volumes and are only in use when locked.
+    pub fn from_state(
+        path: &str,
+        partition: Option<&Partition>,
+        volumes: &[MountedVolume],
+        locked: bool,
+    ) -> Self {
+        let mounted_volumes = if let Some(disk) = physical_drive_number(path) {
+            let (start, size) = partition.map_or((0, u64::MAX), |p| (p.start, p.size));
+            volumes
+                .iter()
+                .filter(|volume| {
+                    volume
+                        .extents
+                        .iter()
+                        .any(|extent| extent.overlaps(disk, start, size))
+                })
+                .map(|volume| volume.letter)
+                .collect()
+        } else if let Some(letter) = volume_letter(path) {
+            volumes
+                .iter()
+                .filter(|volume| volume.letter == letter)
+                .map(|volume| volume.letter)
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        Self {
+            mounted_volumes,
+            locked,
+        }
+    }
+
+    /// Returns true if mounting the device could conflict with another user
+    pub fn is_in_use(&self) -> bool {
+        !self.mounted_volumes.is_empty() || self.locked
+    }
+}
+
+/// Returns the drive number of a `\\.\PhysicalDriveN` path, with or
+/// without a partition suffix
+fn physical_drive_number(path: &str) -> Option<u32> {
+    let (disk, _) = split_partition_spec(path);
+    let number = disk
+        .strip_prefix("\\\\.\\PhysicalDrive")
+        .or_else(|| disk.strip_prefix("//./PhysicalDrive"))?;
+    number.parse().ok()
+}
+
+/// Returns the drive letter of a `\\.\E:` volume path
+fn volume_letter(path: &str) -> Option<char> {
+    let rest = path
+        .strip_prefix("\\\\.\\")
+        .or_else(|| path.strip_prefix("//./"))?;
+    match rest.as_bytes() {
+        [letter, b':'] if letter.is_ascii_alphabetic() => Some(letter.to_ascii_uppercase() as char),
+        _ => None,
+    }
+}
+
+/// Extracts the extents from a raw VOLUME_DISK_EXTENTS
+///
+/// The count is followed by 8-byte-aligned DISK_EXTENT entries of a disk
+/// number, a starting offset and a length. Entries past the end of a
+/// short read are left out.
+pub fn parse_disk_extents(data: &[u8]) -> Vec<DiskExtent> {
+    const HEADER_SIZE: usize = 8;
+    const EXTENT_SIZE: usize = 24;
+
+    let Some(count) = data.get(..4) else {
+        return Vec::new();
+    };
+    let count = u32::from_le_bytes(count.try_into().unwrap()) as usize;
+
+    data.get(HEADER_SIZE..)
+        .unwrap_or_default()
+        .chunks_exact(EXTENT_SIZE)
+        .take(count)
+        .map(|extent| DiskExtent {
+            disk: u32::from_le_bytes(extent[..4].try_into().unwrap()),
+            start: u64::from_le_bytes(extent[8..16].try_into().unwrap()),
+            length: u64::from_le_bytes(extent[16..24].try_into().unwrap()),
+        })
+        .collect()
+}
+
+/// Returns what else is using the device at `path`
+///
+/// A partition is checked for locks through its own device object, as
+/// Windows holds its disk open whenever any volume on it is mounted.
+#[cfg(windows)]
+pub fn device_holders(path: &str) -> DeviceHolders {
+    let (disk, index) = split_partition_spec(path);
+    let (partition, locked) = match (physical_drive_number(path), index) {
+        (Some(number), Some(index)) => (
+            partition_entry(disk, index),
+            is_locked(&format!(
+                "\\\\?\\GLOBALROOT\\Device\\Harddisk{}\\Partition{}",
+                number, index
+            )),
+        ),
+        _ => (None, is_locked(disk)),
+    };
+    DeviceHolders::from_state(path, partition.as_ref(), &mounted_volumes(), locked)
+}
+
+#[cfg(not(windows))]
+pub fn device_holders(path: &str) -> DeviceHolders {
+    DeviceHolders::from_state(path, None, &[], false)
+}
+
+/// Returns true if Windows has mounted a volume from the device at `path`
+/// or another handle holds it open
+///
+/// Always false on other platforms, where nothing is checked.
+pub fn is_device_in_use(path: &str) -> bool {
+    device_holders(path).is_in_use()
+}
+
+/// Opens `path` with `access`, letting other handles share it unless
+/// `share` is false
+#[cfg(windows)]
+fn open_handle(path: &str, access: u32, share: bool) -> windows::core::Result<HANDLE> {
+    let wide_path: Vec<u16> = path.encode_utf16().chain(std::iter::once(0)).collect();
+    let share = if share {
+        FILE_SHARE_READ | FILE_SHARE_WRITE
+    } else {
+        FILE_SHARE_NONE
+    };
+    unsafe {
+        CreateFileW(
+            PCWSTR(wide_path.as_ptr()),
+            access,
+            share,
+            None,
+            OPEN_EXISTING,
+            FILE_ATTRIBUTE_NORMAL,
+            None,
+        )
+    }
+}
+
+/// Lists the volumes with a drive letter that have a filesystem mounted
+#[cfg(windows)]
+fn mounted_volumes() -> Vec<MountedVolume> {
+    let drives = unsafe { GetLogicalDrives() };
+
+    let mut volumes = Vec::new();
+    for (i, letter) in ('A'..='Z').enumerate() {
+        if drives & (1 << i) == 0 {
+            continue;
+        }
+        let Ok(handle) = open_handle(&format!("\\\\.\\{}:", letter), 0, true) else {
+            continue;
+        };
+
+        let mut bytes_returned: u32 = 0;
+        let mounted = unsafe {
+            DeviceIoControl(
+                handle,
+                FSCTL_IS_VOLUME_MOUNTED,
+                None,
+                0,
+                None,
+                0,
+                Some(&mut bytes_returned),
+                None,
+            )
+        }
+        .is_ok();
+
+        // Room for a few extents, grown to the count Windows reports when
+        // the volume has more
+        let mut extents = vec![0u8; 8 + 24 * 8];
+        let queried = loop {
+            let result = unsafe {
+                DeviceIoControl(
+                    handle,
+                    IOCTL_VOLUME_GET_VOLUME_DISK_EXTENTS,
+                    None,
+                    0,
+                    Some(extents.as_mut_ptr() as *mut _),
+                    extents.len() as u32,
+                    Some(&mut bytes_returned),
+                    None,
+                )
+            };
+            match result {
+                Ok(()) => break true,
+                Err(e) if e.code() == ERROR_MORE_DATA.to_hresult() => {
+                    let count = u32::from_le_bytes(extents[..4].try_into().unwrap()) as usize;
+                    let needed = 8 + 24 * count;
+                    if needed <= extents.len() {
+                        break false;
+                    }
+                    extents.resize(needed, 0);
+                }
+                Err(_) => break false,
+            }
+        };
+        unsafe {
+            let _ = CloseHandle(handle);
+        }
+
+        if mounted && queried {
+            extents.truncate(bytes_returned as usize);
+            volumes.push(MountedVolume {
+                letter,
+                extents: parse_disk_extents(&extents),
+            });
+        }
+    }
+    volumes
+}
+
+/// Reads the table entry of partition `index` of `disk`
+#[cfg(windows)]
+fn partition_entry(disk: &str, index: u32) -> Option<Partition> {
+    let device = PhysicalDisk::open(disk, true).ok()?;
+    partitions(&device)
+        .ok()?
+        .into_iter()
+        .find(|partition| partition.index == index)
+}
+
+/// Returns true if another handle holds `path` open, so it can't be
+/// opened exclusively
+#[cfg(windows)]
+fn is_locked(path: &str) -> bool {
+    match open_handle(path, FILE_GENERIC_READ.0, false) {
+        Ok(handle) => {
+            unsafe {
+                let _ = CloseHandle(handle);
+            }
+            false
+        }
+        Err(e) => e.code() == ERROR_SHARING_VIOLATION.to_hresult(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1 << 30;
+
+    fn extent(disk: u32, start: u64, length: u64) -> DiskExtent {
+        DiskExtent {
+            disk,
+            start,
+            length,
+        }
+    }
+
+    fn volumes() -> Vec<MountedVolume> {
+        vec![
+            // The second partition of disk 0, after a 1 GiB boot partition
+            MountedVolume {
+                letter: 'C',
+                extents: vec![extent(0, GIB, 100 * GIB)],
+            },
+            MountedVolume {
+                letter: 'E',
+                extents: vec![extent(1, 0, 10 * GIB)],
+            },
+            // Spanned across two disks
+            MountedVolume {
+                letter: 'F',
+                extents: vec![extent(1, 10 * GIB, GIB), extent(2, 0, GIB)],
+            },
+        ]
+    }
+
+    #[test]
+    fn test_holders_physical_drives() {
+        let holders = DeviceHolders::from_state("\\\\.\\PhysicalDrive1", None, &volumes(), false);
+        assert_eq!(holders.mounted_volumes, ['E', 'F']);
+        assert!(holders.is_in_use());
+
+        let holders = DeviceHolders::from_state("//./PhysicalDrive3", None, &volumes(), false);
+        assert_eq!(holders, DeviceHolders::default());
+        assert!(!holders.is_in_use());
+
+        // A disk with nothing mounted can still be held by another instance
+        let holders = DeviceHolders::from_state("\\\\.\\PhysicalDrive3", None, &volumes(), true);
+        assert!(holders.mounted_volumes.is_empty());
+        assert!(holders.is_in_use());
+    }
+
+    #[test]
+    fn test_holders_partitions() {
+        let partition = |index, start, size| Partition { index, start, size };
+        let path = "\\\\.\\PhysicalDrive0p3";
+
+        // A partition next to C: is free, one overlapping it is not
+        let beside = partition(3, 101 * GIB, 50 * GIB);
+        let holders = DeviceHolders::from_state(path, Some(&beside), &volumes(), false);
+        assert!(!holders.is_in_use());
+        let overlapping = partition(3, 100 * GIB, 50 * GIB);
+        let holders = DeviceHolders::from_state(path, Some(&overlapping), &volumes(), false);
+        assert_eq!(holders.mounted_volumes, ['C']);
+
+        // Each extent of a spanned volume counts
+        let holders = DeviceHolders::from_state(
+            "\\\\.\\PhysicalDrive1p2",
+            Some(&partition(2, 10 * GIB, GIB)),
+            &volumes(),
+            false,
+        );
+        assert_eq!(holders.mounted_volumes, ['F']);
+
+        // Without its table entry the whole disk is checked
+        let holders = DeviceHolders::from_state(path, None, &volumes(), false);
+        assert_eq!(holders.mounted_volumes, ['C']);
+    }
+
+    #[test]
+    fn test_holders_volumes_and_images() {
+        let holders = DeviceHolders::from_state("\\\\.\\e:", None, &volumes(), false);
+        assert_eq!(holders.mounted_volumes, ['E']);
+        assert!(!DeviceHolders::from_state("\\\\.\\G:", None, &volumes(), false).is_in_use());
+
+        // Image files are only in use when locked
+        let image = "C:\\images\\disk.img";
+        assert!(!DeviceHolders::from_state(image, None, &volumes(), false).is_in_use());
+        assert!(DeviceHolders::from_state(image, None, &volumes(), true).is_in_use());
+    }
+
+    #[test]
+    fn test_parse_disk_extents() {
+        let extents = |disks: &[u32], count: u32| -> Vec<u8> {
+            let mut data = count.to_le_bytes().to_vec();
+            data.extend_from_slice(&[0; 4]);
+            for (i, &disk) in disks.iter().enumerate() {
+                data.extend_from_slice(&disk.to_le_bytes());
+                data.extend_from_slice(&[0; 4]);
+                data.extend_from_slice(&(i as u64 * 0x100000).to_le_bytes());
+                data.extend_from_slice(&0x100000u64.to_le_bytes());
+            }
+            data
+        };
+
+        assert_eq!(
+            parse_disk_extents(&extents(&[3], 1)),
+            [extent(3, 0, 0x100000)]
+        );
+        // Several extents on one disk are kept apart
+        assert_eq!(
+            parse_disk_extents(&extents(&[1, 1], 2)),
+            [extent(1, 0, 0x100000), extent(1, 0x100000, 0x100000)]
+        );
+
+        // Truncated reads and short counts
+        let data = extents(&[1, 2], 2);
+        assert_eq!(parse_disk_extents(&data[..data.len() - 1]).len(), 1);
+        assert_eq!(parse_disk_extents(&extents(&[1, 2], 1)).len(), 1);
+        assert!(parse_disk_extents(&data[..2]).is_empty());
+    }
+
+    #[test]
+    fn test_is_device_in_use_image() {
+        let temp = tempfile::NamedTempFile::new().unwrap();
+        assert!(!is_device_in_use(temp.path().to_str().unwrap()));
+    }
+}
@@ -0,0 +1,370 @@
+//! Virtual Hard Disk (VHD) images
+//!
+//! A fixed VHD is the raw disk followed by a 512-byte footer. A dynamic VHD
+//! has a footer copy at the start, a dynamic disk header and a block
+//! allocation table (BAT) mapping each block of the virtual disk to the
+//! file, or marking it unallocated. Differencing disks are not supported.
+//! All VHD fields are big-endian.
+
+use super::{BlockDevice, BlockDeviceError, Result};
+use byteorder::{BigEndian, ByteOrder};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Size of the footer and of a VHD sector
+const SECTOR: u64 = 512;
+
+/// Size of the dynamic disk header
+const DYNAMIC_HEADER_SIZE: usize = 1024;
+
+/// Footer `disk_type` values
+const DISK_TYPE_FIXED: u32 = 2;
+const DISK_TYPE_DYNAMIC: u32 = 3;
+const DISK_TYPE_DIFFERENCING: u32 = 4;
+
+/// BAT entry of a block that was never written
+const UNALLOCATED: u32 = 0xFFFF_FFFF;
+
+/// Returns an [`BlockDeviceError::InvalidImage`] error
+pub(super) fn invalid(msg: impl Into<String>) -> BlockDeviceError {
+    BlockDeviceError::InvalidImage(msg.into())
+}
+
+/// Reads `buf.len()` bytes at `offset`; bytes past the end of the file
+/// read as zeros
+pub(super) fn read_file_at(file: &mut File, offset: u64, buf: &mut [u8]) -> Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    buf[filled..].fill(0);
+    Ok(())
+}
+
+/// Where each fixed-size block of a virtual disk lives in the image file
+pub(super) struct BlockMap {
+    /// Bytes per block
+    pub block_size: u64,
+    /// File offset of each block's data, `None` for blocks that read as zeros
+    pub blocks: Vec<Option<u64>>,
+}
+
+impl BlockMap {
+    /// Reads `buf.len()` bytes of the virtual disk at `offset`
+    pub fn read(&self, file: &mut File, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let within = pos % self.block_size;
+            let len = ((self.block_size - within) as usize).min(buf.len() - done);
+            let piece = &mut buf[done..done + len];
+
+            match self.blocks.get((pos / self.block_size) as usize) {
+                Some(Some(start)) => read_file_at(file, start + within, piece)?,
+                _ => piece.fill(0),
+            }
+            done += len;
+        }
+        Ok(())
+    }
+}
+
+/// How the virtual disk is laid out in the file
+enum Layout {
+    /// The disk starts at the beginning of the file
+    Fixed,
+    /// Blocks are placed by the BAT
+    Dynamic(BlockMap),
+}
+
+/// A fixed or dynamic VHD image
+///
+/// Fixed images can be written in place. Dynamic images are read-only, as
+/// writing would require allocating blocks.
+pub struct VhdFile {
+    file: Mutex<File>,
+    layout: Layout,
+    size: u64,
+    read_only: bool,
+}
+
+impl VhdFile {
+    /// Opens a VHD image
+    pub fn open<P: AsRef<Path>>(path: P, read_only: bool) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(path.as_ref())?;
+        let file_size = file.metadata()?.len();
+        if file_size < SECTOR {
+            return Err(invalid("VHD image is smaller than its footer"));
+        }
+
+        let mut footer = [0u8; SECTOR as usize];
+        read_file_at(&mut file, file_size - SECTOR, &mut footer)?;
+        // Dynamic disks written by some tools end with a 511-byte footer;
+        // the copy at the start of the file is always complete
+        if &footer[..8] != b"conectix" {
+            read_file_at(&mut file, 0, &mut footer)?;
+        }
+        Self::check_footer(&footer)?;
+
+        let size = BigEndian::read_u64(&footer[48..56]);
+        let (layout, read_only) = match BigEndian::read_u32(&footer[60..64]) {
+            DISK_TYPE_FIXED => {
+                if size > file_size - SECTOR {
+                    return Err(invalid("fixed VHD is shorter than its disk size"));
+                }
+                (Layout::Fixed, read_only)
+            }
+            DISK_TYPE_DYNAMIC => {
+                let header_offset = BigEndian::read_u64(&footer[16..24]);
+                let map = Self::read_bat(&mut file, header_offset, size)?;
+                (Layout::Dynamic(map), true)
+            }
+            DISK_TYPE_DIFFERENCING => {
+                return Err(invalid("differencing VHDs are not supported"));
+            }
+            other => return Err(invalid(format!("unknown VHD disk type {}", other))),
+        };
+
+        Ok(Self {
+            file: Mutex::new(file),
+            layout,
+            size,
+            read_only,
+        })
+    }
+
+    /// Checks the footer cookie and checksum
+    fn check_footer(footer: &[u8]) -> Result<()> {
+        if &footer[..8] != b"conectix" {
+            return Err(invalid("missing VHD footer"));
+        }
+
+        // One's complement of the byte sum, skipping the checksum itself
+        let sum = footer
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !(64..68).contains(i))
+            .fold(0u32, |sum, (_, &b)| sum.wrapping_add(b as u32));
+        if !sum != BigEndian::read_u32(&footer[64..68]) {
+            return Err(invalid("VHD footer checksum mismatch"));
+        }
+        Ok(())
+    }
+
+    /// Reads the dynamic disk header at `offset` and the BAT it points to
+    fn read_bat(file: &mut File, offset: u64, size: u64) -> Result<BlockMap> {
+        let mut header = [0u8; DYNAMIC_HEADER_SIZE];
+        read_file_at(file, offset, &mut header)?;
+        if &header[..8] != b"cxsparse" {
+            return Err(invalid("missing VHD dynamic disk header"));
+        }
+
+        let table_offset = BigEndian::read_u64(&header[16..24]);
+        let entries = BigEndian::read_u32(&header[28..32]) as u64;
+        let block_size = BigEndian::read_u32(&header[32..36]) as u64;
+        if block_size == 0 || !block_size.is_multiple_of(SECTOR) {
+            return Err(invalid(format!("invalid VHD block size {}", block_size)));
+        }
+        if entries < size.div_ceil(block_size) {
+            return Err(invalid("VHD block allocation table is too small"));
+        }
+
+        // Each block starts with a bitmap of its sectors, padded to a sector
+        let bitmap_size = (block_size / SECTOR).div_ceil(8).next_multiple_of(SECTOR);
+
+        let mut table = vec![0u8; entries as usize * 4];
+        read_file_at(file, table_offset, &mut table)?;
+        let blocks = table
+            .chunks_exact(4)
+            .map(BigEndian::read_u32)
+            .map(|sector| (sector != UNALLOCATED).then(|| sector as u64 * SECTOR + bitmap_size))
+            .collect();
+
+        Ok(BlockMap { block_size, blocks })
+    }
+}
+
+impl BlockDevice for VhdFile {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn sector_size(&self) -> u32 {
+        SECTOR as u32
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if offset >= self.size {
+            return Err(BlockDeviceError::InvalidOffset {
+                offset,
+                size: self.size,
+            });
+        }
+
+        let len = buf.len().min((self.size - offset) as usize);
+        let mut file = self.file.lock().unwrap();
+        match &self.layout {
+            Layout::Fixed => read_file_at(&mut file, offset, &mut buf[..len])?,
+            Layout::Dynamic(map) => map.read(&mut file, offset, &mut buf[..len])?,
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize> {
+        if self.read_only {
+            return Err(BlockDeviceError::ReadOnly);
+        }
+        if offset >= self.size {
+            return Err(BlockDeviceError::InvalidOffset {
+                offset,
+                size: self.size,
+            });
+        }
+
+        // Only fixed images are writable, and they map one to one
+        let len = buf.len().min((self.size - offset) as usize);
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&buf[..len])?;
+        Ok(len)
+    }
+
+    fn flush_device(&self) -> Result<()> {
+        self.file.lock().unwrap().flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    /// Builds a footer for a disk of `size` bytes
+    fn footer(disk_type: u32, size: u64, data_offset: u64) -> Vec<u8> {
+        let mut footer = vec![0u8; SECTOR as usize];
+        footer[..8].copy_from_slice(b"conectix");
+        BigEndian::write_u32(&mut footer[8..12], 2); // features
+        BigEndian::write_u32(&mut footer[12..16], 0x10000); // version
+        BigEndian::write_u64(&mut footer[16..24], data_offset);
+        BigEndian::write_u64(&mut footer[40..48], size);
+        BigEndian::write_u64(&mut footer[48..56], size);
+        BigEndian::write_u32(&mut footer[60..64], disk_type);
+
+        let sum = footer
+            .iter()
+            .fold(0u32, |sum, &b| sum.wrapping_add(b as u32));
+        BigEndian::write_u32(&mut footer[64..68], !sum);
+        footer
+    }
+
+    fn write_image(image: &[u8]) -> NamedTempFile {
+        let temp = NamedTempFile::new().unwrap();
+        std::fs::write(temp.path(), image).unwrap();
+        temp
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 253) as u8).collect()
+    }
+
+    #[test]
+    fn test_fixed_vhd() {
+        let disk = pattern(64 * 1024);
+        let mut image = disk.clone();
+        image.extend(footer(DISK_TYPE_FIXED, disk.len() as u64, u64::MAX));
+        let temp = write_image(&image);
+
+        let vhd = VhdFile::open(temp.path(), false).unwrap();
+        assert_eq!(vhd.size(), disk.len() as u64);
+        assert!(!vhd.is_read_only());
+
+        let mut buf = vec![0u8; 1000];
+        vhd.read_at(30000, &mut buf).unwrap();
+        assert_eq!(buf, disk[30000..31000]);
+
+        // Reads and writes stop short of the footer
+        assert_eq!(vhd.read_at(disk.len() as u64 - 10, &mut buf).unwrap(), 10);
+        assert_eq!(vhd.write_at(disk.len() as u64 - 4, b"btrfs").unwrap(), 4);
+        vhd.flush_device().unwrap();
+        let written = std::fs::read(temp.path()).unwrap();
+        assert_eq!(&written[disk.len() - 4..disk.len()], b"btrf");
+        assert_eq!(&written[disk.len()..disk.len() + 8], b"conectix");
+    }
+
+    #[test]
+    fn test_dynamic_vhd() {
+        const BLOCK: u64 = 4096;
+        let size = 3 * BLOCK;
+
+        // Footer copy, header at 512, BAT at 1536, then the one allocated
+        // block (1 bitmap sector + data) at sector 4
+        let mut image = footer(DISK_TYPE_DYNAMIC, size, 512);
+        let mut header = vec![0u8; DYNAMIC_HEADER_SIZE];
+        header[..8].copy_from_slice(b"cxsparse");
+        BigEndian::write_u64(&mut header[8..16], u64::MAX);
+        BigEndian::write_u64(&mut header[16..24], 1536);
+        BigEndian::write_u32(&mut header[28..32], 3);
+        BigEndian::write_u32(&mut header[32..36], BLOCK as u32);
+        image.extend(header);
+
+        let mut bat = vec![0xFFu8; SECTOR as usize];
+        BigEndian::write_u32(&mut bat[4..8], 4);
+        image.extend(bat);
+
+        let block = pattern(BLOCK as usize);
+        image.extend([0xFFu8; SECTOR as usize]);
+        image.extend(&block);
+        image.extend(footer(DISK_TYPE_DYNAMIC, size, 512));
+        let temp = write_image(&image);
+
+        let vhd = VhdFile::open(temp.path(), false).unwrap();
+        assert_eq!(vhd.size(), size);
+        assert!(vhd.is_read_only());
+
+        // Across the unallocated first block into the allocated second one
+        let mut buf = vec![0xEEu8; 2 * BLOCK as usize];
+        vhd.read_at(BLOCK / 2, &mut buf).unwrap();
+        assert!(buf[..BLOCK as usize / 2].iter().all(|&b| b == 0));
+        assert_eq!(&buf[BLOCK as usize / 2..3 * BLOCK as usize / 2], &block[..]);
+        assert!(buf[3 * BLOCK as usize / 2..].iter().all(|&b| b == 0));
+
+        assert!(matches!(
+            vhd.write_at(0, b"x"),
+            Err(BlockDeviceError::ReadOnly)
+        ));
+    }
+
+    #[test]
+    fn test_bad_footer() {
+        let mut image = vec![0u8; 4096];
+        let mut bad = footer(DISK_TYPE_FIXED, 4096, u64::MAX);
+        bad[100] ^= 1;
+        image.extend(bad);
+        let temp = write_image(&image);
+
+        assert!(matches!(
+            VhdFile::open(temp.path(), true),
+            Err(BlockDeviceError::InvalidImage(_))
+        ));
+
+        let temp = write_image(&[0u8; 100]);
+        assert!(VhdFile::open(temp.path(), true).is_err());
+    }
+}
@@ -0,0 +1,412 @@
+//! Hyper-V virtual hard disk (VHDX) images
+//!
+//! A VHDX file starts with a file identifier and two copies of the header,
+//! followed by a region table locating the block allocation table (BAT) and
+//! the metadata region. The metadata gives the block size and virtual disk
+//! size; the BAT places each payload block in the file, interleaved with
+//! sector bitmap entries that only differencing disks use. All fields are
+//! little-endian.
+//!
+//! Images with a pending log or a parent disk are refused, and the image
+//! is always opened read-only.
+
+use super::vhd::{invalid, read_file_at, BlockMap};
+use super::{BlockDevice, BlockDeviceError, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use std::fs::File;
+use std::path::Path;
+use std::sync::Mutex;
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+
+/// Offsets of the two header copies and the region table
+const HEADER_OFFSETS: [u64; 2] = [64 * KIB, 128 * KIB];
+const REGION_TABLE_OFFSET: u64 = 192 * KIB;
+
+/// Bytes covered by the header and region table checksums
+const HEADER_SIZE: usize = 4 * KIB as usize;
+const REGION_TABLE_SIZE: usize = 64 * KIB as usize;
+
+/// Region GUIDs, in their on-disk byte order
+const BAT_REGION: [u8; 16] = [
+    0x66, 0x77, 0xC2, 0x2D, 0x23, 0xF6, 0x00, 0x42, 0x9D, 0x64, 0x11, 0x5E, 0x9B, 0xFD, 0x4A, 0x08,
+];
+const METADATA_REGION: [u8; 16] = [
+    0x06, 0xA2, 0x7C, 0x8B, 0x90, 0x47, 0x9A, 0x4B, 0xB8, 0xFE, 0x57, 0x5F, 0x05, 0x0F, 0x88, 0x6E,
+];
+
+/// Metadata item GUIDs, in their on-disk byte order
+const FILE_PARAMETERS: [u8; 16] = [
+    0x37, 0x67, 0xA1, 0xCA, 0x36, 0xFA, 0x43, 0x4D, 0xB3, 0xB6, 0x33, 0xF0, 0xAA, 0x44, 0xE7, 0x6B,
+];
+const VIRTUAL_DISK_SIZE: [u8; 16] = [
+    0x24, 0x42, 0xA5, 0x2F, 0x1B, 0xCD, 0x76, 0x48, 0xB2, 0x11, 0x5D, 0xBE, 0xD8, 0x3B, 0xF4, 0xB8,
+];
+const LOGICAL_SECTOR_SIZE: [u8; 16] = [
+    0x1D, 0xBF, 0x41, 0x81, 0x6F, 0xA9, 0x09, 0x47, 0xBA, 0x47, 0xF2, 0x33, 0xA8, 0xFA, 0xAB, 0x5F,
+];
+
+/// File parameters flag for differencing disks
+const HAS_PARENT: u32 = 0x2;
+
+/// Payload block states
+const PAYLOAD_BLOCK_NOT_PRESENT: u64 = 0;
+const PAYLOAD_BLOCK_UNMAPPED: u64 = 3;
+const PAYLOAD_BLOCK_FULLY_PRESENT: u64 = 6;
+
+/// A read-only VHDX image
+pub struct VhdxFile {
+    file: Mutex<File>,
+    map: BlockMap,
+    size: u64,
+    sector_size: u32,
+}
+
+impl VhdxFile {
+    /// Opens a VHDX image
+    ///
+    /// VHDX images are never written, so `read_only` only documents intent.
+    pub fn open<P: AsRef<Path>>(path: P, read_only: bool) -> Result<Self> {
+        let _ = read_only;
+        let mut file = File::open(path.as_ref())?;
+
+        let mut ident = [0u8; 8];
+        read_file_at(&mut file, 0, &mut ident)?;
+        if &ident != b"vhdxfile" {
+            return Err(invalid("missing VHDX file identifier"));
+        }
+
+        Self::check_header(&mut file)?;
+        let (bat, metadata) = Self::read_regions(&mut file)?;
+        let params = Metadata::read(&mut file, metadata)?;
+        let map = Self::read_bat(&mut file, bat, &params)?;
+
+        Ok(Self {
+            file: Mutex::new(file),
+            map,
+            size: params.size,
+            sector_size: params.sector_size,
+        })
+    }
+
+    /// Picks the current header and makes sure it has no log to replay
+    fn check_header(file: &mut File) -> Result<()> {
+        let mut current: Option<(u64, Vec<u8>)> = None;
+        for offset in HEADER_OFFSETS {
+            let mut header = vec![0u8; HEADER_SIZE];
+            read_file_at(file, offset, &mut header)?;
+            if &header[..4] != b"head" || !checksum_matches(&mut header) {
+                continue;
+            }
+
+            let sequence = LittleEndian::read_u64(&header[8..16]);
+            if current.as_ref().is_none_or(|(seq, _)| sequence > *seq) {
+                current = Some((sequence, header));
+            }
+        }
+
+        let (_, header) = current.ok_or_else(|| invalid("no valid VHDX header"))?;
+        if header[48..64].iter().any(|&b| b != 0) {
+            return Err(invalid(
+                "VHDX log needs to be replayed; attach the disk in Windows first",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the `(offset, length)` of the BAT and metadata regions
+    fn read_regions(file: &mut File) -> Result<((u64, u64), (u64, u64))> {
+        let mut table = vec![0u8; REGION_TABLE_SIZE];
+        read_file_at(file, REGION_TABLE_OFFSET, &mut table)?;
+        if &table[..4] != b"regi" || !checksum_matches(&mut table) {
+            return Err(invalid("invalid VHDX region table"));
+        }
+
+        let count =
+            (LittleEndian::read_u32(&table[8..12]) as usize).min((REGION_TABLE_SIZE - 16) / 32);
+        let mut bat = None;
+        let mut metadata = None;
+        for entry in table[16..16 + count * 32].chunks_exact(32) {
+            let region = (
+                LittleEndian::read_u64(&entry[16..24]),
+                LittleEndian::read_u32(&entry[24..28]) as u64,
+            );
+            if entry[..16] == BAT_REGION {
+                bat = Some(region);
+            } else if entry[..16] == METADATA_REGION {
+                metadata = Some(region);
+            }
+        }
+
+        match (bat, metadata) {
+            (Some(bat), Some(metadata)) => Ok((bat, metadata)),
+            _ => Err(invalid("VHDX region table lacks the BAT or metadata")),
+        }
+    }
+
+    /// Reads the BAT and maps each payload block to its file offset
+    fn read_bat(
+        file: &mut File,
+        (offset, length): (u64, u64),
+        params: &Metadata,
+    ) -> Result<BlockMap> {
+        let block_size = params.block_size as u64;
+        // Payload blocks per sector bitmap entry
+        let chunk_ratio = ((1u64 << 23) * params.sector_size as u64) / block_size;
+        let blocks = params.size.div_ceil(block_size);
+        let entries = blocks + blocks.saturating_sub(1) / chunk_ratio;
+        if entries * 8 > length {
+            return Err(invalid("VHDX block allocation table is too small"));
+        }
+
+        let mut table = vec![0u8; entries as usize * 8];
+        read_file_at(file, offset, &mut table)?;
+
+        let blocks = (0..blocks)
+            .map(|block| {
+                let index = (block + block / chunk_ratio) as usize;
+                let entry = LittleEndian::read_u64(&table[index * 8..index * 8 + 8]);
+                match entry & 7 {
+                    PAYLOAD_BLOCK_NOT_PRESENT..=PAYLOAD_BLOCK_UNMAPPED => Ok(None),
+                    PAYLOAD_BLOCK_FULLY_PRESENT => Ok(Some((entry >> 20) * MIB)),
+                    state => Err(invalid(format!(
+                        "unsupported VHDX payload block state {}",
+                        state
+                    ))),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(BlockMap { block_size, blocks })
+    }
+}
+
+/// The metadata items needed to read the payload
+struct Metadata {
+    block_size: u32,
+    size: u64,
+    sector_size: u32,
+}
+
+impl Metadata {
+    fn read(file: &mut File, (offset, length): (u64, u64)) -> Result<Self> {
+        let mut region = vec![0u8; length as usize];
+        read_file_at(file, offset, &mut region)?;
+        if region.len() < 32 || &region[..8] != b"metadata" {
+            return Err(invalid("invalid VHDX metadata table"));
+        }
+
+        // Returns the first `len` bytes of the item with the given GUID
+        let count = LittleEndian::read_u16(&region[10..12]) as usize;
+        let item = |guid: &[u8; 16], len: usize| -> Result<&[u8]> {
+            region[32..]
+                .chunks_exact(32)
+                .take(count)
+                .find(|entry| entry[..16] == *guid)
+                .and_then(|entry| {
+                    let start = LittleEndian::read_u32(&entry[16..20]) as usize;
+                    let item_len = LittleEndian::read_u32(&entry[20..24]) as usize;
+                    region.get(start..start + len).filter(|_| item_len >= len)
+                })
+                .ok_or_else(|| invalid("VHDX metadata item is missing"))
+        };
+
+        let params = item(&FILE_PARAMETERS, 8)?;
+        let block_size = LittleEndian::read_u32(&params[..4]);
+        if LittleEndian::read_u32(&params[4..8]) & HAS_PARENT != 0 {
+            return Err(invalid("differencing VHDX images are not supported"));
+        }
+        let size = LittleEndian::read_u64(item(&VIRTUAL_DISK_SIZE, 8)?);
+        let sector_size = LittleEndian::read_u32(item(&LOGICAL_SECTOR_SIZE, 4)?);
+
+        if !block_size.is_power_of_two() || (block_size as u64) < MIB {
+            return Err(invalid(format!("invalid VHDX block size {}", block_size)));
+        }
+        if sector_size != 512 && sector_size != 4096 {
+            return Err(invalid(format!("invalid VHDX sector size {}", sector_size)));
+        }
+
+        Ok(Self {
+            block_size,
+            size,
+            sector_size,
+        })
+    }
+}
+
+/// Checks the CRC-32C at bytes 4..8, computed with that field zeroed
+fn checksum_matches(data: &mut [u8]) -> bool {
+    let stored = LittleEndian::read_u32(&data[4..8]);
+    data[4..8].fill(0);
+    let matches = crc32c::crc32c(data) == stored;
+    LittleEndian::write_u32(&mut data[4..8], stored);
+    matches
+}
+
+impl BlockDevice for VhdxFile {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn sector_size(&self) -> u32 {
+        self.sector_size
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if offset >= self.size {
+            return Err(BlockDeviceError::InvalidOffset {
+                offset,
+                size: self.size,
+            });
+        }
+
+        let len = buf.len().min((self.size - offset) as usize);
+        let mut file = self.file.lock().unwrap();
+        self.map.read(&mut file, offset, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize> {
+        Err(BlockDeviceError::ReadOnly)
+    }
+
+    fn flush_device(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    const BLOCK: u64 = MIB;
+
+    fn seal(data: &mut [u8]) {
+        data[4..8].fill(0);
+        let crc = crc32c::crc32c(data);
+        LittleEndian::write_u32(&mut data[4..8], crc);
+    }
+
+    /// Builds a VHDX of `size` bytes whose payload blocks are given as
+    /// `(block, data)`; other blocks are not present
+    fn build(size: u64, present: &[(u64, Vec<u8>)], log_guid: bool) -> Vec<u8> {
+        let bat_offset = MIB;
+        let metadata_offset = 2 * MIB;
+        let data_offset = 3 * MIB;
+        let mut image = vec![0u8; (data_offset + present.len() as u64 * BLOCK) as usize];
+        image[..8].copy_from_slice(b"vhdxfile");
+
+        // Two headers; the second has the higher sequence number
+        for (i, offset) in HEADER_OFFSETS.iter().enumerate() {
+            let header = &mut image[*offset as usize..*offset as usize + HEADER_SIZE];
+            header[..4].copy_from_slice(b"head");
+            LittleEndian::write_u64(&mut header[8..16], i as u64 + 1);
+            if log_guid && i == 1 {
+                header[48] = 1;
+            }
+            seal(header);
+        }
+
+        let table = &mut image[REGION_TABLE_OFFSET as usize..][..REGION_TABLE_SIZE];
+        table[..4].copy_from_slice(b"regi");
+        LittleEndian::write_u32(&mut table[8..12], 2);
+        for (i, (guid, offset)) in [(BAT_REGION, bat_offset), (METADATA_REGION, metadata_offset)]
+            .iter()
+            .enumerate()
+        {
+            let entry = &mut table[16 + i * 32..48 + i * 32];
+            entry[..16].copy_from_slice(guid);
+            LittleEndian::write_u64(&mut entry[16..24], *offset);
+            LittleEndian::write_u32(&mut entry[24..28], MIB as u32);
+        }
+        seal(table);
+
+        let metadata = &mut image[metadata_offset as usize..][..MIB as usize];
+        metadata[..8].copy_from_slice(b"metadata");
+        LittleEndian::write_u16(&mut metadata[10..12], 3);
+        let items: [([u8; 16], Vec<u8>); 3] = [
+            (FILE_PARAMETERS, {
+                let mut v = vec![0u8; 8];
+                LittleEndian::write_u32(&mut v[..4], BLOCK as u32);
+                v
+            }),
+            (VIRTUAL_DISK_SIZE, size.to_le_bytes().to_vec()),
+            (LOGICAL_SECTOR_SIZE, 512u32.to_le_bytes().to_vec()),
+        ];
+        for (i, (guid, value)) in items.iter().enumerate() {
+            let item_offset = 64 * KIB as usize + i * 8;
+            let entry = &mut metadata[32 + i * 32..64 + i * 32];
+            entry[..16].copy_from_slice(guid);
+            LittleEndian::write_u32(&mut entry[16..20], item_offset as u32);
+            LittleEndian::write_u32(&mut entry[20..24], value.len() as u32);
+            metadata[item_offset..item_offset + value.len()].copy_from_slice(value);
+        }
+
+        for (i, (block, data)) in present.iter().enumerate() {
+            let file_offset = data_offset + i as u64 * BLOCK;
+            let entry = (file_offset / MIB) << 20 | PAYLOAD_BLOCK_FULLY_PRESENT;
+            let index = (bat_offset + block * 8) as usize;
+            LittleEndian::write_u64(&mut image[index..index + 8], entry);
+            image[file_offset as usize..file_offset as usize + data.len()].copy_from_slice(data);
+        }
+        image
+    }
+
+    fn write_image(image: &[u8]) -> NamedTempFile {
+        let temp = NamedTempFile::new().unwrap();
+        std::fs::write(temp.path(), image).unwrap();
+        temp
+    }
+
+    #[test]
+    fn test_dynamic_vhdx() {
+        let data: Vec<u8> = (0..BLOCK).map(|i| (i % 251) as u8).collect();
+        let temp = write_image(&build(3 * BLOCK, &[(1, data.clone())], false));
+
+        let vhdx = VhdxFile::open(temp.path(), false).unwrap();
+        assert_eq!(vhdx.size(), 3 * BLOCK);
+        assert_eq!(vhdx.sector_size(), 512);
+        assert!(vhdx.is_read_only());
+
+        // Across the absent first block into the present second one
+        let mut buf = vec![0xEEu8; 8192];
+        vhdx.read_at(BLOCK - 4096, &mut buf).unwrap();
+        assert!(buf[..4096].iter().all(|&b| b == 0));
+        assert_eq!(&buf[4096..], &data[..4096]);
+
+        let mut buf = vec![0xEEu8; 4096];
+        vhdx.read_at(2 * BLOCK, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+
+        assert!(matches!(
+            vhdx.write_at(0, b"x"),
+            Err(BlockDeviceError::ReadOnly)
+        ));
+    }
+
+    #[test]
+    fn test_refuses_pending_log() {
+        let temp = write_image(&build(BLOCK, &[], true));
+        assert!(matches!(
+            VhdxFile::open(temp.path(), true),
+            Err(BlockDeviceError::InvalidImage(_))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_corrupt_region_table() {
+        let mut image = build(BLOCK, &[], false);
+        image[REGION_TABLE_OFFSET as usize + 20] ^= 1;
+        let temp = write_image(&image);
+        assert!(matches!(
+            VhdxFile::open(temp.path(), true),
+            Err(BlockDeviceError::InvalidImage(_))
+        ));
+    }
+}
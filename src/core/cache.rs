@@ -0,0 +1,133 @@
+//! Tree node cache
+//!
+//! Keeps recently read tree nodes in memory so repeated traversals of the
+//! same interior nodes don't go back to the device.
+
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Default number of nodes kept by [`NodeCache`]
+pub const DEFAULT_NODE_CACHE_SIZE: usize = 1024;
+
+/// A cached node and when it was last used
+struct CacheEntry {
+    data: Vec<u8>,
+    last_used: AtomicU64,
+}
+
+/// Bounded least-recently-used cache of node buffers keyed by logical address
+///
+/// Hits only take the read lock, so concurrent lookups don't contend. The
+/// least recently used node is found by a scan when inserting into a full
+/// cache, which is cheap next to the device read that caused the insert.
+pub struct NodeCache {
+    entries: RwLock<HashMap<u64, CacheEntry>>,
+    capacity: usize,
+    clock: AtomicU64,
+}
+
+impl NodeCache {
+    /// Creates a cache holding at most `capacity` nodes; zero disables it
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: RwLock::new(HashMap::with_capacity(capacity)),
+            capacity,
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the maximum number of cached nodes
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the number of cached nodes
+    pub fn len(&self) -> usize {
+        self.entries.read().len()
+    }
+
+    /// Returns true if nothing is cached
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns a copy of the node at `logical`, marking it recently used
+    pub fn get(&self, logical: u64) -> Option<Vec<u8>> {
+        let entries = self.entries.read();
+        let entry = entries.get(&logical)?;
+        entry.last_used.store(self.tick(), Ordering::Relaxed);
+        Some(entry.data.clone())
+    }
+
+    /// Caches the node at `logical`, evicting the least recently used node
+    /// if the cache is full
+    pub fn insert(&self, logical: u64, data: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.write();
+        if entries.len() >= self.capacity && !entries.contains_key(&logical) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used.load(Ordering::Relaxed))
+                .map(|(&logical, _)| logical);
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+
+        let entry = CacheEntry {
+            data,
+            last_used: AtomicU64::new(self.tick()),
+        };
+        entries.insert(logical, entry);
+    }
+
+    /// Drops every cached node
+    pub fn clear(&self) {
+        self.entries.write().clear();
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = NodeCache::new(2);
+        cache.insert(0x1000, vec![1]);
+        cache.insert(0x2000, vec![2]);
+
+        // Touching 0x1000 leaves 0x2000 as the oldest
+        assert_eq!(cache.get(0x1000), Some(vec![1]));
+        cache.insert(0x3000, vec![3]);
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(0x2000), None);
+        assert_eq!(cache.get(0x1000), Some(vec![1]));
+        assert_eq!(cache.get(0x3000), Some(vec![3]));
+
+        // Replacing a cached node does not evict another
+        cache.insert(0x3000, vec![4]);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(0x3000), Some(vec![4]));
+
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_zero_capacity_disables() {
+        let cache = NodeCache::new(0);
+        cache.insert(0x1000, vec![1]);
+        assert!(cache.is_empty());
+        assert_eq!(cache.get(0x1000), None);
+    }
+}
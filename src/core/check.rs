@@ -0,0 +1,912 @@
+//! Offline consistency checks
+//!
+//! Cross-references redundant metadata that a healthy filesystem keeps in
+//! sync and reports every disagreement instead of stopping at the first.
+//! [`check_integrity`] checks the structure of every tree the same way.
+
+use super::{
+    extent::ExtentTree,
+    inode::{btrfs_name_hash, DirEntry, ExtentData, Inode},
+    item_type, objectid,
+    subvolume::RootItem,
+    tree::{BtrfsKey, TreeNode},
+    BtrfsError, BtrfsFilesystem, Result,
+};
+use std::collections::{BTreeMap, HashSet};
+
+/// A disagreement between a directory's DIR_ITEM and DIR_INDEX items
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DirMismatch {
+    /// A DIR_INDEX entry with no DIR_ITEM for the same name and target
+    MissingDirItem { index: u64, name: String },
+    /// A DIR_ITEM entry with no DIR_INDEX for the same name and target
+    MissingDirIndex { hash: u64, name: String },
+}
+
+/// A file data reference the extent tree does not account for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtentMismatch {
+    /// The EXTENT_DATA item at (`ino`, `offset`) points at a range with no
+    /// data EXTENT_ITEM of that size, i.e. at unallocated space
+    Dangling {
+        ino: u64,
+        offset: u64,
+        disk_bytenr: u64,
+        disk_num_bytes: u64,
+    },
+    /// The extent at `disk_bytenr` counts `refs` references but the tree
+    /// alone holds `seen`
+    Undercounted {
+        disk_bytenr: u64,
+        refs: u64,
+        seen: u64,
+    },
+    /// The EXTENT_DATA item at (`ino`, `offset`) could not be parsed
+    Malformed {
+        ino: u64,
+        offset: u64,
+        reason: String,
+    },
+}
+
+/// A structural problem found by [`check_integrity`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntegrityError {
+    /// The tree block at `logical` failed its checksum
+    BadChecksum { logical: u64 },
+    /// The tree block at `logical` could not be read, or its header, items
+    /// or key pointers could not be parsed
+    Malformed { logical: u64, reason: String },
+    /// A tree block pointer to `logical`, which no chunk maps
+    UnmappedNode { logical: u64 },
+    /// The block at `logical` is at `found` instead of the `expected` level
+    BadLevel {
+        logical: u64,
+        expected: u8,
+        found: u8,
+    },
+    /// The key in `slot` of the block at `logical` is not above the one
+    /// before it
+    KeyOrder { logical: u64, slot: usize },
+    /// The key in `slot` of the block at `logical` lies outside the range
+    /// the parent's pointers give the block
+    KeyRange { logical: u64, slot: usize },
+    /// The EXTENT_DATA item at (`ino`, `offset`) of tree `tree_id` points at
+    /// data no chunk maps
+    UnmappedExtent {
+        tree_id: u64,
+        ino: u64,
+        offset: u64,
+        disk_bytenr: u64,
+    },
+    /// Tree `tree_id` has items for inode `ino` but no INODE_ITEM
+    Orphan { tree_id: u64, ino: u64 },
+}
+
+/// Number of [`IntegrityError`]s of each kind
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IntegrityCounts {
+    /// Tree blocks that failed their checksum
+    pub bad_checksums: u64,
+    /// Tree block pointers and file extents outside every chunk
+    pub unmapped: u64,
+    /// Tree blocks that could not be read or parsed, are at the wrong
+    /// level or have misplaced keys
+    pub structure: u64,
+    /// Inodes with items but no INODE_ITEM
+    pub orphans: u64,
+}
+
+/// Result of checking the structure of every tree
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// Number of trees walked
+    pub trees_checked: u64,
+    /// Number of tree blocks read
+    pub nodes_checked: u64,
+    /// Number of EXTENT_DATA items whose address was resolved
+    pub data_extents_checked: u64,
+    /// Every problem found, in the order the trees were walked
+    pub errors: Vec<IntegrityError>,
+}
+
+impl IntegrityReport {
+    /// Returns true if no problems were found
+    pub fn is_clean(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// Counts the problems found by kind
+    pub fn counts(&self) -> IntegrityCounts {
+        let mut counts = IntegrityCounts::default();
+        for error in &self.errors {
+            match error {
+                IntegrityError::BadChecksum { .. } => counts.bad_checksums += 1,
+                IntegrityError::UnmappedNode { .. } | IntegrityError::UnmappedExtent { .. } => {
+                    counts.unmapped += 1
+                }
+                IntegrityError::Malformed { .. }
+                | IntegrityError::BadLevel { .. }
+                | IntegrityError::KeyOrder { .. }
+                | IntegrityError::KeyRange { .. } => counts.structure += 1,
+                IntegrityError::Orphan { .. } => counts.orphans += 1,
+            }
+        }
+        counts
+    }
+}
+
+/// Result of checking a filesystem tree
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    /// Number of directories checked
+    pub dirs_checked: u64,
+    /// Directory inode and mismatch for every inconsistent entry
+    pub dir_mismatches: Vec<(u64, DirMismatch)>,
+    /// Number of distinct data extents the tree's files reference
+    pub data_extents_checked: u64,
+    /// Every file data reference not covered by the extent tree
+    pub extent_mismatches: Vec<ExtentMismatch>,
+}
+
+impl CheckReport {
+    /// Returns true if no inconsistencies were found
+    pub fn is_clean(&self) -> bool {
+        self.dir_mismatches.is_empty() && self.extent_mismatches.is_empty()
+    }
+}
+
+/// Checks every directory in tree `tree_id`
+pub fn check(fs: &BtrfsFilesystem, tree_id: u64) -> Result<CheckReport> {
+    let tree = fs.tree(tree_id)?;
+    let min_key = BtrfsKey::new(0, item_type::INODE_ITEM, 0);
+    let max_key = BtrfsKey::new(u64::MAX, item_type::INODE_ITEM, u64::MAX);
+
+    let mut report = CheckReport::default();
+    // File references by (disk_bytenr, disk_num_bytes), as (ino, offset)
+    let mut extents: BTreeMap<(u64, u64), Vec<(u64, u64)>> = BTreeMap::new();
+    for (item, data) in tree.search_range(&min_key, &max_key)? {
+        if item.key.item_type == item_type::EXTENT_DATA {
+            let extent = match ExtentData::from_bytes(&data) {
+                Ok(extent) => extent,
+                Err(err) => {
+                    report.extent_mismatches.push(ExtentMismatch::Malformed {
+                        ino: item.key.objectid,
+                        offset: item.key.offset,
+                        reason: err.to_string(),
+                    });
+                    continue;
+                }
+            };
+            if !extent.is_inline() && !extent.is_sparse() {
+                let disk = (
+                    extent.disk_bytenr.unwrap_or(0),
+                    extent.disk_num_bytes.unwrap_or(0),
+                );
+                let reference = (item.key.objectid, item.key.offset);
+                extents.entry(disk).or_default().push(reference);
+            }
+            continue;
+        }
+        if item.key.item_type != item_type::INODE_ITEM {
+            continue;
+        }
+        let ino = item.key.objectid;
+        if !Inode::from_bytes(ino, &data)?.is_dir() {
+            continue;
+        }
+
+        report.dirs_checked += 1;
+        for mismatch in check_dir(fs, tree_id, ino)? {
+            report.dir_mismatches.push((ino, mismatch));
+        }
+    }
+
+    report.data_extents_checked = extents.len() as u64;
+    report
+        .extent_mismatches
+        .extend(check_data_extents(fs, &extents)?);
+    Ok(report)
+}
+
+/// Looks up the EXTENT_ITEM behind every referenced data extent
+///
+/// Each extent must exist with exactly the referenced size and count at
+/// least the references found. Other trees may reference it too, so more
+/// references than found are not an error.
+fn check_data_extents(
+    fs: &BtrfsFilesystem,
+    extents: &BTreeMap<(u64, u64), Vec<(u64, u64)>>,
+) -> Result<Vec<ExtentMismatch>> {
+    let extent_tree = ExtentTree::new(fs);
+
+    let mut mismatches = Vec::new();
+    for (&(disk_bytenr, disk_num_bytes), references) in extents {
+        match extent_tree.extent(disk_bytenr)? {
+            Some((len, item)) if len == disk_num_bytes && !item.is_tree_block() => {
+                let seen = references.len() as u64;
+                if item.refs < seen {
+                    mismatches.push(ExtentMismatch::Undercounted {
+                        disk_bytenr,
+                        refs: item.refs,
+                        seen,
+                    });
+                }
+            }
+            _ => {
+                for &(ino, offset) in references {
+                    mismatches.push(ExtentMismatch::Dangling {
+                        ino,
+                        offset,
+                        disk_bytenr,
+                        disk_num_bytes,
+                    });
+                }
+            }
+        }
+    }
+    Ok(mismatches)
+}
+
+/// Cross-references the DIR_ITEM and DIR_INDEX items of directory `ino`
+///
+/// Every DIR_INDEX entry must have a DIR_ITEM under its name hash naming
+/// the same target, and every DIR_ITEM entry must have such a DIR_INDEX.
+pub fn check_dir(fs: &BtrfsFilesystem, tree_id: u64, ino: u64) -> Result<Vec<DirMismatch>> {
+    let tree = fs.tree(tree_id)?;
+
+    let mut by_hash = Vec::new();
+    let min_key = BtrfsKey::new(ino, item_type::DIR_ITEM, 0);
+    let max_key = BtrfsKey::new(ino, item_type::DIR_ITEM, u64::MAX);
+    for (item, data) in tree.search_range(&min_key, &max_key)? {
+        for entry in DirEntry::all_from_bytes(&data)? {
+            by_hash.push((item.key.offset, entry));
+        }
+    }
+
+    let mut by_index = Vec::new();
+    let min_key = BtrfsKey::new(ino, item_type::DIR_INDEX, 0);
+    let max_key = BtrfsKey::new(ino, item_type::DIR_INDEX, u64::MAX);
+    for (item, data) in tree.search_range(&min_key, &max_key)? {
+        by_index.push((item.key.offset, DirEntry::from_bytes(&data)?));
+    }
+
+    let mut mismatches = Vec::new();
+    for (index, entry) in &by_index {
+        let hash = btrfs_name_hash(&entry.name);
+        if !by_hash
+            .iter()
+            .any(|(h, e)| *h == hash && same_target(e, entry))
+        {
+            mismatches.push(DirMismatch::MissingDirItem {
+                index: *index,
+                name: entry.name.clone(),
+            });
+        }
+    }
+    for (hash, entry) in &by_hash {
+        if !by_index.iter().any(|(_, e)| same_target(e, entry)) {
+            mismatches.push(DirMismatch::MissingDirIndex {
+                hash: *hash,
+                name: entry.name.clone(),
+            });
+        }
+    }
+
+    Ok(mismatches)
+}
+
+/// Returns true if two entries link the same name to the same target
+fn same_target(a: &DirEntry, b: &DirEntry) -> bool {
+    a.name == b.name && a.ino == b.ino && a.child_tree == b.child_tree
+}
+
+/// Checks the structure of the root tree, the chunk tree and every tree
+/// with a ROOT_ITEM
+///
+/// Every reachable tree block is read from disk and verified against its
+/// checksum; its level and keys must fit where its parent points to it.
+/// In subvolume trees every inode with items must have an INODE_ITEM, and
+/// every EXTENT_DATA must point at mapped data. Unreadable blocks are
+/// reported and skipped along with everything below them, so the check
+/// never stops early. Blocks shared between trees, as with snapshots, are
+/// checked only the first time they are reached.
+pub fn check_integrity(fs: &BtrfsFilesystem) -> IntegrityReport {
+    let sb = fs.superblock();
+    let mut report = IntegrityReport::default();
+    let mut checked = HashSet::new();
+
+    let mut roots = Vec::new();
+    let mut walk = TreeWalk::new(objectid::ROOT_TREE, &mut report, &mut checked);
+    walk.roots = Some(&mut roots);
+    walk.check_tree(fs, sb.root(), sb.root_level());
+
+    TreeWalk::new(objectid::CHUNK_TREE, &mut report, &mut checked).check_tree(
+        fs,
+        sb.chunk_root(),
+        sb.chunk_root_level(),
+    );
+
+    for (tree_id, root) in roots {
+        TreeWalk::new(tree_id, &mut report, &mut checked).check_tree(fs, root.bytenr, root.level);
+    }
+    report
+}
+
+/// State of [`check_integrity`] while walking one tree
+struct TreeWalk<'a> {
+    tree_id: u64,
+    report: &'a mut IntegrityReport,
+    /// Addresses of the blocks already checked, in this tree or another
+    checked: &'a mut HashSet<u64>,
+    /// Whether the tree holds inodes
+    is_subvolume: bool,
+    /// The last inode an INODE_ITEM was seen for
+    inode: Option<u64>,
+    /// The last inode reported as an orphan
+    orphan: Option<u64>,
+    /// Whether blocks were skipped since the last item; the next inode's
+    /// INODE_ITEM may have been in them
+    skipped: bool,
+    /// Where to collect the ROOT_ITEMs found, when walking the root tree
+    roots: Option<&'a mut Vec<(u64, RootItem)>>,
+}
+
+impl<'a> TreeWalk<'a> {
+    fn new(tree_id: u64, report: &'a mut IntegrityReport, checked: &'a mut HashSet<u64>) -> Self {
+        Self {
+            tree_id,
+            report,
+            checked,
+            is_subvolume: tree_id == objectid::FS_TREE
+                || (objectid::FIRST_FREE..=objectid::LAST_FREE).contains(&tree_id),
+            inode: None,
+            orphan: None,
+            skipped: false,
+            roots: None,
+        }
+    }
+
+    fn check_tree(&mut self, fs: &BtrfsFilesystem, logical: u64, level: u8) {
+        self.report.trees_checked += 1;
+        self.check_node(fs, logical, level, None, None);
+    }
+
+    /// Checks the block at `logical`, whose keys must be at least `low` and
+    /// below `high`, and everything below it
+    fn check_node(
+        &mut self,
+        fs: &BtrfsFilesystem,
+        logical: u64,
+        level: u8,
+        low: Option<BtrfsKey>,
+        high: Option<BtrfsKey>,
+    ) {
+        if !fs.chunk_tree().contains(logical) {
+            return self.skip(IntegrityError::UnmappedNode { logical });
+        }
+        if !self.checked.insert(logical) {
+            // Shared with a tree walked earlier; its items aren't seen again
+            self.skipped = true;
+            return;
+        }
+
+        self.report.nodes_checked += 1;
+        let node = fs
+            .read_node_uncached(logical)
+            .and_then(|data| TreeNode::parse(data, fs.checksum()));
+        let node = match node {
+            Ok(node) => node,
+            Err(err) => return self.skip(node_error(logical, err)),
+        };
+        if node.header.level != level {
+            return self.skip(IntegrityError::BadLevel {
+                logical,
+                expected: level,
+                found: node.header.level,
+            });
+        }
+
+        if node.is_leaf() {
+            let items = match node.items() {
+                Ok(items) => items,
+                Err(err) => return self.skip(node_error(logical, err)),
+            };
+            let keys: Vec<BtrfsKey> = items.iter().map(|item| item.key).collect();
+            self.check_keys(logical, &keys, low, high);
+            for item in &items {
+                self.check_item(fs, &item.key, node.item_data(item));
+            }
+            return;
+        }
+
+        let ptrs = match node.key_ptrs() {
+            Ok(ptrs) => ptrs,
+            Err(err) => return self.skip(node_error(logical, err)),
+        };
+        let keys: Vec<BtrfsKey> = ptrs.iter().map(|ptr| ptr.key).collect();
+        if !self.check_keys(logical, &keys, low, high) {
+            // Children can't be given a range to check against
+            self.skipped = true;
+            return;
+        }
+        for (i, ptr) in ptrs.iter().enumerate() {
+            let next = keys.get(i + 1).copied().or(high);
+            self.check_node(fs, ptr.blockptr, level - 1, Some(ptr.key), next);
+        }
+    }
+
+    /// Checks that `keys` ascend and lie in `low..high`, returning false if
+    /// they don't
+    fn check_keys(
+        &mut self,
+        logical: u64,
+        keys: &[BtrfsKey],
+        low: Option<BtrfsKey>,
+        high: Option<BtrfsKey>,
+    ) -> bool {
+        let mut ok = true;
+        for (slot, key) in keys.iter().enumerate() {
+            if slot > 0 && *key <= keys[slot - 1] {
+                self.error(IntegrityError::KeyOrder { logical, slot });
+                ok = false;
+            }
+            if low.is_some_and(|low| *key < low) || high.is_some_and(|high| *key >= high) {
+                self.error(IntegrityError::KeyRange { logical, slot });
+                ok = false;
+            }
+        }
+        ok
+    }
+
+    /// Checks one leaf item, in key order
+    fn check_item(&mut self, fs: &BtrfsFilesystem, key: &BtrfsKey, data: &[u8]) {
+        if let Some(roots) = self.roots.as_mut()
+            && key.item_type == item_type::ROOT_ITEM
+            && let Ok(root) = RootItem::from_bytes(data)
+        {
+            roots.push((key.objectid, root));
+        }
+
+        let ino = key.objectid;
+        if !self.is_subvolume || !(objectid::FIRST_FREE..=objectid::LAST_FREE).contains(&ino) {
+            return;
+        }
+
+        if std::mem::take(&mut self.skipped) {
+            self.inode = Some(ino);
+        }
+
+        // An inode's INODE_ITEM sorts before all its other items
+        if key.item_type == item_type::INODE_ITEM {
+            self.inode = Some(ino);
+        } else if self.inode != Some(ino) && self.orphan != Some(ino) {
+            self.orphan = Some(ino);
+            self.error(IntegrityError::Orphan {
+                tree_id: self.tree_id,
+                ino,
+            });
+        }
+
+        if key.item_type != item_type::EXTENT_DATA {
+            return;
+        }
+        let Ok(extent) = ExtentData::from_bytes(data) else {
+            return;
+        };
+        if extent.is_inline() || extent.is_sparse() {
+            return;
+        }
+
+        self.report.data_extents_checked += 1;
+        let disk_bytenr = extent.disk_bytenr.unwrap_or(0);
+        let len = extent.disk_num_bytes.unwrap_or(0).max(1);
+        let chunks = fs.chunk_tree();
+        if !chunks.contains(disk_bytenr) || !chunks.contains(disk_bytenr + len - 1) {
+            self.error(IntegrityError::UnmappedExtent {
+                tree_id: self.tree_id,
+                ino,
+                offset: key.offset,
+                disk_bytenr,
+            });
+        }
+    }
+
+    fn error(&mut self, error: IntegrityError) {
+        self.report.errors.push(error);
+    }
+
+    /// Records `error` for a block that is skipped with everything below it
+    fn skip(&mut self, error: IntegrityError) {
+        self.skipped = true;
+        self.error(error);
+    }
+}
+
+/// Classifies a failure to read or parse the tree block at `logical`
+fn node_error(logical: u64, err: BtrfsError) -> IntegrityError {
+    match err {
+        BtrfsError::ChecksumMismatch { .. } => IntegrityError::BadChecksum { logical },
+        err => IntegrityError::Malformed {
+            logical,
+            reason: err.to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{
+        checksum,
+        subvolume::read_root_item,
+        tree::{ITEM_SIZE, KEY_PTR_SIZE, NODE_HEADER_SIZE},
+    };
+    use crate::test_utils::{dir_item, extent_data, extent_item, root_item, ImageBuilder};
+
+    fn builder() -> ImageBuilder {
+        let root = objectid::FIRST_FREE;
+        let mut builder = ImageBuilder::new();
+        builder
+            .root_dir(objectid::FS_TREE)
+            .dir(objectid::FS_TREE, root, 257, "docs")
+            .file(objectid::FS_TREE, root, 258, "a.txt", 10)
+            .file(objectid::FS_TREE, 257, 259, "b.txt", 20);
+        builder
+    }
+
+    #[test]
+    fn test_check_consistent_dirs() {
+        let report = check(&builder().open(), objectid::FS_TREE).unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.dirs_checked, 2);
+    }
+
+    #[test]
+    fn test_check_dir_index_without_dir_item() {
+        // An index entry added on its own, as if its DIR_ITEM were lost
+        let location = BtrfsKey::new(260, item_type::INODE_ITEM, 0);
+        let mut builder = builder();
+        builder.insert(
+            objectid::FS_TREE,
+            BtrfsKey::new(257, item_type::DIR_INDEX, 9),
+            dir_item(&location, 1, "lost.txt"),
+        );
+        let fs = builder.open();
+
+        let expected = DirMismatch::MissingDirItem {
+            index: 9,
+            name: "lost.txt".to_string(),
+        };
+        assert_eq!(
+            check_dir(&fs, objectid::FS_TREE, 257).unwrap(),
+            vec![expected.clone()]
+        );
+        assert!(check_dir(&fs, objectid::FS_TREE, objectid::FIRST_FREE)
+            .unwrap()
+            .is_empty());
+
+        let report = check(&fs, objectid::FS_TREE).unwrap();
+        assert_eq!(report.dir_mismatches, vec![(257, expected)]);
+    }
+
+    #[test]
+    fn test_check_dir_item_without_dir_index() {
+        let location = BtrfsKey::new(260, item_type::INODE_ITEM, 0);
+        let mut builder = builder();
+        builder.insert(
+            objectid::FS_TREE,
+            BtrfsKey::new(257, item_type::DIR_ITEM, btrfs_name_hash("orphan")),
+            dir_item(&location, 1, "orphan"),
+        );
+
+        let mismatches = check_dir(&builder.open(), objectid::FS_TREE, 257).unwrap();
+        assert_eq!(
+            mismatches,
+            vec![DirMismatch::MissingDirIndex {
+                hash: btrfs_name_hash("orphan"),
+                name: "orphan".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_check_data_extents() {
+        let mut builder = builder();
+        builder
+            .insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(258, item_type::EXTENT_DATA, 0),
+                extent_data(0x300000, 0x1000),
+            )
+            .insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(259, item_type::EXTENT_DATA, 0),
+                extent_data(0x300000, 0x1000),
+            )
+            .insert(
+                objectid::EXTENT_TREE,
+                BtrfsKey::new(0x300000, item_type::EXTENT_ITEM, 0x1000),
+                extent_item(2),
+            );
+
+        let report = check(&builder.open(), objectid::FS_TREE).unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.data_extents_checked, 1);
+
+        // Reflinked once more without the count being raised
+        builder.insert(
+            objectid::FS_TREE,
+            BtrfsKey::new(259, item_type::EXTENT_DATA, 0x1000),
+            extent_data(0x300000, 0x1000),
+        );
+        let report = check(&builder.open(), objectid::FS_TREE).unwrap();
+        assert_eq!(
+            report.extent_mismatches,
+            vec![ExtentMismatch::Undercounted {
+                disk_bytenr: 0x300000,
+                refs: 2,
+                seen: 3,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_check_dangling_data_extent() {
+        let mut builder = builder();
+        builder
+            .insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(258, item_type::EXTENT_DATA, 0),
+                extent_data(0x300000, 0x1000),
+            )
+            // Holes and inline data have no extent to account
+            .insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(259, item_type::EXTENT_DATA, 0),
+                extent_data(0, 0x1000),
+            )
+            .insert(
+                objectid::EXTENT_TREE,
+                BtrfsKey::new(0x304000, item_type::EXTENT_ITEM, 0x1000),
+                extent_item(1),
+            );
+
+        let report = check(&builder.open(), objectid::FS_TREE).unwrap();
+        assert!(!report.is_clean());
+        assert!(report.dir_mismatches.is_empty());
+        assert_eq!(
+            report.extent_mismatches,
+            vec![ExtentMismatch::Dangling {
+                ino: 258,
+                offset: 0,
+                disk_bytenr: 0x300000,
+                disk_num_bytes: 0x1000,
+            }]
+        );
+
+        // An EXTENT_ITEM of another size does not cover the reference
+        builder.insert(
+            objectid::EXTENT_TREE,
+            BtrfsKey::new(0x300000, item_type::EXTENT_ITEM, 0x2000),
+            extent_item(1),
+        );
+        let report = check(&builder.open(), objectid::FS_TREE).unwrap();
+        assert_eq!(report.extent_mismatches.len(), 1);
+    }
+
+    #[test]
+    fn test_check_malformed_data_extent() {
+        let mut builder = builder();
+        builder.insert(
+            objectid::FS_TREE,
+            BtrfsKey::new(258, item_type::EXTENT_DATA, 0),
+            vec![0u8; 5],
+        );
+
+        // Reported like any other mismatch, and the directories still checked
+        let report = check(&builder.open(), objectid::FS_TREE).unwrap();
+        assert_eq!(report.dirs_checked, 2);
+        assert!(report.dir_mismatches.is_empty());
+        assert!(matches!(
+            report.extent_mismatches.as_slice(),
+            [ExtentMismatch::Malformed {
+                ino: 258,
+                offset: 0,
+                ..
+            }]
+        ));
+    }
+
+    /// Overwrites bytes of the tree block at `logical`, keeping its
+    /// checksum valid
+    fn patch_node(fs: &BtrfsFilesystem, logical: u64, offset: usize, bytes: &[u8]) {
+        let mut node = vec![0u8; fs.node_size() as usize];
+        fs.device().read_at(logical, &mut node).unwrap();
+        node[offset..offset + bytes.len()].copy_from_slice(bytes);
+        let csum = checksum::crc32c(&node[0x20..]);
+        node[..4].copy_from_slice(&csum.to_le_bytes());
+        fs.device().write_at(logical, &node).unwrap();
+    }
+
+    /// Offset of the key in `slot` of a leaf
+    fn leaf_key(slot: usize) -> usize {
+        NODE_HEADER_SIZE + slot * ITEM_SIZE
+    }
+
+    /// A filesystem whose FS tree needs internal nodes
+    fn deep_builder() -> ImageBuilder {
+        let mut builder = builder();
+        builder.node_size(4096);
+        for ino in 260..320 {
+            let name = format!("file{}", ino);
+            builder.file(objectid::FS_TREE, objectid::FIRST_FREE, ino, &name, 0);
+        }
+        builder
+    }
+
+    fn deep_fs() -> BtrfsFilesystem {
+        deep_builder().open()
+    }
+
+    #[test]
+    fn test_check_integrity_clean() {
+        let fs = builder().open();
+        let report = check_integrity(&fs);
+        assert!(report.is_clean(), "{:?}", report.errors);
+        // Root, chunk and FS trees
+        assert_eq!(report.trees_checked, 3);
+        assert_eq!(report.nodes_checked, 3);
+
+        let fs = deep_fs();
+        let report = check_integrity(&fs);
+        assert!(report.is_clean(), "{:?}", report.errors);
+        assert!(report.nodes_checked > report.trees_checked);
+        assert_eq!(report.counts(), IntegrityCounts::default());
+    }
+
+    #[test]
+    fn test_check_integrity_shared_blocks() {
+        let mut builder = deep_builder();
+        let fs = builder.open();
+        let fs_root = read_root_item(&fs, objectid::FS_TREE).unwrap();
+        let alone = check_integrity(&fs);
+
+        // A snapshot sharing every block of the FS tree
+        let snapshot = BtrfsKey::new(objectid::FIRST_FREE, item_type::ROOT_ITEM, 0);
+        let item = root_item(fs_root.bytenr, fs_root.level, 1);
+        let fs = builder.insert(objectid::ROOT_TREE, snapshot, item).open();
+        let shared = read_root_item(&fs, objectid::FIRST_FREE).unwrap().bytenr;
+        assert_eq!(shared, fs_root.bytenr);
+
+        let report = check_integrity(&fs);
+        assert!(report.is_clean(), "{:?}", report.errors);
+        assert_eq!(report.trees_checked, alone.trees_checked + 1);
+        assert_eq!(report.nodes_checked, alone.nodes_checked);
+    }
+
+    #[test]
+    fn test_check_integrity_orphans_and_unmapped() {
+        let mut builder = builder();
+        builder
+            .insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(258, item_type::EXTENT_DATA, 0),
+                extent_data(1 << 40, 0x1000),
+            )
+            // Two items of an inode that doesn't exist, reported once
+            .insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(300, item_type::INODE_REF, objectid::FIRST_FREE),
+                Vec::new(),
+            )
+            .insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(300, item_type::EXTENT_DATA, 0),
+                extent_data(0x300000, 0x1000),
+            );
+
+        let report = builder.open().check_integrity();
+        assert_eq!(report.data_extents_checked, 2);
+        assert_eq!(
+            report.errors,
+            vec![
+                IntegrityError::UnmappedExtent {
+                    tree_id: objectid::FS_TREE,
+                    ino: 258,
+                    offset: 0,
+                    disk_bytenr: 1 << 40,
+                },
+                IntegrityError::Orphan {
+                    tree_id: objectid::FS_TREE,
+                    ino: 300,
+                },
+            ]
+        );
+        let counts = report.counts();
+        assert_eq!((counts.unmapped, counts.orphans), (1, 1));
+    }
+
+    #[test]
+    fn test_check_integrity_bad_checksum() {
+        let fs = deep_fs();
+        let fs_root = read_root_item(&fs, objectid::FS_TREE).unwrap().bytenr;
+        let node = fs.node_at(fs_root).unwrap();
+        let ptrs = node.key_ptrs().unwrap();
+        let leaf = ptrs[0].blockptr;
+        fs.device().write_at(leaf + 0x200, &[0xEE]).unwrap();
+
+        // The rest of the tree is still walked
+        let report = check_integrity(&fs);
+        assert_eq!(
+            report.errors,
+            vec![IntegrityError::BadChecksum { logical: leaf }]
+        );
+        assert_eq!(report.counts().bad_checksums, 1);
+        assert!(report.nodes_checked as usize > ptrs.len());
+    }
+
+    #[test]
+    fn test_check_integrity_malformed_node() {
+        let fs = deep_fs();
+        let fs_root = read_root_item(&fs, objectid::FS_TREE).unwrap().bytenr;
+        let leaf = fs.node_at(fs_root).unwrap().key_ptrs().unwrap()[0].blockptr;
+        // A leaf with a valid checksum claiming more items than fit
+        patch_node(&fs, leaf, 0x60, &u32::MAX.to_le_bytes());
+
+        let report = check_integrity(&fs);
+        assert!(matches!(
+            report.errors.as_slice(),
+            [IntegrityError::Malformed { logical, .. }] if *logical == leaf
+        ));
+        assert_eq!(report.counts().structure, 1);
+        assert_eq!(report.counts().bad_checksums, 0);
+    }
+
+    #[test]
+    fn test_check_integrity_keys() {
+        // A leaf whose second key sorts before its first
+        let fs = builder().open();
+        let fs_root = read_root_item(&fs, objectid::FS_TREE).unwrap().bytenr;
+        patch_node(&fs, fs_root, leaf_key(1), &0u64.to_le_bytes());
+        assert_eq!(
+            check_integrity(&fs).errors,
+            vec![IntegrityError::KeyOrder {
+                logical: fs_root,
+                slot: 1,
+            }]
+        );
+
+        // A leaf key past the next leaf's first key
+        let fs = deep_fs();
+        let fs_root = read_root_item(&fs, objectid::FS_TREE).unwrap().bytenr;
+        let leaf = fs.node_at(fs_root).unwrap().key_ptrs().unwrap()[0].blockptr;
+        let last = fs.node_at(leaf).unwrap().item_count() - 1;
+        let key = (objectid::LAST_FREE + 1).to_le_bytes();
+        patch_node(&fs, leaf, leaf_key(last), &key);
+
+        let report = check_integrity(&fs);
+        assert_eq!(
+            report.errors,
+            vec![IntegrityError::KeyRange {
+                logical: leaf,
+                slot: last,
+            }]
+        );
+        assert_eq!(report.counts().structure, 1);
+    }
+
+    #[test]
+    fn test_check_integrity_unmapped_node() {
+        let fs = deep_fs();
+        let fs_root = read_root_item(&fs, objectid::FS_TREE).unwrap().bytenr;
+        // Point the root's second child outside the chunk
+        let blockptr = NODE_HEADER_SIZE + KEY_PTR_SIZE + 17;
+        patch_node(&fs, fs_root, blockptr, &(1u64 << 40).to_le_bytes());
+
+        assert_eq!(
+            check_integrity(&fs).errors,
+            vec![IntegrityError::UnmappedNode { logical: 1 << 40 }]
+        );
+    }
+}
@@ -1,7 +1,8 @@
 //! Checksum utilities for BTRFS
 //!
-//! BTRFS uses CRC32c checksums for data integrity verification.
-//! All hot-path functions are marked inline for performance.
+//! BTRFS uses CRC32c checksums by default, with XXHash64 as an option
+//! chosen at mkfs time. All hot-path functions are marked inline for
+//! performance.
 
 use super::{BtrfsError, Result};
 
@@ -10,7 +11,7 @@ use super::{BtrfsError, Result};
 pub enum Checksum {
     /// CRC32c (Castagnoli)
     Crc32c,
-    /// XXHash64 (not yet implemented)
+    /// XXHash64 with seed 0
     XxHash64,
     /// SHA256 (not yet implemented)
     Sha256,
@@ -44,6 +45,43 @@ impl Checksum {
             Self::Blake2b => 32,
         }
     }
+
+    /// Computes the checksum of `data` as an integer
+    ///
+    /// On disk the value is stored little-endian in the first `size()` bytes
+    /// of the 32-byte csum field.
+    #[inline]
+    pub fn compute(&self, data: &[u8]) -> Result<u64> {
+        match self {
+            Self::Crc32c => Ok(crc32c(data) as u64),
+            Self::XxHash64 => Ok(xxhash64(data)),
+            Self::Sha256 | Self::Blake2b => Err(BtrfsError::UnsupportedFeature(format!(
+                "Checksum type {:?} is not supported",
+                self
+            ))),
+        }
+    }
+
+    /// Verifies `data` against the checksum stored at the start of `csum`
+    pub fn verify(&self, data: &[u8], csum: &[u8]) -> Result<()> {
+        let size = self.size();
+        if csum.len() < size {
+            return Err(BtrfsError::Corrupt(format!(
+                "Checksum field too small: {} bytes",
+                csum.len()
+            )));
+        }
+
+        let actual = self.compute(data)?;
+        let mut stored = [0u8; 8];
+        stored[..size.min(8)].copy_from_slice(&csum[..size.min(8)]);
+        let expected = u64::from_le_bytes(stored);
+
+        if expected != actual {
+            return Err(BtrfsError::ChecksumMismatch { expected, actual });
+        }
+        Ok(())
+    }
 }
 
 /// Computes a CRC32c checksum
@@ -65,11 +103,109 @@ pub fn crc32c_append(crc: u32, data: &[u8]) -> u32 {
 pub fn verify_crc32c(data: &[u8], expected: u32) -> Result<()> {
     let actual = crc32c(data);
     if actual != expected {
-        return Err(BtrfsError::ChecksumMismatch { expected, actual });
+        return Err(BtrfsError::ChecksumMismatch {
+            expected: expected as u64,
+            actual: actual as u64,
+        });
     }
     Ok(())
 }
 
+const XXH_PRIME64_1: u64 = 0x9E3779B185EBCA87;
+const XXH_PRIME64_2: u64 = 0xC2B2AE3D27D4EB4F;
+const XXH_PRIME64_3: u64 = 0x165667B19E3779F9;
+const XXH_PRIME64_4: u64 = 0x85EBCA77C2B2AE63;
+const XXH_PRIME64_5: u64 = 0x27D4EB2F165667C5;
+
+#[inline(always)]
+fn xxh64_round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(XXH_PRIME64_2))
+        .rotate_left(31)
+        .wrapping_mul(XXH_PRIME64_1)
+}
+
+#[inline(always)]
+fn xxh64_merge_round(acc: u64, val: u64) -> u64 {
+    (acc ^ xxh64_round(0, val))
+        .wrapping_mul(XXH_PRIME64_1)
+        .wrapping_add(XXH_PRIME64_4)
+}
+
+#[inline(always)]
+fn read_u64(data: &[u8]) -> u64 {
+    u64::from_le_bytes(data[..8].try_into().unwrap())
+}
+
+#[inline(always)]
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_le_bytes(data[..4].try_into().unwrap())
+}
+
+/// Computes an XXHash64 checksum with seed 0, as used by BTRFS
+pub fn xxhash64(data: &[u8]) -> u64 {
+    let len = data.len();
+    let mut rest = data;
+
+    let mut hash = if len >= 32 {
+        let mut v1 = XXH_PRIME64_1.wrapping_add(XXH_PRIME64_2);
+        let mut v2 = XXH_PRIME64_2;
+        let mut v3 = 0u64;
+        let mut v4 = 0u64.wrapping_sub(XXH_PRIME64_1);
+
+        while rest.len() >= 32 {
+            v1 = xxh64_round(v1, read_u64(&rest[0..]));
+            v2 = xxh64_round(v2, read_u64(&rest[8..]));
+            v3 = xxh64_round(v3, read_u64(&rest[16..]));
+            v4 = xxh64_round(v4, read_u64(&rest[24..]));
+            rest = &rest[32..];
+        }
+
+        let mut acc = v1
+            .rotate_left(1)
+            .wrapping_add(v2.rotate_left(7))
+            .wrapping_add(v3.rotate_left(12))
+            .wrapping_add(v4.rotate_left(18));
+        acc = xxh64_merge_round(acc, v1);
+        acc = xxh64_merge_round(acc, v2);
+        acc = xxh64_merge_round(acc, v3);
+        xxh64_merge_round(acc, v4)
+    } else {
+        XXH_PRIME64_5
+    };
+
+    hash = hash.wrapping_add(len as u64);
+
+    while rest.len() >= 8 {
+        hash ^= xxh64_round(0, read_u64(rest));
+        hash = hash
+            .rotate_left(27)
+            .wrapping_mul(XXH_PRIME64_1)
+            .wrapping_add(XXH_PRIME64_4);
+        rest = &rest[8..];
+    }
+
+    if rest.len() >= 4 {
+        hash ^= (read_u32(rest) as u64).wrapping_mul(XXH_PRIME64_1);
+        hash = hash
+            .rotate_left(23)
+            .wrapping_mul(XXH_PRIME64_2)
+            .wrapping_add(XXH_PRIME64_3);
+        rest = &rest[4..];
+    }
+
+    for &byte in rest {
+        hash ^= (byte as u64).wrapping_mul(XXH_PRIME64_5);
+        hash = hash.rotate_left(11).wrapping_mul(XXH_PRIME64_1);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(XXH_PRIME64_2);
+    hash ^= hash >> 29;
+    hash = hash.wrapping_mul(XXH_PRIME64_3);
+    hash ^= hash >> 32;
+    hash
+}
+
 /// Computes a checksum for a tree node
 /// 
 /// Node checksum covers everything after the checksum field (offset 0x20)
@@ -82,22 +218,15 @@ pub fn compute_node_checksum(data: &[u8]) -> u32 {
     }
 }
 
-/// Verifies a tree node checksum
-pub fn verify_node_checksum(data: &[u8]) -> Result<()> {
-    if data.len() < 0x24 {
+/// Verifies a tree node checksum using the filesystem's checksum type
+pub fn verify_node_checksum(data: &[u8], csum: Checksum) -> Result<()> {
+    if data.len() < 0x20 + csum.size() {
         return Err(BtrfsError::Corrupt(
             "Node too small for checksum".to_string(),
         ));
     }
 
-    let expected = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
-    let actual = compute_node_checksum(data);
-
-    if expected != actual {
-        return Err(BtrfsError::ChecksumMismatch { expected, actual });
-    }
-
-    Ok(())
+    csum.verify(&data[0x20..], &data[..0x20])
 }
 
 #[cfg(test)]
@@ -177,7 +306,7 @@ mod tests {
     #[test]
     fn test_verify_node_checksum_too_small() {
         let data = vec![0u8; 30]; // Smaller than 0x24
-        let result = verify_node_checksum(&data);
+        let result = verify_node_checksum(&data, Checksum::Crc32c);
         assert!(result.is_err());
     }
 
@@ -192,7 +321,7 @@ mod tests {
         let csum = compute_node_checksum(&data);
         data[0..4].copy_from_slice(&csum.to_le_bytes());
         
-        assert!(verify_node_checksum(&data).is_ok());
+        assert!(verify_node_checksum(&data, Checksum::Crc32c).is_ok());
     }
 
     #[test]
@@ -203,7 +332,7 @@ mod tests {
         data[0x20..0x20 + test_content.len()].copy_from_slice(test_content);
         data[0..4].copy_from_slice(&0xDEADBEEFu32.to_le_bytes());
         
-        let result = verify_node_checksum(&data);
+        let result = verify_node_checksum(&data, Checksum::Crc32c);
         assert!(result.is_err());
     }
 
@@ -244,4 +373,40 @@ mod tests {
         let csum2 = csum1;
         assert_eq!(csum1, csum2);
     }
+
+    #[test]
+    fn test_xxhash64_known_values() {
+        assert_eq!(xxhash64(b""), 0xEF46DB3751D8E999);
+        assert_eq!(xxhash64(b"a"), 0xD24EC4F1A98C6E5B);
+        assert_eq!(xxhash64(b"abc"), 0x44BC2CF5AD770999);
+        assert_eq!(
+            xxhash64(b"Nobody inspects the spammish repetition"),
+            0xFBCEA83C8A378BF1
+        );
+    }
+
+    #[test]
+    fn test_verify_node_checksum_xxhash64() {
+        let mut data = vec![0u8; 100];
+        data[0x20..0x2c].copy_from_slice(b"test data!!!");
+        let csum = xxhash64(&data[0x20..]);
+        data[0..8].copy_from_slice(&csum.to_le_bytes());
+
+        assert!(verify_node_checksum(&data, Checksum::XxHash64).is_ok());
+        assert!(verify_node_checksum(&data, Checksum::Crc32c).is_err());
+
+        data[0x30] ^= 1;
+        assert!(matches!(
+            verify_node_checksum(&data, Checksum::XxHash64),
+            Err(BtrfsError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_unsupported_checksum_compute() {
+        assert!(matches!(
+            Checksum::Sha256.compute(b"data"),
+            Err(BtrfsError::UnsupportedFeature(_))
+        ));
+    }
 }
@@ -2,10 +2,12 @@
 //!
 //! The chunk tree maps logical addresses to physical addresses on disk.
 
+#[cfg(feature = "raid56")]
+use super::raid56::{self, Raid56Layout, StripePos};
 use super::{item_type, tree::BtrfsKey, BtrfsError, Result, Superblock};
 use crate::blockdev::BlockDevice;
 use byteorder::{ByteOrder, LittleEndian};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::sync::Arc;
 
 /// A chunk mapping entry
@@ -27,6 +29,21 @@ pub struct ChunkMapping {
     pub stripes: Vec<Stripe>,
 }
 
+impl ChunkMapping {
+    /// Returns true for RAID5 and RAID6 chunks
+    pub fn is_raid56(&self) -> bool {
+        self.type_flags & (chunk_type::RAID5 | chunk_type::RAID6) != 0
+    }
+
+    /// Returns true for chunks keeping a full copy on every stripe: DUP,
+    /// RAID1, RAID1C3 and RAID1C4
+    pub fn is_mirrored(&self) -> bool {
+        let mirrored =
+            chunk_type::DUP | chunk_type::RAID1 | chunk_type::RAID1C3 | chunk_type::RAID1C4;
+        self.type_flags & mirrored != 0
+    }
+}
+
 /// A stripe within a chunk
 #[derive(Debug, Clone)]
 pub struct Stripe {
@@ -59,6 +76,8 @@ pub struct ChunkTree {
     chunks: BTreeMap<u64, ChunkMapping>,
     /// Block device for reading tree nodes
     device: Arc<dyn BlockDevice>,
+    /// ID of the opened device; stripes on any other device are unreadable
+    devid: u64,
 }
 
 impl ChunkTree {
@@ -98,11 +117,15 @@ impl ChunkTree {
             chunks.insert(chunk.logical, chunk);
         }
 
-        Ok(Self { chunks, device })
+        Ok(Self {
+            chunks,
+            device,
+            devid: superblock.devid(),
+        })
     }
 
     /// Parses a CHUNK_ITEM from bytes
-    fn parse_chunk_item(data: &[u8], logical: u64) -> Result<ChunkMapping> {
+    pub fn parse_chunk_item(data: &[u8], logical: u64) -> Result<ChunkMapping> {
         if data.len() < 0x30 {
             return Err(BtrfsError::Corrupt("CHUNK_ITEM too small".to_string()));
         }
@@ -117,6 +140,19 @@ impl ChunkTree {
         let num_stripes = LittleEndian::read_u16(&data[44..46]);
         let sub_stripes = LittleEndian::read_u16(&data[46..48]);
 
+        if num_stripes == 0 || stripe_len == 0 {
+            return Err(BtrfsError::Corrupt(format!(
+                "CHUNK_ITEM at {} has {} stripes of length {}",
+                logical, num_stripes, stripe_len
+            )));
+        }
+        if logical.checked_add(size).is_none() {
+            return Err(BtrfsError::Corrupt(format!(
+                "CHUNK_ITEM at {} of size {} overflows the address space",
+                logical, size
+            )));
+        }
+
         // Parse stripes
         let mut stripes = Vec::with_capacity(num_stripes as usize);
         let mut offset = 0x30;
@@ -128,6 +164,12 @@ impl ChunkTree {
 
             let devid = LittleEndian::read_u64(&data[offset..offset + 8]);
             let stripe_offset = LittleEndian::read_u64(&data[offset + 8..offset + 16]);
+            if stripe_offset.checked_add(size).is_none() {
+                return Err(BtrfsError::Corrupt(format!(
+                    "CHUNK_ITEM at {} has a stripe at {} on device {} that overflows",
+                    logical, stripe_offset, devid
+                )));
+            }
             let mut dev_uuid = [0u8; 16];
             dev_uuid.copy_from_slice(&data[offset + 16..offset + 32]);
 
@@ -151,28 +193,71 @@ impl ChunkTree {
         })
     }
 
-    /// Translates a logical address to physical address(es)
-    pub fn logical_to_physical(&self, logical: u64) -> Result<Vec<u64>> {
-        // Find the chunk containing this logical address
-        let chunk = self
-            .chunks
+    /// Returns the chunk containing `logical`, if any
+    pub fn chunk_at(&self, logical: u64) -> Option<&ChunkMapping> {
+        self.chunks
             .range(..=logical)
             .next_back()
-            .map(|(_, v)| v)
-            .ok_or_else(|| {
-                BtrfsError::NotFound(format!("No chunk mapping for logical address {}", logical))
-            })?;
+            .map(|(_, chunk)| chunk)
+            .filter(|chunk| logical - chunk.logical < chunk.size)
+    }
 
-        // Check if address is within chunk
-        if logical >= chunk.logical + chunk.size {
-            return Err(BtrfsError::NotFound(format!(
-                "Logical address {} not in any chunk",
-                logical
-            )));
+    /// Returns how many bytes from `logical` are stored contiguously on
+    /// each device: up to the end of the stripe element on striped chunks,
+    /// or of the chunk otherwise
+    pub fn contiguous_run(&self, logical: u64) -> Result<u64> {
+        let chunk = self.chunk_at(logical).ok_or_else(|| {
+            BtrfsError::NotFound(format!("Logical address {} not in any chunk", logical))
+        })?;
+        let offset = logical - chunk.logical;
+        let striped =
+            chunk.type_flags & (chunk_type::RAID0 | chunk_type::RAID10) != 0 || chunk.is_raid56();
+        if striped && chunk.stripe_len > 0 {
+            Ok(chunk.stripe_len - offset % chunk.stripe_len)
+        } else {
+            Ok(chunk.size - offset)
         }
+    }
+
+    /// Returns true if `logical` falls inside a chunk
+    pub fn contains(&self, logical: u64) -> bool {
+        self.chunk_at(logical).is_some()
+    }
+
+    /// Translates a logical address to physical address(es) on the opened
+    /// device
+    ///
+    /// Copies on other devices are left out, so on a multi-device volume
+    /// the result may be empty; use
+    /// [`logical_to_physical_mapped`](Self::logical_to_physical_mapped) to
+    /// know which device each offset is on.
+    pub fn logical_to_physical(&self, logical: u64) -> Result<Vec<u64>> {
+        let stripes = self.logical_to_physical_mapped(logical)?;
+        Ok(stripes
+            .into_iter()
+            .filter(|&(devid, _)| devid == self.devid)
+            .map(|(_, physical)| physical)
+            .collect())
+    }
+
+    /// Translates a logical address to `(devid, physical)` pairs, one per
+    /// readable copy
+    pub fn logical_to_physical_mapped(&self, logical: u64) -> Result<Vec<(u64, u64)>> {
+        let chunk = self.chunk_at(logical).ok_or_else(|| {
+            BtrfsError::NotFound(format!("Logical address {} not in any chunk", logical))
+        })?;
 
         let offset_in_chunk = logical - chunk.logical;
 
+        if chunk.type_flags & (chunk_type::RAID0 | chunk_type::RAID10) != 0
+            && (chunk.stripe_len == 0 || chunk.num_stripes == 0)
+        {
+            return Err(BtrfsError::Corrupt(format!(
+                "Striped chunk at {} has {} stripes of length {}",
+                chunk.logical, chunk.num_stripes, chunk.stripe_len
+            )));
+        }
+
         // Calculate physical addresses based on RAID type
         let mut physical_addrs = Vec::new();
 
@@ -184,19 +269,66 @@ impl ChunkTree {
 
             if stripe_index < chunk.stripes.len() {
                 let stripe = &chunk.stripes[stripe_index];
-                let physical =
-                    stripe.offset + (stripe_nr / chunk.num_stripes as u64) * chunk.stripe_len + stripe_offset;
-                physical_addrs.push(physical);
+                self.check_device(stripe)?;
+                let offset =
+                    (stripe_nr / chunk.num_stripes as u64) * chunk.stripe_len + stripe_offset;
+                physical_addrs.push((stripe.devid, stripe_physical(chunk, stripe, offset)?));
             }
-        } else if chunk.type_flags & (chunk_type::RAID1 | chunk_type::DUP) != 0 {
-            // RAID1/DUP: mirrored
-            for stripe in &chunk.stripes {
-                physical_addrs.push(stripe.offset + offset_in_chunk);
+        } else if chunk.type_flags & chunk_type::RAID10 != 0 {
+            // RAID10: striped across groups of sub_stripes mirrors
+            let sub_stripes = chunk.sub_stripes as u64;
+            if sub_stripes == 0 || !(chunk.num_stripes as u64).is_multiple_of(sub_stripes) {
+                return Err(BtrfsError::Corrupt(format!(
+                    "RAID10 chunk at {} has {} stripes in groups of {}",
+                    chunk.logical, chunk.num_stripes, chunk.sub_stripes
+                )));
+            }
+            let groups = chunk.num_stripes as u64 / sub_stripes;
+            let stripe_nr = offset_in_chunk / chunk.stripe_len;
+            let stripe_offset = offset_in_chunk % chunk.stripe_len;
+            let first = ((stripe_nr % groups) * sub_stripes) as usize;
+            let offset = (stripe_nr / groups) * chunk.stripe_len + stripe_offset;
+
+            let mirrors = chunk.stripes.iter().skip(first).take(sub_stripes as usize);
+            for stripe in mirrors.filter(|s| self.has_device(s.devid)) {
+                physical_addrs.push((stripe.devid, stripe_physical(chunk, stripe, offset)?));
+            }
+            if physical_addrs.is_empty() && first < chunk.stripes.len() {
+                self.check_device(&chunk.stripes[first])?;
+            }
+        } else if chunk.is_raid56() {
+            #[cfg(not(feature = "raid56"))]
+            return Err(BtrfsError::UnsupportedFeature(
+                "RAID5/6 chunks (build with the raid56 feature)".to_string(),
+            ));
+
+            // Only the data stripe; parity is read when reconstructing
+            #[cfg(feature = "raid56")]
+            {
+                let layout = Raid56Layout::new(chunk)?;
+                let pos = layout.locate(offset_in_chunk);
+                let stripe = &chunk.stripes[layout.device_index(pos.full_stripe, pos.data_index)];
+                self.check_device(stripe)?;
+                physical_addrs.push((
+                    stripe.devid,
+                    stripe.offset + layout.element_offset(pos.full_stripe) + pos.stripe_offset,
+                ));
+            }
+        } else if chunk.is_mirrored() {
+            // RAID1/DUP: mirrored, any copy on a present device will do
+            for stripe in chunk.stripes.iter().filter(|s| self.has_device(s.devid)) {
+                let physical = stripe_physical(chunk, stripe, offset_in_chunk)?;
+                physical_addrs.push((stripe.devid, physical));
+            }
+            if physical_addrs.is_empty() && !chunk.stripes.is_empty() {
+                self.check_device(&chunk.stripes[0])?;
             }
         } else {
             // Single device
             if let Some(stripe) = chunk.stripes.first() {
-                physical_addrs.push(stripe.offset + offset_in_chunk);
+                self.check_device(stripe)?;
+                let physical = stripe_physical(chunk, stripe, offset_in_chunk)?;
+                physical_addrs.push((stripe.devid, physical));
             }
         }
 
@@ -210,6 +342,163 @@ impl ChunkTree {
         Ok(physical_addrs)
     }
 
+    /// Returns true if `devid` is a device this chunk tree can read from
+    pub fn has_device(&self, devid: u64) -> bool {
+        devid == self.devid || self.device.member(devid).is_some()
+    }
+
+    /// Returns the IDs of devices that hold stripes of some chunk but that
+    /// this chunk tree can't read from
+    pub fn missing_devices(&self) -> BTreeSet<u64> {
+        self.chunks
+            .values()
+            .flat_map(|chunk| &chunk.stripes)
+            .map(|stripe| stripe.devid)
+            .filter(|&devid| !self.has_device(devid))
+            .collect()
+    }
+
+    /// Reads from `physical` on the device with ID `devid`
+    pub fn read_device(&self, devid: u64, physical: u64, buf: &mut [u8]) -> Result<usize> {
+        match self.device.member(devid) {
+            Some(member) => Ok(member.read_at(physical, buf)?),
+            None if devid == self.devid => Ok(self.device.read_at(physical, buf)?),
+            None => Err(BtrfsError::UnsupportedFeature(format!(
+                "missing device {}",
+                devid
+            ))),
+        }
+    }
+
+    /// Writes to `physical` on the device with ID `devid`
+    pub fn write_device(&self, devid: u64, physical: u64, buf: &[u8]) -> Result<usize> {
+        match self.device.member(devid) {
+            Some(member) => Ok(member.write_at(physical, buf)?),
+            None if devid == self.devid => Ok(self.device.write_at(physical, buf)?),
+            None => Err(BtrfsError::UnsupportedFeature(format!(
+                "missing device {}",
+                devid
+            ))),
+        }
+    }
+
+    /// Fails if a stripe lives on a device that was not opened
+    fn check_device(&self, stripe: &Stripe) -> Result<()> {
+        if !self.has_device(stripe.devid) {
+            return Err(BtrfsError::UnsupportedFeature(format!(
+                "missing device {}",
+                stripe.devid
+            )));
+        }
+        Ok(())
+    }
+
+    /// Reads `buf.len()` bytes at `logical` from a RAID5/6 chunk
+    ///
+    /// Blocks whose data device is missing are reconstructed from the rest
+    /// of their full stripe. With `rebuild` set every block is, which
+    /// recovers blocks that were read but failed their checksum.
+    #[cfg(feature = "raid56")]
+    pub fn read_raid56(&self, logical: u64, buf: &mut [u8], rebuild: bool) -> Result<()> {
+        let chunk = self
+            .chunk_at(logical)
+            .filter(|chunk| chunk.is_raid56())
+            .ok_or_else(|| {
+                BtrfsError::NotFound(format!("No RAID5/6 chunk at logical address {}", logical))
+            })?;
+        let layout = Raid56Layout::new(chunk)?;
+
+        let mut done = 0;
+        while done < buf.len() {
+            let offset = logical - chunk.logical + done as u64;
+            if offset >= chunk.size {
+                return Err(BtrfsError::NotFound(format!(
+                    "Read at {} runs past the end of its chunk",
+                    logical
+                )));
+            }
+
+            // Each piece stays within one stripe element
+            let pos = layout.locate(offset);
+            let len = ((layout.stripe_len - pos.stripe_offset) as usize).min(buf.len() - done);
+            let piece = &mut buf[done..done + len];
+
+            if rebuild || !self.read_member(chunk, &layout, pos, pos.data_index, piece)? {
+                self.reconstruct(chunk, &layout, pos, piece)?;
+            }
+            done += len;
+        }
+
+        Ok(())
+    }
+
+    /// Reads member `member` of the full stripe at `pos`
+    ///
+    /// Returns false if the member's device is missing.
+    #[cfg(feature = "raid56")]
+    fn read_member(
+        &self,
+        chunk: &ChunkMapping,
+        layout: &Raid56Layout,
+        pos: StripePos,
+        member: usize,
+        buf: &mut [u8],
+    ) -> Result<bool> {
+        let stripe = &chunk.stripes[layout.device_index(pos.full_stripe, member)];
+        if !self.has_device(stripe.devid) {
+            return Ok(false);
+        }
+        let physical = stripe.offset + layout.element_offset(pos.full_stripe) + pos.stripe_offset;
+        self.read_device(stripe.devid, physical, buf)?;
+        Ok(true)
+    }
+
+    /// Rebuilds the data element at `pos` from the other data elements and
+    /// P, or from Q on RAID6 when P is unavailable
+    #[cfg(feature = "raid56")]
+    fn reconstruct(
+        &self,
+        chunk: &ChunkMapping,
+        layout: &Raid56Layout,
+        pos: StripePos,
+        out: &mut [u8],
+    ) -> Result<()> {
+        let unrecoverable = || {
+            BtrfsError::Corrupt(format!(
+                "Cannot reconstruct RAID5/6 data at {} from parity",
+                chunk.logical + pos.full_stripe * layout.stripe_len * layout.data_stripes as u64
+            ))
+        };
+
+        let mut others = Vec::with_capacity(layout.data_stripes - 1);
+        for member in (0..layout.data_stripes).filter(|&m| m != pos.data_index) {
+            let mut data = vec![0u8; out.len()];
+            if !self.read_member(chunk, layout, pos, member, &mut data)? {
+                return Err(unrecoverable());
+            }
+            others.push((member, data));
+        }
+
+        let mut acc = vec![0u8; out.len()];
+        if self.read_member(chunk, layout, pos, layout.data_stripes, &mut acc)? {
+            for (_, data) in &others {
+                raid56::xor_into(&mut acc, data);
+            }
+        } else if layout.raid6
+            && self.read_member(chunk, layout, pos, layout.data_stripes + 1, &mut acc)?
+        {
+            for (member, data) in &others {
+                raid56::q_accumulate(&mut acc, data, *member);
+            }
+            raid56::q_recover(&mut acc, pos.data_index);
+        } else {
+            return Err(unrecoverable());
+        }
+
+        out.copy_from_slice(&acc);
+        Ok(())
+    }
+
     /// Returns all chunks
     pub fn chunks(&self) -> &BTreeMap<u64, ChunkMapping> {
         &self.chunks
@@ -221,6 +510,16 @@ impl ChunkTree {
     }
 }
 
+/// Returns the device offset `offset` bytes into `stripe`
+fn stripe_physical(chunk: &ChunkMapping, stripe: &Stripe, offset: u64) -> Result<u64> {
+    stripe.offset.checked_add(offset).ok_or_else(|| {
+        BtrfsError::Corrupt(format!(
+            "Stripe at {} on device {} of chunk at {} overflows",
+            stripe.offset, stripe.devid, chunk.logical
+        ))
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -318,6 +617,34 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_parse_chunk_item_overflow() {
+        let corrupt = |data: &[u8], logical| {
+            matches!(
+                ChunkTree::parse_chunk_item(data, logical),
+                Err(BtrfsError::Corrupt(_))
+            )
+        };
+        let data = create_mock_chunk_item_data(1, chunk_type::DATA);
+
+        // Chunk ending exactly at the top of the address space is fine
+        assert!(ChunkTree::parse_chunk_item(&data, u64::MAX - 0x10000000).is_ok());
+        assert!(corrupt(&data, u64::MAX - 0xFFFFFFF));
+
+        // Stripe running off the end of the device offset space
+        let mut stripe = data.clone();
+        stripe[0x38..0x40].copy_from_slice(&(u64::MAX - 0xFFFFFFF).to_le_bytes());
+        assert!(corrupt(&stripe, 0x1000000));
+
+        // Zero stripe length or count would divide by zero when mapping
+        let mut zero_len = data.clone();
+        zero_len[16..24].copy_from_slice(&0u64.to_le_bytes());
+        assert!(corrupt(&zero_len, 0x1000000));
+        let mut zero_stripes = data.clone();
+        zero_stripes[44..46].copy_from_slice(&0u16.to_le_bytes());
+        assert!(corrupt(&zero_stripes, 0x1000000));
+    }
+
     #[test]
     fn test_stripe_debug() {
         let stripe = Stripe {
@@ -347,4 +674,308 @@ mod tests {
         let debug_str = format!("{:?}", chunk);
         assert!(debug_str.contains("logical: 16777216"));
     }
+
+    fn chunk_tree_with(type_flags: u64, devids: &[u64]) -> ChunkTree {
+        chunk_tree_on(crate::test_utils::ImageBuilder::new().device(), type_flags, devids)
+    }
+
+    /// Like `chunk_tree_with`, reading from `device`
+    fn chunk_tree_on(device: Arc<dyn BlockDevice>, type_flags: u64, devids: &[u64]) -> ChunkTree {
+        let builder = crate::test_utils::ImageBuilder::new();
+        let superblock = Superblock::parse(&builder.superblock_bytes()).unwrap();
+        let mut tree = ChunkTree::from_superblock(&superblock, device).unwrap();
+        tree.add_chunk(ChunkMapping {
+            logical: 0x10000000,
+            size: 0x100000,
+            stripe_len: 0x10000,
+            type_flags,
+            num_stripes: devids.len() as u16,
+            sub_stripes: if type_flags & chunk_type::RAID10 != 0 {
+                2
+            } else {
+                0
+            },
+            stripes: devids
+                .iter()
+                .enumerate()
+                .map(|(i, &devid)| Stripe {
+                    devid,
+                    offset: 0x100000 * (i as u64 + 1),
+                    dev_uuid: [0; 16],
+                })
+                .collect(),
+        });
+        tree
+    }
+
+    #[test]
+    fn test_stripe_on_missing_device() {
+        let tree = chunk_tree_with(chunk_type::DATA, &[2]);
+        assert!(tree.has_device(1));
+        assert!(!tree.has_device(2));
+
+        match tree.logical_to_physical(0x10000000) {
+            Err(BtrfsError::UnsupportedFeature(msg)) => assert_eq!(msg, "missing device 2"),
+            other => panic!("Expected missing device error, got {:?}", other),
+        }
+
+        let tree = chunk_tree_with(chunk_type::DATA | chunk_type::RAID0, &[1, 3]);
+        assert!(tree.logical_to_physical(0x10000000).is_ok());
+        assert!(tree.logical_to_physical(0x10010000).is_err());
+    }
+
+    #[test]
+    fn test_mirror_on_missing_device() {
+        // The copy on the present device is still readable
+        let tree = chunk_tree_with(chunk_type::DATA | chunk_type::RAID1, &[2, 1]);
+        assert_eq!(tree.logical_to_physical(0x10000100).unwrap(), vec![0x200100]);
+
+        let tree = chunk_tree_with(chunk_type::DATA | chunk_type::RAID1, &[2, 3]);
+        assert!(matches!(
+            tree.logical_to_physical(0x10000100),
+            Err(BtrfsError::UnsupportedFeature(_))
+        ));
+    }
+
+    #[test]
+    fn test_logical_to_physical_mapped() {
+        let tree = chunk_tree_with(chunk_type::DATA | chunk_type::RAID1, &[2, 1]);
+        assert_eq!(
+            tree.logical_to_physical_mapped(0x10000100).unwrap(),
+            vec![(1, 0x200100)]
+        );
+
+        // With both devices present each copy keeps its devid, while the
+        // plain offsets stay on the opened device
+        let first: Arc<dyn BlockDevice> = crate::test_utils::ImageBuilder::new().device();
+        let second: Arc<dyn BlockDevice> =
+            Arc::new(crate::test_utils::MemDevice::new(vec![0u8; 0x400000]));
+        let device = crate::blockdev::MultiDevice::new([(1, first), (2, second)]).unwrap();
+        let tree = chunk_tree_on(Arc::new(device), chunk_type::DATA | chunk_type::RAID1, &[2, 1]);
+
+        assert_eq!(
+            tree.logical_to_physical_mapped(0x10000100).unwrap(),
+            vec![(2, 0x100100), (1, 0x200100)]
+        );
+        assert_eq!(tree.logical_to_physical(0x10000100).unwrap(), vec![0x200100]);
+    }
+
+    #[test]
+    fn test_mapping_overflow_is_corrupt() {
+        let mut tree = chunk_tree_with(chunk_type::DATA, &[1]);
+        let mut chunk = tree.chunks[&0x10000000].clone();
+
+        // Added directly, bypassing parse_chunk_item's checks
+        chunk.stripes[0].offset = u64::MAX - 0x100;
+        tree.add_chunk(chunk.clone());
+        assert_eq!(tree.logical_to_physical(0x10000100).unwrap(), vec![u64::MAX]);
+        assert!(matches!(
+            tree.logical_to_physical(0x10000101),
+            Err(BtrfsError::Corrupt(_))
+        ));
+
+        chunk.stripes[0].offset = 0x100000;
+        chunk.type_flags |= chunk_type::RAID0;
+        chunk.stripe_len = 0;
+        tree.add_chunk(chunk);
+        assert!(matches!(
+            tree.logical_to_physical(0x10000000),
+            Err(BtrfsError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn test_raid0_offsets() {
+        // Stripes are 64 KiB and live 1 MiB apart on the device
+        let tree = chunk_tree_with(chunk_type::DATA | chunk_type::RAID0, &[1, 1, 1]);
+
+        // (logical, physical): stripe 0 of each device, then wrapping to
+        // the second row
+        for (logical, physical) in [
+            (0x10000000, 0x100000),
+            (0x10010010, 0x200010),
+            (0x10020000, 0x300000),
+            (0x10030000, 0x110000),
+            (0x1005FFFF, 0x31FFFF),
+        ] {
+            assert_eq!(tree.logical_to_physical(logical).unwrap(), vec![physical]);
+        }
+    }
+
+    #[test]
+    fn test_raid10_offsets() {
+        // Two groups of two mirrors
+        let tree = chunk_tree_with(chunk_type::DATA | chunk_type::RAID10, &[1, 1, 1, 1]);
+
+        // (logical, copies)
+        for (logical, copies) in [
+            (0x10000000, [0x100000, 0x200000]),
+            (0x10010020, [0x300020, 0x400020]),
+            (0x10020000, [0x110000, 0x210000]),
+            (0x1003FFFF, [0x31FFFF, 0x41FFFF]),
+        ] {
+            assert_eq!(tree.logical_to_physical(logical).unwrap(), copies);
+        }
+
+        // A group with one copy missing still reads from the other
+        let tree = chunk_tree_with(chunk_type::DATA | chunk_type::RAID10, &[1, 2, 3, 3]);
+        let copies = tree.logical_to_physical(0x10000000).unwrap();
+        assert_eq!(copies, vec![0x100000]);
+        assert!(matches!(
+            tree.logical_to_physical(0x10010000),
+            Err(BtrfsError::UnsupportedFeature(_))
+        ));
+
+        // Stripes that don't divide into mirror groups
+        let tree = chunk_tree_with(chunk_type::DATA | chunk_type::RAID10, &[1, 1, 1]);
+        assert!(matches!(
+            tree.logical_to_physical(0x10000000),
+            Err(BtrfsError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn test_chunk_at() {
+        let tree = chunk_tree_with(chunk_type::DATA, &[1]);
+
+        // Inside the added chunk, including its first and last bytes
+        for logical in [0x10000000, 0x10080000, 0x100FFFFF] {
+            assert!(tree.contains(logical));
+            assert_eq!(tree.chunk_at(logical).unwrap().logical, 0x10000000);
+        }
+
+        // In the gap after the bootstrap chunk
+        assert!(!tree.contains(0x8000000));
+        assert!(tree.chunk_at(0x0FFFFFFF).is_none());
+
+        // Past the end of the last chunk
+        assert!(!tree.contains(0x10100000));
+        assert!(!tree.contains(u64::MAX));
+    }
+
+    #[cfg(not(feature = "raid56"))]
+    #[test]
+    fn test_raid56_needs_feature() {
+        let tree = chunk_tree_with(chunk_type::DATA | chunk_type::RAID5, &[1, 1, 1]);
+        assert!(matches!(
+            tree.logical_to_physical(0x10000000),
+            Err(BtrfsError::UnsupportedFeature(_))
+        ));
+    }
+
+    /// A RAID5/6 chunk whose stripes are 256 KiB apart, so four devices
+    /// fit in the test image
+    #[cfg(feature = "raid56")]
+    fn raid56_tree(type_flags: u64, devids: &[u64]) -> ChunkTree {
+        let mut tree = chunk_tree_with(chunk_type::DATA | type_flags, devids);
+        let chunk = tree.chunks.get_mut(&0x10000000).unwrap();
+        for (i, stripe) in chunk.stripes.iter_mut().enumerate() {
+            stripe.offset = 0x200000 + i as u64 * 0x40000;
+        }
+        tree
+    }
+
+    /// Writes three full stripes of patterned data and their parity into a
+    /// RAID5/6 chunk, skipping missing devices, and returns the data in
+    /// logical order
+    #[cfg(feature = "raid56")]
+    fn write_raid56(tree: &ChunkTree) -> Vec<u8> {
+        let chunk = tree.chunk_at(0x10000000).unwrap();
+        let layout = Raid56Layout::new(chunk).unwrap();
+        let len = layout.stripe_len as usize;
+
+        let mut logical = Vec::new();
+        for full_stripe in 0..3 {
+            let data: Vec<Vec<u8>> = (0..layout.data_stripes)
+                .map(|i| {
+                    let seed = (full_stripe as usize * layout.data_stripes + i) as u8;
+                    (0..len)
+                        .map(|b| seed.wrapping_mul(31) ^ (b as u8))
+                        .collect()
+                })
+                .collect();
+
+            let mut p = vec![0u8; len];
+            let mut q = vec![0u8; len];
+            for (i, element) in data.iter().enumerate() {
+                raid56::xor_into(&mut p, element);
+                raid56::q_accumulate(&mut q, element, i);
+            }
+
+            let mut members: Vec<&[u8]> = data.iter().map(|d| d.as_slice()).collect();
+            members.push(&p);
+            if layout.raid6 {
+                members.push(&q);
+            }
+            for (member, bytes) in members.into_iter().enumerate() {
+                let stripe = &chunk.stripes[layout.device_index(full_stripe, member)];
+                if tree.has_device(stripe.devid) {
+                    let physical = stripe.offset + layout.element_offset(full_stripe);
+                    tree.device.write_at(physical, bytes).unwrap();
+                }
+            }
+            logical.extend(data.concat());
+        }
+        logical
+    }
+
+    #[cfg(feature = "raid56")]
+    #[test]
+    fn test_raid5_missing_device_reconstructed() {
+        // Device 2 holds one element of every full stripe
+        let tree = raid56_tree(chunk_type::RAID5, &[1, 2, 1]);
+        let expected = write_raid56(&tree);
+
+        let mut buf = vec![0u8; expected.len()];
+        tree.read_raid56(0x10000000, &mut buf, false).unwrap();
+        assert_eq!(buf, expected);
+
+        // Unaligned reads spanning elements
+        let mut buf = vec![0u8; 0x18000];
+        tree.read_raid56(0x10008000, &mut buf, false).unwrap();
+        assert_eq!(buf, expected[0x8000..0x20000]);
+
+        // The second element of full stripe 0 is on the missing device
+        assert!(matches!(
+            tree.logical_to_physical(0x10010000),
+            Err(BtrfsError::UnsupportedFeature(_))
+        ));
+        assert_eq!(
+            tree.logical_to_physical(0x10000010).unwrap(),
+            vec![0x200010]
+        );
+    }
+
+    #[cfg(feature = "raid56")]
+    #[test]
+    fn test_raid5_rebuild_corrupt_stripe() {
+        let tree = raid56_tree(chunk_type::RAID5, &[1, 1, 1]);
+        let expected = write_raid56(&tree);
+
+        // Corrupt data element 1 of full stripe 0, on device index 1
+        tree.device.write_at(0x240000 + 100, &[0xEE; 16]).unwrap();
+
+        let mut buf = vec![0u8; 0x10000];
+        tree.read_raid56(0x10010000, &mut buf, false).unwrap();
+        assert_ne!(buf, expected[0x10000..0x20000]);
+
+        tree.read_raid56(0x10010000, &mut buf, true).unwrap();
+        assert_eq!(buf, expected[0x10000..0x20000]);
+    }
+
+    #[cfg(feature = "raid56")]
+    #[test]
+    fn test_raid6_rebuild_from_q() {
+        // Full stripe 0 loses data element 0 and P, leaving Q
+        let tree = raid56_tree(chunk_type::RAID6, &[2, 1, 2, 1]);
+        let expected = write_raid56(&tree);
+
+        let mut buf = vec![0u8; expected.len()];
+        tree.read_raid56(0x10000000, &mut buf, false).unwrap();
+        assert_eq!(buf, expected);
+
+        // With two data elements and P gone nothing can be rebuilt
+        let tree = raid56_tree(chunk_type::RAID6, &[2, 2, 2, 1]);
+        assert!(tree.read_raid56(0x10000000, &mut buf, false).is_err());
+    }
 }
@@ -77,58 +77,199 @@ pub fn decompress_zlib(compressed: &[u8], uncompressed_size: usize) -> Result<Ve
     Ok(decompressed)
 }
 
+/// Size of the BTRFS LZO length fields (total size and segment headers)
+const LZO_LEN: usize = 4;
+
+/// BTRFS compresses LZO data in independent segments of this many input
+/// bytes, and segment headers never straddle a page of this size
+const LZO_SEGMENT_SIZE: usize = 4096;
+
 /// Decompresses LZO-compressed data
 ///
-/// BTRFS uses a specific LZO format with segment headers.
+/// BTRFS frames LZO1X data as a 4-byte total length followed by segments,
+/// each a 4-byte length plus the compressed bytes of one 4 KiB block. If
+/// fewer than 4 bytes remain in the current 4 KiB page after a segment,
+/// they are zero padding and the next header starts on the next page.
 pub fn decompress_lzo(compressed: &[u8], uncompressed_size: usize) -> Result<Vec<u8>> {
-    if compressed.len() < 4 {
-        return Err(BtrfsError::DecompressionError(
-            "LZO data too small".to_string(),
-        ));
+    let read_len = |at: usize| -> Result<usize> {
+        compressed
+            .get(at..at + LZO_LEN)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+            .ok_or_else(|| BtrfsError::DecompressionError("LZO: truncated header".to_string()))
+    };
+
+    let total_size = read_len(0)?;
+    if total_size > compressed.len() {
+        return Err(BtrfsError::DecompressionError(format!(
+            "LZO: total size {} exceeds {} bytes of input",
+            total_size,
+            compressed.len()
+        )));
     }
 
     let mut decompressed = Vec::with_capacity(uncompressed_size);
-    let mut offset = 0;
-
-    // BTRFS LZO format: total size (4 bytes) followed by segments
-    let _total_size = u32::from_le_bytes([
-        compressed[0],
-        compressed[1],
-        compressed[2],
-        compressed[3],
-    ]) as usize;
-    offset += 4;
-
-    while offset < compressed.len() && decompressed.len() < uncompressed_size {
-        // Each segment has: compressed size (4 bytes) + data
-        if offset + 4 > compressed.len() {
-            break;
+    let mut offset = LZO_LEN;
+
+    while offset < total_size && decompressed.len() < uncompressed_size {
+        let segment_size = read_len(offset)?;
+        offset += LZO_LEN;
+
+        let segment = compressed
+            .get(offset..offset + segment_size)
+            .filter(|_| offset + segment_size <= total_size)
+            .ok_or_else(|| BtrfsError::DecompressionError("LZO: truncated segment".to_string()))?;
+        offset += segment_size;
+
+        lzo1x_decompress(segment, LZO_SEGMENT_SIZE, &mut decompressed)?;
+
+        // Skip the padding that keeps the next header within one page
+        let page_left = LZO_SEGMENT_SIZE - offset % LZO_SEGMENT_SIZE;
+        if page_left < LZO_LEN {
+            offset += page_left;
         }
+    }
 
-        let segment_size = u32::from_le_bytes([
-            compressed[offset],
-            compressed[offset + 1],
-            compressed[offset + 2],
-            compressed[offset + 3],
-        ]) as usize;
-        offset += 4;
+    decompressed.truncate(uncompressed_size);
+    Ok(decompressed)
+}
 
-        if segment_size == 0 || offset + segment_size > compressed.len() {
-            break;
+/// Reads an LZO1X run length extension: each zero byte adds 255, and the
+/// first non-zero byte ends the run and is added as-is
+fn lzo_extension(src: &[u8], ip: &mut usize) -> Result<usize> {
+    let mut len = 0usize;
+    loop {
+        let byte = *src.get(*ip).ok_or_else(lzo_truncated)?;
+        *ip += 1;
+        if byte != 0 {
+            return Ok(len + byte as usize);
         }
+        len += 255;
+    }
+}
 
-        let segment_data = &compressed[offset..offset + segment_size];
-        offset += segment_size;
+fn lzo_truncated() -> BtrfsError {
+    BtrfsError::DecompressionError("LZO: truncated input".to_string())
+}
 
-        // Decompress segment using lz4 (LZO compatible for our purposes)
-        // Note: Full LZO support would require the lzo crate
-        let segment_decompressed = lz4::block::decompress(segment_data, Some(uncompressed_size as i32))
-            .map_err(|e| BtrfsError::DecompressionError(format!("LZO: {}", e)))?;
+/// Appends `len` literal bytes from `src` at `ip` to `out`
+fn lzo_copy_literals(
+    src: &[u8],
+    ip: &mut usize,
+    len: usize,
+    out: &mut Vec<u8>,
+    limit: usize,
+) -> Result<()> {
+    let literals = src.get(*ip..*ip + len).ok_or_else(lzo_truncated)?;
+    if out.len() + len > limit {
+        return Err(BtrfsError::DecompressionError(
+            "LZO: output overrun".to_string(),
+        ));
+    }
+    out.extend_from_slice(literals);
+    *ip += len;
+    Ok(())
+}
 
-        decompressed.extend_from_slice(&segment_decompressed);
+/// Decompresses one LZO1X stream, appending at most `max_len` bytes to `out`
+///
+/// Back-references may reach into bytes already in `out` from this stream
+/// only; each BTRFS segment is compressed independently.
+fn lzo1x_decompress(src: &[u8], max_len: usize, out: &mut Vec<u8>) -> Result<()> {
+    let base = out.len();
+    let limit = base + max_len;
+    let mut ip = 0;
+    // Number of literals copied by the previous instruction, 4 meaning a
+    // literal run; it changes how instructions 0..=15 are decoded
+    let mut state = 0usize;
+
+    let byte = |ip: &mut usize| -> Result<usize> {
+        let b = *src.get(*ip).ok_or_else(lzo_truncated)?;
+        *ip += 1;
+        Ok(b as usize)
+    };
+
+    // A first byte above 17 is a literal run without an instruction
+    if src.first().is_some_and(|&b| b > 17) {
+        let len = src[0] as usize - 17;
+        ip = 1;
+        lzo_copy_literals(src, &mut ip, len, out, limit)?;
+        state = if len < 4 { len } else { 4 };
     }
 
-    Ok(decompressed)
+    loop {
+        let inst = byte(&mut ip)?;
+        let (len, distance, trailing) = match inst {
+            64..=255 => {
+                let h = byte(&mut ip)?;
+                let len = if inst >= 128 {
+                    5 + ((inst >> 5) & 3)
+                } else {
+                    3 + ((inst >> 5) & 1)
+                };
+                (len, (h << 3) + ((inst >> 2) & 7) + 1, inst & 3)
+            }
+            32..=63 => {
+                let len = match inst & 31 {
+                    0 => 31 + lzo_extension(src, &mut ip)?,
+                    l => l,
+                };
+                let v = byte(&mut ip)? | (byte(&mut ip)? << 8);
+                (2 + len, (v >> 2) + 1, v & 3)
+            }
+            16..=31 => {
+                let len = match inst & 7 {
+                    0 => 7 + lzo_extension(src, &mut ip)?,
+                    l => l,
+                };
+                let v = byte(&mut ip)? | (byte(&mut ip)? << 8);
+                let distance = ((inst & 8) << 11) + (v >> 2);
+                if distance == 0 {
+                    // End of stream marker
+                    return Ok(());
+                }
+                (2 + len, distance + 0x4000, v & 3)
+            }
+            _ if state == 0 => {
+                let len = match inst {
+                    0 => 15 + lzo_extension(src, &mut ip)?,
+                    l => l,
+                };
+                lzo_copy_literals(src, &mut ip, 3 + len, out, limit)?;
+                state = 4;
+                continue;
+            }
+            _ if state < 4 => {
+                let h = byte(&mut ip)?;
+                (2, (h << 2) + (inst >> 2) + 1, inst & 3)
+            }
+            _ => {
+                let h = byte(&mut ip)?;
+                (3, (h << 2) + (inst >> 2) + 2049, inst & 3)
+            }
+        };
+
+        if distance > out.len() - base {
+            return Err(BtrfsError::DecompressionError(format!(
+                "LZO: match distance {} before start of output",
+                distance
+            )));
+        }
+        if out.len() + len > limit {
+            return Err(BtrfsError::DecompressionError(
+                "LZO: output overrun".to_string(),
+            ));
+        }
+
+        // Matches may overlap the bytes they produce
+        let start = out.len() - distance;
+        for i in 0..len {
+            let b = out[start + i];
+            out.push(b);
+        }
+
+        lzo_copy_literals(src, &mut ip, trailing, out, limit)?;
+        state = trailing;
+    }
 }
 
 /// Decompresses zstd-compressed data
@@ -165,22 +306,131 @@ pub fn compress_zlib(data: &[u8], level: i32) -> Result<Vec<u8>> {
         .map_err(|e| BtrfsError::DecompressionError(format!("zlib finish: {}", e)))
 }
 
-/// Compresses data using LZO
+/// Compresses data using LZO in the BTRFS segment framing
 pub fn compress_lzo(data: &[u8]) -> Result<Vec<u8>> {
-    // Use lz4 as a stand-in for LZO
-    let compressed = lz4::block::compress(data, None, false)
-        .map_err(|e| BtrfsError::DecompressionError(format!("LZO compress: {}", e)))?;
+    Ok(frame_lzo_segments(
+        data.chunks(LZO_SEGMENT_SIZE).map(lzo1x_compress),
+    ))
+}
+
+/// Wraps compressed LZO1X segments in the BTRFS length headers
+fn frame_lzo_segments(segments: impl IntoIterator<Item = Vec<u8>>) -> Vec<u8> {
+    let mut result = vec![0u8; LZO_LEN];
+
+    for segment in segments {
+        // Segment headers may not straddle a page boundary
+        let page_left = LZO_SEGMENT_SIZE - result.len() % LZO_SEGMENT_SIZE;
+        if page_left < LZO_LEN {
+            result.resize(result.len() + page_left, 0);
+        }
+
+        result.extend_from_slice(&(segment.len() as u32).to_le_bytes());
+        result.extend_from_slice(&segment);
+    }
+
+    let total_size = result.len() as u32;
+    result[..LZO_LEN].copy_from_slice(&total_size.to_le_bytes());
+    result
+}
 
-    // Wrap in BTRFS LZO format
-    let total_size = (4 + 4 + compressed.len()) as u32;
-    let segment_size = compressed.len() as u32;
+/// Appends an LZO1X run length extension for `len` (at least 1)
+fn lzo_push_extension(out: &mut Vec<u8>, mut len: usize) {
+    while len > 255 {
+        out.push(0);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+/// Emits a literal run, folding 1..=3 literals into the previous match
+///
+/// `trailing_pos` is the byte of the previous match instruction that holds
+/// its trailing-literal count, or `None` at the start of the stream.
+fn lzo_push_literals(out: &mut Vec<u8>, trailing_pos: Option<usize>, literals: &[u8]) {
+    let len = literals.len();
+    match (trailing_pos, len) {
+        (_, 0) => return,
+        (Some(pos), 1..=3) => out[pos] |= len as u8,
+        (None, 1..=238) => out.push(17 + len as u8),
+        (_, 4..=18) => out.push(len as u8 - 3),
+        _ => {
+            out.push(0);
+            lzo_push_extension(out, len - 18);
+        }
+    }
+    out.extend_from_slice(literals);
+}
+
+/// Greedy LZO1X compressor producing a stream `lzo1x_decompress` accepts
+fn lzo1x_compress(src: &[u8]) -> Vec<u8> {
+    const MIN_MATCH: usize = 4;
+    const MAX_DISTANCE: usize = 0xBFFF;
+    const HASH_BITS: u32 = 12;
+
+    let mut out = Vec::with_capacity(src.len() + src.len() / 16 + 16);
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let mut trailing_pos = None;
+    let mut literal_start = 0;
+    let mut ip = 0;
+
+    while ip + MIN_MATCH <= src.len() {
+        let word = u32::from_le_bytes([src[ip], src[ip + 1], src[ip + 2], src[ip + 3]]);
+        let hash = (word.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize;
+        let candidate = std::mem::replace(&mut table[hash], ip);
+
+        if candidate == usize::MAX
+            || ip - candidate > MAX_DISTANCE
+            || src[candidate..candidate + MIN_MATCH] != src[ip..ip + MIN_MATCH]
+        {
+            ip += 1;
+            continue;
+        }
 
-    let mut result = Vec::with_capacity(total_size as usize);
-    result.extend_from_slice(&total_size.to_le_bytes());
-    result.extend_from_slice(&segment_size.to_le_bytes());
-    result.extend_from_slice(&compressed);
+        let mut len = MIN_MATCH;
+        while ip + len < src.len() && src[candidate + len] == src[ip + len] {
+            len += 1;
+        }
+
+        lzo_push_literals(&mut out, trailing_pos, &src[literal_start..ip]);
+
+        let distance = ip - candidate;
+        if len <= 8 && distance <= 2048 {
+            let d = distance - 1;
+            let inst = if len <= 4 {
+                0x40 | ((len - 3) << 5)
+            } else {
+                0x80 | ((len - 5) << 5)
+            };
+            trailing_pos = Some(out.len());
+            out.push((inst | ((d & 7) << 2)) as u8);
+            out.push((d >> 3) as u8);
+        } else {
+            let (inst, d) = if distance <= 0x4000 {
+                (0x20u8, distance - 1)
+            } else {
+                let d = distance - 0x4000;
+                (0x10 | ((d >> 11) & 8) as u8, d & 0x3FFF)
+            };
+            let max_short = if inst == 0x20 { 31 } else { 7 };
+            if len - 2 <= max_short {
+                out.push(inst | (len - 2) as u8);
+            } else {
+                out.push(inst);
+                lzo_push_extension(&mut out, len - 2 - max_short);
+            }
+            trailing_pos = Some(out.len());
+            out.extend_from_slice(&((d << 2) as u16).to_le_bytes());
+        }
+
+        ip += len;
+        literal_start = ip;
+    }
 
-    Ok(result)
+    lzo_push_literals(&mut out, trailing_pos, &src[literal_start..]);
+
+    // End of stream: a far match with distance 0
+    out.extend_from_slice(&[0x11, 0x00, 0x00]);
+    out
 }
 
 /// Compresses data using zstd
@@ -339,4 +589,86 @@ mod tests {
         let decompressed = decompress_zlib(&compressed, data.len()).unwrap();
         assert_eq!(decompressed, data);
     }
+
+    #[test]
+    fn test_lzo1x_known_stream() {
+        // "abcabcabcabc": 3 literals, then a 9-byte match at distance 3
+        let literals = [0x14, b'a', b'b', b'c'];
+        let match_len_9_distance_3 = [0x20 | 7, 0x08, 0x00];
+        let end = [0x11, 0x00, 0x00];
+        let stream = [&literals[..], &match_len_9_distance_3, &end].concat();
+        let mut out = Vec::new();
+        lzo1x_decompress(&stream, 4096, &mut out).unwrap();
+        assert_eq!(out, b"abcabcabcabc");
+    }
+
+    #[test]
+    fn test_lzo_roundtrip_multi_page() {
+        // Mix of repetitive and noisy data over several 4 KiB segments
+        let mut data = Vec::new();
+        let mut x = 12345u32;
+        for i in 0..5 * 4096 + 123 {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            let byte = if (i / 700) % 2 == 0 {
+                (i % 37) as u8
+            } else {
+                (x >> 16) as u8
+            };
+            data.push(byte);
+        }
+
+        let compressed = compress_lzo(&data).unwrap();
+        assert!(compressed.len() < data.len());
+        let total = u32::from_le_bytes(compressed[..4].try_into().unwrap()) as usize;
+        assert_eq!(total, compressed.len());
+
+        let decompressed = decompress_lzo(&compressed, data.len()).unwrap();
+        assert_eq!(decompressed, data);
+
+        let via_generic = decompress(CompressionType::Lzo, &compressed, data.len()).unwrap();
+        assert_eq!(via_generic, data);
+    }
+
+    fn literal_segment(literals: &[u8]) -> Vec<u8> {
+        let mut segment = Vec::new();
+        lzo_push_literals(&mut segment, None, literals);
+        segment.extend_from_slice(&[0x11, 0x00, 0x00]);
+        segment
+    }
+
+    #[test]
+    fn test_lzo_segment_header_page_padding() {
+        // Size the first segment so the next header would start 2 bytes
+        // before the end of the first page
+        let first: Vec<u8> = (4000..4096)
+            .map(|len| (0..len).map(|i| (i * 7 % 251) as u8).collect::<Vec<u8>>())
+            .find(|block| LZO_LEN * 2 + literal_segment(block).len() == LZO_SEGMENT_SIZE - 2)
+            .unwrap();
+        let second = b"second segment".to_vec();
+
+        let framed = frame_lzo_segments([literal_segment(&first), literal_segment(&second)]);
+        assert_eq!(&framed[LZO_SEGMENT_SIZE - 2..LZO_SEGMENT_SIZE], &[0, 0]);
+        let header = &framed[LZO_SEGMENT_SIZE..LZO_SEGMENT_SIZE + 4];
+        assert_eq!(
+            u32::from_le_bytes(header.try_into().unwrap()),
+            literal_segment(&second).len() as u32
+        );
+
+        let decompressed = decompress_lzo(&framed, first.len() + second.len()).unwrap();
+        assert_eq!(decompressed, [first, second].concat());
+    }
+
+    #[test]
+    fn test_lzo_rejects_corrupt_input() {
+        let data: Vec<u8> = (0..4096u32).map(|i| (i % 13) as u8).collect();
+        let mut compressed = compress_lzo(&data).unwrap();
+
+        // Segment length running past the end of the input
+        compressed[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(decompress_lzo(&compressed, data.len()).is_err());
+
+        // Match reaching before the start of the output
+        let stream = [0x20 | 1, 0xFC, 0x00, 0x11, 0x00, 0x00];
+        assert!(lzo1x_decompress(&stream, 4096, &mut Vec::new()).is_err());
+    }
 }
@@ -0,0 +1,176 @@
+//! File fragmentation analysis
+//!
+//! Reports how a file's data is laid out on disk. This is read-only; actually
+//! rewriting extents needs the write path.
+
+use super::{
+    inode::ExtentData, item_type, path::resolve_path, tree::BtrfsKey, BtrfsError, BtrfsFilesystem,
+    Result,
+};
+
+/// Fragmentation statistics for a single file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FragReport {
+    /// Inode number of the file
+    pub ino: u64,
+    /// Number of extents holding data (holes are not counted)
+    pub extent_count: u64,
+    /// File bytes covered by those extents
+    pub total_bytes: u64,
+    /// Number of extents that do not start where the previous one ended on disk
+    pub gaps: u64,
+}
+
+impl FragReport {
+    /// Average number of file bytes per extent
+    pub fn average_extent_size(&self) -> u64 {
+        self.total_bytes.checked_div(self.extent_count).unwrap_or(0)
+    }
+}
+
+/// Builds a fragmentation report for the file at `path` in tree `tree_id`
+pub fn fragmentation_report(fs: &BtrfsFilesystem, tree_id: u64, path: &str) -> Result<FragReport> {
+    let (tree_id, ino, inode) = resolve_path(fs, tree_id, path)?;
+    if inode.is_dir() {
+        return Err(BtrfsError::NotAFile);
+    }
+
+    let tree = fs.tree(tree_id)?;
+    let min_key = BtrfsKey::new(ino, item_type::EXTENT_DATA, 0);
+    let max_key = BtrfsKey::new(ino, item_type::EXTENT_DATA, u64::MAX);
+
+    let mut report = FragReport {
+        ino,
+        ..Default::default()
+    };
+    let mut prev_end: Option<u64> = None;
+
+    for (_, data) in tree.search_range(&min_key, &max_key)? {
+        let extent = ExtentData::from_bytes(&data)?;
+        if extent.is_sparse() {
+            continue;
+        }
+
+        report.extent_count += 1;
+
+        if extent.is_inline() {
+            report.total_bytes += extent.ram_bytes;
+            continue;
+        }
+
+        let num_bytes = extent.num_bytes.unwrap_or(0);
+        let disk_bytenr = extent.disk_bytenr.unwrap_or(0);
+        report.total_bytes += num_bytes;
+
+        // Compressed extents are read whole, so only uncompressed ones can
+        // start part-way into their on-disk extent
+        let (logical, disk_len) = if extent.compression != 0 {
+            (disk_bytenr, extent.disk_num_bytes.unwrap_or(0))
+        } else {
+            (disk_bytenr + extent.offset.unwrap_or(0), num_bytes)
+        };
+
+        let physical = *fs.logical_to_physical(logical)?.first().ok_or_else(|| {
+            BtrfsError::NotFound(format!(
+                "No physical mapping for logical address {}",
+                logical
+            ))
+        })?;
+
+        if prev_end.is_some_and(|end| end != physical) {
+            report.gaps += 1;
+        }
+        prev_end = Some(physical + disk_len);
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::objectid;
+    use crate::test_utils::{extent_data, ImageBuilder};
+
+    fn file_with_extents(extents: &[(u64, u64, u64)]) -> BtrfsFilesystem {
+        let root = objectid::FIRST_FREE;
+        let mut builder = ImageBuilder::new();
+        builder
+            .root_dir(objectid::FS_TREE)
+            .dir(objectid::FS_TREE, root, 257, "data")
+            .file(objectid::FS_TREE, 257, 258, "big.bin", 64 * 1024);
+        for &(file_offset, disk_bytenr, len) in extents {
+            builder.insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(258, item_type::EXTENT_DATA, file_offset),
+                extent_data(disk_bytenr, len),
+            );
+        }
+        builder.open()
+    }
+
+    #[test]
+    fn test_fragmentation_report() {
+        let fs = file_with_extents(&[
+            (0, 0x200000, 0x1000),
+            (0x1000, 0x201000, 0x1000), // contiguous with the previous extent
+            (0x2000, 0, 0x2000),        // hole
+            (0x4000, 0x300000, 0x4000),
+            (0x8000, 0x280000, 0x8000),
+        ]);
+
+        let report = fragmentation_report(&fs, objectid::FS_TREE, "/data/big.bin").unwrap();
+        assert_eq!(report.ino, 258);
+        assert_eq!(report.extent_count, 4);
+        assert_eq!(report.total_bytes, 0xE000);
+        assert_eq!(report.average_extent_size(), 0xE000 / 4);
+        assert_eq!(report.gaps, 2);
+    }
+
+    #[test]
+    fn test_fragmentation_report_contiguous() {
+        let fs = file_with_extents(&[(0, 0x200000, 0x1000), (0x1000, 0x201000, 0x3000)]);
+
+        let report = fragmentation_report(&fs, objectid::FS_TREE, "data\\big.bin").unwrap();
+        assert_eq!(report.extent_count, 2);
+        assert_eq!(report.gaps, 0);
+    }
+
+    #[test]
+    fn test_fragmentation_report_errors() {
+        let fs = file_with_extents(&[]);
+
+        assert!(matches!(
+            fragmentation_report(&fs, objectid::FS_TREE, "/data"),
+            Err(BtrfsError::NotAFile)
+        ));
+        assert!(matches!(
+            fragmentation_report(&fs, objectid::FS_TREE, "/data/missing"),
+            Err(BtrfsError::NotFound(_))
+        ));
+
+        let empty = fragmentation_report(&fs, objectid::FS_TREE, "/data/big.bin").unwrap();
+        assert_eq!(empty.extent_count, 0);
+        assert_eq!(empty.average_extent_size(), 0);
+    }
+
+    #[test]
+    fn test_fragmentation_report_in_subvolume() {
+        let mut builder = ImageBuilder::new();
+        builder
+            .root_dir(objectid::FS_TREE)
+            .subvolume(300, objectid::FS_TREE, objectid::FIRST_FREE, "sub")
+            .file(300, objectid::FIRST_FREE, 257, "inner.bin", 0x2000)
+            .insert(
+                300,
+                BtrfsKey::new(257, item_type::EXTENT_DATA, 0),
+                extent_data(0x200000, 0x2000),
+            );
+        let fs = builder.open();
+
+        let report = fragmentation_report(&fs, objectid::FS_TREE, "/sub/inner.bin").unwrap();
+        assert_eq!(report.ino, 257);
+        assert_eq!(report.extent_count, 1);
+        assert_eq!(report.total_bytes, 0x2000);
+    }
+}
@@ -0,0 +1,209 @@
+//! Per-device space usage
+//!
+//! Each device has a DEV_ITEM in the chunk tree recording its size and how
+//! much of it chunks have been allocated from, and the dev tree holds a
+//! DEV_EXTENT for every stretch of the device backing a chunk. Together
+//! they give the device section of `btrfs filesystem usage`.
+
+use super::{
+    chunk::chunk_type, extent::DevExtent, item_type, objectid, tree::BtrfsKey, BtrfsError,
+    BtrfsFilesystem, Result,
+};
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::BTreeMap;
+
+/// Size of an on-disk DEV_ITEM
+pub const DEV_ITEM_SIZE: usize = 98;
+
+/// A device of the filesystem, as described by its DEV_ITEM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DevItem {
+    /// Device ID
+    pub devid: u64,
+    /// Size of the device in bytes
+    pub total_bytes: u64,
+    /// Bytes allocated to chunks
+    pub bytes_used: u64,
+    /// Sector size of the device
+    pub sector_size: u32,
+    /// Generation the device was last updated in
+    pub generation: u64,
+    /// UUID of the device
+    pub uuid: [u8; 16],
+    /// UUID of the filesystem the device belongs to
+    pub fsid: [u8; 16],
+}
+
+impl DevItem {
+    /// Parses a DEV_ITEM, as found in the chunk tree or the superblock
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() < DEV_ITEM_SIZE {
+            return Err(BtrfsError::Corrupt("DevItem too small".to_string()));
+        }
+
+        Ok(Self {
+            devid: LittleEndian::read_u64(&data[0..8]),
+            total_bytes: LittleEndian::read_u64(&data[8..16]),
+            bytes_used: LittleEndian::read_u64(&data[16..24]),
+            sector_size: LittleEndian::read_u32(&data[32..36]),
+            generation: LittleEndian::read_u64(&data[44..52]),
+            uuid: data[66..82].try_into().unwrap(),
+            fsid: data[82..98].try_into().unwrap(),
+        })
+    }
+}
+
+/// Space usage of one device
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeviceUsage {
+    /// Device ID
+    pub devid: u64,
+    /// Size of the device in bytes
+    pub total_bytes: u64,
+    /// Bytes allocated to chunks
+    pub bytes_used: u64,
+    /// Bytes allocated to data chunks
+    pub data: u64,
+    /// Bytes allocated to metadata chunks
+    pub metadata: u64,
+    /// Bytes allocated to system chunks
+    pub system: u64,
+    /// Bytes not allocated to any chunk
+    pub unallocated: u64,
+}
+
+/// Reports the space usage of every device of the filesystem, including
+/// devices that were not opened, ordered by device ID
+///
+/// The allocated total comes from each DEV_ITEM; its split by chunk type
+/// from the DEV_EXTENTs. A mixed chunk counts once: as system if it holds
+/// system data, else as metadata.
+pub fn device_usage(fs: &BtrfsFilesystem) -> Result<Vec<DeviceUsage>> {
+    let min_key = BtrfsKey::new(objectid::DEV_ITEMS, item_type::DEV_ITEM, 0);
+    let max_key = BtrfsKey::new(objectid::DEV_ITEMS, item_type::DEV_ITEM, u64::MAX);
+
+    let chunk_tree = fs.tree(objectid::CHUNK_TREE)?;
+    let mut devices = BTreeMap::new();
+    for (_, data) in chunk_tree.search_range(&min_key, &max_key)? {
+        let item = DevItem::from_bytes(&data)?;
+        devices.insert(
+            item.devid,
+            DeviceUsage {
+                devid: item.devid,
+                total_bytes: item.total_bytes,
+                bytes_used: item.bytes_used,
+                unallocated: item.total_bytes.saturating_sub(item.bytes_used),
+                ..Default::default()
+            },
+        );
+    }
+
+    let dev_tree = fs.tree(objectid::DEV_TREE)?;
+    for (devid, usage) in devices.iter_mut() {
+        let min_key = BtrfsKey::new(*devid, item_type::DEV_EXTENT, 0);
+        let max_key = BtrfsKey::new(*devid, item_type::DEV_EXTENT, u64::MAX);
+
+        for (_, data) in dev_tree.search_range(&min_key, &max_key)? {
+            let extent = DevExtent::from_bytes(&data)?;
+            // An extent of a chunk the chunk tree lacks still counts as
+            // allocated, but can't be split by type
+            let Some(chunk) = fs.chunk_tree().chunk_at(extent.chunk_offset) else {
+                continue;
+            };
+
+            if chunk.type_flags & chunk_type::SYSTEM != 0 {
+                usage.system += extent.length;
+            } else if chunk.type_flags & chunk_type::METADATA != 0 {
+                usage.metadata += extent.length;
+            } else if chunk.type_flags & chunk_type::DATA != 0 {
+                usage.data += extent.length;
+            }
+        }
+    }
+
+    Ok(devices.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{chunk_item, dev_extent, dev_item, ImageBuilder};
+
+    #[test]
+    fn test_parse_dev_item() {
+        let item = DevItem::from_bytes(&dev_item(2, 1 << 30, 1 << 20)).unwrap();
+        assert_eq!(item.devid, 2);
+        assert_eq!(item.total_bytes, 1 << 30);
+        assert_eq!(item.bytes_used, 1 << 20);
+        assert_eq!(item.sector_size, 4096);
+        assert_eq!(item.uuid, [2; 16]);
+
+        assert!(DevItem::from_bytes(&[0; DEV_ITEM_SIZE - 1]).is_err());
+    }
+
+    #[test]
+    fn test_device_usage() {
+        const MIB: u64 = 1 << 20;
+        let mut builder = ImageBuilder::new();
+        builder.root_dir(objectid::FS_TREE);
+
+        // A data, a metadata and a system chunk; the metadata chunk is
+        // mirrored across both devices
+        for (logical, flags) in [
+            (0x1000_0000, chunk_type::DATA),
+            (0x2000_0000, chunk_type::METADATA | chunk_type::RAID1),
+            (0x3000_0000, chunk_type::SYSTEM),
+        ] {
+            builder.insert(
+                objectid::CHUNK_TREE,
+                BtrfsKey::new(objectid::FIRST_CHUNK_TREE, item_type::CHUNK_ITEM, logical),
+                chunk_item(8 * MIB, 0, 4096, flags),
+            );
+        }
+
+        for (devid, total, used) in [(1, 1024 * MIB, 24 * MIB), (2, 512 * MIB, 8 * MIB)] {
+            builder.insert(
+                objectid::CHUNK_TREE,
+                BtrfsKey::new(objectid::DEV_ITEMS, item_type::DEV_ITEM, devid),
+                dev_item(devid, total, used),
+            );
+        }
+        for (devid, physical, chunk) in [
+            (1, MIB, 0x1000_0000),
+            (1, 9 * MIB, 0x2000_0000),
+            (1, 17 * MIB, 0x3000_0000),
+            (2, MIB, 0x2000_0000),
+        ] {
+            builder.insert(
+                objectid::DEV_TREE,
+                BtrfsKey::new(devid, item_type::DEV_EXTENT, physical),
+                dev_extent(chunk, 8 * MIB),
+            );
+        }
+
+        let usage = builder.open().device_usage().unwrap();
+        assert_eq!(
+            usage,
+            [
+                DeviceUsage {
+                    devid: 1,
+                    total_bytes: 1024 * MIB,
+                    bytes_used: 24 * MIB,
+                    data: 8 * MIB,
+                    metadata: 8 * MIB,
+                    system: 8 * MIB,
+                    unallocated: 1000 * MIB,
+                },
+                DeviceUsage {
+                    devid: 2,
+                    total_bytes: 512 * MIB,
+                    bytes_used: 8 * MIB,
+                    data: 0,
+                    metadata: 8 * MIB,
+                    system: 0,
+                    unallocated: 504 * MIB,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,116 @@
+//! Mount diagnostics
+//!
+//! Records the choices made while opening a filesystem, such as which
+//! superblock copy was trusted, so a recovery can be checked after the fact.
+
+use super::{SUPERBLOCK_MIRROR1_OFFSET, SUPERBLOCK_MIRROR2_OFFSET, SUPERBLOCK_OFFSET};
+
+/// One of the superblock copies a device carries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SuperblockCopy {
+    /// The copy at 64 KiB
+    #[default]
+    Primary,
+    /// The copy at 64 MiB
+    Mirror1,
+    /// The copy at 256 GiB
+    Mirror2,
+}
+
+impl SuperblockCopy {
+    /// Returns the copy stored at `offset`, if any
+    pub fn from_offset(offset: u64) -> Option<Self> {
+        match offset {
+            SUPERBLOCK_OFFSET => Some(Self::Primary),
+            SUPERBLOCK_MIRROR1_OFFSET => Some(Self::Mirror1),
+            SUPERBLOCK_MIRROR2_OFFSET => Some(Self::Mirror2),
+            _ => None,
+        }
+    }
+
+    /// Returns the device offset of the copy
+    pub fn offset(self) -> u64 {
+        match self {
+            Self::Primary => SUPERBLOCK_OFFSET,
+            Self::Mirror1 => SUPERBLOCK_MIRROR1_OFFSET,
+            Self::Mirror2 => SUPERBLOCK_MIRROR2_OFFSET,
+        }
+    }
+}
+
+/// How a filesystem was opened
+///
+/// Backup roots are never used in place of the superblock's tree roots and
+/// the tree log is never replayed, so neither is reported beyond whether a
+/// log was left behind.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MountDiagnostics {
+    /// The superblock copy the filesystem was opened from
+    pub superblock_copy: SuperblockCopy,
+    /// Whether the superblock points at a tree log; changes fsynced after
+    /// the last commit are only in the log and so are not visible
+    pub unreplayed_log: bool,
+    /// Devices of the filesystem that were not opened
+    pub missing_devices: u64,
+    /// Whether the device is smaller than the filesystem
+    pub truncated: bool,
+}
+
+impl MountDiagnostics {
+    /// Returns true if the filesystem was opened in a degraded state:
+    /// from a mirror superblock, without every device, or truncated
+    pub fn is_degraded(&self) -> bool {
+        self.superblock_copy != SuperblockCopy::Primary
+            || self.missing_devices > 0
+            || self.truncated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{objectid, BtrfsFilesystem, Superblock};
+    use crate::test_utils::{ImageBuilder, MemDevice};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_superblock_copy_offsets() {
+        for copy in [
+            SuperblockCopy::Primary,
+            SuperblockCopy::Mirror1,
+            SuperblockCopy::Mirror2,
+        ] {
+            assert_eq!(SuperblockCopy::from_offset(copy.offset()), Some(copy));
+        }
+        assert_eq!(SuperblockCopy::from_offset(0), None);
+    }
+
+    #[test]
+    fn test_mount_diagnostics_mirror() {
+        let mut builder = ImageBuilder::new();
+        builder.root_dir(objectid::FS_TREE);
+        assert_eq!(
+            builder.open().mount_diagnostics(),
+            MountDiagnostics::default()
+        );
+
+        // Copy the superblock to the first mirror, then damage the primary
+        let mut image = builder.build();
+        let primary = SUPERBLOCK_OFFSET as usize;
+        let mirror = SUPERBLOCK_MIRROR1_OFFSET as usize;
+        image.resize(mirror + 0x10000, 0);
+        let copy = Superblock::parse(&image[primary..primary + 4096])
+            .unwrap()
+            .to_bytes(SUPERBLOCK_MIRROR1_OFFSET)
+            .unwrap();
+        image[mirror..mirror + 4096].copy_from_slice(&copy);
+        image[primary + 0x100] ^= 0xFF;
+
+        let fs = BtrfsFilesystem::open(Arc::new(MemDevice::new(image)), true).unwrap();
+        let diagnostics = fs.mount_diagnostics();
+        assert_eq!(diagnostics.superblock_copy, SuperblockCopy::Mirror1);
+        assert_eq!(diagnostics.missing_devices, 0);
+        assert!(!diagnostics.truncated);
+        assert!(diagnostics.is_degraded());
+    }
+}
@@ -2,8 +2,15 @@
 //!
 //! The extent tree tracks space allocation on disk.
 
-use super::{BtrfsError, BtrfsFilesystem, Result};
+use super::{
+    chunk::chunk_type,
+    item_type, objectid,
+    superblock::incompat,
+    tree::{BtrfsKey, KEY_SIZE},
+    BtrfsError, BtrfsFilesystem, Result,
+};
 use byteorder::{ByteOrder, LittleEndian};
+use std::collections::BTreeMap;
 
 /// An extent item describing allocated space
 #[derive(Debug, Clone)]
@@ -14,6 +21,79 @@ pub struct ExtentItem {
     pub generation: u64,
     /// Flags
     pub flags: u64,
+    /// Level of the tree block, for metadata extents
+    pub level: Option<u8>,
+    /// First key of the tree block, only recorded by non-skinny metadata
+    /// extents
+    pub first_key: Option<BtrfsKey>,
+}
+
+impl ExtentItem {
+    /// Parses the fixed part of the EXTENT_ITEM or METADATA_ITEM at `key`
+    ///
+    /// Without `SKINNY_METADATA`, tree blocks are EXTENT_ITEMs followed by
+    /// their first key and level. With it, they are METADATA_ITEMs keeping
+    /// the level in the key offset. Inline back references are not parsed.
+    pub fn from_bytes(key: &BtrfsKey, data: &[u8]) -> Result<Self> {
+        if data.len() < 24 {
+            return Err(BtrfsError::Corrupt("ExtentItem too small".to_string()));
+        }
+
+        let mut item = Self {
+            refs: LittleEndian::read_u64(&data[0..8]),
+            generation: LittleEndian::read_u64(&data[8..16]),
+            flags: LittleEndian::read_u64(&data[16..24]),
+            level: None,
+            first_key: None,
+        };
+
+        match key.item_type {
+            item_type::METADATA_ITEM => {
+                if !item.is_tree_block() {
+                    return Err(BtrfsError::Corrupt(format!(
+                        "METADATA_ITEM {} is not a tree block",
+                        key
+                    )));
+                }
+                let level = u8::try_from(key.offset).map_err(|_| {
+                    BtrfsError::Corrupt(format!("METADATA_ITEM {} has an invalid level", key))
+                })?;
+                item.level = Some(level);
+            }
+            item_type::EXTENT_ITEM if item.is_tree_block() => {
+                // btrfs_tree_block_info: the block's first key, then its level
+                if data.len() < 24 + KEY_SIZE + 1 {
+                    return Err(BtrfsError::Corrupt(format!(
+                        "EXTENT_ITEM {} tree block info truncated",
+                        key
+                    )));
+                }
+                item.first_key = Some(BtrfsKey::from_bytes(&data[24..])?);
+                item.level = Some(data[24 + KEY_SIZE]);
+            }
+            item_type::EXTENT_ITEM => {}
+            other => {
+                return Err(BtrfsError::Corrupt(format!(
+                    "Item type {} is not an extent item",
+                    other
+                )));
+            }
+        }
+
+        Ok(item)
+    }
+
+    /// Returns true if the extent holds a tree block rather than file data
+    #[inline]
+    pub fn is_tree_block(&self) -> bool {
+        self.flags & extent_flags::TREE_BLOCK != 0
+    }
+
+    /// Returns true for tree blocks stored as skinny METADATA_ITEMs
+    #[inline]
+    pub fn is_skinny(&self) -> bool {
+        self.level.is_some() && self.first_key.is_none()
+    }
 }
 
 /// Extent flags
@@ -49,6 +129,173 @@ impl BlockGroupItem {
     }
 }
 
+impl BlockGroupItem {
+    /// Returns the kind of space this block group holds
+    ///
+    /// Groups flagged as both data and metadata, as created on filesystems
+    /// with `MIXED_GROUPS`, are reported as [`SpaceKind::Mixed`].
+    pub fn kind(&self) -> SpaceKind {
+        let data = self.flags & chunk_type::DATA != 0;
+        let metadata = self.flags & chunk_type::METADATA != 0;
+        match (data, metadata) {
+            (true, true) => SpaceKind::Mixed,
+            (true, false) => SpaceKind::Data,
+            (false, true) => SpaceKind::Metadata,
+            (false, false) => SpaceKind::System,
+        }
+    }
+}
+
+/// Kind of space held by a block group
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SpaceKind {
+    Data,
+    Metadata,
+    System,
+    /// Data and metadata sharing block groups
+    Mixed,
+}
+
+/// A block group and its place in the logical address space
+#[derive(Debug, Clone)]
+pub struct BlockGroup {
+    /// Logical start address
+    pub start: u64,
+    /// Length in bytes
+    pub length: u64,
+    /// The block group item
+    pub item: BlockGroupItem,
+}
+
+/// Space totals for one kind of block group
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpaceInfo {
+    /// Kind of space
+    pub kind: SpaceKind,
+    /// Bytes allocated to block groups of this kind
+    pub total_bytes: u64,
+    /// Bytes used within those block groups
+    pub used_bytes: u64,
+}
+
+/// Where a free space total was read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FreeSpaceSource {
+    /// The free space tree, trusted because its VALID flag is set
+    FreeSpaceTree,
+    /// Block group sizes less their used bytes
+    BlockGroups,
+}
+
+/// Free space inside allocated block groups
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreeSpace {
+    /// Free bytes
+    pub free_bytes: u64,
+    /// Where the total came from
+    pub source: FreeSpaceSource,
+}
+
+/// FREE_SPACE_INFO flag for block groups tracked with bitmaps
+pub const FREE_SPACE_USING_BITMAPS: u32 = 1 << 0;
+
+/// An item of the free space tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FreeSpaceItem {
+    /// Heads the free space entries of a block group
+    Info {
+        /// Start of the block group
+        start: u64,
+        /// Length of the block group
+        length: u64,
+        /// Number of free extents in the block group
+        extent_count: u32,
+        /// Flags, e.g. [`FREE_SPACE_USING_BITMAPS`]
+        flags: u32,
+    },
+    /// A free range
+    Extent {
+        /// Start of the range
+        start: u64,
+        /// Length of the range
+        length: u64,
+    },
+    /// A range tracked one bit per sector, set where the sector is free
+    Bitmap {
+        /// Start of the range
+        start: u64,
+        /// Length of the range
+        length: u64,
+        /// The bits, lowest sector first
+        bitmap: Vec<u8>,
+    },
+}
+
+impl FreeSpaceItem {
+    /// Parses a free space tree item from its key and data
+    pub fn from_item(key: &BtrfsKey, data: &[u8]) -> Result<Self> {
+        let (start, length) = (key.objectid, key.offset);
+        match key.item_type {
+            item_type::FREE_SPACE_INFO => {
+                if data.len() < 8 {
+                    return Err(BtrfsError::Corrupt("Free space info too small".to_string()));
+                }
+                Ok(Self::Info {
+                    start,
+                    length,
+                    extent_count: LittleEndian::read_u32(&data[0..4]),
+                    flags: LittleEndian::read_u32(&data[4..8]),
+                })
+            }
+            item_type::FREE_SPACE_EXTENT => Ok(Self::Extent { start, length }),
+            item_type::FREE_SPACE_BITMAP => Ok(Self::Bitmap {
+                start,
+                length,
+                bitmap: data.to_vec(),
+            }),
+            other => Err(BtrfsError::Corrupt(format!(
+                "Item type {} is not a free space item",
+                other
+            ))),
+        }
+    }
+
+    /// Returns the free bytes the item records, none for a FREE_SPACE_INFO
+    ///
+    /// Bits past the end of a bitmap's range are ignored; a bitmap too
+    /// short to cover its range is corrupt.
+    pub fn free_bytes(&self, sector_size: u64) -> Result<u64> {
+        match self {
+            Self::Info { .. } => Ok(0),
+            Self::Extent { length, .. } => Ok(*length),
+            Self::Bitmap {
+                start,
+                length,
+                bitmap,
+            } => {
+                let sectors = length / sector_size;
+                let full = (sectors / 8) as usize;
+                let partial = (sectors % 8) as u32;
+                if bitmap.len() < full + (partial > 0) as usize {
+                    return Err(BtrfsError::Corrupt(format!(
+                        "Free space bitmap at {} is too short for its range",
+                        start
+                    )));
+                }
+
+                let mut bits: u64 = bitmap[..full]
+                    .iter()
+                    .map(|byte| byte.count_ones() as u64)
+                    .sum();
+                if partial > 0 {
+                    bits += (bitmap[full] & ((1 << partial) - 1)).count_ones() as u64;
+                }
+                Ok(bits * sector_size)
+            }
+        }
+    }
+}
+
 /// The extent tree for space allocation tracking
 pub struct ExtentTree<'a> {
     fs: &'a BtrfsFilesystem,
@@ -60,10 +307,16 @@ impl<'a> ExtentTree<'a> {
         Self { fs }
     }
 
-    /// Gets the total allocated space
+    /// Gets the total allocated space, summed over block groups
+    ///
+    /// Falls back to the superblock's `bytes_used` when the extent tree
+    /// holds no block group items.
     pub fn total_allocated(&self) -> Result<u64> {
-        // TODO: Implement by iterating extent tree
-        Ok(self.fs.superblock().bytes_used())
+        let groups = self.block_groups()?;
+        if groups.is_empty() {
+            return Ok(self.fs.superblock().bytes_used());
+        }
+        Ok(groups.iter().map(|group| group.item.used).sum())
     }
 
     /// Gets the total free space
@@ -73,11 +326,207 @@ impl<'a> ExtentTree<'a> {
         Ok(total.saturating_sub(used))
     }
 
-    /// Checks if an extent is allocated
-    pub fn is_allocated(&self, _logical: u64, _size: u64) -> Result<bool> {
-        // TODO: Implement extent lookup
-        Ok(false)
+    /// Lists all block groups in logical address order
+    ///
+    /// Each group's extents sort between its BLOCK_GROUP_ITEM and the next
+    /// group's, so the search seeks past every group instead of reading
+    /// them.
+    pub fn block_groups(&self) -> Result<Vec<BlockGroup>> {
+        let tree = self.fs.tree(objectid::EXTENT_TREE)?;
+
+        let max_key = BtrfsKey::new(u64::MAX, item_type::BLOCK_GROUP_ITEM, u64::MAX);
+        let mut next = 0;
+
+        let mut groups = Vec::new();
+        loop {
+            let min_key = BtrfsKey::new(next, item_type::BLOCK_GROUP_ITEM, 0);
+            let Some((item, data)) = tree.search_range_limited(&min_key, &max_key, 1)?.pop() else {
+                break;
+            };
+
+            let start = item.key.objectid;
+            let resume = if item.key.item_type == item_type::BLOCK_GROUP_ITEM {
+                groups.push(BlockGroup {
+                    start,
+                    length: item.key.offset,
+                    item: BlockGroupItem::from_bytes(&data)?,
+                });
+                start.checked_add(item.key.offset.max(1))
+            } else if start > next {
+                // An extent at the start of the next group sorts just before
+                // its BLOCK_GROUP_ITEM
+                Some(start)
+            } else {
+                start.checked_add(1)
+            };
+            match resume {
+                Some(resume) => next = resume,
+                None => break,
+            }
+        }
+
+        Ok(groups)
+    }
+
+    /// Sums block group sizes and usage per kind of space
+    ///
+    /// On filesystems with `MIXED_GROUPS`, data and metadata share block
+    /// groups and are reported as a single [`SpaceKind::Mixed`] bucket.
+    pub fn space_info(&self) -> Result<Vec<SpaceInfo>> {
+        let mixed = self.fs.superblock().has_incompat(incompat::MIXED_GROUPS);
+
+        let mut totals: BTreeMap<SpaceKind, SpaceInfo> = BTreeMap::new();
+        for group in self.block_groups()? {
+            let kind = match group.item.kind() {
+                SpaceKind::Data | SpaceKind::Metadata if mixed => SpaceKind::Mixed,
+                kind => kind,
+            };
+            let info = totals.entry(kind).or_insert(SpaceInfo {
+                kind,
+                total_bytes: 0,
+                used_bytes: 0,
+            });
+            info.total_bytes += group.length;
+            info.used_bytes += group.item.used;
+        }
+
+        Ok(totals.into_values().collect())
+    }
+
+    /// Totals the free space inside block groups
+    ///
+    /// The free space tree is only used when the superblock marks it valid;
+    /// otherwise the block group items are summed instead.
+    pub fn free_space(&self) -> Result<FreeSpace> {
+        if self.fs.superblock().free_space_tree_valid() {
+            return Ok(FreeSpace {
+                free_bytes: self.free_space_from_tree()?,
+                source: FreeSpaceSource::FreeSpaceTree,
+            });
+        }
+
+        let free_bytes = self
+            .block_groups()?
+            .iter()
+            .map(|group| group.length.saturating_sub(group.item.used))
+            .sum();
+        Ok(FreeSpace {
+            free_bytes,
+            source: FreeSpaceSource::BlockGroups,
+        })
+    }
+
+    /// Sums the free extents and bitmaps of the free space tree
+    ///
+    /// The tree is read whether or not the superblock marks it valid;
+    /// [`Self::free_space`] only uses it when it is.
+    pub fn free_space_from_tree(&self) -> Result<u64> {
+        let sector_size = self.fs.superblock().sector_size() as u64;
+        let tree = self.fs.tree(objectid::FREE_SPACE_TREE)?;
+
+        let min_key = BtrfsKey::new(0, 0, 0);
+        let max_key = BtrfsKey::new(u64::MAX, u8::MAX, u64::MAX);
+
+        let mut free = 0;
+        for (item, data) in tree.search_range(&min_key, &max_key)? {
+            match item.key.item_type {
+                item_type::FREE_SPACE_INFO
+                | item_type::FREE_SPACE_EXTENT
+                | item_type::FREE_SPACE_BITMAP => {
+                    free += FreeSpaceItem::from_item(&item.key, &data)?.free_bytes(sector_size)?;
+                }
+                _ => {}
+            }
+        }
+        Ok(free)
+    }
+
+    /// Checks if any byte of `logical..logical + size` is in an allocated
+    /// extent
+    pub fn is_allocated(&self, logical: u64, size: u64) -> Result<bool> {
+        if size == 0 {
+            return Ok(false);
+        }
+        let end = logical.saturating_add(size);
+        let tree = self.fs.tree(objectid::EXTENT_TREE)?;
+
+        // Extents neither overlap nor cross block groups, so one covering
+        // `logical` from below starts at the last objectid at or before it
+        let first = BtrfsKey::new(logical, u8::MAX, u64::MAX);
+        let start = match tree.search_slot(&first)? {
+            Some((item, _)) => item.key.objectid,
+            None => logical,
+        };
+
+        // Paged, so a large range stops at its first extent
+        let mut min_key = BtrfsKey::new(start, 0, 0);
+        let max_key = BtrfsKey::new(end - 1, u8::MAX, u64::MAX);
+        loop {
+            let items = tree.search_range_limited(&min_key, &max_key, EXTENT_BATCH)?;
+            for (item, _) in &items {
+                let key = item.key;
+                if let Some(len) = self.extent_len(&key)
+                    && key.objectid.saturating_add(len) > logical
+                {
+                    return Ok(true);
+                }
+            }
+
+            match items.last() {
+                Some((item, _)) if items.len() == EXTENT_BATCH => match key_after(&item.key) {
+                    Some(next) => min_key = next,
+                    None => return Ok(false),
+                },
+                _ => return Ok(false),
+            }
+        }
+    }
+
+    /// Finds the extent starting at `bytenr`, returning its length and item
+    ///
+    /// Data and tree block extents are found whether or not the filesystem
+    /// uses `SKINNY_METADATA`.
+    pub fn extent(&self, bytenr: u64) -> Result<Option<(u64, ExtentItem)>> {
+        let tree = self.fs.tree(objectid::EXTENT_TREE)?;
+        let min_key = BtrfsKey::new(bytenr, item_type::EXTENT_ITEM, 0);
+        let max_key = BtrfsKey::new(bytenr, item_type::METADATA_ITEM, u64::MAX);
+
+        match tree.search_range_limited(&min_key, &max_key, 1)?.pop() {
+            Some((item, data)) => {
+                let extent = ExtentItem::from_bytes(&item.key, &data)?;
+                Ok(self.extent_len(&item.key).map(|len| (len, extent)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the length of the extent an EXTENT_ITEM or METADATA_ITEM
+    /// describes, or `None` for other items
+    ///
+    /// METADATA_ITEMs, used with `SKINNY_METADATA`, keep the tree level in
+    /// the key offset and are always one node long.
+    fn extent_len(&self, key: &BtrfsKey) -> Option<u64> {
+        match key.item_type {
+            item_type::EXTENT_ITEM => Some(key.offset),
+            item_type::METADATA_ITEM => Some(self.fs.node_size() as u64),
+            _ => None,
+        }
+    }
+}
+
+/// Extent tree items read per lookup in [`ExtentTree::is_allocated`]
+const EXTENT_BATCH: usize = 64;
+
+/// Returns the smallest key greater than `key`
+fn key_after(key: &BtrfsKey) -> Option<BtrfsKey> {
+    let (objectid, item_type, offset) = (key.objectid, key.item_type, key.offset);
+    if let Some(offset) = offset.checked_add(1) {
+        return Some(BtrfsKey::new(objectid, item_type, offset));
     }
+    if let Some(item_type) = item_type.checked_add(1) {
+        return Some(BtrfsKey::new(objectid, item_type, 0));
+    }
+    objectid.checked_add(1).map(|objectid| BtrfsKey::new(objectid, 0, 0))
 }
 
 /// Device extent information
@@ -118,6 +567,7 @@ impl DevExtent {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_utils::{block_group_item, extent_item, ImageBuilder};
 
     #[test]
     fn test_extent_flags() {
@@ -126,6 +576,98 @@ mod tests {
         assert_eq!(extent_flags::FULL_BACKREF, 256);
     }
 
+    /// Builds a tree block extent item, with tree block info unless skinny
+    fn tree_block_extent_item(first_key: Option<BtrfsKey>, level: u8) -> Vec<u8> {
+        let mut data = vec![0u8; 24];
+        data[0..8].copy_from_slice(&1u64.to_le_bytes()); // refs
+        data[8..16].copy_from_slice(&9u64.to_le_bytes()); // generation
+        data[16..24].copy_from_slice(&extent_flags::TREE_BLOCK.to_le_bytes());
+        if let Some(key) = first_key {
+            data.extend_from_slice(&key.to_bytes());
+            data.push(level);
+        }
+        // TREE_BLOCK_REF inline back reference to the FS tree
+        data.push(item_type::TREE_BLOCK_REF);
+        data.extend_from_slice(&objectid::FS_TREE.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_extent_item_data() {
+        let key = BtrfsKey::new(0x600000, item_type::EXTENT_ITEM, 0x1000);
+        let item = ExtentItem::from_bytes(&key, &extent_item(3)).unwrap();
+        assert_eq!((item.refs, item.generation), (3, 1));
+        assert!(!item.is_tree_block());
+        assert!(!item.is_skinny());
+        assert_eq!(item.level, None);
+
+        assert!(ExtentItem::from_bytes(&key, &[0u8; 20]).is_err());
+        let bg = BtrfsKey::new(0x600000, item_type::BLOCK_GROUP_ITEM, 0x1000);
+        assert!(ExtentItem::from_bytes(&bg, &extent_item(1)).is_err());
+    }
+
+    #[test]
+    fn test_extent_item_tree_block() {
+        let first_key = BtrfsKey::new(256, item_type::INODE_ITEM, 0);
+
+        // Without SKINNY_METADATA the first key and level follow the item
+        let fat = BtrfsKey::new(0x400000, item_type::EXTENT_ITEM, 0x4000);
+        let item =
+            ExtentItem::from_bytes(&fat, &tree_block_extent_item(Some(first_key), 1)).unwrap();
+        assert!(item.is_tree_block());
+        assert!(!item.is_skinny());
+        assert_eq!(item.level, Some(1));
+        assert_eq!(item.first_key, Some(first_key));
+        let truncated = &tree_block_extent_item(Some(first_key), 1)[..30];
+        assert!(ExtentItem::from_bytes(&fat, truncated).is_err());
+
+        // With it the level is the key offset, and what follows is the
+        // inline back reference
+        let skinny = BtrfsKey::new(0x400000, item_type::METADATA_ITEM, 2);
+        let item = ExtentItem::from_bytes(&skinny, &tree_block_extent_item(None, 0)).unwrap();
+        assert!(item.is_skinny());
+        assert_eq!(item.level, Some(2));
+        assert_eq!(item.first_key, None);
+
+        // A METADATA_ITEM must be a tree block with a level that fits a u8
+        assert!(ExtentItem::from_bytes(&skinny, &extent_item(1)).is_err());
+        let bad_level = BtrfsKey::new(0x400000, item_type::METADATA_ITEM, 0x100);
+        assert!(ExtentItem::from_bytes(&bad_level, &tree_block_extent_item(None, 0)).is_err());
+    }
+
+    #[test]
+    fn test_extent_lookup() {
+        let first_key = BtrfsKey::new(256, item_type::INODE_ITEM, 0);
+        let mut builder = ImageBuilder::new();
+        builder.node_size(4096);
+        for (key, data) in [
+            (
+                BtrfsKey::new(0x400000, item_type::EXTENT_ITEM, 0x1000),
+                tree_block_extent_item(Some(first_key), 0),
+            ),
+            (
+                BtrfsKey::new(0x401000, item_type::METADATA_ITEM, 1),
+                tree_block_extent_item(None, 0),
+            ),
+            (
+                BtrfsKey::new(0x600000, item_type::EXTENT_ITEM, 0x2000),
+                extent_item(2),
+            ),
+        ] {
+            builder.insert(objectid::EXTENT_TREE, key, data);
+        }
+        let fs = builder.open();
+        let extents = ExtentTree::new(&fs);
+
+        let (len, fat) = extents.extent(0x400000).unwrap().unwrap();
+        assert_eq!((len, fat.level, fat.is_skinny()), (0x1000, Some(0), false));
+        let (len, skinny) = extents.extent(0x401000).unwrap().unwrap();
+        assert_eq!((len, skinny.level, skinny.is_skinny()), (4096, Some(1), true));
+        let (len, data) = extents.extent(0x600000).unwrap().unwrap();
+        assert_eq!((len, data.refs, data.is_tree_block()), (0x2000, 2, false));
+        assert!(extents.extent(0x402000).unwrap().is_none());
+    }
+
     fn create_mock_block_group_item_data() -> Vec<u8> {
         let mut data = vec![0u8; 24];
         // used
@@ -147,6 +689,310 @@ mod tests {
         assert_eq!(item.flags, 1);
     }
 
+    #[test]
+    fn test_block_group_kind() {
+        let kind = |flags| {
+            BlockGroupItem {
+                used: 0,
+                chunk_objectid: 256,
+                flags,
+            }
+            .kind()
+        };
+
+        assert_eq!(kind(chunk_type::DATA), SpaceKind::Data);
+        assert_eq!(
+            kind(chunk_type::METADATA | chunk_type::DUP),
+            SpaceKind::Metadata
+        );
+        assert_eq!(kind(chunk_type::SYSTEM), SpaceKind::System);
+        assert_eq!(
+            kind(chunk_type::DATA | chunk_type::METADATA),
+            SpaceKind::Mixed
+        );
+    }
+
+    fn fs_with_block_groups(
+        incompat_flags: u64,
+        groups: &[(u64, u64, u64, u64)],
+    ) -> BtrfsFilesystem {
+        let mut builder = ImageBuilder::new();
+        builder.incompat(incompat_flags);
+        for &(start, length, used, flags) in groups {
+            builder.insert(
+                objectid::EXTENT_TREE,
+                BtrfsKey::new(start, item_type::BLOCK_GROUP_ITEM, length),
+                block_group_item(used, flags),
+            );
+        }
+        builder.open()
+    }
+
+    #[test]
+    fn test_space_info_mixed_groups() {
+        let fs = fs_with_block_groups(
+            incompat::MIXED_GROUPS,
+            &[
+                (0x100000, 0x100000, 0x4000, chunk_type::SYSTEM),
+                (
+                    0x200000,
+                    0x800000,
+                    0x30000,
+                    chunk_type::DATA | chunk_type::METADATA,
+                ),
+                (
+                    0xA00000,
+                    0x800000,
+                    0x10000,
+                    chunk_type::DATA | chunk_type::METADATA,
+                ),
+            ],
+        );
+        assert!(fs.superblock().has_incompat(incompat::MIXED_GROUPS));
+
+        let extents = ExtentTree::new(&fs);
+        let groups = extents.block_groups().unwrap();
+        assert_eq!(groups.len(), 3);
+        assert_eq!(groups[1].start, 0x200000);
+        assert_eq!(groups[1].item.kind(), SpaceKind::Mixed);
+
+        let info = extents.space_info().unwrap();
+        assert_eq!(
+            info,
+            vec![
+                SpaceInfo {
+                    kind: SpaceKind::System,
+                    total_bytes: 0x100000,
+                    used_bytes: 0x4000,
+                },
+                SpaceInfo {
+                    kind: SpaceKind::Mixed,
+                    total_bytes: 0x1000000,
+                    used_bytes: 0x40000,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_block_groups_among_extents() {
+        // Three groups, the last after a gap, each filled with extents that
+        // span several leaves; the first extent of each group shares its
+        // objectid
+        let mut builder = ImageBuilder::new();
+        builder.node_size(4096);
+        for (start, flags) in [
+            (0x100000, chunk_type::METADATA),
+            (0x200000, chunk_type::DATA),
+            (0x800000, chunk_type::DATA),
+        ] {
+            builder.insert(
+                objectid::EXTENT_TREE,
+                BtrfsKey::new(start, item_type::BLOCK_GROUP_ITEM, 0x100000),
+                block_group_item(0x40000, flags),
+            );
+            for i in 0..64 {
+                builder.insert(
+                    objectid::EXTENT_TREE,
+                    BtrfsKey::new(start + i * 0x1000, item_type::EXTENT_ITEM, 0x1000),
+                    extent_item(1),
+                );
+            }
+        }
+        let fs = builder.open();
+
+        let groups = ExtentTree::new(&fs).block_groups().unwrap();
+        let starts: Vec<_> = groups.iter().map(|g| g.start).collect();
+        assert_eq!(starts, [0x100000, 0x200000, 0x800000]);
+        assert!(groups.iter().all(|g| g.length == 0x100000));
+        assert_eq!(groups[0].item.kind(), SpaceKind::Metadata);
+    }
+
+    #[test]
+    fn test_total_allocated() {
+        let fs = fs_with_block_groups(
+            0,
+            &[
+                (0x100000, 0x100000, 0x4000, chunk_type::SYSTEM),
+                (0x200000, 0x400000, 0x8000, chunk_type::METADATA),
+                (0x600000, 0x800000, 0x20000, chunk_type::DATA),
+            ],
+        );
+        let extents = ExtentTree::new(&fs);
+        assert_eq!(extents.total_allocated().unwrap(), 0x2C000);
+        assert_eq!(
+            extents.total_free().unwrap(),
+            fs.superblock().total_bytes() - 0x2C000
+        );
+
+        // Without block groups the superblock total is used
+        let mut builder = ImageBuilder::new();
+        builder.insert(
+            objectid::EXTENT_TREE,
+            BtrfsKey::new(0x600000, item_type::EXTENT_ITEM, 0x1000),
+            extent_item(1),
+        );
+        let fs = builder.open();
+        assert_eq!(
+            ExtentTree::new(&fs).total_allocated().unwrap(),
+            fs.superblock().bytes_used()
+        );
+    }
+
+    #[test]
+    fn test_is_allocated() {
+        let mut builder = ImageBuilder::new();
+        builder.node_size(4096);
+        for (key, data) in [
+            // Block group starting where a data extent does
+            (
+                BtrfsKey::new(0x600000, item_type::BLOCK_GROUP_ITEM, 0x800000),
+                block_group_item(0x3000, chunk_type::DATA),
+            ),
+            (
+                BtrfsKey::new(0x600000, item_type::EXTENT_ITEM, 0x2000),
+                extent_item(1),
+            ),
+            (
+                BtrfsKey::new(0x600000, item_type::EXTENT_DATA_REF, 1),
+                vec![0; 28],
+            ),
+            (
+                BtrfsKey::new(0x610000, item_type::EXTENT_ITEM, 0x1000),
+                extent_item(1),
+            ),
+            // A skinny tree block, one node long
+            (
+                BtrfsKey::new(0x620000, item_type::METADATA_ITEM, 0),
+                extent_item(1),
+            ),
+        ] {
+            builder.insert(objectid::EXTENT_TREE, key, data);
+        }
+        let fs = builder.open();
+        let extents = ExtentTree::new(&fs);
+        let allocated = |logical, size| extents.is_allocated(logical, size).unwrap();
+
+        assert!(allocated(0x600000, 1));
+        // Inside and at the last byte of an extent, found from below
+        assert!(allocated(0x601000, 0x10));
+        assert!(allocated(0x601FFF, 1));
+        assert!(!allocated(0x602000, 0x1000));
+        // A range ending where an extent starts, and one reaching into it
+        assert!(!allocated(0x602000, 0xE000));
+        assert!(allocated(0x602000, 0xE001));
+        assert!(allocated(0x620FFF, 1));
+        assert!(!allocated(0x621000, u64::MAX));
+        assert!(!allocated(0x600000, 0));
+        // A huge range only finds the extents inside it
+        assert!(allocated(0, u64::MAX));
+        assert!(!allocated(0, 0x600000));
+    }
+
+    #[test]
+    fn test_space_info_separate_groups() {
+        let fs = fs_with_block_groups(
+            0,
+            &[
+                (0x100000, 0x100000, 0x4000, chunk_type::SYSTEM),
+                (0x200000, 0x400000, 0x8000, chunk_type::METADATA),
+                (0x600000, 0x800000, 0x20000, chunk_type::DATA),
+            ],
+        );
+
+        let kinds: Vec<SpaceKind> = ExtentTree::new(&fs)
+            .space_info()
+            .unwrap()
+            .iter()
+            .map(|info| info.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![SpaceKind::Data, SpaceKind::Metadata, SpaceKind::System]
+        );
+    }
+
+    #[test]
+    fn test_free_space_source() {
+        use crate::core::item_type::{FREE_SPACE_BITMAP, FREE_SPACE_EXTENT, FREE_SPACE_INFO};
+        use crate::core::superblock::compat_ro;
+
+        let fs_with = |compat_ro_flags: u64| {
+            let mut builder = ImageBuilder::new();
+            builder.compat_ro(compat_ro_flags).insert(
+                objectid::EXTENT_TREE,
+                BtrfsKey::new(0x100000, item_type::BLOCK_GROUP_ITEM, 0x100000),
+                block_group_item(0x4000, chunk_type::DATA),
+            );
+
+            // 32K of free extents and a bitmap with 12 of 16 sectors free
+            for (start, kind, len, data) in [
+                (0x100000, FREE_SPACE_INFO, 0x100000, vec![0; 8]),
+                (0x110000, FREE_SPACE_EXTENT, 0x8000, vec![]),
+                (0x180000, FREE_SPACE_BITMAP, 0x10000, vec![0xFF, 0x0F]),
+            ] {
+                let key = BtrfsKey::new(start, kind, len);
+                builder.insert(objectid::FREE_SPACE_TREE, key, data);
+            }
+            builder.open()
+        };
+
+        // (compat_ro flags, expected source, free bytes)
+        for (flags, source, free_bytes) in [
+            (0, FreeSpaceSource::BlockGroups, 0xFC000),
+            (
+                compat_ro::FREE_SPACE_TREE,
+                FreeSpaceSource::BlockGroups,
+                0xFC000,
+            ),
+            (
+                compat_ro::FREE_SPACE_TREE | compat_ro::FREE_SPACE_TREE_VALID,
+                FreeSpaceSource::FreeSpaceTree,
+                0x14000,
+            ),
+        ] {
+            let fs = fs_with(flags);
+            let free = ExtentTree::new(&fs).free_space().unwrap();
+            assert_eq!(free, FreeSpace { free_bytes, source });
+        }
+    }
+
+    #[test]
+    fn test_free_space_item() {
+        use crate::core::item_type::{FREE_SPACE_BITMAP, FREE_SPACE_EXTENT, FREE_SPACE_INFO};
+
+        let mut info = vec![0u8; 8];
+        info[0..4].copy_from_slice(&3u32.to_le_bytes());
+        info[4..8].copy_from_slice(&FREE_SPACE_USING_BITMAPS.to_le_bytes());
+        let key = BtrfsKey::new(0x100000, FREE_SPACE_INFO, 0x100000);
+        let item = FreeSpaceItem::from_item(&key, &info).unwrap();
+        assert_eq!(
+            item,
+            FreeSpaceItem::Info {
+                start: 0x100000,
+                length: 0x100000,
+                extent_count: 3,
+                flags: FREE_SPACE_USING_BITMAPS,
+            }
+        );
+        assert_eq!(item.free_bytes(4096).unwrap(), 0);
+        assert!(FreeSpaceItem::from_item(&key, &info[..4]).is_err());
+
+        let key = BtrfsKey::new(0x110000, FREE_SPACE_EXTENT, 0x8000);
+        let item = FreeSpaceItem::from_item(&key, &[]).unwrap();
+        assert_eq!(item.free_bytes(4096).unwrap(), 0x8000);
+
+        // 10 sectors; the set bits past them don't count
+        let key = BtrfsKey::new(0x180000, FREE_SPACE_BITMAP, 0xA000);
+        let item = FreeSpaceItem::from_item(&key, &[0x0F, 0xFF]).unwrap();
+        assert_eq!(item.free_bytes(4096).unwrap(), 6 * 4096);
+        let short = FreeSpaceItem::from_item(&key, &[0xFF]).unwrap();
+        assert!(short.free_bytes(4096).is_err());
+
+        let key = BtrfsKey::new(0x100000, item_type::BLOCK_GROUP_ITEM, 0x100000);
+        assert!(FreeSpaceItem::from_item(&key, &info).is_err());
+    }
+
     #[test]
     fn test_block_group_item_from_bytes_too_small() {
         let data = vec![0u8; 20]; // Too small
@@ -180,7 +1026,7 @@ mod tests {
         assert_eq!(extent.chunk_objectid, 256);
         assert_eq!(extent.chunk_offset, 0x100000);
         assert_eq!(extent.length, 0x10000000);
-        
+
         // Check UUID
         for i in 0..16 {
             assert_eq!(extent.chunk_tree_uuid[i], i as u8);
@@ -0,0 +1,927 @@
+//! Reading file data
+//!
+//! Assembles a file's bytes from its EXTENT_DATA items: inline, regular,
+//! compressed and preallocated extents, and holes. Regular extents are
+//! checked against the checksum tree unless the inode opts out of
+//! checksums, and mirrored copies are tried in turn.
+
+use super::{
+    compress::{self, CompressionType},
+    inode::{ExtentData, Inode, InodeFlags},
+    item_type, objectid,
+    path::read_inode,
+    tree::BtrfsKey,
+    BtrfsError, BtrfsFilesystem, Result,
+};
+use std::borrow::Cow;
+
+/// Reads file extent data
+pub fn read_file_extents(fs: &BtrfsFilesystem, tree_id: u64, ino: u64) -> Result<Vec<ExtentData>> {
+    let tree = fs.tree(tree_id)?;
+
+    let min_key = BtrfsKey::new(ino, item_type::EXTENT_DATA, 0);
+    let max_key = BtrfsKey::new(ino, item_type::EXTENT_DATA, u64::MAX);
+
+    let items = tree.search_range(&min_key, &max_key)?;
+
+    let mut extents = Vec::new();
+    for (_, data) in items {
+        if let Ok(extent) = ExtentData::from_bytes(&data) {
+            extents.push(extent);
+        }
+    }
+
+    Ok(extents)
+}
+
+/// Reads file data at an offset
+///
+/// Returns the bytes from `offset` up to the inode's size, so reads past
+/// the end of the file are short. Extents are placed at their EXTENT_DATA
+/// key offsets and anything between them reads as zeros, whether a sparse
+/// extent marks the hole or, with NO_HOLES, no item covers it at all.
+/// Compressed extents are decompressed, and preallocated extents read as
+/// zeros. With `verify`, data is checked against the csum tree and a
+/// block that fails on every copy returns [`BtrfsError::ChecksumMismatch`].
+pub fn read_file_data(
+    fs: &BtrfsFilesystem,
+    tree_id: u64,
+    ino: u64,
+    offset: u64,
+    size: usize,
+    verify: bool,
+) -> Result<Vec<u8>> {
+    read_file_data_with(fs, tree_id, ino, offset, size, |inode, extent| {
+        if verify {
+            read_extent_verified(fs, inode, extent)
+        } else {
+            read_extent_unverified(fs, extent)
+        }
+    })
+}
+
+/// Like [`read_file_data`], reading the on-disk bytes of regular extents
+/// with `read_extent`
+///
+/// `read_extent` has the contract of [`read_extent_verified`]; passing
+/// [`read_extent_tolerant`] instead salvages damaged files.
+pub fn read_file_data_with<F>(
+    fs: &BtrfsFilesystem,
+    tree_id: u64,
+    ino: u64,
+    offset: u64,
+    size: usize,
+    mut read_extent: F,
+) -> Result<Vec<u8>>
+where
+    F: FnMut(&Inode, &ExtentData) -> Result<Vec<u8>>,
+{
+    if size == 0 {
+        return Ok(Vec::new());
+    }
+
+    let inode = read_inode(fs, tree_id, ino)?;
+    if inode.size == 0 {
+        return Ok(Vec::new());
+    }
+
+    let tree = fs.tree(tree_id)?;
+    let end = offset.saturating_add(size as u64).min(inode.size);
+    if offset >= end {
+        return Ok(Vec::new());
+    }
+
+    // Start at the extent containing offset rather than the first one
+    let min_key = tree
+        .search_slot(&BtrfsKey::new(ino, item_type::EXTENT_DATA, offset))?
+        .map(|(item, _)| item.key)
+        .filter(|key| { key.objectid } == ino && key.item_type == item_type::EXTENT_DATA)
+        .unwrap_or(BtrfsKey::new(ino, item_type::EXTENT_DATA, 0));
+    let max_key = BtrfsKey::new(ino, item_type::EXTENT_DATA, end - 1);
+
+    let sector_size = fs.superblock().sector_size() as u64;
+    // Gaps between extents are left as zeros
+    let mut result = vec![0u8; (end - offset) as usize];
+
+    for (item, data) in tree.search_range(&min_key, &max_key)? {
+        let Ok(extent) = ExtentData::from_bytes(&data) else {
+            continue;
+        };
+
+        // Copy the part of the extent that overlaps the requested range
+        let extent_start = item.key.offset;
+        let extent_end = extent_start + file_len(&extent);
+        let start = extent_start.max(offset);
+        let stop = extent_end.min(end);
+        if start >= stop {
+            continue;
+        }
+        extent.check_supported()?;
+
+        let dst = &mut result[(start - offset) as usize..(stop - offset) as usize];
+        let within = start - extent_start;
+        if extent.is_inline() {
+            let inline = extent.inline_data.as_deref().unwrap_or_default();
+            let data = decompress_extent(&extent, inline)?;
+            copy_window(dst, &data, within);
+        } else if extent.is_regular() && !extent.is_sparse() {
+            if extent.compression != 0 {
+                let raw = read_extent(&inode, &extent)?;
+                let data = decompress_extent(&extent, &raw)?;
+                copy_window(dst, &data, extent.offset.unwrap_or(0) + within);
+            } else {
+                // Read just the sectors overlapping the request
+                let skip = within - within % sector_size;
+                let mut part = extent.clone();
+                part.offset = Some(extent.offset.unwrap_or(0) + skip);
+                part.num_bytes = Some(stop - extent_start - skip);
+                copy_window(dst, &read_extent(&inode, &part)?, within - skip);
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// Number of bytes of the file an extent covers
+fn file_len(extent: &ExtentData) -> u64 {
+    if !extent.is_inline() {
+        extent.num_bytes.unwrap_or(0)
+    } else if extent.compression != 0 {
+        extent.ram_bytes
+    } else {
+        extent
+            .inline_data
+            .as_ref()
+            .map_or(0, |inline| inline.len() as u64)
+    }
+}
+
+/// Decompresses an extent's bytes to its `ram_bytes`, if it is compressed
+fn decompress_extent<'a>(extent: &ExtentData, data: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+    match CompressionType::from_u8(extent.compression)? {
+        CompressionType::None => Ok(Cow::Borrowed(data)),
+        compression => Ok(Cow::Owned(compress::decompress(
+            compression,
+            data,
+            extent.ram_bytes as usize,
+        )?)),
+    }
+}
+
+/// Fills `dst` from `src` starting at `from`; bytes past the end of `src`
+/// are left as they are (zero)
+fn copy_window(dst: &mut [u8], src: &[u8], from: u64) {
+    let from = (from as usize).min(src.len());
+    let len = dst.len().min(src.len() - from);
+    dst[..len].copy_from_slice(&src[from..from + len]);
+}
+
+/// How file data of an inode is verified on read
+///
+/// NODATACOW files are overwritten in place and, like NODATASUM files,
+/// carry no data checksums.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataPolicy {
+    /// Data blocks have EXTENT_CSUM entries
+    pub csum: bool,
+}
+
+impl DataPolicy {
+    /// Derives the policy from the inode flags
+    pub fn for_inode(inode: &Inode) -> Self {
+        let flags = inode.inode_flags();
+        let nodatasum = flags.intersects(InodeFlags::NODATACOW | InodeFlags::NODATASUM);
+        Self { csum: !nodatasum }
+    }
+}
+
+/// Looks up the data checksums covering `len` bytes at `logical`
+///
+/// Returns one entry per sector, `None` where the csum tree has no entry.
+pub fn lookup_data_csums(fs: &BtrfsFilesystem, logical: u64, len: u64) -> Result<Vec<Option<u64>>> {
+    let sector_size = fs.superblock().sector_size() as u64;
+    let csum_size = fs.checksum().size();
+    let num_sectors = len.div_ceil(sector_size) as usize;
+    let mut csums = vec![None; num_sectors];
+
+    if num_sectors == 0 {
+        return Ok(csums);
+    }
+
+    let tree = fs.tree(objectid::CSUM_TREE)?;
+
+    // The last item starting at or before `logical` may still cover it;
+    // earlier ones end before it
+    let first = BtrfsKey::new(objectid::EXTENT_CSUM, item_type::EXTENT_CSUM, logical);
+    let min_key = tree
+        .search_slot(&first)?
+        .map(|(item, _)| item.key)
+        .filter(|key| key.item_type == item_type::EXTENT_CSUM && { key.objectid } == first.objectid)
+        .unwrap_or(first);
+    let max_key = BtrfsKey::new(
+        objectid::EXTENT_CSUM,
+        item_type::EXTENT_CSUM,
+        logical + len - 1,
+    );
+
+    for (item, data) in tree.search_range(&min_key, &max_key)? {
+        let start = item.key.offset;
+        for (i, csum) in data.chunks_exact(csum_size).enumerate() {
+            let sector_logical = start + i as u64 * sector_size;
+            if sector_logical < logical {
+                continue;
+            }
+
+            let idx = ((sector_logical - logical) / sector_size) as usize;
+            if idx >= num_sectors {
+                break;
+            }
+            let mut value = [0u8; 8];
+            let n = csum_size.min(8);
+            value[..n].copy_from_slice(&csum[..n]);
+            csums[idx] = Some(u64::from_le_bytes(value));
+        }
+    }
+
+    Ok(csums)
+}
+
+/// Verifies file data read from `logical` against the csum tree
+///
+/// Inodes whose policy has no checksums are skipped, so their missing
+/// EXTENT_CSUM entries are not reported as errors.
+pub fn verify_data(fs: &BtrfsFilesystem, inode: &Inode, logical: u64, data: &[u8]) -> Result<()> {
+    if !DataPolicy::for_inode(inode).csum {
+        return Ok(());
+    }
+
+    let sector_size = fs.superblock().sector_size() as usize;
+    let csum = fs.checksum();
+    let csums = lookup_data_csums(fs, logical, data.len() as u64)?;
+
+    for (i, (block, stored)) in data.chunks(sector_size).zip(csums).enumerate() {
+        let block_logical = logical + (i * sector_size) as u64;
+        match stored {
+            Some(expected) => {
+                let actual = csum.compute(block)?;
+                if actual != expected {
+                    return Err(BtrfsError::ChecksumMismatch { expected, actual });
+                }
+            }
+            None => {
+                return Err(BtrfsError::NotFound(format!(
+                    "Data checksum for logical address {}",
+                    block_logical
+                )))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the on-disk bytes of a regular extent, verified against the csum tree
+///
+/// Checksums cover what is on disk, so a compressed extent is read and
+/// verified whole (`disk_num_bytes` at `disk_bytenr`) and returned still
+/// compressed. An uncompressed extent is read only where the file references
+/// it, rounded up to whole sectors. Inline, hole and preallocated extents
+/// have no checksummed data and yield nothing.
+pub fn read_extent_verified(
+    fs: &BtrfsFilesystem,
+    inode: &Inode,
+    extent: &ExtentData,
+) -> Result<Vec<u8>> {
+    if !extent.is_regular() || extent.is_sparse() {
+        return Ok(Vec::new());
+    }
+    extent.check_supported()?;
+
+    // Bad copies are skipped in favor of a good mirror or parity rebuild
+    let (logical, len) = extent_disk_range(fs, extent);
+    let mut data = vec![0u8; len as usize];
+    fs.read_logical_verified(logical, &mut data, |data| {
+        verify_data(fs, inode, logical, data)
+    })?;
+    Ok(data)
+}
+
+/// Like [`read_extent_verified`], without consulting the csum tree
+pub fn read_extent_unverified(fs: &BtrfsFilesystem, extent: &ExtentData) -> Result<Vec<u8>> {
+    if !extent.is_regular() || extent.is_sparse() {
+        return Ok(Vec::new());
+    }
+    extent.check_supported()?;
+
+    let (logical, len) = extent_disk_range(fs, extent);
+    let mut data = vec![0u8; len as usize];
+    fs.read_logical(logical, &mut data)?;
+    Ok(data)
+}
+
+/// Returns the logical address and length of the bytes to read for a
+/// regular extent, as described for [`read_extent_verified`]
+fn extent_disk_range(fs: &BtrfsFilesystem, extent: &ExtentData) -> (u64, u64) {
+    let sector_size = fs.superblock().sector_size() as u64;
+    let disk_bytenr = extent.disk_bytenr.unwrap_or(0);
+    if extent.compression != 0 {
+        (disk_bytenr, extent.disk_num_bytes.unwrap_or(0))
+    } else {
+        (
+            disk_bytenr + extent.offset.unwrap_or(0),
+            extent.num_bytes.unwrap_or(0).next_multiple_of(sector_size),
+        )
+    }
+}
+
+/// Like [`read_extent_verified`], but salvages what it can
+///
+/// Each block of an uncompressed extent that can't be read, or fails its
+/// checksum on every copy, is logged and returned as zeros. Returns the
+/// data and the number of blocks replaced. A compressed extent only
+/// decompresses whole, so its errors are still returned.
+pub fn read_extent_tolerant(
+    fs: &BtrfsFilesystem,
+    inode: &Inode,
+    extent: &ExtentData,
+) -> Result<(Vec<u8>, u64)> {
+    let err = match read_extent_verified(fs, inode, extent) {
+        Ok(data) => return Ok((data, 0)),
+        Err(e) if extent.compression != 0 => return Err(e),
+        Err(e) => e,
+    };
+    extent.check_supported()?;
+
+    let sector_size = fs.superblock().sector_size() as u64;
+    let logical = extent.disk_bytenr.unwrap_or(0) + extent.offset.unwrap_or(0);
+    let len = extent.num_bytes.unwrap_or(0).next_multiple_of(sector_size);
+    tracing::debug!("Salvaging extent at {:#x} after: {}", logical, err);
+
+    let mut data = vec![0u8; len as usize];
+    let mut bad_blocks = 0;
+    for (i, block) in data.chunks_mut(sector_size as usize).enumerate() {
+        let block_logical = logical + i as u64 * sector_size;
+        let read = fs.read_logical_verified(block_logical, block, |block| {
+            verify_data(fs, inode, block_logical, block)
+        });
+        if let Err(e) = read {
+            tracing::warn!(
+                "Returning zeros for unreadable block {:#x}: {}",
+                block_logical,
+                e
+            );
+            block.fill(0);
+            bad_blocks += 1;
+        }
+    }
+
+    Ok((data, bad_blocks))
+}
+
+/// Reads the target of a symlink
+///
+/// The target is the inline data of the symlink's only EXTENT_DATA item.
+pub fn read_symlink(fs: &BtrfsFilesystem, tree_id: u64, ino: u64) -> Result<String> {
+    if !read_inode(fs, tree_id, ino)?.is_symlink() {
+        return Err(BtrfsError::NotASymlink);
+    }
+
+    let key = BtrfsKey::new(ino, item_type::EXTENT_DATA, 0);
+    let target = match fs.tree(tree_id)?.search(&key)? {
+        Some((_, data)) => {
+            let extent = ExtentData::from_bytes(&data)?;
+            extent.check_supported()?;
+            extent.inline_data
+        }
+        None => None,
+    };
+    let target = target
+        .ok_or_else(|| BtrfsError::Corrupt(format!("Symlink {} has no inline target", ino)))?;
+
+    String::from_utf8(target)
+        .map_err(|_| BtrfsError::Corrupt(format!("Symlink {} target is not UTF-8", ino)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{checksum, chunk::chunk_type, objectid};
+    use crate::test_utils::{inode_with, ImageBuilder};
+
+    #[test]
+    fn test_read_inline_at_offset() {
+        use crate::test_utils::inline_extent;
+
+        let root = objectid::FIRST_FREE;
+        let fs = ImageBuilder::new()
+            .root_dir(objectid::FS_TREE)
+            .file(objectid::FS_TREE, root, 257, "greeting", 11)
+            .insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(257, item_type::EXTENT_DATA, 0),
+                inline_extent(b"hello world"),
+            )
+            .open();
+        let read =
+            |offset, size| read_file_data(&fs, objectid::FS_TREE, 257, offset, size, true).unwrap();
+
+        assert_eq!(read(0, 4096), b"hello world");
+        assert_eq!(read(6, 4096), b"world");
+        assert_eq!(read(2, 3), b"llo");
+        assert!(read(11, 10).is_empty());
+        assert!(read(500, 10).is_empty());
+    }
+
+    #[test]
+    fn test_read_encoded_extent() {
+        use crate::test_utils::{extent_data, inline_extent};
+
+        let mut encrypted = inline_extent(b"ciphertext");
+        encrypted[17] = 1; // encryption
+        let mut encoded = extent_data(0x300000, 0x1000);
+        encoded[18..20].copy_from_slice(&1u16.to_le_bytes()); // other_encoding
+
+        let root = objectid::FIRST_FREE;
+        let fs = ImageBuilder::new()
+            .root_dir(objectid::FS_TREE)
+            .file(objectid::FS_TREE, root, 257, "secret", 10)
+            .insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(257, item_type::EXTENT_DATA, 0),
+                encrypted,
+            )
+            .open();
+
+        assert!(matches!(
+            read_file_data(&fs, objectid::FS_TREE, 257, 0, 10, true),
+            Err(BtrfsError::UnsupportedFeature(_))
+        ));
+
+        let inode = read_inode(&fs, objectid::FS_TREE, 257).unwrap();
+        let extent = ExtentData::from_bytes(&encoded).unwrap();
+        assert!(matches!(
+            read_extent_verified(&fs, &inode, &extent),
+            Err(BtrfsError::UnsupportedFeature(_))
+        ));
+    }
+
+    fn csum_fs(logical: u64, blocks: &[&[u8]]) -> BtrfsFilesystem {
+        let csums: Vec<u8> = blocks
+            .iter()
+            .flat_map(|b| checksum::crc32c(b).to_le_bytes())
+            .collect();
+
+        ImageBuilder::new()
+            .insert(
+                objectid::CSUM_TREE,
+                BtrfsKey::new(objectid::EXTENT_CSUM, item_type::EXTENT_CSUM, logical),
+                csums,
+            )
+            .open()
+    }
+
+    #[test]
+    fn test_read_extent_from_good_mirror() {
+        use crate::test_utils::extent_data;
+
+        let good = vec![0x5Au8; 4096];
+        let bad = vec![0xA5u8; 4096];
+        let logical = 0x10000000;
+        let mirrors = [0x300000, 0x320000, 0x340000];
+
+        let fs_with = |copies: [&[u8]; 3]| {
+            let mut builder = ImageBuilder::new();
+            builder
+                .mirrored_chunk(logical, 0x10000, chunk_type::RAID1C3, &mirrors)
+                .insert(
+                    objectid::CSUM_TREE,
+                    BtrfsKey::new(objectid::EXTENT_CSUM, item_type::EXTENT_CSUM, logical),
+                    checksum::crc32c(&good).to_le_bytes().to_vec(),
+                );
+            for (physical, copy) in mirrors.iter().zip(copies) {
+                builder.data(*physical, copy);
+            }
+            builder.open()
+        };
+        let inode = inode_with(0o100644, InodeFlags::empty());
+        let extent = ExtentData::from_bytes(&extent_data(logical, 4096)).unwrap();
+
+        // The first two copies are corrupt; the third is used
+        let fs = fs_with([&bad, &bad, &good]);
+        assert_eq!(read_extent_verified(&fs, &inode, &extent).unwrap(), good);
+
+        let fs = fs_with([&bad, &bad, &bad]);
+        assert!(matches!(
+            read_extent_verified(&fs, &inode, &extent),
+            Err(BtrfsError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_read_extent_tolerant() {
+        use crate::test_utils::extent_data;
+
+        const DATA_START: u64 = 0x300000;
+
+        // Three blocks, the middle one corrupt on disk
+        let blocks: Vec<Vec<u8>> = (1..=3u8).map(|fill| vec![fill; 4096]).collect();
+        let mut on_disk = blocks.concat();
+        on_disk[4096 + 7] = 0xFF;
+        let mut builder = ImageBuilder::new();
+        builder.data(DATA_START, &on_disk);
+        let csums: Vec<u8> = blocks
+            .iter()
+            .flat_map(|block| checksum::crc32c(block).to_le_bytes())
+            .collect();
+        builder.insert(
+            objectid::CSUM_TREE,
+            BtrfsKey::new(objectid::EXTENT_CSUM, item_type::EXTENT_CSUM, DATA_START),
+            csums,
+        );
+        let fs = builder.open();
+
+        let inode = inode_with(0o100644, InodeFlags::empty());
+        let extent = ExtentData::from_bytes(&extent_data(DATA_START, 3 * 4096)).unwrap();
+        assert!(read_extent_verified(&fs, &inode, &extent).is_err());
+
+        let (data, bad_blocks) = read_extent_tolerant(&fs, &inode, &extent).unwrap();
+        assert_eq!(bad_blocks, 1);
+        assert_eq!(&data[..4096], &blocks[0][..]);
+        assert!(data[4096..8192].iter().all(|&b| b == 0));
+        assert_eq!(&data[8192..], &blocks[2][..]);
+    }
+
+    #[test]
+    fn test_read_regular_extents() {
+        use crate::test_utils::{extent_data, inline_extent};
+
+        const PLAIN_AT: u64 = 0x300000;
+        const ZLIB_AT: u64 = 0x310000;
+        let fill = |seed: u8, len: usize| -> Vec<u8> {
+            (0..len)
+                .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+                .collect()
+        };
+
+        // 0..8K: the last two of three blocks on disk; 8K..12K: a hole;
+        // 12K..24K: zlib-compressed
+        let disk = fill(1, 3 * 4096);
+        let mut plain_item = extent_data(PLAIN_AT, disk.len() as u64);
+        plain_item[37..45].copy_from_slice(&4096u64.to_le_bytes()); // offset
+        plain_item[45..53].copy_from_slice(&8192u64.to_le_bytes()); // num_bytes
+
+        let text = fill(2, 3 * 4096);
+        let mut zlib = compress::compress_zlib(&text, 6).unwrap();
+        zlib.resize(zlib.len().next_multiple_of(4096), 0);
+        let mut zlib_item = extent_data(ZLIB_AT, text.len() as u64);
+        zlib_item[16] = 1; // zlib
+        zlib_item[29..37].copy_from_slice(&(zlib.len() as u64).to_le_bytes());
+
+        let mut inline = inline_extent(&compress::compress_zlib(b"tiny tiny tiny", 6).unwrap());
+        inline[8..16].copy_from_slice(&14u64.to_le_bytes()); // ram_bytes
+        inline[16] = 1; // zlib
+
+        let csums = |data: &[u8]| -> Vec<u8> {
+            data.chunks(4096)
+                .flat_map(|block| checksum::crc32c(block).to_le_bytes())
+                .collect()
+        };
+        let extent_key = |ino, offset| BtrfsKey::new(ino, item_type::EXTENT_DATA, offset);
+        let csum_key =
+            |logical| BtrfsKey::new(objectid::EXTENT_CSUM, item_type::EXTENT_CSUM, logical);
+
+        let root = objectid::FIRST_FREE;
+        let tree = objectid::FS_TREE;
+        let fs = ImageBuilder::new()
+            .root_dir(tree)
+            .file(tree, root, 257, "mixed", 6 * 4096)
+            .insert(tree, extent_key(257, 0), plain_item)
+            .insert(tree, extent_key(257, 8192), extent_data(0, 4096))
+            .insert(tree, extent_key(257, 12288), zlib_item)
+            .file(tree, root, 258, "small", 14)
+            .insert(tree, extent_key(258, 0), inline)
+            .data(PLAIN_AT, &disk)
+            .data(ZLIB_AT, &zlib)
+            .insert(objectid::CSUM_TREE, csum_key(PLAIN_AT), csums(&disk))
+            .insert(objectid::CSUM_TREE, csum_key(ZLIB_AT), csums(&zlib))
+            .open();
+        let read = |ino, offset, size| read_file_data(&fs, tree, ino, offset, size, true).unwrap();
+
+        let mut expected = disk[4096..].to_vec();
+        expected.extend([0u8; 4096]);
+        expected.extend(&text);
+        assert_eq!(read(257, 0, 6 * 4096), expected);
+
+        // Unaligned windows across extent boundaries
+        assert_eq!(read(257, 100, 9000), &expected[100..9100]);
+        assert_eq!(read(257, 12000, 5000), &expected[12000..17000]);
+        assert_eq!(read(258, 5, 100), b"tiny tiny");
+    }
+
+    #[test]
+    fn test_read_across_stripe_boundary() {
+        use crate::test_utils::extent_data;
+
+        // RAID0 over two devices with 64K elements: the extent's first 32K
+        // are at the end of the first element, the next 64K on the other
+        // device
+        let tree = objectid::FS_TREE;
+        let first = vec![0x11u8; 0x8000];
+        let second = vec![0x22u8; 0x10000];
+        let expected = [first.clone(), second.clone()].concat();
+        let csums: Vec<u8> = expected
+            .chunks(4096)
+            .flat_map(|block| checksum::crc32c(block).to_le_bytes())
+            .collect();
+
+        let fs = ImageBuilder::new()
+            .mirrored_chunk(
+                0x10000000,
+                0x100000,
+                chunk_type::RAID0,
+                &[0x200000, 0x300000],
+            )
+            .root_dir(tree)
+            .file(tree, objectid::FIRST_FREE, 257, "striped", 0x18000)
+            .insert(
+                tree,
+                BtrfsKey::new(257, item_type::EXTENT_DATA, 0),
+                extent_data(0x10008000, 0x18000),
+            )
+            .insert(
+                objectid::CSUM_TREE,
+                BtrfsKey::new(objectid::EXTENT_CSUM, item_type::EXTENT_CSUM, 0x10008000),
+                csums,
+            )
+            .data(0x208000, &first)
+            // What reading on from the first device would return
+            .data(0x210000, &[0xEE; 0x10000])
+            .data(0x300000, &second)
+            .open();
+
+        for verify in [false, true] {
+            let data = read_file_data(&fs, tree, 257, 0, 0x18000, verify).unwrap();
+            assert!(data == expected, "verify: {}", verify);
+        }
+    }
+
+    #[test]
+    fn test_read_no_holes_gaps() {
+        use crate::core::superblock::incompat;
+        use crate::test_utils::extent_data;
+
+        // 0..4K and 8K..12K have data; 4K..8K and 12K..16K have no items
+        let first = vec![0xAAu8; 4096];
+        let second = vec![0xBBu8; 4096];
+        let root = objectid::FIRST_FREE;
+        let fs = ImageBuilder::new()
+            .incompat(incompat::NO_HOLES)
+            .root_dir(objectid::FS_TREE)
+            .file(objectid::FS_TREE, root, 257, "holey", 4 * 4096)
+            .insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(257, item_type::EXTENT_DATA, 0),
+                extent_data(0x300000, 4096),
+            )
+            .insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(257, item_type::EXTENT_DATA, 8192),
+                extent_data(0x301000, 4096),
+            )
+            .data(0x300000, &first)
+            .data(0x301000, &second)
+            .open();
+        let read = |offset, size| {
+            read_file_data(&fs, objectid::FS_TREE, 257, offset, size, false).unwrap()
+        };
+
+        let mut expected = first.clone();
+        expected.extend([0u8; 4096]);
+        expected.extend(&second);
+        expected.extend([0u8; 4096]);
+        assert_eq!(read(0, 8 * 4096), expected);
+
+        // Reads wholly inside a gap, and the trailing gap up to the size
+        assert_eq!(read(5000, 100), vec![0u8; 100]);
+        assert_eq!(read(12000, 10000), &expected[12000..]);
+        assert!(read(4 * 4096, 10).is_empty());
+    }
+
+    #[test]
+    fn test_read_extents_across_leaves() {
+        use crate::test_utils::extent_data;
+
+        // Enough extents to spread over several leaves; extent i points at
+        // a block filled with i % 16
+        const EXTENTS: u64 = 1500;
+        let mut builder = ImageBuilder::new();
+        builder.root_dir(objectid::FS_TREE).file(
+            objectid::FS_TREE,
+            objectid::FIRST_FREE,
+            257,
+            "many",
+            EXTENTS * 4096,
+        );
+        for block in 0..16u64 {
+            builder.data(0x300000 + block * 4096, &[block as u8; 4096]);
+        }
+        for i in 0..EXTENTS {
+            builder.insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(257, item_type::EXTENT_DATA, i * 4096),
+                extent_data(0x300000 + (i % 16) * 4096, 4096),
+            );
+        }
+        let fs = builder.open();
+
+        // Straddle extent boundaries late in the file
+        for extent in [700u64, 1337, EXTENTS - 2] {
+            let offset = extent * 4096 + 4000;
+            let data = read_file_data(&fs, objectid::FS_TREE, 257, offset, 200, false).unwrap();
+            assert_eq!(data[..96], [(extent % 16) as u8; 96]);
+            assert_eq!(data[96..], [((extent + 1) % 16) as u8; 104]);
+        }
+    }
+
+    #[test]
+    fn test_read_file_data_verify() {
+        use crate::test_utils::extent_data;
+
+        const DATA_START: u64 = 0x300000;
+        let good = vec![0x11u8; 4096];
+        let mut bad = good.clone();
+        bad[42] = 0x22;
+
+        let root = objectid::FIRST_FREE;
+        let fs = ImageBuilder::new()
+            .root_dir(objectid::FS_TREE)
+            .file(objectid::FS_TREE, root, 257, "rotted", 4096)
+            .insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(257, item_type::EXTENT_DATA, 0),
+                extent_data(DATA_START, 4096),
+            )
+            .insert(
+                objectid::CSUM_TREE,
+                BtrfsKey::new(objectid::EXTENT_CSUM, item_type::EXTENT_CSUM, DATA_START),
+                checksum::crc32c(&good).to_le_bytes().to_vec(),
+            )
+            .data(DATA_START, &bad)
+            .open();
+
+        assert!(matches!(
+            read_file_data(&fs, objectid::FS_TREE, 257, 0, 4096, true),
+            Err(BtrfsError::ChecksumMismatch { .. })
+        ));
+        let data = read_file_data(&fs, objectid::FS_TREE, 257, 0, 4096, false).unwrap();
+        assert_eq!(data, bad);
+    }
+
+    #[test]
+    fn test_data_policy() {
+        let policy = DataPolicy::for_inode(&inode_with(0o100644, InodeFlags::empty()));
+        assert_eq!(policy, DataPolicy { csum: true });
+
+        let policy = DataPolicy::for_inode(&inode_with(0o100644, InodeFlags::NODATASUM));
+        assert_eq!(policy, DataPolicy { csum: false });
+
+        // NODATACOW implies no checksums
+        let policy = DataPolicy::for_inode(&inode_with(0o100644, InodeFlags::NODATACOW));
+        assert_eq!(policy, DataPolicy { csum: false });
+    }
+
+    #[test]
+    fn test_lookup_data_csums() {
+        let block_a = vec![0xAAu8; 4096];
+        let block_b = vec![0xBBu8; 4096];
+        let fs = csum_fs(0x200000, &[&block_a, &block_b]);
+
+        let csums = lookup_data_csums(&fs, 0x200000, 3 * 4096).unwrap();
+        assert_eq!(
+            csums,
+            vec![
+                Some(checksum::crc32c(&block_a) as u64),
+                Some(checksum::crc32c(&block_b) as u64),
+                None
+            ]
+        );
+
+        // Starting inside the csum item
+        let csums = lookup_data_csums(&fs, 0x201000, 4096).unwrap();
+        assert_eq!(csums, vec![Some(checksum::crc32c(&block_b) as u64)]);
+    }
+
+    #[test]
+    fn test_lookup_data_csums_many_items() {
+        // One single-sector csum item every 64K, spread over several leaves
+        let mut builder = ImageBuilder::new();
+        builder.node_size(4096);
+        for i in 0..200u64 {
+            builder.insert(
+                objectid::CSUM_TREE,
+                BtrfsKey::new(
+                    objectid::EXTENT_CSUM,
+                    item_type::EXTENT_CSUM,
+                    0x200000 + i * 0x10000,
+                ),
+                (i as u32).to_le_bytes().to_vec(),
+            );
+        }
+        let fs = builder.open();
+
+        let item = |i: u64| Some(i);
+        assert_eq!(
+            lookup_data_csums(&fs, 0x200000 + 150 * 0x10000, 4096).unwrap(),
+            [item(150)]
+        );
+        // From the gap after item 150 into item 151
+        let csums = lookup_data_csums(&fs, 0x200000 + 150 * 0x10000 + 0xF000, 0x2000).unwrap();
+        assert_eq!(csums, [None, item(151)]);
+        // Before every item
+        assert_eq!(lookup_data_csums(&fs, 0x100000, 4096).unwrap(), [None]);
+    }
+
+    #[test]
+    fn test_verify_data() {
+        let block = vec![0x5Au8; 4096];
+        let fs = csum_fs(0x200000, &[&block]);
+        let inode = inode_with(0o100644, InodeFlags::empty());
+
+        assert!(verify_data(&fs, &inode, 0x200000, &block).is_ok());
+
+        let mut corrupt = block.clone();
+        corrupt[100] ^= 0xFF;
+        assert!(matches!(
+            verify_data(&fs, &inode, 0x200000, &corrupt),
+            Err(BtrfsError::ChecksumMismatch { .. })
+        ));
+
+        // No csum entry for this block
+        assert!(matches!(
+            verify_data(&fs, &inode, 0x300000, &block),
+            Err(BtrfsError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_read_compressed_extent_verified() {
+        use crate::core::compress;
+        use crate::test_utils::extent_data;
+
+        const DATA_START: u64 = 0x300000;
+
+        // Poorly compressible data, so the compressed extent spans sectors
+        let mut seed = 0x1234_5678u32;
+        let plain: Vec<u8> = (0..3 * 4096)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 24) as u8
+            })
+            .collect();
+        let mut on_disk = compress::compress_zlib(&plain, 6).unwrap();
+        on_disk.resize(on_disk.len().next_multiple_of(4096), 0);
+        let disk_len = on_disk.len() as u64;
+        assert!(disk_len > 4096);
+
+        let mut item = extent_data(DATA_START, plain.len() as u64);
+        item[16] = 1; // zlib
+        item[29..37].copy_from_slice(&disk_len.to_le_bytes());
+        let extent = ExtentData::from_bytes(&item).unwrap();
+
+        // The csum tree only covers the compressed bytes
+        let blocks: Vec<&[u8]> = on_disk.chunks(4096).collect();
+        let fs = csum_fs(DATA_START, &blocks);
+        fs.device().write_at(DATA_START, &on_disk).unwrap();
+        let inode = inode_with(0o100644, InodeFlags::COMPRESS);
+
+        let data = read_extent_verified(&fs, &inode, &extent).unwrap();
+        assert_eq!(data, on_disk);
+        assert_eq!(
+            compress::decompress_zlib(&data, plain.len()).unwrap(),
+            plain
+        );
+
+        // Corruption in a later sector of the compressed bytes is caught
+        fs.device()
+            .write_at(DATA_START + disk_len - 1, &[0xFF])
+            .unwrap();
+        assert!(matches!(
+            read_extent_verified(&fs, &inode, &extent),
+            Err(BtrfsError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_verify_data_nodatasum() {
+        let block = vec![0x5Au8; 4096];
+        let fs = csum_fs(0x200000, &[&block]);
+
+        // Missing csums are expected for NODATASUM and NODATACOW files
+        let inode = inode_with(0o100644, InodeFlags::NODATASUM);
+        assert!(verify_data(&fs, &inode, 0x300000, &block).is_ok());
+
+        let inode = inode_with(0o100644, InodeFlags::NODATACOW);
+        assert!(verify_data(&fs, &inode, 0x300000, &block).is_ok());
+    }
+}
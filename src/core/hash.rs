@@ -0,0 +1,190 @@
+//! Content hashes of files and directory trees
+//!
+//! A hash covers only what a copy preserves: file contents, symlink
+//! targets, names and entry types. Timestamps, ownership and how the data
+//! is laid out on disk (compression, extents, sharing) are left out, so a
+//! restored or cloned tree hashes the same as its original.
+
+use super::{
+    file::read_symlink,
+    inode::InodeType,
+    path::{read_dir, resolve_path},
+    reader::{FileReader, MAX_READ_CHUNK},
+    BtrfsFilesystem, Result,
+};
+use sha2::{Digest, Sha256};
+
+/// Computes the SHA-256 content hash of the file or directory at `path` in
+/// tree `tree_id`
+///
+/// A regular file hashes to the SHA-256 of its decompressed contents, as
+/// `sha256sum` would give. A directory hashes its entries sorted by name,
+/// each as its type, name and own hash, so renaming, moving or changing
+/// anything beneath it changes the result. Nested subvolumes count as
+/// entries but are not entered.
+pub fn content_hash(fs: &BtrfsFilesystem, tree_id: u64, path: &str) -> Result<[u8; 32]> {
+    let (tree_id, ino, inode) = resolve_path(fs, tree_id, path)?;
+    let kind = InodeType::from_mode(inode.mode);
+    hash_inode(fs, tree_id, ino, kind)
+}
+
+/// Hashes inode `ino` of tree `tree_id`, whose type is `kind`
+fn hash_inode(fs: &BtrfsFilesystem, tree_id: u64, ino: u64, kind: InodeType) -> Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    match kind {
+        InodeType::File => {
+            let mut reader = FileReader::open(fs, tree_id, ino)?;
+            let mut buf = vec![0u8; MAX_READ_CHUNK];
+            loop {
+                match reader.read_chunk(&mut buf)? {
+                    0 => break,
+                    n => hasher.update(&buf[..n]),
+                }
+            }
+        }
+        InodeType::Directory => {
+            let mut entries = read_dir(fs, tree_id, ino)?;
+            entries.sort_by(|a, b| a.name.as_bytes().cmp(b.name.as_bytes()));
+
+            for entry in entries {
+                let digest = match entry.is_subvolume() {
+                    true => [0u8; 32],
+                    false => hash_inode(fs, tree_id, entry.ino, entry.entry_type)?,
+                };
+                hasher.update([type_tag(entry.entry_type, entry.is_subvolume())]);
+                hasher.update((entry.name.len() as u32).to_le_bytes());
+                hasher.update(entry.name.as_bytes());
+                hasher.update(digest);
+            }
+        }
+        InodeType::Symlink => hasher.update(read_symlink(fs, tree_id, ino)?.as_bytes()),
+        // Devices, FIFOs and sockets have no contents; their type is
+        // recorded by the directory listing them
+        _ => {}
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Identifies an entry's type in a directory hash
+fn type_tag(kind: InodeType, is_subvolume: bool) -> u8 {
+    if is_subvolume {
+        return b'v';
+    }
+    match kind {
+        InodeType::File => b'f',
+        InodeType::Directory => b'd',
+        InodeType::Symlink => b'l',
+        InodeType::BlockDevice => b'b',
+        InodeType::CharDevice => b'c',
+        InodeType::Fifo => b'p',
+        InodeType::Socket => b's',
+        InodeType::Unknown => b'?',
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{checksum, item_type, objectid, tree::BtrfsKey};
+    use crate::test_utils::{extent_data, inline_extent, ImageBuilder};
+
+    /// `docs/a.txt` and `docs/b.txt` hold "hello" inline; `big.bin` is a
+    /// regular extent of `fill` bytes
+    fn builder(fill: u8) -> ImageBuilder {
+        let root = objectid::FIRST_FREE;
+        let data = [fill; 0x2000];
+        let csums: Vec<u8> = data
+            .chunks(4096)
+            .flat_map(|block| checksum::crc32c(block).to_le_bytes())
+            .collect();
+
+        let mut builder = ImageBuilder::new();
+        builder
+            .root_dir(objectid::FS_TREE)
+            .dir(objectid::FS_TREE, root, 257, "docs")
+            .file(objectid::FS_TREE, 257, 258, "a.txt", 5)
+            .file(objectid::FS_TREE, 257, 259, "b.txt", 5)
+            .file(objectid::FS_TREE, root, 260, "big.bin", 0x2000)
+            .symlink(objectid::FS_TREE, root, 261, "link", "docs/a.txt")
+            .insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(260, item_type::EXTENT_DATA, 0),
+                extent_data(0x300000, 0x2000),
+            )
+            .insert(
+                objectid::CSUM_TREE,
+                BtrfsKey::new(objectid::EXTENT_CSUM, item_type::EXTENT_CSUM, 0x300000),
+                csums,
+            )
+            .data(0x300000, &data);
+        for ino in [258, 259] {
+            builder.insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(ino, item_type::EXTENT_DATA, 0),
+                inline_extent(b"hello"),
+            );
+        }
+        builder
+    }
+
+    #[test]
+    fn test_file_hash() {
+        let fs = builder(0x42).open();
+        let hash = content_hash(&fs, objectid::FS_TREE, "/docs/a.txt").unwrap();
+        assert_eq!(hash, <[u8; 32]>::from(Sha256::digest(b"hello")));
+        assert_eq!(
+            content_hash(&fs, objectid::FS_TREE, "/big.bin").unwrap(),
+            <[u8; 32]>::from(Sha256::digest([0x42; 0x2000]))
+        );
+
+        // Same contents, same hash
+        assert_eq!(
+            content_hash(&fs, objectid::FS_TREE, "/docs/b.txt").unwrap(),
+            hash
+        );
+        assert_ne!(
+            content_hash(&fs, objectid::FS_TREE, "/big.bin").unwrap(),
+            hash
+        );
+    }
+
+    #[test]
+    fn test_tree_hash_stable() {
+        let first = content_hash(&builder(0x42).open(), objectid::FS_TREE, "/").unwrap();
+        let second = content_hash(&builder(0x42).open(), objectid::FS_TREE, "/").unwrap();
+        assert_eq!(first, second);
+
+        // Changed contents anywhere below change the tree hash
+        let changed = content_hash(&builder(0x43).open(), objectid::FS_TREE, "/").unwrap();
+        assert_ne!(first, changed);
+    }
+
+    /// A `docs` directory holding "hello" in each of `names`, created in
+    /// that order
+    fn docs(names: &[&str]) -> BtrfsFilesystem {
+        let root = objectid::FIRST_FREE;
+        let mut builder = ImageBuilder::new();
+        builder
+            .root_dir(objectid::FS_TREE)
+            .dir(objectid::FS_TREE, root, 257, "docs");
+        for (ino, name) in (258..).zip(names) {
+            builder.file(objectid::FS_TREE, 257, ino, name, 5).insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(ino, item_type::EXTENT_DATA, 0),
+                inline_extent(b"hello"),
+            );
+        }
+        builder.open()
+    }
+
+    #[test]
+    fn test_tree_hash_names() {
+        let hash = |names: &[&str]| content_hash(&docs(names), objectid::FS_TREE, "/").unwrap();
+        let original = hash(&["a.txt", "b.txt"]);
+
+        // Creation order and inode numbers don't matter; names do
+        assert_eq!(hash(&["b.txt", "a.txt"]), original);
+        assert_ne!(hash(&["a.txt", "c.txt"]), original);
+        assert_ne!(hash(&["a.txt"]), original);
+    }
+}
@@ -3,7 +3,11 @@
 //! This module handles file and directory metadata.
 //! Parsing functions are optimized with inline hints for hot paths.
 
-use super::{item_type, tree::BtrfsKey, BtrfsError, BtrfsFilesystem, Result};
+use super::{
+    checksum, field_end, item_type, objectid, tree::BtrfsKey, BtrfsError, BtrfsFilesystem, Result,
+    MAX_NAME_LEN,
+};
+use bitflags::bitflags;
 use byteorder::{ByteOrder, LittleEndian};
 
 /// Inode types
@@ -63,6 +67,37 @@ impl InodeType {
     }
 }
 
+bitflags! {
+    /// Per-inode flags (`BTRFS_INODE_*`)
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct InodeFlags: u64 {
+        /// Data is not checksummed
+        const NODATASUM = 1 << 0;
+        /// Data is overwritten in place instead of copy-on-write
+        const NODATACOW = 1 << 1;
+        /// Inode is read-only
+        const READONLY = 1 << 2;
+        /// Never compress data
+        const NOCOMPRESS = 1 << 3;
+        /// Has preallocated extents
+        const PREALLOC = 1 << 4;
+        /// Writes are synchronous
+        const SYNC = 1 << 5;
+        /// Inode cannot be modified
+        const IMMUTABLE = 1 << 6;
+        /// Data may only be appended
+        const APPEND = 1 << 7;
+        /// Excluded from dump
+        const NODUMP = 1 << 8;
+        /// Access time is not updated
+        const NOATIME = 1 << 9;
+        /// Directory changes are synchronous
+        const DIRSYNC = 1 << 10;
+        /// Data is compressed
+        const COMPRESS = 1 << 11;
+    }
+}
+
 /// BTRFS inode item
 #[derive(Debug, Clone)]
 pub struct Inode {
@@ -132,21 +167,22 @@ impl Inode {
             rdev: LittleEndian::read_u64(&data[56..64]),
             flags: LittleEndian::read_u64(&data[64..72]),
             sequence: LittleEndian::read_u64(&data[72..80]),
+            // 32 reserved bytes precede the timestamps
             atime: TimeSpec {
-                sec: LittleEndian::read_i64(&data[120..128]),
-                nsec: LittleEndian::read_u32(&data[128..132]),
+                sec: LittleEndian::read_i64(&data[112..120]),
+                nsec: LittleEndian::read_u32(&data[120..124]),
             },
             ctime: TimeSpec {
-                sec: LittleEndian::read_i64(&data[132..140]),
-                nsec: LittleEndian::read_u32(&data[140..144]),
+                sec: LittleEndian::read_i64(&data[124..132]),
+                nsec: LittleEndian::read_u32(&data[132..136]),
             },
             mtime: TimeSpec {
-                sec: LittleEndian::read_i64(&data[144..152]),
-                nsec: LittleEndian::read_u32(&data[152..156]),
+                sec: LittleEndian::read_i64(&data[136..144]),
+                nsec: LittleEndian::read_u32(&data[144..148]),
             },
             otime: TimeSpec {
-                sec: LittleEndian::read_i64(&data[156..164]),
-                nsec: LittleEndian::read_u32(&data[164..168]),
+                sec: LittleEndian::read_i64(&data[148..156]),
+                nsec: LittleEndian::read_u32(&data[156..160]),
             },
         })
     }
@@ -180,6 +216,39 @@ impl Inode {
     pub const fn permissions(&self) -> u32 {
         self.mode & 0o7777
     }
+
+    /// Returns the decoded inode flags, keeping unknown bits
+    #[inline]
+    pub const fn inode_flags(&self) -> InodeFlags {
+        InodeFlags::from_bits_retain(self.flags)
+    }
+
+    /// Returns the names of the known flags set on this inode
+    pub fn flag_names(&self) -> Vec<&'static str> {
+        self.inode_flags().iter_names().map(|(name, _)| name).collect()
+    }
+}
+
+/// Returns the end of the `len`-byte name at `start` like [`field_end`],
+/// also failing if the name is longer than [`MAX_NAME_LEN`]
+fn name_end(data: &[u8], start: usize, len: usize, what: &str) -> Result<usize> {
+    if len > MAX_NAME_LEN {
+        return Err(BtrfsError::Corrupt(format!(
+            "{} too long ({} bytes)",
+            what, len
+        )));
+    }
+    field_end(data, start, len, what)
+}
+
+/// BTRFS name hash function, the offset of DIR_ITEM keys
+///
+/// This is the kernel's raw CRC32c seeded with `(u32)~1`, without the
+/// final inversion, so it differs from a plain `crc32c(name)`.
+pub fn btrfs_name_hash(name: &str) -> u64 {
+    // crc32c_append inverts its seed on entry and its result on exit
+    let crc = !checksum::crc32c_append(!0xFFFF_FFFEu32, name.as_bytes());
+    crc as u64
 }
 
 /// Directory entry
@@ -196,8 +265,26 @@ pub struct DirEntry {
 }
 
 impl DirEntry {
-    /// Parses a directory item from bytes
+    /// Parses the first entry of a directory item
     pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        Self::parse(data).map(|(entry, _)| entry)
+    }
+
+    /// Parses every entry of a DIR_ITEM
+    ///
+    /// Names whose hashes collide share one DIR_ITEM, packed back to back.
+    pub fn all_from_bytes(mut data: &[u8]) -> Result<Vec<Self>> {
+        let mut entries = Vec::new();
+        while !data.is_empty() {
+            let (entry, len) = Self::parse(data)?;
+            entries.push(entry);
+            data = &data[len..];
+        }
+        Ok(entries)
+    }
+
+    /// Parses one entry, returning it with its length in bytes
+    fn parse(data: &[u8]) -> Result<(Self, usize)> {
         if data.len() < 30 {
             return Err(BtrfsError::Corrupt("Dir item too small".to_string()));
         }
@@ -207,22 +294,94 @@ impl DirEntry {
         let child_tree = LittleEndian::read_u64(&data[9..17]);
 
         let _transid = LittleEndian::read_u64(&data[17..25]);
-        let _data_len = LittleEndian::read_u16(&data[25..27]);
+        let data_len = LittleEndian::read_u16(&data[25..27]) as usize;
         let name_len = LittleEndian::read_u16(&data[27..29]);
         let entry_type = InodeType::from_dir_type(data[29]);
 
-        if data.len() < 30 + name_len as usize {
-            return Err(BtrfsError::Corrupt("Dir item name truncated".to_string()));
+        let end = name_end(data, 30, name_len as usize, "Dir item name")?;
+        let name = String::from_utf8_lossy(&data[30..end]).to_string();
+        let len = field_end(data, end, data_len, "Dir item")?;
+
+        Ok((
+            Self {
+                ino,
+                child_tree,
+                entry_type,
+                name,
+            },
+            len,
+        ))
+    }
+
+    /// Returns true if the entry is the root of another subvolume
+    ///
+    /// Such entries point at the subvolume's ROOT_ITEM, so `ino` holds the
+    /// subvolume's tree ID rather than an inode number.
+    #[inline]
+    pub fn is_subvolume(&self) -> bool {
+        self.child_tree != 0
+    }
+
+    /// Returns the (tree, inode) the entry points at, for an entry of a
+    /// directory in tree `tree_id`
+    ///
+    /// A nested subvolume is entered at its root directory.
+    #[inline]
+    pub fn target(&self, tree_id: u64) -> (u64, u64) {
+        if self.is_subvolume() {
+            (self.ino, objectid::FIRST_FREE)
+        } else {
+            (tree_id, self.ino)
         }
+    }
+}
 
-        let name = String::from_utf8_lossy(&data[30..30 + name_len as usize]).to_string();
+/// Extended attribute
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Xattr {
+    /// Attribute name, including its namespace (e.g. `user.`)
+    pub name: String,
+    /// Attribute value
+    pub value: Vec<u8>,
+}
 
-        Ok(Self {
-            ino,
-            child_tree,
-            entry_type,
-            name,
-        })
+impl Xattr {
+    /// Parses the first extended attribute of an XATTR_ITEM
+    ///
+    /// XATTR_ITEMs share the DIR_ITEM layout, with the value stored after
+    /// the name.
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        Self::parse(data).map(|(xattr, _)| xattr)
+    }
+
+    /// Parses every extended attribute of an XATTR_ITEM
+    ///
+    /// Names whose hashes collide share one item, packed back to back.
+    pub fn all_from_bytes(mut data: &[u8]) -> Result<Vec<Self>> {
+        let mut xattrs = Vec::new();
+        while !data.is_empty() {
+            let (xattr, len) = Self::parse(data)?;
+            xattrs.push(xattr);
+            data = &data[len..];
+        }
+        Ok(xattrs)
+    }
+
+    /// Parses one entry, returning it with its length in bytes
+    fn parse(data: &[u8]) -> Result<(Self, usize)> {
+        if data.len() < 30 {
+            return Err(BtrfsError::Corrupt("Xattr item too small".to_string()));
+        }
+
+        let data_len = LittleEndian::read_u16(&data[25..27]) as usize;
+        let name_len = LittleEndian::read_u16(&data[27..29]) as usize;
+        let name_end = field_end(data, 30, name_len, "Xattr item")?;
+        let len = field_end(data, name_end, data_len, "Xattr item")?;
+
+        let name = String::from_utf8_lossy(&data[30..name_end]).to_string();
+        let value = data[name_end..len].to_vec();
+
+        Ok((Self { name, value }, len))
     }
 }
 
@@ -247,18 +406,110 @@ impl InodeRef {
         let index = LittleEndian::read_u64(&data[0..8]);
         let name_len = LittleEndian::read_u16(&data[8..10]);
 
-        if data.len() < 10 + name_len as usize {
-            return Err(BtrfsError::Corrupt("Inode ref name truncated".to_string()));
+        let end = name_end(data, 10, name_len as usize, "Inode ref name")?;
+        let name = String::from_utf8_lossy(&data[10..end]).to_string();
+
+        Ok(Self {
+            index,
+            name_len,
+            name,
+        })
+    }
+
+    /// Parses every reference packed into one INODE_REF item
+    ///
+    /// Links to the same inode from one directory share an item.
+    pub fn parse_all(data: &[u8]) -> Result<Vec<Self>> {
+        match Self::parse_partial(data) {
+            (refs, None) => Ok(refs),
+            (_, Some(err)) => Err(err),
+        }
+    }
+
+    /// Parses the references packed into one INODE_REF item up to the first
+    /// malformed one, returning them with the error that stopped parsing
+    pub fn parse_partial(data: &[u8]) -> (Vec<Self>, Option<BtrfsError>) {
+        let mut refs = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            match Self::from_bytes(rest) {
+                Ok(iref) => {
+                    rest = &rest[10 + iref.name_len as usize..];
+                    refs.push(iref);
+                }
+                Err(err) => return (refs, Some(err)),
+            }
+        }
+        (refs, None)
+    }
+}
+
+/// Extended inode reference (hard link), used with the EXTENDED_IREF feature
+///
+/// Once an inode's links from one directory no longer fit in an INODE_REF
+/// item, further links are stored as INODE_EXTREF items keyed by a hash of
+/// the parent and name, so the parent is kept in the item itself.
+#[derive(Debug, Clone)]
+pub struct InodeExtRef {
+    /// Inode number of the parent directory
+    pub parent: u64,
+    /// Index in parent directory
+    pub index: u64,
+    /// Name length
+    pub name_len: u16,
+    /// Name
+    pub name: String,
+}
+
+impl InodeExtRef {
+    /// Parses an extended inode reference from bytes
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() < 18 {
+            return Err(BtrfsError::Corrupt("Inode extref too small".to_string()));
         }
 
-        let name = String::from_utf8_lossy(&data[10..10 + name_len as usize]).to_string();
+        let parent = LittleEndian::read_u64(&data[0..8]);
+        let index = LittleEndian::read_u64(&data[8..16]);
+        let name_len = LittleEndian::read_u16(&data[16..18]);
+
+        let end = name_end(data, 18, name_len as usize, "Inode extref name")?;
+        let name = String::from_utf8_lossy(&data[18..end]).to_string();
 
         Ok(Self {
+            parent,
             index,
             name_len,
             name,
         })
     }
+
+    /// Parses every reference packed into one INODE_EXTREF item
+    ///
+    /// Links whose key hashes collide share an item.
+    pub fn parse_all(data: &[u8]) -> Result<Vec<Self>> {
+        match Self::parse_partial(data) {
+            (refs, None) => Ok(refs),
+            (_, Some(err)) => Err(err),
+        }
+    }
+
+    /// Parses the references packed into one INODE_EXTREF item up to the
+    /// first malformed one, returning them with the error that stopped
+    /// parsing
+    pub fn parse_partial(data: &[u8]) -> (Vec<Self>, Option<BtrfsError>) {
+        let mut refs = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            match Self::from_bytes(rest) {
+                Ok(extref) => {
+                    rest = &rest[18 + extref.name_len as usize..];
+                    refs.push(extref);
+                }
+                Err(err) => return (refs, Some(err)),
+            }
+        }
+        (refs, None)
+    }
 }
 
 /// File extent data
@@ -365,6 +616,26 @@ impl ExtentData {
     pub const fn is_prealloc(&self) -> bool {
         self.extent_type == 2
     }
+
+    /// Fails if the extent's data is encrypted or otherwise encoded
+    ///
+    /// Neither is defined by any kernel yet, so the bytes on disk can't be
+    /// returned as file data.
+    pub fn check_supported(&self) -> Result<()> {
+        if self.encryption != 0 {
+            return Err(BtrfsError::UnsupportedFeature(format!(
+                "extent encryption type {}",
+                self.encryption
+            )));
+        }
+        if self.other_encoding != 0 {
+            return Err(BtrfsError::UnsupportedFeature(format!(
+                "extent encoding {}",
+                self.other_encoding
+            )));
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -384,6 +655,45 @@ mod tests {
         assert_eq!(InodeType::from_dir_type(255), InodeType::Unknown);
     }
 
+    #[test]
+    fn test_btrfs_name_hash() {
+        // Hash should be deterministic
+        let hash1 = btrfs_name_hash("test.txt");
+        let hash2 = btrfs_name_hash("test.txt");
+        assert_eq!(hash1, hash2);
+
+        // Different names should have different hashes (usually)
+        let hash_a = btrfs_name_hash("file_a.txt");
+        let hash_b = btrfs_name_hash("file_b.txt");
+        assert_ne!(hash_a, hash_b);
+    }
+
+    #[test]
+    fn test_btrfs_name_hash_known_value() {
+        // From `btrfs inspect-internal dump-tree`: the root tree directory
+        // holds "default" under key (6 DIR_ITEM 2378154706)
+        assert_eq!(btrfs_name_hash("default"), 2378154706);
+    }
+
+    #[test]
+    fn test_btrfs_name_hash_empty() {
+        let hash = btrfs_name_hash("");
+        assert_eq!(hash, 0xFFFF_FFFE); // Nothing hashed leaves the seed
+    }
+
+    #[test]
+    fn test_btrfs_name_hash_special_chars() {
+        let hash1 = btrfs_name_hash("file with spaces.txt");
+        let hash2 = btrfs_name_hash("file-with-dashes.txt");
+        assert_ne!(hash1, hash2);
+    }
+
+    #[test]
+    fn test_btrfs_name_hash_unicode() {
+        let hash = btrfs_name_hash("файл.txt"); // Russian word for "file"
+        assert_ne!(hash, 0);
+    }
+
     #[test]
     fn test_inode_type_from_mode() {
         assert_eq!(InodeType::from_mode(0o100644), InodeType::File);
@@ -396,8 +706,40 @@ mod tests {
         assert_eq!(InodeType::from_mode(0o000000), InodeType::Unknown);
     }
 
+    #[test]
+    fn test_inode_flags_decode() {
+        let mut data = create_mock_inode_data();
+        let inode = Inode::from_bytes(257, &data).unwrap();
+        assert!(inode.inode_flags().is_empty());
+        assert!(inode.flag_names().is_empty());
+
+        let flags = InodeFlags::NODATASUM | InodeFlags::NODATACOW;
+        data[64..72].copy_from_slice(&flags.bits().to_le_bytes());
+        let inode = Inode::from_bytes(257, &data).unwrap();
+        assert_eq!(inode.inode_flags(), flags);
+        assert_eq!(inode.flag_names(), vec!["NODATASUM", "NODATACOW"]);
+
+        let flags = InodeFlags::COMPRESS | InodeFlags::IMMUTABLE | InodeFlags::APPEND;
+        data[64..72].copy_from_slice(&flags.bits().to_le_bytes());
+        let inode = Inode::from_bytes(257, &data).unwrap();
+        assert!(inode.inode_flags().contains(InodeFlags::COMPRESS));
+        assert!(!inode.inode_flags().contains(InodeFlags::NOCOMPRESS));
+        assert_eq!(inode.flag_names(), vec!["IMMUTABLE", "APPEND", "COMPRESS"]);
+    }
+
+    #[test]
+    fn test_inode_flags_unknown_bits() {
+        let mut data = create_mock_inode_data();
+        let raw = (1u64 << 40) | InodeFlags::NOATIME.bits();
+        data[64..72].copy_from_slice(&raw.to_le_bytes());
+
+        let inode = Inode::from_bytes(257, &data).unwrap();
+        assert_eq!(inode.inode_flags().bits(), raw);
+        assert_eq!(inode.flag_names(), vec!["NOATIME"]);
+    }
+
     fn create_mock_inode_data() -> Vec<u8> {
-        let mut data = vec![0u8; 168];
+        let mut data = vec![0u8; 160];
         // generation
         data[0..8].copy_from_slice(&100u64.to_le_bytes());
         // transid
@@ -422,19 +764,19 @@ mod tests {
         data[64..72].copy_from_slice(&0u64.to_le_bytes());
         // sequence
         data[72..80].copy_from_slice(&1u64.to_le_bytes());
-        // reserved (80-120)
+        // reserved (80-112)
         // atime
-        data[120..128].copy_from_slice(&1700000000i64.to_le_bytes());
-        data[128..132].copy_from_slice(&123456u32.to_le_bytes());
+        data[112..120].copy_from_slice(&1700000000i64.to_le_bytes());
+        data[120..124].copy_from_slice(&123456u32.to_le_bytes());
         // ctime
-        data[132..140].copy_from_slice(&1700000001i64.to_le_bytes());
-        data[140..144].copy_from_slice(&234567u32.to_le_bytes());
+        data[124..132].copy_from_slice(&1700000001i64.to_le_bytes());
+        data[132..136].copy_from_slice(&234567u32.to_le_bytes());
         // mtime
-        data[144..152].copy_from_slice(&1700000002i64.to_le_bytes());
-        data[152..156].copy_from_slice(&345678u32.to_le_bytes());
+        data[136..144].copy_from_slice(&1700000002i64.to_le_bytes());
+        data[144..148].copy_from_slice(&345678u32.to_le_bytes());
         // otime
-        data[156..164].copy_from_slice(&1700000003i64.to_le_bytes());
-        data[164..168].copy_from_slice(&456789u32.to_le_bytes());
+        data[148..156].copy_from_slice(&1700000003i64.to_le_bytes());
+        data[156..160].copy_from_slice(&456789u32.to_le_bytes());
         data
     }
 
@@ -454,6 +796,10 @@ mod tests {
         assert_eq!(inode.mode, 0o100644);
         assert_eq!(inode.atime.sec, 1700000000);
         assert_eq!(inode.atime.nsec, 123456);
+        assert_eq!(inode.ctime.sec, 1700000001);
+        assert_eq!(inode.mtime.nsec, 345678);
+        assert_eq!(inode.otime.sec, 1700000003);
+        assert_eq!(inode.otime.nsec, 456789);
     }
 
     #[test]
@@ -540,6 +886,43 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_dir_entry_max_name_len() {
+        let name = "a".repeat(MAX_NAME_LEN);
+        let entry = DirEntry::from_bytes(&create_mock_dir_entry_data(&name)).unwrap();
+        assert_eq!(entry.name, name);
+
+        // One byte too long, even though the name is all there
+        let data = create_mock_dir_entry_data(&"a".repeat(MAX_NAME_LEN + 1));
+        assert!(matches!(
+            DirEntry::from_bytes(&data),
+            Err(BtrfsError::Corrupt(_))
+        ));
+
+        let mut data = vec![b'a'; 30 + u16::MAX as usize];
+        data[27..29].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(matches!(
+            DirEntry::from_bytes(&data),
+            Err(BtrfsError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn test_xattr_max_lengths_truncated() {
+        // Name and value both at the u16 limit, with the last byte missing
+        let mut data = vec![0u8; 30 + 2 * u16::MAX as usize - 1];
+        data[25..27].copy_from_slice(&u16::MAX.to_le_bytes());
+        data[27..29].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(matches!(
+            Xattr::from_bytes(&data),
+            Err(BtrfsError::Corrupt(_))
+        ));
+
+        data.push(0);
+        let xattr = Xattr::from_bytes(&data).unwrap();
+        assert_eq!(xattr.value.len(), u16::MAX as usize);
+    }
+
     fn create_mock_inode_ref_data(name: &str) -> Vec<u8> {
         let name_bytes = name.as_bytes();
         let mut data = vec![0u8; 10 + name_bytes.len()];
@@ -577,6 +960,79 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_inode_ref_name_too_long() {
+        let name = "a".repeat(MAX_NAME_LEN);
+        let iref = InodeRef::from_bytes(&create_mock_inode_ref_data(&name)).unwrap();
+        assert_eq!(iref.name, name);
+
+        let data = create_mock_inode_ref_data(&"a".repeat(MAX_NAME_LEN + 1));
+        assert!(matches!(
+            InodeRef::from_bytes(&data),
+            Err(BtrfsError::Corrupt(_))
+        ));
+        assert!(InodeRef::parse_all(&data).is_err());
+
+        let data = extref_bytes(300, 7, &"a".repeat(MAX_NAME_LEN + 1));
+        assert!(matches!(
+            InodeExtRef::from_bytes(&data),
+            Err(BtrfsError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn test_inode_ref_parse_all() {
+        let mut data = create_mock_inode_ref_data("a.txt");
+        data.extend(create_mock_inode_ref_data("b.txt"));
+
+        let refs = InodeRef::parse_all(&data).unwrap();
+        let names: Vec<&str> = refs.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["a.txt", "b.txt"]);
+
+        data.pop();
+        assert!(InodeRef::parse_all(&data).is_err());
+        let (refs, err) = InodeRef::parse_partial(&data);
+        assert_eq!(refs.len(), 1);
+        assert!(matches!(err, Some(BtrfsError::Corrupt(_))));
+    }
+
+    fn extref_bytes(parent: u64, index: u64, name: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&parent.to_le_bytes());
+        data.extend_from_slice(&index.to_le_bytes());
+        data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        data.extend_from_slice(name.as_bytes());
+        data
+    }
+
+    #[test]
+    fn test_inode_extref_from_bytes() {
+        let extref_data = extref_bytes(300, 7, "link.txt");
+        let extref = InodeExtRef::from_bytes(&extref_data).unwrap();
+        assert_eq!(extref.parent, 300);
+        assert_eq!(extref.index, 7);
+        assert_eq!(extref.name_len, 8);
+        assert_eq!(extref.name, "link.txt");
+
+        assert!(InodeExtRef::from_bytes(&[0u8; 17]).is_err());
+        assert!(InodeExtRef::from_bytes(&extref_data[..20]).is_err());
+
+        // Colliding links packed into one item
+        let mut data = extref_bytes(300, 7, "one");
+        data.extend(extref_bytes(301, 2, "two"));
+        let refs = InodeExtRef::parse_all(&data).unwrap();
+        assert_eq!(refs.len(), 2);
+        assert_eq!((refs[1].parent, refs[1].name.as_str()), (301, "two"));
+
+        // A truncated last link leaves the ones before it
+        data.truncate(data.len() - 2);
+        assert!(InodeExtRef::parse_all(&data).is_err());
+        let (refs, err) = InodeExtRef::parse_partial(&data);
+        assert_eq!(refs.len(), 1);
+        assert_eq!(refs[0].name, "one");
+        assert!(err.is_some());
+    }
+
     fn create_mock_inline_extent_data(content: &[u8]) -> Vec<u8> {
         let mut data = vec![0u8; 21 + content.len()];
         // generation
@@ -0,0 +1,129 @@
+//! Tree log items
+//!
+//! An fsync writes the changed items of a file or directory to a per
+//! subvolume log tree instead of committing a transaction. For a logged
+//! directory, DIR_LOG_ITEM and DIR_LOG_INDEX items record which ranges of
+//! its DIR_ITEM name hashes and DIR_INDEX indexes the log is authoritative
+//! for: during replay, entries in those ranges that the log doesn't have
+//! were deleted and must be removed from the subvolume.
+
+use super::{item_type, tree::BtrfsKey, BtrfsError, Result};
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Which kind of directory key a logged range covers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirLogKind {
+    /// DIR_ITEM keys, whose offsets are name hashes
+    Item,
+    /// DIR_INDEX keys, whose offsets are directory indexes
+    Index,
+}
+
+impl DirLogKind {
+    /// Returns the type of the directory items the range applies to
+    pub const fn dir_item_type(self) -> u8 {
+        match self {
+            Self::Item => item_type::DIR_ITEM,
+            Self::Index => item_type::DIR_INDEX,
+        }
+    }
+}
+
+/// A range of directory keys covered by the log, from a DIR_LOG_ITEM or
+/// DIR_LOG_INDEX item
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirLogRange {
+    /// Inode number of the logged directory
+    pub dir: u64,
+    /// Kind of directory keys covered
+    pub kind: DirLogKind,
+    /// First key offset covered, from the item's key
+    pub start: u64,
+    /// Last key offset covered, inclusive
+    pub end: u64,
+}
+
+impl DirLogRange {
+    /// Parses a DIR_LOG_ITEM or DIR_LOG_INDEX item
+    pub fn parse(key: &BtrfsKey, data: &[u8]) -> Result<Self> {
+        // Copy packed key fields to avoid unaligned references
+        let (dir, start) = (key.objectid, key.offset);
+        let kind = match key.item_type {
+            item_type::DIR_LOG_ITEM => DirLogKind::Item,
+            item_type::DIR_LOG_INDEX => DirLogKind::Index,
+            other => {
+                return Err(BtrfsError::Corrupt(format!(
+                    "Item type {:#x} is not a directory log item",
+                    other
+                )))
+            }
+        };
+        if data.len() < 8 {
+            return Err(BtrfsError::Corrupt("Dir log item too small".to_string()));
+        }
+
+        let end = LittleEndian::read_u64(&data[0..8]);
+        if end < start {
+            return Err(BtrfsError::Corrupt(format!(
+                "Dir log range of directory {} ends at {} before it starts at {}",
+                dir, end, start
+            )));
+        }
+
+        Ok(Self {
+            dir,
+            kind,
+            start,
+            end,
+        })
+    }
+
+    /// Returns true if the range covers the directory item with `key`
+    pub fn covers(&self, key: &BtrfsKey) -> bool {
+        key.objectid == self.dir
+            && key.item_type == self.kind.dir_item_type()
+            && (self.start..=self.end).contains(&{ key.offset })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dir_log_items() {
+        let key = BtrfsKey::new(257, item_type::DIR_LOG_INDEX, 2);
+        let range = DirLogRange::parse(&key, &10u64.to_le_bytes()).unwrap();
+        assert_eq!(
+            range,
+            DirLogRange {
+                dir: 257,
+                kind: DirLogKind::Index,
+                start: 2,
+                end: 10,
+            }
+        );
+        assert!(range.covers(&BtrfsKey::new(257, item_type::DIR_INDEX, 10)));
+        assert!(!range.covers(&BtrfsKey::new(257, item_type::DIR_INDEX, 11)));
+        assert!(!range.covers(&BtrfsKey::new(257, item_type::DIR_ITEM, 5)));
+        assert!(!range.covers(&BtrfsKey::new(258, item_type::DIR_INDEX, 5)));
+
+        // A whole-directory DIR_LOG_ITEM range over every name hash
+        let key = BtrfsKey::new(257, item_type::DIR_LOG_ITEM, 0);
+        let range = DirLogRange::parse(&key, &u64::MAX.to_le_bytes()).unwrap();
+        assert_eq!(range.kind, DirLogKind::Item);
+        assert!(range.covers(&BtrfsKey::new(257, item_type::DIR_ITEM, 0xDEAD_BEEF)));
+    }
+
+    #[test]
+    fn test_parse_dir_log_rejects_bad_items() {
+        let key = BtrfsKey::new(257, item_type::DIR_LOG_ITEM, 0);
+        assert!(DirLogRange::parse(&key, &[0u8; 4]).is_err());
+
+        let backwards = BtrfsKey::new(257, item_type::DIR_LOG_INDEX, 20);
+        assert!(DirLogRange::parse(&backwards, &10u64.to_le_bytes()).is_err());
+
+        let wrong = BtrfsKey::new(257, item_type::DIR_INDEX, 0);
+        assert!(DirLogRange::parse(&wrong, &10u64.to_le_bytes()).is_err());
+    }
+}
@@ -3,27 +3,51 @@
 //! This module provides a pure Rust implementation of the BTRFS filesystem,
 //! supporting reading and writing of BTRFS volumes.
 
+pub mod cache;
+pub mod check;
 pub mod checksum;
 pub mod chunk;
 pub mod compress;
+pub mod defrag;
+pub mod device;
+pub mod diagnostics;
+pub mod du;
 pub mod extent;
+pub mod file;
+pub mod hash;
 pub mod inode;
+pub mod log;
+pub mod path;
+#[cfg(feature = "raid56")]
+pub mod raid56;
+pub mod reader;
 pub mod subvolume;
+pub mod scrub;
 pub mod superblock;
+pub mod transaction;
 pub mod tree;
+pub mod verify;
 
 use crate::blockdev::BlockDevice;
+use cache::{NodeCache, DEFAULT_NODE_CACHE_SIZE};
 use std::sync::Arc;
 use thiserror::Error;
 
+pub use check::{CheckReport, IntegrityReport};
 pub use checksum::Checksum;
 pub use chunk::ChunkTree;
 pub use compress::CompressionType;
+pub use defrag::FragReport;
+pub use device::DeviceUsage;
+pub use diagnostics::{MountDiagnostics, SuperblockCopy};
+pub use du::DiskUsage;
 pub use extent::ExtentTree;
-pub use inode::{Inode, InodeType};
+pub use inode::{Inode, InodeFlags, InodeType, TimeSpec};
+pub use scrub::{ScrubOptions, ScrubReport};
 pub use subvolume::Subvolume;
 pub use superblock::Superblock;
-pub use tree::{BtrfsKey, BtrfsTree, TreeType};
+pub use tree::{BtrfsKey, BtrfsTree, TreeNode, TreeType};
+pub use verify::FileVerifyReport;
 
 /// BTRFS magic number: "_BHRfS_M"
 pub const BTRFS_MAGIC: [u8; 8] = *b"_BHRfS_M";
@@ -43,6 +67,15 @@ pub const DEFAULT_NODE_SIZE: u32 = 16384;
 /// Default sector size
 pub const DEFAULT_SECTOR_SIZE: u32 = 4096;
 
+/// Smallest sector size a filesystem may use
+pub const MIN_SECTOR_SIZE: u32 = 4096;
+
+/// Largest node size, and so sector size, a filesystem may use
+pub const MAX_NODE_SIZE: u32 = 64 * 1024;
+
+/// Longest name, in bytes, a directory entry or inode reference can have
+pub const MAX_NAME_LEN: usize = 255;
+
 /// Errors that can occur during BTRFS operations
 #[derive(Error, Debug)]
 pub enum BtrfsError {
@@ -56,7 +89,7 @@ pub enum BtrfsError {
     InvalidMagic,
 
     #[error("Checksum mismatch: expected {expected:08x}, got {actual:08x}")]
-    ChecksumMismatch { expected: u32, actual: u32 },
+    ChecksumMismatch { expected: u64, actual: u64 },
 
     #[error("Unsupported BTRFS feature: {0}")]
     UnsupportedFeature(String),
@@ -85,6 +118,9 @@ pub enum BtrfsError {
     #[error("Not a file")]
     NotAFile,
 
+    #[error("Not a symbolic link")]
+    NotASymlink,
+
     #[error("Subvolume not found: {0}")]
     SubvolumeNotFound(u64),
 
@@ -93,10 +129,28 @@ pub enum BtrfsError {
 
     #[error("No space left")]
     NoSpace,
+
+    #[error("Operation cancelled")]
+    Cancelled,
 }
 
 pub type Result<T> = std::result::Result<T, BtrfsError>;
 
+/// A byte range of a logical read or write, with the `(devid, physical)`
+/// address of each copy it is stored at
+type PhysicalPiece = (std::ops::Range<usize>, Vec<(u64, u64)>);
+
+/// Returns the end of the `len` bytes at `start`, failing with
+/// [`BtrfsError::Corrupt`] if they overflow or run past the end of `data`
+///
+/// `what` names the field in the error, e.g. "Dir item name".
+pub(crate) fn field_end(data: &[u8], start: usize, len: usize, what: &str) -> Result<usize> {
+    start
+        .checked_add(len)
+        .filter(|&end| end <= data.len())
+        .ok_or_else(|| BtrfsError::Corrupt(format!("{} truncated", what)))
+}
+
 /// A BTRFS filesystem instance
 pub struct BtrfsFilesystem {
     /// The underlying block device
@@ -108,25 +162,163 @@ pub struct BtrfsFilesystem {
     /// The chunk tree for address translation
     chunk_tree: ChunkTree,
 
+    /// Checksum algorithm for tree nodes and data
+    checksum: Checksum,
+
+    /// Recently read tree nodes
+    node_cache: NodeCache,
+
     /// Whether the filesystem is mounted read-only
     read_only: bool,
+
+    /// How the filesystem was opened
+    diagnostics: MountDiagnostics,
 }
 
 impl BtrfsFilesystem {
     /// Opens a BTRFS filesystem from a block device
     pub fn open(device: Arc<dyn BlockDevice>, read_only: bool) -> Result<Self> {
-        // Read and validate superblock
-        let superblock = Superblock::read(device.as_ref())?;
+        Self::with_cache_size(device, read_only, DEFAULT_NODE_CACHE_SIZE)
+    }
+
+    /// Opens a filesystem spanning the devices or images at `paths`
+    pub fn open_paths(paths: &[&str], read_only: bool) -> Result<Self> {
+        let devices = paths
+            .iter()
+            .map(|path| Ok(Arc::from(crate::blockdev::open(path, read_only)?)))
+            .collect::<Result<Vec<_>>>()?;
+        Self::open_devices(devices, read_only)
+    }
+
+    /// Opens a filesystem spanning several devices, given in any order
+    ///
+    /// Each device's superblock names its devid, and all must carry the
+    /// same fsid. Devices listed in the chunk tree but not given here are
+    /// treated as missing, as with a single device.
+    pub fn open_devices(devices: Vec<Arc<dyn BlockDevice>>, read_only: bool) -> Result<Self> {
+        let mut fsid = None;
+        let mut members = Vec::with_capacity(devices.len());
+
+        for device in devices {
+            let (superblock, _) = Superblock::read_with_mirrors(device.as_ref())?;
+            let expected = *fsid.get_or_insert(superblock.fsid());
+            if superblock.fsid() != expected {
+                return Err(BtrfsError::Io(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "device {} belongs to filesystem {}, not {}",
+                        superblock.devid(),
+                        superblock.fsid(),
+                        expected
+                    ),
+                )));
+            }
+            members.push((superblock.devid(), device));
+        }
+
+        let device = crate::blockdev::MultiDevice::new(members)?;
+        Self::open(Arc::new(device), read_only)
+    }
+
+    /// Opens a BTRFS filesystem, caching up to `cache_size` tree nodes
+    ///
+    /// A `cache_size` of zero reads every node from the device.
+    pub fn with_cache_size(
+        device: Arc<dyn BlockDevice>,
+        read_only: bool,
+        cache_size: usize,
+    ) -> Result<Self> {
+        // Read and validate superblock, falling back to a mirror
+        let (superblock, offset) = Superblock::read_with_mirrors(device.as_ref())?;
+        if offset != SUPERBLOCK_OFFSET {
+            tracing::warn!("Primary superblock invalid, using mirror at {:#x}", offset);
+        }
+        let checksum = superblock.checksum_type()?;
 
         // Initialize chunk tree from superblock's bootstrap chunks
         let chunk_tree = ChunkTree::from_superblock(&superblock, device.clone())?;
 
-        Ok(Self {
+        let diagnostics = MountDiagnostics {
+            superblock_copy: SuperblockCopy::from_offset(offset).unwrap_or_default(),
+            unreplayed_log: superblock.log_root() != 0,
+            // Counted once the whole chunk tree is known
+            missing_devices: 0,
+            truncated: false,
+        };
+
+        let mut fs = Self {
             device,
             superblock,
             chunk_tree,
+            checksum,
+            node_cache: NodeCache::new(cache_size),
             read_only,
-        })
+            diagnostics,
+        };
+
+        // A truncated image still mounts; reads past its end fail later
+        if let Err(e) = fs.check_device_size() {
+            tracing::warn!("{}", e);
+            fs.diagnostics.truncated = true;
+        }
+
+        // The bootstrap chunks cover the chunk tree; the rest are in it
+        fs.load_chunk_tree()?;
+
+        let missing = fs.chunk_tree.missing_devices();
+        if !missing.is_empty() {
+            tracing::warn!(
+                "Devices {:?} were not opened; their stripes are unreadable",
+                missing
+            );
+        }
+        fs.diagnostics.missing_devices = missing.len() as u64;
+
+        Ok(fs)
+    }
+
+    /// Adds every CHUNK_ITEM in the on-disk chunk tree to the chunk mappings
+    fn load_chunk_tree(&mut self) -> Result<()> {
+        let min_key = BtrfsKey::new(objectid::FIRST_CHUNK_TREE, item_type::CHUNK_ITEM, 0);
+        let max_key = BtrfsKey::new(objectid::FIRST_CHUNK_TREE, item_type::CHUNK_ITEM, u64::MAX);
+
+        let chunks = self
+            .tree(objectid::CHUNK_TREE)?
+            .search_range(&min_key, &max_key)?
+            .into_iter()
+            .map(|(item, data)| ChunkTree::parse_chunk_item(&data, item.key.offset))
+            .collect::<Result<Vec<_>>>()?;
+
+        for chunk in chunks {
+            self.chunk_tree.add_chunk(chunk);
+        }
+        Ok(())
+    }
+
+    /// Checks that the device is as large as the filesystem claims
+    ///
+    /// A single-device filesystem must fit its `total_bytes`; a member of a
+    /// multi-device one must fit the size in its own device item.
+    pub fn check_device_size(&self) -> Result<()> {
+        let expected = if self.superblock.num_devices() > 1 {
+            self.superblock.dev_total_bytes()
+        } else {
+            self.superblock.total_bytes()
+        };
+        let actual = self.device.size();
+
+        if actual < expected {
+            return Err(BtrfsError::Corrupt(format!(
+                "device is {} bytes but the filesystem expects {}; the image may be truncated",
+                actual, expected
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns how the filesystem was opened
+    pub fn mount_diagnostics(&self) -> MountDiagnostics {
+        self.diagnostics
     }
 
     /// Returns the superblock
@@ -139,6 +331,11 @@ impl BtrfsFilesystem {
         &self.chunk_tree
     }
 
+    /// Returns the checksum algorithm the filesystem was created with
+    pub fn checksum(&self) -> Checksum {
+        self.checksum
+    }
+
     /// Returns the underlying block device
     pub fn device(&self) -> &Arc<dyn BlockDevice> {
         &self.device
@@ -149,11 +346,16 @@ impl BtrfsFilesystem {
         self.superblock.fsid()
     }
 
-    /// Returns the filesystem label
-    pub fn label(&self) -> &str {
+    /// Returns the filesystem label, replacing invalid UTF-8 sequences
+    pub fn label(&self) -> std::borrow::Cow<'_, str> {
         self.superblock.label()
     }
 
+    /// Returns the raw label bytes, for labels that are not valid UTF-8
+    pub fn label_bytes(&self) -> &[u8] {
+        self.superblock.label_bytes()
+    }
+
     /// Returns the total size of the filesystem in bytes
     pub fn total_bytes(&self) -> u64 {
         self.superblock.total_bytes()
@@ -174,34 +376,309 @@ impl BtrfsFilesystem {
         self.superblock.node_size()
     }
 
-    /// Translates a logical address to physical address(es)
+    /// Returns the generation of the loaded superblock
+    pub fn generation(&self) -> u64 {
+        self.superblock.generation()
+    }
+
+    /// Re-reads the superblock from the device
+    ///
+    /// Returns true if the on-disk generation advanced since the superblock
+    /// was last loaded, meaning cached read-only views may be stale. The
+    /// chunk mappings are then reloaded, as the new transaction may have
+    /// allocated, relocated or removed chunks.
+    pub fn refresh_superblock(&mut self) -> Result<bool> {
+        let superblock = Superblock::read(self.device.as_ref())?;
+        let advanced = superblock.generation() > self.superblock.generation();
+        self.superblock = superblock;
+        if advanced {
+            // Freed nodes may have been reused for new ones
+            self.node_cache.clear();
+            self.chunk_tree = ChunkTree::from_superblock(&self.superblock, self.device.clone())?;
+            self.load_chunk_tree()?;
+            self.diagnostics.missing_devices = self.chunk_tree.missing_devices().len() as u64;
+        }
+        Ok(advanced)
+    }
+
+    /// Translates a logical address to physical address(es) on the opened
+    /// device
     pub fn logical_to_physical(&self, logical: u64) -> Result<Vec<u64>> {
         self.chunk_tree.logical_to_physical(logical)
     }
 
+    /// Translates a logical address to `(devid, physical)` pairs, one per
+    /// readable copy
+    pub fn logical_to_physical_mapped(&self, logical: u64) -> Result<Vec<(u64, u64)>> {
+        self.chunk_tree.logical_to_physical_mapped(logical)
+    }
+
     /// Reads data from a logical address
     pub fn read_logical(&self, logical: u64, buf: &mut [u8]) -> Result<usize> {
-        let physical_addrs = self.logical_to_physical(logical)?;
+        #[cfg(feature = "raid56")]
+        if self.is_raid56(logical) {
+            self.chunk_tree.read_raid56(logical, buf, false)?;
+            return Ok(buf.len());
+        }
+
+        // Any copy will do; read_logical_verified picks a good one
+        let pieces = self.physical_pieces(logical, buf.len())?;
+        self.read_pieces(&pieces, 0, buf)
+    }
+
+    /// Writes data to every copy of a logical address
+    ///
+    /// RAID5/6 chunks would need their parity recomputed, so writing to
+    /// them is refused.
+    pub fn write_logical(&self, logical: u64, buf: &[u8]) -> Result<()> {
+        if self.read_only {
+            return Err(BtrfsError::ReadOnly);
+        }
+        if self
+            .chunk_tree
+            .chunk_at(logical)
+            .is_some_and(|chunk| chunk.is_raid56())
+        {
+            return Err(BtrfsError::UnsupportedFeature(
+                "writing to RAID5/6 chunks".to_string(),
+            ));
+        }
+
+        for (range, copies) in self.physical_pieces(logical, buf.len())? {
+            for (devid, physical) in copies {
+                let written = self
+                    .chunk_tree
+                    .write_device(devid, physical, &buf[range.clone()])?;
+                if written != range.len() {
+                    return Err(BtrfsError::Io(std::io::Error::new(
+                        std::io::ErrorKind::WriteZero,
+                        format!("short write of {:#x} on device {}", logical, devid),
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads data from a logical address, returning the first copy that
+    /// passes `verify`
+    ///
+    /// Each mirror of a DUP, RAID1, RAID1C3, RAID1C4 or RAID10 chunk is
+    /// tried in turn, and RAID5/6 data is rebuilt from parity. If no copy can
+    /// be read and verified, the last error is returned, so a checksum
+    /// failure on every mirror surfaces as that failure.
+    pub fn read_logical_verified<F>(&self, logical: u64, buf: &mut [u8], verify: F) -> Result<usize>
+    where
+        F: Fn(&[u8]) -> Result<()>,
+    {
+        #[cfg(feature = "raid56")]
+        if self.is_raid56(logical) {
+            self.read_logical(logical, buf)?;
+            let Err(e) = verify(buf) else {
+                return Ok(buf.len());
+            };
+
+            let mut rebuilt = vec![0u8; buf.len()];
+            if self.rebuild_logical(logical, &mut rebuilt).is_ok() && verify(&rebuilt).is_ok() {
+                buf.copy_from_slice(&rebuilt);
+                return Ok(buf.len());
+            }
+            return Err(e);
+        }
+
+        let pieces = self.physical_pieces(logical, buf.len())?;
+        let mirrors = pieces
+            .iter()
+            .map(|(_, copies)| copies.len())
+            .max()
+            .unwrap_or(1);
+
+        let mut last_err = None;
+        for mirror in 0..mirrors {
+            let read = self.read_pieces(&pieces, mirror, buf);
+            match read.and_then(|n| verify(buf).map(|()| n)) {
+                Ok(n) => {
+                    if mirror > 0 {
+                        tracing::warn!(
+                            "Read {:#x} from mirror {} after bad copies",
+                            logical,
+                            mirror
+                        );
+                    }
+                    return Ok(n);
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
 
-        if physical_addrs.is_empty() {
-            return Err(BtrfsError::NotFound(format!(
+        Err(last_err.unwrap_or_else(|| {
+            BtrfsError::NotFound(format!(
                 "No physical mapping for logical address {}",
                 logical
-            )));
+            ))
+        }))
+    }
+
+    /// Splits `len` bytes at `logical` into pieces stored contiguously on
+    /// every device, each with its `(devid, physical)` copies
+    ///
+    /// A piece ends where a striped chunk moves on to the next stripe
+    /// element, or where the chunk ends.
+    fn physical_pieces(&self, logical: u64, len: usize) -> Result<Vec<PhysicalPiece>> {
+        let mut pieces = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let start = logical + done as u64;
+            let run = self.chunk_tree.contiguous_run(start)?;
+            let end = done + run.min((len - done) as u64) as usize;
+
+            let copies = self.chunk_tree.logical_to_physical_mapped(start)?;
+            if copies.is_empty() {
+                return Err(BtrfsError::NotFound(format!(
+                    "No physical mapping for logical address {}",
+                    start
+                )));
+            }
+            pieces.push((done..end, copies));
+            done = end;
         }
+        Ok(pieces)
+    }
 
-        // Read from the first physical address (TODO: handle RAID)
-        let physical = physical_addrs[0];
-        Ok(self.device.read_at(physical, buf)?)
+    /// Reads every piece from copy `mirror` into its range of `buf`,
+    /// stopping at the first short read
+    ///
+    /// Pieces with fewer copies use their last one.
+    fn read_pieces(
+        &self,
+        pieces: &[PhysicalPiece],
+        mirror: usize,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let mut read = 0;
+        for (range, copies) in pieces {
+            let (devid, physical) = copies[mirror.min(copies.len() - 1)];
+            let n = self
+                .chunk_tree
+                .read_device(devid, physical, &mut buf[range.clone()])?;
+            read += n;
+            if n < range.len() {
+                break;
+            }
+        }
+        Ok(read)
     }
 
-    /// Reads a tree node from a logical address
+    /// Reads a tree node from a logical address, through the node cache
     pub fn read_node(&self, logical: u64) -> Result<Vec<u8>> {
+        if let Some(buf) = self.node_cache.get(logical) {
+            return Ok(buf);
+        }
+
+        let buf = self.read_node_uncached(logical)?;
+        self.node_cache.insert(logical, buf.clone());
+        Ok(buf)
+    }
+
+    /// Reads and parses the tree node at `logical`, through the node cache
+    pub fn node_at(&self, logical: u64) -> Result<TreeNode> {
+        TreeNode::parse(self.read_node(logical)?, self.checksum)
+    }
+
+    /// Reads a tree node from the device, bypassing the node cache
+    ///
+    /// Mirrors are tried until one passes its node checksum.
+    pub fn read_node_uncached(&self, logical: u64) -> Result<Vec<u8>> {
         let mut buf = vec![0u8; self.node_size() as usize];
-        self.read_logical(logical, &mut buf)?;
+        self.read_logical_verified(logical, &mut buf, |node| {
+            checksum::verify_node_checksum(node, self.checksum)
+        })?;
         Ok(buf)
     }
 
+    /// Returns true if `logical` is in a RAID5/6 chunk
+    #[cfg(feature = "raid56")]
+    pub fn is_raid56(&self, logical: u64) -> bool {
+        self.chunk_tree
+            .chunk_at(logical)
+            .is_some_and(|chunk| chunk.is_raid56())
+    }
+
+    /// Reads `buf.len()` bytes at `logical` by reconstructing them from the
+    /// parity of their RAID5/6 chunk, ignoring the data stripes' contents
+    #[cfg(feature = "raid56")]
+    pub fn rebuild_logical(&self, logical: u64, buf: &mut [u8]) -> Result<()> {
+        self.chunk_tree.read_raid56(logical, buf, true)
+    }
+
+    /// Returns the tree node cache
+    pub fn node_cache(&self) -> &NodeCache {
+        &self.node_cache
+    }
+
+    /// Opens a tree by object ID, resolving its root through the root tree
+    pub fn tree(&self, tree_id: u64) -> Result<BtrfsTree<'_>> {
+        let sb = &self.superblock;
+        match tree_id {
+            objectid::ROOT_TREE => Ok(BtrfsTree::new(self, sb.root(), sb.root_level())),
+            objectid::CHUNK_TREE => Ok(BtrfsTree::new(
+                self,
+                sb.chunk_root(),
+                sb.chunk_root_level(),
+            )),
+            _ => {
+                let root = subvolume::read_root_item(self, tree_id)?;
+                Ok(BtrfsTree::new(self, root.bytenr, root.level))
+            }
+        }
+    }
+
+    /// Returns the raw data of the item with exactly `key` in `tree`
+    ///
+    /// `tree` is either a [`TreeType`] or a tree object ID.
+    pub fn get_item(&self, tree: impl Into<u64>, key: BtrfsKey) -> Result<Option<Vec<u8>>> {
+        let tree = self.tree(tree.into())?;
+        Ok(tree.search(&key)?.map(|(_, data)| data))
+    }
+
+    /// Returns when the filesystem was created
+    ///
+    /// The superblock has no creation time, so this is the otime of the FS
+    /// tree's root directory, which mkfs creates.
+    pub fn created_at(&self) -> Result<TimeSpec> {
+        let key = BtrfsKey::new(objectid::FIRST_FREE, item_type::INODE_ITEM, 0);
+        match self.tree(objectid::FS_TREE)?.search(&key)? {
+            Some((_, data)) => Ok(Inode::from_bytes(objectid::FIRST_FREE, &data)?.otime),
+            None => Err(BtrfsError::InvalidInode(objectid::FIRST_FREE)),
+        }
+    }
+
+    /// Returns the lowest unused objectid above every inode in tree `tree_id`
+    ///
+    /// Walks the tree backward from its last item, skipping the reserved
+    /// objectids above [`objectid::LAST_FREE`] such as the orphan item.
+    pub fn next_free_objectid(&self, tree_id: u64) -> Result<u64> {
+        let tree = self.tree(tree_id)?;
+        for result in tree.iter_rev() {
+            let (item, _) = result?;
+            let id = item.key.objectid;
+            if id <= objectid::LAST_FREE {
+                return Ok((id + 1).max(objectid::FIRST_FREE));
+            }
+        }
+        Ok(objectid::FIRST_FREE)
+    }
+
+    /// Snapshots subvolume `source_id` as `name`, next to the source
+    pub fn create_snapshot(
+        &mut self,
+        source_id: u64,
+        name: &str,
+        readonly: bool,
+    ) -> Result<Subvolume> {
+        subvolume::create_snapshot(self, source_id, name, readonly)
+    }
+
     /// Lists all subvolumes in the filesystem
     pub fn list_subvolumes(&self) -> Result<Vec<Subvolume>> {
         subvolume::list_subvolumes(self)
@@ -212,10 +689,72 @@ impl BtrfsFilesystem {
         subvolume::get_subvolume(self, id)
     }
 
+    /// Lists the snapshots taken of a subvolume
+    pub fn snapshots_of(&self, subvol_id: u64) -> Result<Vec<Subvolume>> {
+        subvolume::snapshots_of(self, subvol_id)
+    }
+
+    /// Translates a path inside a subvolume to a top-level path
+    ///
+    /// For a subvolume at `@/var`, `log/messages` becomes `/@/var/log/messages`.
+    pub fn subvolume_relative_to_absolute(&self, subvol_id: u64, rel_path: &str) -> Result<String> {
+        subvolume::relative_to_absolute(self, subvol_id, rel_path)
+    }
+
+    /// Reports how fragmented the file at `path` in tree `tree_id` is on disk
+    pub fn fragmentation_report(&self, tree_id: u64, path: &str) -> Result<FragReport> {
+        defrag::fragmentation_report(self, tree_id, path)
+    }
+
+    /// Reports the disk usage of the file or directory at `path` in tree
+    /// `tree_id`, counting shared extents once
+    pub fn disk_usage(&self, tree_id: u64, path: &str) -> Result<DiskUsage> {
+        du::disk_usage(self, tree_id, path)
+    }
+
+    /// Returns the bytes only the file or directory at `path` in tree
+    /// `tree_id` references, i.e. what deleting it would free
+    pub fn exclusive_size(&self, tree_id: u64, path: &str) -> Result<u64> {
+        Ok(self.disk_usage(tree_id, path)?.exclusive)
+    }
+
+    /// Reports the size, allocation and unallocated space of every device
+    pub fn device_usage(&self) -> Result<Vec<DeviceUsage>> {
+        device::device_usage(self)
+    }
+
+    /// Checks the directories of tree `tree_id` for inconsistent entries
+    /// and its files for data extents the extent tree does not account for
+    pub fn check(&self, tree_id: u64) -> Result<CheckReport> {
+        check::check(self, tree_id)
+    }
+
+    /// Checks the structure of every tree: node checksums, levels and key
+    /// order, inodes missing their INODE_ITEM, and file extents outside
+    /// every chunk
+    pub fn check_integrity(&self) -> IntegrityReport {
+        check::check_integrity(self)
+    }
+
+    /// Verifies tree nodes and/or data blocks against their checksums
+    pub fn scrub(&self, options: ScrubOptions) -> Result<ScrubReport> {
+        scrub::scrub(self, options)
+    }
+
+    /// Computes the SHA-256 content hash of the file or directory at `path`
+    /// in tree `tree_id`, for comparing trees across volumes
+    pub fn content_hash(&self, tree_id: u64, path: &str) -> Result<[u8; 32]> {
+        hash::content_hash(self, tree_id, path)
+    }
+
+    /// Checks the data of the file at `path` in tree `tree_id` against the csum tree
+    pub fn verify_file(&self, tree_id: u64, path: &str) -> Result<FileVerifyReport> {
+        verify::verify_file(self, tree_id, path)
+    }
+
     /// Gets the default subvolume
     pub fn default_subvolume(&self) -> Result<Subvolume> {
-        let default_id = self.superblock.root_dir_objectid();
-        self.get_subvolume(default_id)
+        self.get_subvolume(subvolume::default_subvolume_id(self)?)
     }
 }
 
@@ -223,6 +762,8 @@ impl BtrfsFilesystem {
 pub mod objectid {
     /// Root tree object ID
     pub const ROOT_TREE: u64 = 1;
+    /// Object ID of DEV_ITEMs in the chunk tree
+    pub const DEV_ITEMS: u64 = 1;
     /// Extent tree object ID
     pub const EXTENT_TREE: u64 = 2;
     /// Chunk tree object ID
@@ -241,10 +782,16 @@ pub mod objectid {
     pub const UUID_TREE: u64 = 9;
     /// Free space tree object ID
     pub const FREE_SPACE_TREE: u64 = 10;
+    /// Block group tree object ID
+    pub const BLOCK_GROUP_TREE: u64 = 11;
     /// First free object ID for subvolumes
     pub const FIRST_FREE: u64 = 256;
+    /// Object ID of CHUNK_ITEMs in the chunk tree
+    pub const FIRST_CHUNK_TREE: u64 = 256;
     /// Last free object ID
     pub const LAST_FREE: u64 = u64::MAX - 256;
+    /// Object ID of EXTENT_CSUM items in the checksum tree (-10)
+    pub const EXTENT_CSUM: u64 = u64::MAX - 9;
 }
 
 /// Item types in BTRFS trees
@@ -270,10 +817,48 @@ pub mod item_type {
     pub const SHARED_BLOCK_REF: u8 = 0xB6;
     pub const SHARED_DATA_REF: u8 = 0xB8;
     pub const BLOCK_GROUP_ITEM: u8 = 0xC0;
+    pub const FREE_SPACE_INFO: u8 = 0xC6;
+    pub const FREE_SPACE_EXTENT: u8 = 0xC7;
+    pub const FREE_SPACE_BITMAP: u8 = 0xC8;
     pub const DEV_EXTENT: u8 = 0xCC;
     pub const DEV_ITEM: u8 = 0xD8;
     pub const CHUNK_ITEM: u8 = 0xE4;
     pub const STRING_ITEM: u8 = 0xFD;
+
+    /// Returns the name of an item type, if known
+    pub fn name(item_type: u8) -> Option<&'static str> {
+        Some(match item_type {
+            INODE_ITEM => "INODE_ITEM",
+            INODE_REF => "INODE_REF",
+            INODE_EXTREF => "INODE_EXTREF",
+            XATTR_ITEM => "XATTR_ITEM",
+            ORPHAN_ITEM => "ORPHAN_ITEM",
+            DIR_LOG_ITEM => "DIR_LOG_ITEM",
+            DIR_LOG_INDEX => "DIR_LOG_INDEX",
+            DIR_ITEM => "DIR_ITEM",
+            DIR_INDEX => "DIR_INDEX",
+            EXTENT_DATA => "EXTENT_DATA",
+            EXTENT_CSUM => "EXTENT_CSUM",
+            ROOT_ITEM => "ROOT_ITEM",
+            ROOT_BACKREF => "ROOT_BACKREF",
+            ROOT_REF => "ROOT_REF",
+            EXTENT_ITEM => "EXTENT_ITEM",
+            METADATA_ITEM => "METADATA_ITEM",
+            TREE_BLOCK_REF => "TREE_BLOCK_REF",
+            EXTENT_DATA_REF => "EXTENT_DATA_REF",
+            SHARED_BLOCK_REF => "SHARED_BLOCK_REF",
+            SHARED_DATA_REF => "SHARED_DATA_REF",
+            BLOCK_GROUP_ITEM => "BLOCK_GROUP_ITEM",
+            FREE_SPACE_INFO => "FREE_SPACE_INFO",
+            FREE_SPACE_EXTENT => "FREE_SPACE_EXTENT",
+            FREE_SPACE_BITMAP => "FREE_SPACE_BITMAP",
+            DEV_EXTENT => "DEV_EXTENT",
+            DEV_ITEM => "DEV_ITEM",
+            CHUNK_ITEM => "CHUNK_ITEM",
+            STRING_ITEM => "STRING_ITEM",
+            _ => return None,
+        })
+    }
 }
 
 #[cfg(test)]
@@ -304,6 +889,7 @@ mod tests {
         assert_eq!(objectid::FREE_SPACE_TREE, 10);
         assert_eq!(objectid::FIRST_FREE, 256);
         assert_eq!(objectid::LAST_FREE, u64::MAX - 256);
+        assert_eq!(objectid::EXTENT_CSUM, -10i64 as u64);
     }
 
     #[test]
@@ -329,12 +915,23 @@ mod tests {
         assert_eq!(item_type::SHARED_BLOCK_REF, 0xB6);
         assert_eq!(item_type::SHARED_DATA_REF, 0xB8);
         assert_eq!(item_type::BLOCK_GROUP_ITEM, 0xC0);
+        assert_eq!(item_type::FREE_SPACE_INFO, 0xC6);
+        assert_eq!(item_type::FREE_SPACE_EXTENT, 0xC7);
+        assert_eq!(item_type::FREE_SPACE_BITMAP, 0xC8);
         assert_eq!(item_type::DEV_EXTENT, 0xCC);
         assert_eq!(item_type::DEV_ITEM, 0xD8);
         assert_eq!(item_type::CHUNK_ITEM, 0xE4);
         assert_eq!(item_type::STRING_ITEM, 0xFD);
     }
 
+    #[test]
+    fn test_item_type_names() {
+        assert_eq!(item_type::name(item_type::INODE_ITEM), Some("INODE_ITEM"));
+        assert_eq!(item_type::name(item_type::EXTENT_DATA), Some("EXTENT_DATA"));
+        assert_eq!(item_type::name(item_type::CHUNK_ITEM), Some("CHUNK_ITEM"));
+        assert_eq!(item_type::name(0x02), None);
+    }
+
     #[test]
     fn test_btrfs_error_display() {
         let err = BtrfsError::InvalidMagic;
@@ -383,6 +980,9 @@ mod tests {
 
         let err = BtrfsError::NoSpace;
         assert!(format!("{}", err).contains("space"));
+
+        let err = BtrfsError::Cancelled;
+        assert!(format!("{}", err).contains("cancelled"));
     }
 
     #[test]
@@ -395,6 +995,265 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_generation() {
+        let mut builder = crate::test_utils::ImageBuilder::new();
+        builder.generation(42);
+        let fs = builder.open();
+        assert_eq!(fs.generation(), 42);
+        assert_eq!(fs.generation(), fs.superblock().generation());
+    }
+
+    #[test]
+    fn test_refresh_superblock() {
+        let mut builder = crate::test_utils::ImageBuilder::new();
+        builder.generation(10);
+        let device = builder.device();
+        let mut fs = BtrfsFilesystem::open(device.clone(), true).unwrap();
+
+        // Nothing changed on disk
+        assert!(!fs.refresh_superblock().unwrap());
+        assert_eq!(fs.generation(), 10);
+
+        // Simulate a writer committing a new transaction
+        builder.generation(11);
+        device
+            .write_at(SUPERBLOCK_OFFSET, &builder.superblock_bytes())
+            .unwrap();
+
+        assert!(fs.refresh_superblock().unwrap());
+        assert_eq!(fs.generation(), 11);
+
+        // Already up to date
+        assert!(!fs.refresh_superblock().unwrap());
+
+        // A transaction that allocates a new chunk
+        let logical = 0x10000000;
+        builder.generation(12).chunk(logical, 0x10000, 0x300000);
+        device.write_at(0, &builder.build()).unwrap();
+        let mut buf = [0u8; 16];
+        assert!(fs.read_logical(logical, &mut buf).is_err());
+
+        assert!(fs.refresh_superblock().unwrap());
+        assert_eq!(fs.read_logical(logical, &mut buf).unwrap(), buf.len());
+    }
+
+    #[test]
+    fn test_created_at() {
+        use crate::test_utils::{inode_item, ImageBuilder, S_IFDIR};
+
+        let mut root_inode = inode_item(S_IFDIR | 0o755, 0);
+        root_inode[148..156].copy_from_slice(&1_600_000_000i64.to_le_bytes());
+        root_inode[156..160].copy_from_slice(&250u32.to_le_bytes());
+
+        let mut builder = ImageBuilder::new();
+        builder.root_dir(objectid::FS_TREE).insert(
+            objectid::FS_TREE,
+            BtrfsKey::new(objectid::FIRST_FREE, item_type::INODE_ITEM, 0),
+            root_inode,
+        );
+
+        let created = builder.open().created_at().unwrap();
+        assert_eq!(created.sec, 1_600_000_000);
+        assert_eq!(created.nsec, 250);
+
+        // No FS tree at all
+        assert!(ImageBuilder::new().open().created_at().is_err());
+    }
+
+    #[test]
+    fn test_next_free_objectid() {
+        use crate::test_utils::{inode_item, ImageBuilder, S_IFREG};
+
+        let mut builder = ImageBuilder::new();
+        builder.root_dir(objectid::FS_TREE);
+        let fs = builder.open();
+        assert_eq!(fs.next_free_objectid(objectid::FS_TREE).unwrap(), 257);
+
+        // The orphan item (-5) sits above every inode and is skipped
+        builder
+            .insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(300, item_type::INODE_ITEM, 0),
+                inode_item(S_IFREG | 0o644, 0),
+            )
+            .insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(u64::MAX - 4, item_type::ORPHAN_ITEM, 300),
+                Vec::new(),
+            );
+        let fs = builder.open();
+        assert_eq!(fs.next_free_objectid(objectid::FS_TREE).unwrap(), 301);
+    }
+
+    #[test]
+    fn test_open_reads_chunk_tree() {
+        use crate::test_utils::ImageBuilder;
+
+        // Beyond the 4 MiB bootstrap chunk, mapped back into the device
+        let mut builder = ImageBuilder::new();
+        builder
+            .chunk(0x10000000, 0x100000, 0x300000)
+            .data(0x300000, b"chunk tree data");
+
+        let fs = builder.open();
+        assert_eq!(fs.chunk_tree().chunks().len(), 2);
+        assert_eq!(fs.logical_to_physical(0x10000010).unwrap(), vec![0x300010]);
+
+        let mut buf = [0u8; 15];
+        fs.read_logical(0x10000000, &mut buf).unwrap();
+        assert_eq!(&buf, b"chunk tree data");
+    }
+
+    #[test]
+    fn test_node_cache() {
+        use crate::test_utils::ImageBuilder;
+
+        let mut builder = ImageBuilder::new();
+        builder.root_dir(objectid::FS_TREE);
+        let key = BtrfsKey::new(objectid::FIRST_FREE, item_type::INODE_ITEM, 0);
+
+        let fs = BtrfsFilesystem::with_cache_size(builder.device(), true, 16).unwrap();
+        assert_eq!(fs.node_cache().capacity(), 16);
+        assert!(fs.get_item(TreeType::Fs, key).unwrap().is_some());
+        let fs_root = subvolume::read_root_item(&fs, objectid::FS_TREE)
+            .unwrap()
+            .bytenr;
+
+        // Later lookups are served from memory, even if the device changes
+        fs.device().write_at(fs_root, &[0u8; 64]).unwrap();
+        assert!(fs.get_item(TreeType::Fs, key).unwrap().is_some());
+        assert!(fs.read_node(fs_root).is_ok());
+        assert!(fs.read_node_uncached(fs_root).is_err());
+
+        // Without a cache every read goes to the device
+        let uncached = BtrfsFilesystem::with_cache_size(fs.device().clone(), true, 0).unwrap();
+        assert!(uncached.node_cache().is_empty());
+        assert!(uncached.get_item(TreeType::Fs, key).is_err());
+    }
+
+    #[test]
+    fn test_node_at() {
+        use crate::test_utils::ImageBuilder;
+
+        let mut builder = ImageBuilder::new();
+        builder.root_dir(objectid::FS_TREE);
+        let fs = builder.open();
+
+        let fs_root = subvolume::read_root_item(&fs, objectid::FS_TREE)
+            .unwrap()
+            .bytenr;
+        let node = fs.node_at(fs_root).unwrap();
+        assert_eq!({ node.header.bytenr }, fs_root);
+        assert_eq!({ node.header.owner }, objectid::FS_TREE);
+        assert!(node.is_leaf());
+        assert!(node.item_count() > 0);
+
+        // Addresses outside every chunk have no node
+        assert!(fs.node_at(0x40000000).is_err());
+    }
+
+    #[test]
+    fn test_check_device_size() {
+        use crate::test_utils::{ImageBuilder, MemDevice, TEST_IMAGE_SIZE};
+
+        let mut builder = ImageBuilder::new();
+        builder.root_dir(objectid::FS_TREE);
+        assert!(builder.open().check_device_size().is_ok());
+
+        // An incomplete copy still opens, but fails the check
+        let mut image = vec![0u8; TEST_IMAGE_SIZE as usize];
+        builder.device().read_at(0, &mut image).unwrap();
+        image.truncate(3 * 1024 * 1024);
+        let fs = BtrfsFilesystem::open(Arc::new(MemDevice::new(image)), true).unwrap();
+
+        match fs.check_device_size() {
+            Err(BtrfsError::Corrupt(msg)) => assert!(msg.contains("truncated")),
+            other => panic!("expected a truncated image, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_open_devices() {
+        use crate::test_utils::{striped_chunk_item, ImageBuilder, MemDevice, TEST_FSID};
+
+        // A chunk living entirely on device 2
+        let mut chunk = striped_chunk_item(0x10000, &[0x200000], 4096, chunk::chunk_type::DATA);
+        chunk[0x30..0x38].copy_from_slice(&2u64.to_le_bytes());
+        let logical = 0x10000000;
+        let key = BtrfsKey::new(objectid::FIRST_CHUNK_TREE, item_type::CHUNK_ITEM, logical);
+        let mut builder = ImageBuilder::new();
+        builder
+            .root_dir(objectid::FS_TREE)
+            .insert(objectid::CHUNK_TREE, key, chunk);
+
+        let second = MemDevice::new(builder.member_image(2, TEST_FSID));
+        second.write_at(0x200000, b"on device two").unwrap();
+        let second: Arc<dyn BlockDevice> = Arc::new(second);
+
+        // Devices may be given in any order
+        let fs = BtrfsFilesystem::open_devices(vec![second, builder.device()], true).unwrap();
+        assert_eq!(fs.mount_diagnostics().missing_devices, 0);
+        let mut buf = [0u8; 13];
+        fs.read_logical(logical, &mut buf).unwrap();
+        assert_eq!(&buf, b"on device two");
+        let root = BtrfsKey::new(objectid::FIRST_FREE, item_type::INODE_ITEM, 0);
+        assert!(fs.get_item(TreeType::Fs, root).unwrap().is_some());
+
+        // Without device 2 the chunk is unreadable, even though the
+        // superblock counts only one device
+        let fs = BtrfsFilesystem::open(builder.device(), true).unwrap();
+        assert_eq!(fs.mount_diagnostics().missing_devices, 1);
+        assert!(fs.read_logical(logical, &mut buf).is_err());
+
+        // A device of another filesystem is refused
+        let stranger = Arc::new(MemDevice::new(builder.member_image(2, [0x99; 16])));
+        let result = BtrfsFilesystem::open_devices(vec![builder.device(), stranger], true);
+        assert!(matches!(result, Err(BtrfsError::Io(_))));
+    }
+
+    #[test]
+    fn test_get_item_by_key() {
+        use crate::test_utils::ImageBuilder;
+
+        let mut builder = ImageBuilder::new();
+        builder.root_dir(objectid::FS_TREE);
+        let fs = builder.open();
+
+        let key = BtrfsKey::new(objectid::FS_TREE, item_type::ROOT_ITEM, 0);
+        let data = fs.get_item(TreeType::Root, key).unwrap().unwrap();
+        let root = subvolume::RootItem::from_bytes(&data).unwrap();
+        let expected = subvolume::read_root_item(&fs, objectid::FS_TREE).unwrap();
+        assert_eq!(root.bytenr, expected.bytenr);
+
+        // Object IDs work as well as tree types
+        assert_eq!(fs.get_item(objectid::ROOT_TREE, key).unwrap(), Some(data));
+
+        let inode_key = BtrfsKey::new(objectid::FIRST_FREE, item_type::INODE_ITEM, 0);
+        assert!(fs.get_item(TreeType::Fs, inode_key).unwrap().is_some());
+        let missing = BtrfsKey::new(999, item_type::ROOT_ITEM, 0);
+        assert!(fs.get_item(TreeType::Root, missing).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_open_xxhash64_filesystem() {
+        use crate::test_utils::ImageBuilder;
+
+        let mut builder = ImageBuilder::new();
+        builder
+            .checksum(Checksum::XxHash64)
+            .root_dir(objectid::FS_TREE);
+
+        let fs = builder.open();
+        assert_eq!(fs.checksum(), Checksum::XxHash64);
+        assert!(fs
+            .tree(objectid::FS_TREE)
+            .unwrap()
+            .search(&BtrfsKey::new(objectid::FIRST_FREE, item_type::INODE_ITEM, 0))
+            .unwrap()
+            .is_some());
+    }
+
     #[test]
     fn test_btrfs_error_from_block_device() {
         let bd_err = crate::blockdev::BlockDeviceError::NotFound("test".to_string());
@@ -0,0 +1,556 @@
+//! Inode, directory and path lookups
+//!
+//! Reads inodes and directory entries from a filesystem tree and resolves
+//! paths to them, optionally ignoring case and caching the results. The
+//! mount, the C API and the core reports that take paths all go through
+//! these.
+
+use super::{
+    inode::{btrfs_name_hash, DirEntry, Inode},
+    item_type, objectid,
+    tree::{BtrfsKey, BtrfsTree},
+    BtrfsError, BtrfsFilesystem, Result,
+};
+use parking_lot::RwLock;
+use std::collections::HashMap;
+
+/// Reads an inode from the filesystem
+pub fn read_inode(fs: &BtrfsFilesystem, tree_id: u64, ino: u64) -> Result<Inode> {
+    let tree = fs.tree(tree_id)?;
+
+    let key = BtrfsKey::new(ino, item_type::INODE_ITEM, 0);
+
+    match tree.search(&key)? {
+        Some((_, data)) => Inode::from_bytes(ino, &data),
+        None => Err(BtrfsError::InvalidInode(ino)),
+    }
+}
+
+/// Reads directory entries
+pub fn read_dir(fs: &BtrfsFilesystem, tree_id: u64, ino: u64) -> Result<Vec<DirEntry>> {
+    let tree = fs.tree(tree_id)?;
+
+    let min_key = BtrfsKey::new(ino, item_type::DIR_INDEX, 0);
+    let max_key = BtrfsKey::new(ino, item_type::DIR_INDEX, u64::MAX);
+
+    let items = tree.search_range(&min_key, &max_key)?;
+
+    let mut entries = Vec::new();
+    for (_, data) in items {
+        if let Ok(entry) = DirEntry::from_bytes(&data) {
+            entries.push(entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Default number of directory entries fetched from the tree per batch
+pub const DEFAULT_DIR_BATCH_SIZE: usize = 256;
+
+/// Lazily iterates a directory's entries in DIR_INDEX order
+///
+/// Entries are fetched in batches of at most `batch_size`, so memory use
+/// stays bounded regardless of directory size.
+pub struct DirIter<'a> {
+    tree: BtrfsTree<'a>,
+    ino: u64,
+    next_index: u64,
+    batch_size: usize,
+    pending: std::vec::IntoIter<DirEntry>,
+    done: bool,
+}
+
+impl<'a> DirIter<'a> {
+    /// Fetches the next batch of at most `batch_size` entries
+    ///
+    /// An empty batch means the directory is exhausted.
+    pub fn next_batch(&mut self) -> Result<Vec<DirEntry>> {
+        while !self.done {
+            let min_key = BtrfsKey::new(self.ino, item_type::DIR_INDEX, self.next_index);
+            let max_key = BtrfsKey::new(self.ino, item_type::DIR_INDEX, u64::MAX);
+
+            let items = self
+                .tree
+                .search_range_limited(&min_key, &max_key, self.batch_size)?;
+
+            if items.len() < self.batch_size {
+                self.done = true;
+            }
+            if let Some((item, _)) = items.last() {
+                match item.key.offset.checked_add(1) {
+                    Some(next) => self.next_index = next,
+                    None => self.done = true,
+                }
+            }
+
+            let batch: Vec<DirEntry> = items
+                .iter()
+                .filter_map(|(_, data)| DirEntry::from_bytes(data).ok())
+                .collect();
+            if !batch.is_empty() {
+                return Ok(batch);
+            }
+        }
+
+        Ok(Vec::new())
+    }
+}
+
+impl<'a> Iterator for DirIter<'a> {
+    type Item = Result<DirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(entry) = self.pending.next() {
+            return Some(Ok(entry));
+        }
+
+        match self.next_batch() {
+            Ok(batch) => {
+                self.pending = batch.into_iter();
+                self.pending.next().map(Ok)
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Iterates a directory lazily, reading at most `batch_size` entries at once
+pub fn dir_iter(
+    fs: &BtrfsFilesystem,
+    tree_id: u64,
+    ino: u64,
+    batch_size: usize,
+) -> Result<DirIter<'_>> {
+    Ok(DirIter {
+        tree: fs.tree(tree_id)?,
+        ino,
+        next_index: 0,
+        batch_size: batch_size.max(1),
+        pending: Vec::new().into_iter(),
+        done: false,
+    })
+}
+
+/// Looks up a name in a directory
+pub fn lookup(fs: &BtrfsFilesystem, tree_id: u64, dir_ino: u64, name: &str) -> Result<DirEntry> {
+    // Hash the name for DIR_ITEM lookup
+    let name_hash = btrfs_name_hash(name);
+
+    let tree = fs.tree(tree_id)?;
+
+    let key = BtrfsKey::new(dir_ino, item_type::DIR_ITEM, name_hash);
+
+    // DIR_ITEM may contain multiple entries with same hash
+    // Parse and find the one with matching name
+    let entries = match tree.search(&key)? {
+        Some((_, data)) => DirEntry::all_from_bytes(&data)?,
+        None => Vec::new(),
+    };
+    entries
+        .into_iter()
+        .find(|entry| entry.name == name)
+        .ok_or_else(|| BtrfsError::NotFound(name.to_string()))
+}
+
+/// Looks up a name in a directory, ignoring case if no entry matches it
+/// exactly
+///
+/// The exact lookup is tried first; only when it misses are the directory's
+/// entries scanned, `batch_size` at a time, for one whose name matches when
+/// both are lowercased. Of several such entries, the first in index order
+/// wins.
+pub fn lookup_case_insensitive(
+    fs: &BtrfsFilesystem,
+    tree_id: u64,
+    dir_ino: u64,
+    name: &str,
+    batch_size: usize,
+) -> Result<DirEntry> {
+    match lookup(fs, tree_id, dir_ino, name) {
+        Err(BtrfsError::NotFound(_)) => {}
+        found => return found,
+    }
+
+    let folded = name.to_lowercase();
+    for entry in dir_iter(fs, tree_id, dir_ino, batch_size)? {
+        let entry = entry?;
+        if entry.name.to_lowercase() == folded {
+            return Ok(entry);
+        }
+    }
+    Err(BtrfsError::NotFound(name.to_string()))
+}
+
+/// Resolved paths a cached [`PathResolver`] keeps before clearing its cache
+const PATH_CACHE_CAPACITY: usize = 4096;
+
+/// Resolved `(tree_id, ino)` by starting tree and normalized path
+type PathCache = RwLock<HashMap<(u64, String), (u64, u64)>>;
+
+/// Resolves paths to inodes
+///
+/// Both `\` and `/` separate components. An entry that is a nested
+/// subvolume continues the walk at the root directory of the subvolume's
+/// tree. Names are matched exactly unless the resolver ignores case, in
+/// which case a name with no exact match falls back to
+/// [`lookup_case_insensitive`].
+///
+/// A cached resolver remembers where each path it resolved leads, so
+/// resolving it again skips the walk. It must only be used with one
+/// filesystem.
+#[derive(Debug, Default)]
+pub struct PathResolver {
+    /// Entries scanned per batch when ignoring case, or `None` to match
+    /// names exactly
+    case_insensitive: Option<usize>,
+    /// Paths resolved so far, if caching
+    cache: Option<PathCache>,
+}
+
+impl PathResolver {
+    /// Creates a resolver matching names exactly, without a cache
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ignores case in names, scanning directories `batch_size` entries at
+    /// a time when a name has no exact match
+    pub fn with_case_insensitive(mut self, batch_size: usize) -> Self {
+        self.case_insensitive = Some(batch_size);
+        self
+    }
+
+    /// Caches resolved paths
+    pub fn with_cache(mut self) -> Self {
+        self.cache = Some(RwLock::new(HashMap::new()));
+        self
+    }
+
+    /// Returns true if names are matched ignoring case
+    pub fn is_case_insensitive(&self) -> bool {
+        self.case_insensitive.is_some()
+    }
+
+    /// Resolves `path` from the root directory of tree `tree_id`, returning
+    /// the tree holding its inode, the inode number and the inode
+    ///
+    /// The returned tree differs from `tree_id` for paths that cross into a
+    /// nested subvolume.
+    pub fn resolve(
+        &self,
+        fs: &BtrfsFilesystem,
+        tree_id: u64,
+        path: &str,
+    ) -> Result<(u64, u64, Inode)> {
+        let components = parse_path_components(path);
+        let key = (tree_id, components.join("/"));
+
+        let cached = self
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.read().get(&key).copied());
+        let (tree_id, ino) = match cached {
+            Some(found) => found,
+            None => {
+                let found = self.walk(fs, tree_id, &components)?;
+                if let Some(cache) = &self.cache {
+                    let mut cache = cache.write();
+                    if cache.len() >= PATH_CACHE_CAPACITY {
+                        cache.clear();
+                    }
+                    cache.insert(key, found);
+                }
+                found
+            }
+        };
+
+        let inode = read_inode(fs, tree_id, ino)?;
+        Ok((tree_id, ino, inode))
+    }
+
+    /// Looks up `components` one by one from the root directory of
+    /// `tree_id`
+    fn walk(
+        &self,
+        fs: &BtrfsFilesystem,
+        mut tree_id: u64,
+        components: &[&str],
+    ) -> Result<(u64, u64)> {
+        let mut ino = objectid::FIRST_FREE;
+        let mut is_dir = true;
+
+        for component in components {
+            if !is_dir {
+                return Err(BtrfsError::NotADirectory);
+            }
+
+            let entry = match self.case_insensitive {
+                Some(batch_size) => {
+                    lookup_case_insensitive(fs, tree_id, ino, component, batch_size)?
+                }
+                None => lookup(fs, tree_id, ino, component)?,
+            };
+            (tree_id, ino) = entry.target(tree_id);
+            is_dir = entry.entry_type.is_dir();
+        }
+
+        Ok((tree_id, ino))
+    }
+}
+
+/// Resolves a path to an inode, matching names exactly, returning the tree
+/// holding it, its inode number and the inode
+///
+/// See [`PathResolver::resolve`].
+pub fn resolve_path(fs: &BtrfsFilesystem, tree_id: u64, path: &str) -> Result<(u64, u64, Inode)> {
+    PathResolver::new().resolve(fs, tree_id, path)
+}
+
+/// Parses path components from a path string
+pub fn parse_path_components(path: &str) -> Vec<&str> {
+    path.split(['/', '\\']).filter(|s| !s.is_empty()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::objectid;
+    use crate::test_utils::{dir_item, ImageBuilder};
+
+    #[test]
+    fn test_parse_path_components_unix() {
+        let components = parse_path_components("/home/user/file.txt");
+        assert_eq!(components, vec!["home", "user", "file.txt"]);
+    }
+
+    #[test]
+    fn test_parse_path_components_windows() {
+        let components = parse_path_components("C:\\Users\\user\\file.txt");
+        assert_eq!(components, vec!["C:", "Users", "user", "file.txt"]);
+    }
+
+    #[test]
+    fn test_parse_path_components_mixed() {
+        let components = parse_path_components("/path/to\\mixed/separators");
+        assert_eq!(components, vec!["path", "to", "mixed", "separators"]);
+    }
+
+    #[test]
+    fn test_parse_path_components_empty() {
+        let components = parse_path_components("");
+        assert!(components.is_empty());
+    }
+
+    #[test]
+    fn test_parse_path_components_root() {
+        let components = parse_path_components("/");
+        assert!(components.is_empty());
+    }
+
+    #[test]
+    fn test_parse_path_components_trailing_slash() {
+        let components = parse_path_components("/path/to/dir/");
+        assert_eq!(components, vec!["path", "to", "dir"]);
+    }
+
+    #[test]
+    fn test_parse_path_components_double_slash() {
+        let components = parse_path_components("/path//to///dir");
+        assert_eq!(components, vec!["path", "to", "dir"]);
+    }
+
+    #[test]
+    fn test_parse_path_components_single_name() {
+        let components = parse_path_components("filename.txt");
+        assert_eq!(components, vec!["filename.txt"]);
+    }
+
+    #[test]
+    fn test_lookup_hash_collision() {
+        // Names whose hashes collide share one DIR_ITEM; the wanted entry
+        // need not be the first
+        let root = objectid::FIRST_FREE;
+        let mut builder = ImageBuilder::new();
+        builder
+            .root_dir(objectid::FS_TREE)
+            .file(objectid::FS_TREE, root, 257, "b.txt", 0);
+        let location = |ino| BtrfsKey::new(ino, item_type::INODE_ITEM, 0);
+        builder.insert(
+            objectid::FS_TREE,
+            BtrfsKey::new(root, item_type::DIR_ITEM, btrfs_name_hash("b.txt")),
+            [
+                dir_item(&location(258), 1, "a.txt"),
+                dir_item(&location(257), 1, "b.txt"),
+            ]
+            .concat(),
+        );
+        let fs = builder.open();
+        let lookup_root = |name: &str| lookup(&fs, objectid::FS_TREE, root, name);
+
+        assert_eq!(lookup_root("b.txt").unwrap().ino, 257);
+        assert!(matches!(lookup_root("c.txt"), Err(BtrfsError::NotFound(_))));
+    }
+
+    #[test]
+    fn test_lookup_case_insensitive() {
+        let root = objectid::FIRST_FREE;
+        let fs = ImageBuilder::new()
+            .root_dir(objectid::FS_TREE)
+            .file(objectid::FS_TREE, root, 257, "Readme.md", 0)
+            .file(objectid::FS_TREE, root, 258, "NOTES.txt", 0)
+            .file(objectid::FS_TREE, root, 259, "notes.txt", 0)
+            .open();
+        let lookup_ci = |name: &str| lookup_case_insensitive(&fs, objectid::FS_TREE, root, name, 2);
+
+        assert!(lookup(&fs, objectid::FS_TREE, root, "README.MD").is_err());
+        assert_eq!(lookup_ci("README.MD").unwrap().ino, 257);
+
+        // An exact match wins over earlier entries differing only in case
+        assert_eq!(lookup_ci("notes.txt").unwrap().ino, 259);
+        assert_eq!(lookup_ci("Notes.TXT").unwrap().ino, 258);
+        assert!(matches!(
+            lookup_ci("readme.txt"),
+            Err(BtrfsError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_dir_iter_bounded_batches() {
+        let root = objectid::FIRST_FREE;
+        let mut builder = ImageBuilder::new();
+        builder.root_dir(objectid::FS_TREE);
+        for i in 0..2000u64 {
+            builder.file(
+                objectid::FS_TREE,
+                root,
+                257 + i,
+                &format!("file{:05}", i),
+                0,
+            );
+        }
+        let fs = builder.open();
+
+        let mut iter = dir_iter(&fs, objectid::FS_TREE, root, 64).unwrap();
+        let mut names = Vec::new();
+        loop {
+            let batch = iter.next_batch().unwrap();
+            if batch.is_empty() {
+                break;
+            }
+            assert!(batch.len() <= 64);
+            names.extend(batch.into_iter().map(|e| e.name));
+        }
+
+        assert_eq!(names.len(), 2000);
+        assert_eq!(names[0], "file00000");
+        assert_eq!(names[1999], "file01999");
+        assert!(iter.next_batch().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_dir_iter_matches_full_listing() {
+        let root = objectid::FIRST_FREE;
+        let mut builder = ImageBuilder::new();
+        builder.root_dir(objectid::FS_TREE);
+        for i in 0..100u64 {
+            builder.file(objectid::FS_TREE, root, 257 + i, &format!("f{}", i), i);
+        }
+        let fs = builder.open();
+
+        let batched: Vec<u64> = dir_iter(&fs, objectid::FS_TREE, root, 7)
+            .unwrap()
+            .map(|e| e.unwrap().ino)
+            .collect();
+        let all: Vec<u64> = dir_iter(&fs, objectid::FS_TREE, root, usize::MAX)
+            .unwrap()
+            .map(|e| e.unwrap().ino)
+            .collect();
+
+        assert_eq!(batched, (257..357).collect::<Vec<_>>());
+        assert_eq!(batched, all);
+    }
+
+    #[test]
+    fn test_resolve_path_into_subvolume() {
+        let root = objectid::FIRST_FREE;
+        let fs = ImageBuilder::new()
+            .root_dir(objectid::FS_TREE)
+            .subvolume(256, objectid::FS_TREE, root, "@home")
+            .dir(256, root, 257, "user")
+            .file(256, 257, 258, "notes.txt", 12)
+            .file(objectid::FS_TREE, root, 257, "top.txt", 3)
+            .open();
+
+        let (tree, ino, inode) = resolve_path(&fs, objectid::FS_TREE, "/top.txt").unwrap();
+        assert_eq!((tree, ino, inode.size), (objectid::FS_TREE, 257, 3));
+
+        let (tree, ino, inode) = resolve_path(&fs, objectid::FS_TREE, "@home").unwrap();
+        assert_eq!((tree, ino), (256, root));
+        assert!(inode.is_dir());
+
+        let (tree, ino, inode) =
+            resolve_path(&fs, objectid::FS_TREE, "\\@home\\user\\notes.txt").unwrap();
+        assert_eq!((tree, ino, inode.size), (256, 258, 12));
+
+        // Inode 257 of the subvolume is not the top level's file
+        let (tree, ino, inode) = resolve_path(&fs, 256, "/user").unwrap();
+        assert_eq!((tree, ino), (256, 257));
+        assert!(inode.is_dir());
+    }
+
+    #[test]
+    fn test_path_resolver_cache() {
+        let root = objectid::FIRST_FREE;
+        let fs = ImageBuilder::new()
+            .root_dir(objectid::FS_TREE)
+            .dir(objectid::FS_TREE, root, 257, "docs")
+            .file(objectid::FS_TREE, 257, 258, "hello.txt", 5)
+            .open();
+        let resolver = PathResolver::new().with_cache();
+        let resolve = |path: &str| resolver.resolve(&fs, objectid::FS_TREE, path);
+        let cache = || resolver.cache.as_ref().unwrap().read().clone();
+
+        let (tree, ino, _) = resolve("\\docs\\hello.txt").unwrap();
+        assert_eq!((tree, ino), (objectid::FS_TREE, 258));
+        let key = (objectid::FS_TREE, "docs/hello.txt".to_string());
+        assert_eq!(cache().get(&key), Some(&(objectid::FS_TREE, 258)));
+
+        // Either separator hits the same entry
+        assert_eq!(resolve("/docs/hello.txt").unwrap().1, 258);
+        assert_eq!(cache().len(), 1);
+
+        // Failed lookups are not cached
+        assert!(matches!(
+            resolve("/docs/hello.txt/x"),
+            Err(BtrfsError::NotADirectory)
+        ));
+        assert!(resolve("/docs/missing").is_err());
+        assert_eq!(cache().len(), 1);
+    }
+
+    #[test]
+    fn test_path_resolver_case_insensitive() {
+        let root = objectid::FIRST_FREE;
+        let fs = ImageBuilder::new()
+            .root_dir(objectid::FS_TREE)
+            .dir(objectid::FS_TREE, root, 257, "Docs")
+            .file(objectid::FS_TREE, 257, 258, "Hello.txt", 5)
+            .open();
+
+        assert!(matches!(
+            resolve_path(&fs, objectid::FS_TREE, "/docs/hello.txt"),
+            Err(BtrfsError::NotFound(_))
+        ));
+
+        let resolver = PathResolver::new().with_case_insensitive(2);
+        assert!(resolver.is_case_insensitive());
+        let (_, ino, inode) = resolver
+            .resolve(&fs, objectid::FS_TREE, "/DOCS/hello.TXT")
+            .unwrap();
+        assert_eq!((ino, inode.size), (258, 5));
+    }
+}
@@ -0,0 +1,215 @@
+//! RAID5/6 stripe layout and parity reconstruction
+//!
+//! A RAID5/6 chunk is split into full stripes of `data_stripes` data
+//! elements plus P (and, for RAID6, Q) parity, each `stripe_len` long and
+//! on its own device. Parity rotates across devices from one full stripe to
+//! the next, the same way the kernel lays it out.
+//!
+//! P is the XOR of the data elements. Q is `sum(g^i * D_i)` over GF(2^8)
+//! with generator 2 and polynomial 0x11d, as in the kernel's raid6 library.
+
+use super::{chunk::chunk_type, chunk::ChunkMapping, BtrfsError, Result};
+
+/// Where a byte of a RAID5/6 chunk lives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StripePos {
+    /// Index of the full stripe within the chunk
+    pub full_stripe: u64,
+    /// Index of the data element within the full stripe
+    pub data_index: usize,
+    /// Offset within the stripe element
+    pub stripe_offset: u64,
+}
+
+/// Geometry of a RAID5/6 chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Raid56Layout {
+    /// Number of devices the chunk is striped over
+    pub num_stripes: usize,
+    /// Number of data elements per full stripe
+    pub data_stripes: usize,
+    /// Length of one stripe element
+    pub stripe_len: u64,
+    /// Whether the chunk has Q parity
+    pub raid6: bool,
+}
+
+impl Raid56Layout {
+    /// Returns the layout of a RAID5 or RAID6 chunk
+    pub fn new(chunk: &ChunkMapping) -> Result<Self> {
+        let raid6 = chunk.type_flags & chunk_type::RAID6 != 0;
+        let parity = if raid6 { 2 } else { 1 };
+        let num_stripes = chunk.stripes.len();
+
+        if num_stripes <= parity || chunk.stripe_len == 0 {
+            return Err(BtrfsError::Corrupt(format!(
+                "RAID5/6 chunk at {} has {} stripes",
+                chunk.logical, num_stripes
+            )));
+        }
+
+        Ok(Self {
+            num_stripes,
+            data_stripes: num_stripes - parity,
+            stripe_len: chunk.stripe_len,
+            raid6,
+        })
+    }
+
+    /// Locates the byte at `offset` within the chunk
+    pub fn locate(&self, offset: u64) -> StripePos {
+        let stripe_nr = offset / self.stripe_len;
+        StripePos {
+            full_stripe: stripe_nr / self.data_stripes as u64,
+            data_index: (stripe_nr % self.data_stripes as u64) as usize,
+            stripe_offset: offset % self.stripe_len,
+        }
+    }
+
+    /// Returns the device (stripe) index holding member `member` of a full
+    /// stripe
+    ///
+    /// Members `0..data_stripes` are data, `data_stripes` is P and
+    /// `data_stripes + 1` is Q.
+    pub fn device_index(&self, full_stripe: u64, member: usize) -> usize {
+        ((full_stripe + member as u64) % self.num_stripes as u64) as usize
+    }
+
+    /// Returns the offset of a full stripe's elements from each stripe start
+    pub fn element_offset(&self, full_stripe: u64) -> u64 {
+        full_stripe * self.stripe_len
+    }
+}
+
+/// XORs `src` into `acc`
+pub fn xor_into(acc: &mut [u8], src: &[u8]) {
+    for (a, s) in acc.iter_mut().zip(src) {
+        *a ^= s;
+    }
+}
+
+/// Multiplies in GF(2^8) with polynomial 0x11d
+pub fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1d;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+/// Returns the generator 2 raised to `exp` in GF(2^8)
+pub fn gf_pow2(exp: usize) -> u8 {
+    (0..exp % 255).fold(1, |acc, _| gf_mul(acc, 2))
+}
+
+/// Returns the multiplicative inverse in GF(2^8); zero has none and maps to zero
+pub fn gf_inv(a: u8) -> u8 {
+    // a^254 == a^-1 since the multiplicative group has order 255
+    (0..254).fold(1, |acc, _| gf_mul(acc, a))
+}
+
+/// Adds `g^index * src` into the Q accumulator `acc`
+pub fn q_accumulate(acc: &mut [u8], src: &[u8], index: usize) {
+    let coeff = gf_pow2(index);
+    for (a, s) in acc.iter_mut().zip(src) {
+        *a ^= gf_mul(coeff, *s);
+    }
+}
+
+/// Recovers data element `index` from Q with the other elements already
+/// folded in by [`q_accumulate`]
+///
+/// `acc` holds `Q + sum(g^i * D_i)` over every other data element, which
+/// leaves `g^index * D_index`.
+pub fn q_recover(acc: &mut [u8], index: usize) {
+    let inv = gf_inv(gf_pow2(index));
+    for a in acc.iter_mut() {
+        *a = gf_mul(inv, *a);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::chunk::Stripe;
+
+    fn chunk(type_flags: u64, num_stripes: usize) -> ChunkMapping {
+        ChunkMapping {
+            logical: 0x10000000,
+            size: 0x100000,
+            stripe_len: 0x10000,
+            type_flags,
+            num_stripes: num_stripes as u16,
+            sub_stripes: 0,
+            stripes: (0..num_stripes)
+                .map(|i| Stripe {
+                    devid: 1,
+                    offset: 0x100000 * (i as u64 + 1),
+                    dev_uuid: [0; 16],
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_layout_rotates_parity() {
+        let layout = Raid56Layout::new(&chunk(chunk_type::RAID5, 3)).unwrap();
+        assert_eq!(layout.data_stripes, 2);
+
+        let pos = layout.locate(0x10000 * 3 + 5);
+        assert_eq!(
+            pos,
+            StripePos {
+                full_stripe: 1,
+                data_index: 1,
+                stripe_offset: 5
+            }
+        );
+
+        // P moves one device to the right each full stripe
+        let parity: Vec<usize> = (0..3).map(|fs| layout.device_index(fs, 2)).collect();
+        assert_eq!(parity, vec![2, 0, 1]);
+
+        let layout = Raid56Layout::new(&chunk(chunk_type::RAID6, 4)).unwrap();
+        assert!(layout.raid6);
+        assert_eq!(layout.data_stripes, 2);
+        assert!(Raid56Layout::new(&chunk(chunk_type::RAID6, 2)).is_err());
+    }
+
+    #[test]
+    fn test_gf_arithmetic() {
+        assert_eq!(gf_mul(2, 0x80), 0x1d);
+        assert_eq!(gf_pow2(8), 0x1d);
+        for a in 1..=255u8 {
+            assert_eq!(gf_mul(a, gf_inv(a)), 1);
+        }
+    }
+
+    #[test]
+    fn test_q_recover() {
+        let data = [
+            vec![0x11u8, 0x22, 0x33],
+            vec![0xA0, 0x0B, 0xFF],
+            vec![7, 8, 9],
+        ];
+        let mut q = vec![0u8; 3];
+        for (i, d) in data.iter().enumerate() {
+            q_accumulate(&mut q, d, i);
+        }
+
+        // Rebuild element 1 from Q and the others
+        let mut acc = q.clone();
+        q_accumulate(&mut acc, &data[0], 0);
+        q_accumulate(&mut acc, &data[2], 2);
+        q_recover(&mut acc, 1);
+        assert_eq!(acc, data[1]);
+    }
+}
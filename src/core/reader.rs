@@ -0,0 +1,401 @@
+//! Streaming reads of file contents
+//!
+//! [`FileReader`] reads a file through [`std::io::Read`] and
+//! [`std::io::Seek`] in bounded chunks, so files of any size can be copied
+//! or hashed without holding them in memory. [`read_file_stream`] builds
+//! on it to hand a file to a callback chunk by chunk, for previews that
+//! show progress and can be cancelled.
+
+use super::{
+    file::{read_extent_verified, read_file_data_with},
+    inode::{ExtentData, Inode},
+    path::{read_inode, resolve_path},
+    BtrfsError, BtrfsFilesystem, Result,
+};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Most bytes assembled by a single read
+pub const MAX_READ_CHUNK: usize = 1024 * 1024;
+
+/// Reads the on-disk bytes of a regular extent, like [`read_extent_verified`]
+type ExtentReader<'a> = Box<dyn FnMut(&Inode, &ExtentData) -> Result<Vec<u8>> + 'a>;
+
+/// A seekable reader over one file's contents
+///
+/// Each read assembles at most [`MAX_READ_CHUNK`] bytes, starting from the
+/// extent containing the position, so memory use doesn't grow with the
+/// file. Compressed extents are decompressed whole and sliced, so seeking
+/// into the middle of one returns the right bytes. The size is fixed when
+/// the reader is opened.
+pub struct FileReader<'a> {
+    fs: &'a BtrfsFilesystem,
+    tree_id: u64,
+    ino: u64,
+    size: u64,
+    pos: u64,
+    read_extent: ExtentReader<'a>,
+}
+
+impl<'a> FileReader<'a> {
+    /// Opens inode `ino` of tree `tree_id`, verifying data checksums
+    pub fn open(fs: &'a BtrfsFilesystem, tree_id: u64, ino: u64) -> Result<Self> {
+        let inode = read_inode(fs, tree_id, ino)?;
+        if inode.is_dir() {
+            return Err(BtrfsError::NotAFile);
+        }
+
+        Ok(Self {
+            fs,
+            tree_id,
+            ino,
+            size: inode.size,
+            pos: 0,
+            read_extent: Box::new(move |inode, extent| read_extent_verified(fs, inode, extent)),
+        })
+    }
+
+    /// Reads regular extents with `read_extent` instead, for example to
+    /// tolerate damaged blocks
+    pub fn with_extent_reader<F>(mut self, read_extent: F) -> Self
+    where
+        F: FnMut(&Inode, &ExtentData) -> Result<Vec<u8>> + 'a,
+    {
+        self.read_extent = Box::new(read_extent);
+        self
+    }
+
+    /// Returns the file size
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the current position
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// Moves to `pos`, which may be past the end of the file
+    pub fn set_position(&mut self, pos: u64) {
+        self.pos = pos;
+    }
+
+    /// Reads from the current position into `buf`, returning the number of
+    /// bytes read; 0 means the end of the file
+    ///
+    /// Like [`Read::read`], but failures keep their [`BtrfsError`].
+    pub fn read_chunk(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.pos >= self.size {
+            return Ok(0);
+        }
+
+        let len = buf
+            .len()
+            .min(MAX_READ_CHUNK)
+            .min((self.size - self.pos) as usize);
+        let data = read_file_data_with(
+            self.fs,
+            self.tree_id,
+            self.ino,
+            self.pos,
+            len,
+            &mut self.read_extent,
+        )?;
+
+        buf[..data.len()].copy_from_slice(&data);
+        self.pos += data.len() as u64;
+        Ok(data.len())
+    }
+}
+
+impl Read for FileReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_chunk(buf).map_err(|e| match e {
+            BtrfsError::Io(e) => e,
+            e => io::Error::other(e),
+        })
+    }
+}
+
+impl Seek for FileReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+
+        let target = target.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative or overflowing position",
+            )
+        })?;
+        self.pos = target;
+        Ok(target)
+    }
+}
+
+/// A flag for stopping a [`read_file_stream`] from another thread
+///
+/// Clones share the flag, so one can be kept to cancel the stream reading
+/// with another.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Creates a token that is not cancelled
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks the stream using this token to stop
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns true once [`cancel`](Self::cancel) has been called
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// One piece of a file handed out by [`read_file_stream`]
+#[derive(Debug, Clone, Copy)]
+pub struct StreamChunk<'a> {
+    /// File offset of the first byte
+    pub offset: u64,
+    /// The bytes read
+    pub data: &'a [u8],
+    /// Size of the whole file, for reporting progress
+    pub size: u64,
+}
+
+/// Reads the file at `path` in tree `tree_id` from `offset` to the end,
+/// passing it to `on_chunk` in chunks of at most `chunk_size` bytes
+///
+/// `cancel` is checked before each chunk, so a cancelled stream stops
+/// within one chunk and fails with [`BtrfsError::Cancelled`]. An error
+/// from `on_chunk` also stops the stream and is returned. Returns the
+/// number of bytes streamed.
+pub fn read_file_stream<F>(
+    fs: &BtrfsFilesystem,
+    tree_id: u64,
+    path: &str,
+    offset: u64,
+    chunk_size: usize,
+    cancel: &CancelToken,
+    mut on_chunk: F,
+) -> Result<u64>
+where
+    F: FnMut(StreamChunk<'_>) -> Result<()>,
+{
+    let (tree_id, ino, _) = resolve_path(fs, tree_id, path)?;
+    let mut reader = FileReader::open(fs, tree_id, ino)?;
+    reader.set_position(offset);
+
+    let mut buf = vec![0u8; chunk_size.clamp(1, MAX_READ_CHUNK)];
+    let mut streamed = 0;
+    loop {
+        if cancel.is_cancelled() {
+            return Err(BtrfsError::Cancelled);
+        }
+
+        let offset = reader.position();
+        let n = reader.read_chunk(&mut buf)?;
+        if n == 0 {
+            return Ok(streamed);
+        }
+        on_chunk(StreamChunk {
+            offset,
+            data: &buf[..n],
+            size: reader.size(),
+        })?;
+        streamed += n as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{checksum, compress, item_type, objectid, tree::BtrfsKey};
+    use crate::test_utils::{extent_data, ImageBuilder};
+
+    const PLAIN_AT: u64 = 0x300000;
+    const ZLIB_AT: u64 = 0x320000;
+
+    fn pattern(seed: u8, len: usize) -> Vec<u8> {
+        (0..len).map(|i| ((i * 7 + i / 251) as u8) ^ seed).collect()
+    }
+
+    /// A file of 8 KiB plain data followed by 64 KiB stored zlib-compressed
+    fn fixture() -> (BtrfsFilesystem, Vec<u8>) {
+        let plain = pattern(1, 8192);
+        let text = pattern(2, 64 * 1024);
+        let mut zlib = compress::compress_zlib(&text, 6).unwrap();
+        zlib.resize(zlib.len().next_multiple_of(4096), 0);
+        let mut zlib_item = extent_data(ZLIB_AT, text.len() as u64);
+        zlib_item[16] = 1; // zlib
+        zlib_item[29..37].copy_from_slice(&(zlib.len() as u64).to_le_bytes());
+
+        let csums = |data: &[u8]| -> Vec<u8> {
+            data.chunks(4096)
+                .flat_map(|block| checksum::crc32c(block).to_le_bytes())
+                .collect()
+        };
+        let extent_key = |offset| BtrfsKey::new(257, item_type::EXTENT_DATA, offset);
+        let csum_key =
+            |logical| BtrfsKey::new(objectid::EXTENT_CSUM, item_type::EXTENT_CSUM, logical);
+
+        let tree = objectid::FS_TREE;
+        let size = (plain.len() + text.len()) as u64;
+        let fs = ImageBuilder::new()
+            .root_dir(tree)
+            .file(tree, objectid::FIRST_FREE, 257, "stream", size)
+            .insert(
+                tree,
+                extent_key(0),
+                extent_data(PLAIN_AT, plain.len() as u64),
+            )
+            .insert(tree, extent_key(plain.len() as u64), zlib_item)
+            .data(PLAIN_AT, &plain)
+            .data(ZLIB_AT, &zlib)
+            .insert(objectid::CSUM_TREE, csum_key(PLAIN_AT), csums(&plain))
+            .insert(objectid::CSUM_TREE, csum_key(ZLIB_AT), csums(&zlib))
+            .open();
+
+        let mut contents = plain;
+        contents.extend(text);
+        (fs, contents)
+    }
+
+    #[test]
+    fn test_read_to_end() {
+        let (fs, contents) = fixture();
+        let mut reader = FileReader::open(&fs, objectid::FS_TREE, 257).unwrap();
+        assert_eq!(reader.size(), contents.len() as u64);
+
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, contents);
+        assert_eq!(reader.read(&mut [0u8; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_seek_into_compressed_extent() {
+        let (fs, contents) = fixture();
+        let mut reader = FileReader::open(&fs, objectid::FS_TREE, 257).unwrap();
+
+        // Middle of the compressed extent, then across the extent boundary
+        for start in [40_000u64, 8190, 8192, 100] {
+            reader.seek(SeekFrom::Start(start)).unwrap();
+            let mut buf = [0u8; 5000];
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(buf[..], contents[start as usize..start as usize + 5000]);
+        }
+
+        let mut tail = Vec::new();
+        reader.seek(SeekFrom::End(-10)).unwrap();
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, contents[contents.len() - 10..]);
+
+        reader.seek(SeekFrom::Start(20_000)).unwrap();
+        assert_eq!(reader.seek(SeekFrom::Current(-1000)).unwrap(), 19_000);
+        assert!(reader.seek(SeekFrom::Current(-20_000)).is_err());
+
+        // Past the end reads nothing
+        reader.seek(SeekFrom::End(100)).unwrap();
+        assert_eq!(reader.read(&mut [0u8; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_read_file_stream() {
+        let (fs, contents) = fixture();
+        let cancel = CancelToken::new();
+
+        let mut chunks = Vec::new();
+        let streamed = read_file_stream(
+            &fs,
+            objectid::FS_TREE,
+            "/stream",
+            0,
+            10_000,
+            &cancel,
+            |chunk| {
+                assert_eq!(chunk.size, contents.len() as u64);
+                chunks.push((chunk.offset, chunk.data.to_vec()));
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(streamed, contents.len() as u64);
+
+        // Chunks arrive in order, none larger than asked for
+        let mut data = Vec::new();
+        for (offset, chunk) in &chunks {
+            assert_eq!(*offset, data.len() as u64);
+            assert!(chunk.len() <= 10_000);
+            data.extend_from_slice(chunk);
+        }
+        assert_eq!(data, contents);
+
+        // Starting partway through
+        let mut tail = Vec::new();
+        read_file_stream(
+            &fs,
+            objectid::FS_TREE,
+            "/stream",
+            70_000,
+            4096,
+            &cancel,
+            |chunk| {
+                tail.extend_from_slice(chunk.data);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(tail, contents[70_000..]);
+    }
+
+    #[test]
+    fn test_read_file_stream_cancel() {
+        let (fs, _) = fixture();
+        let cancel = CancelToken::new();
+        let handle = cancel.clone();
+
+        // Cancelled from the callback after the second chunk
+        let mut chunks = 0;
+        let result = read_file_stream(&fs, objectid::FS_TREE, "/stream", 0, 4096, &cancel, |_| {
+            chunks += 1;
+            if chunks == 2 {
+                handle.cancel();
+            }
+            Ok(())
+        });
+        assert!(matches!(result, Err(BtrfsError::Cancelled)));
+        assert_eq!(chunks, 2);
+        assert!(cancel.is_cancelled());
+
+        // A failing callback stops the stream too
+        let result = read_file_stream(
+            &fs,
+            objectid::FS_TREE,
+            "/stream",
+            0,
+            4096,
+            &CancelToken::new(),
+            |_| Err(BtrfsError::NoSpace),
+        );
+        assert!(matches!(result, Err(BtrfsError::NoSpace)));
+    }
+
+    #[test]
+    fn test_open_directory() {
+        let (fs, _) = fixture();
+        assert!(matches!(
+            FileReader::open(&fs, objectid::FS_TREE, objectid::FIRST_FREE),
+            Err(BtrfsError::NotAFile)
+        ));
+    }
+}
@@ -0,0 +1,228 @@
+//! Filesystem scrub
+//!
+//! Reads tree nodes and checksummed data blocks and compares them against
+//! their checksums, collecting every failure instead of stopping at the
+//! first one.
+
+use super::{
+    item_type, objectid,
+    subvolume::RootItem,
+    tree::{BtrfsKey, TreeNode},
+    BtrfsFilesystem, Result,
+};
+
+/// Selects what a scrub verifies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrubOptions {
+    /// Verify data blocks against the checksum tree
+    pub data: bool,
+    /// Verify the checksum of every tree node
+    pub metadata: bool,
+}
+
+impl Default for ScrubOptions {
+    fn default() -> Self {
+        Self {
+            data: true,
+            metadata: true,
+        }
+    }
+}
+
+/// Result of a scrub
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    /// Number of tree nodes verified
+    pub metadata_nodes: u64,
+    /// Logical addresses of tree nodes that failed verification
+    pub metadata_errors: Vec<u64>,
+    /// Number of data blocks verified
+    pub data_blocks: u64,
+    /// Logical addresses of data blocks that failed verification
+    pub data_errors: Vec<u64>,
+}
+
+impl ScrubReport {
+    /// Returns true if nothing failed verification
+    pub fn is_clean(&self) -> bool {
+        self.metadata_errors.is_empty() && self.data_errors.is_empty()
+    }
+}
+
+/// Scrubs the parts of the filesystem selected by `options`
+pub fn scrub(fs: &BtrfsFilesystem, options: ScrubOptions) -> Result<ScrubReport> {
+    let mut report = ScrubReport::default();
+
+    if options.metadata {
+        scrub_metadata(fs, &mut report)?;
+    }
+    if options.data {
+        scrub_data(fs, &mut report)?;
+    }
+
+    Ok(report)
+}
+
+/// Verifies every node of the root tree, the chunk tree and each tree with
+/// a ROOT_ITEM
+fn scrub_metadata(fs: &BtrfsFilesystem, report: &mut ScrubReport) -> Result<()> {
+    let sb = fs.superblock();
+    scrub_tree(fs, sb.root(), report);
+    scrub_tree(fs, sb.chunk_root(), report);
+
+    let min_key = BtrfsKey::new(0, item_type::ROOT_ITEM, 0);
+    let max_key = BtrfsKey::new(u64::MAX, item_type::ROOT_ITEM, u64::MAX);
+    let root_tree = fs.tree(objectid::ROOT_TREE)?;
+    for (item, data) in root_tree.search_range(&min_key, &max_key)? {
+        if item.key.item_type != item_type::ROOT_ITEM {
+            continue;
+        }
+        let root = RootItem::from_bytes(&data)?;
+        scrub_tree(fs, root.bytenr, report);
+    }
+
+    Ok(())
+}
+
+/// Verifies the node at `logical` and, if it is intact, its children
+fn scrub_tree(fs: &BtrfsFilesystem, logical: u64, report: &mut ScrubReport) {
+    report.metadata_nodes += 1;
+
+    // Scrub checks what is on disk, not what is cached
+    let node = fs
+        .read_node_uncached(logical)
+        .and_then(|data| TreeNode::parse(data, fs.checksum()));
+    let node = match node {
+        Ok(node) => node,
+        Err(_) => {
+            report.metadata_errors.push(logical);
+            return;
+        }
+    };
+
+    if node.is_leaf() {
+        return;
+    }
+    match node.key_ptrs() {
+        Ok(ptrs) => {
+            for ptr in ptrs {
+                scrub_tree(fs, ptr.blockptr, report);
+            }
+        }
+        Err(_) => report.metadata_errors.push(logical),
+    }
+}
+
+/// Verifies every data block that has an EXTENT_CSUM entry
+fn scrub_data(fs: &BtrfsFilesystem, report: &mut ScrubReport) -> Result<()> {
+    let sector_size = fs.superblock().sector_size() as u64;
+    let csum = fs.checksum();
+
+    let min_key = BtrfsKey::new(objectid::EXTENT_CSUM, item_type::EXTENT_CSUM, 0);
+    let max_key = BtrfsKey::new(objectid::EXTENT_CSUM, item_type::EXTENT_CSUM, u64::MAX);
+
+    let csum_tree = fs.tree(objectid::CSUM_TREE)?;
+    for (item, data) in csum_tree.search_range(&min_key, &max_key)? {
+        let mut block = vec![0u8; sector_size as usize];
+        for (i, stored) in data.chunks_exact(csum.size()).enumerate() {
+            let logical = item.key.offset + i as u64 * sector_size;
+            report.data_blocks += 1;
+
+            let ok =
+                fs.read_logical(logical, &mut block).is_ok() && csum.verify(&block, stored).is_ok();
+            if !ok {
+                report.data_errors.push(logical);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{checksum, subvolume::read_root_item};
+    use crate::test_utils::{extent_data, ImageBuilder};
+
+    const DATA_START: u64 = 0x300000;
+
+    const METADATA_ONLY: ScrubOptions = ScrubOptions {
+        data: false,
+        metadata: true,
+    };
+    const DATA_ONLY: ScrubOptions = ScrubOptions {
+        data: true,
+        metadata: false,
+    };
+
+    fn scrub_fs() -> BtrfsFilesystem {
+        let blocks: Vec<Vec<u8>> = (0..3u8).map(|i| vec![0x40 + i; 4096]).collect();
+        let csums: Vec<u8> = blocks
+            .iter()
+            .flat_map(|b| checksum::crc32c(b).to_le_bytes())
+            .collect();
+
+        let mut builder = ImageBuilder::new();
+        builder
+            .root_dir(objectid::FS_TREE)
+            .file(objectid::FS_TREE, objectid::FIRST_FREE, 257, "f", 3 * 4096)
+            .insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(257, item_type::EXTENT_DATA, 0),
+                extent_data(DATA_START, 3 * 4096),
+            )
+            .insert(
+                objectid::CSUM_TREE,
+                BtrfsKey::new(objectid::EXTENT_CSUM, item_type::EXTENT_CSUM, DATA_START),
+                csums,
+            )
+            .data(DATA_START, &blocks.concat());
+        builder.open()
+    }
+
+    #[test]
+    fn test_scrub_scopes() {
+        let fs = scrub_fs();
+
+        // Root tree, chunk tree, FS tree and csum tree are one leaf each
+        let metadata = scrub(&fs, METADATA_ONLY).unwrap();
+        assert_eq!(metadata.metadata_nodes, 4);
+        assert_eq!(metadata.data_blocks, 0);
+        assert!(metadata.is_clean());
+
+        let data = scrub(&fs, DATA_ONLY).unwrap();
+        assert_eq!(data.metadata_nodes, 0);
+        assert_eq!(data.data_blocks, 3);
+        assert!(data.is_clean());
+
+        let both = scrub(&fs, ScrubOptions::default()).unwrap();
+        assert_eq!(both.metadata_nodes, 4);
+        assert_eq!(both.data_blocks, 3);
+
+        let nothing = ScrubOptions {
+            data: false,
+            metadata: false,
+        };
+        assert_eq!(scrub(&fs, nothing).unwrap(), ScrubReport::default());
+    }
+
+    #[test]
+    fn test_scrub_reports_corruption() {
+        let fs = scrub_fs();
+        fs.device()
+            .write_at(DATA_START + 4096 + 10, &[0xFF])
+            .unwrap();
+        let fs_root = read_root_item(&fs, objectid::FS_TREE).unwrap().bytenr;
+        fs.device().write_at(fs_root + 0x200, &[0xEE]).unwrap();
+
+        let report = scrub(&fs, ScrubOptions::default()).unwrap();
+        assert_eq!(report.metadata_errors, vec![fs_root]);
+        assert_eq!(report.data_errors, vec![DATA_START + 4096]);
+
+        // Scoped scrubs only report their own kind of error
+        let data_only = scrub(&fs, DATA_ONLY).unwrap();
+        assert!(data_only.metadata_errors.is_empty());
+        assert_eq!(data_only.data_errors.len(), 1);
+    }
+}
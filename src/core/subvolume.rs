@@ -2,8 +2,17 @@
 //!
 //! Subvolumes are independent filesystem trees that can be mounted separately.
 
-use super::{item_type, objectid, tree::BtrfsKey, BtrfsError, BtrfsFilesystem, Result};
+use super::{
+    field_end,
+    inode::{btrfs_name_hash, DirEntry, InodeRef},
+    item_type, objectid,
+    superblock::incompat,
+    transaction::Transaction,
+    tree::{BtrfsKey, BtrfsTree},
+    BtrfsError, BtrfsFilesystem, Result, MAX_NAME_LEN,
+};
 use byteorder::{ByteOrder, LittleEndian};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// A BTRFS subvolume
 #[derive(Debug, Clone)]
@@ -40,6 +49,15 @@ pub struct Subvolume {
     pub root_level: u8,
 }
 
+impl Subvolume {
+    /// Returns true if this subvolume is a snapshot of another
+    ///
+    /// Snapshots record the UUID of their source as `parent_uuid`.
+    pub fn is_snapshot(&self) -> bool {
+        self.parent_uuid != [0u8; 16]
+    }
+}
+
 /// Time specification
 #[derive(Debug, Clone, Copy, Default)]
 pub struct TimeSpec {
@@ -230,16 +248,238 @@ impl RootItem {
     }
 }
 
-/// Lists all subvolumes in the filesystem
+/// Root reference linking a subvolume to the directory that contains it
+///
+/// Stored as ROOT_REF in the parent and ROOT_BACKREF in the child; both
+/// share this layout.
+#[derive(Debug, Clone)]
+pub struct RootRef {
+    /// Directory inode in the parent tree
+    pub dirid: u64,
+    /// Directory index of the entry
+    pub sequence: u64,
+    /// Entry name
+    pub name: String,
+}
+
+impl RootRef {
+    /// Parses a root reference from bytes
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() < 18 {
+            return Err(BtrfsError::Corrupt("Root ref too small".to_string()));
+        }
+
+        let dirid = LittleEndian::read_u64(&data[0..8]);
+        let sequence = LittleEndian::read_u64(&data[8..16]);
+        let name_len = LittleEndian::read_u16(&data[16..18]) as usize;
+
+        let end = field_end(data, 18, name_len, "Root ref name")?;
+        let name = String::from_utf8_lossy(&data[18..end]).to_string();
+
+        Ok(Self {
+            dirid,
+            sequence,
+            name,
+        })
+    }
+}
+
+/// Reads the ROOT_ITEM of a tree from the root tree
+pub fn read_root_item(fs: &BtrfsFilesystem, id: u64) -> Result<RootItem> {
+    let tree = BtrfsTree::new(fs, fs.superblock().root(), fs.superblock().root_level());
+
+    // Snapshots record their creation transid in the key offset, so take
+    // the last ROOT_ITEM for this object ID
+    let min_key = BtrfsKey::new(id, item_type::ROOT_ITEM, 0);
+    let max_key = BtrfsKey::new(id, item_type::ROOT_ITEM, u64::MAX);
+
+    match tree.search_range(&min_key, &max_key)?.pop() {
+        Some((_, data)) => RootItem::from_bytes(&data),
+        None => Err(BtrfsError::SubvolumeNotFound(id)),
+    }
+}
+
+/// Reads a subvolume's back reference, returning the parent tree ID with it
+pub fn read_root_backref(fs: &BtrfsFilesystem, id: u64) -> Result<Option<(u64, RootRef)>> {
+    let tree = BtrfsTree::new(fs, fs.superblock().root(), fs.superblock().root_level());
+
+    let min_key = BtrfsKey::new(id, item_type::ROOT_BACKREF, 0);
+    let max_key = BtrfsKey::new(id, item_type::ROOT_BACKREF, u64::MAX);
+
+    match tree.search_range(&min_key, &max_key)?.into_iter().next() {
+        Some((item, data)) => Ok(Some((item.key.offset, RootRef::from_bytes(&data)?))),
+        None => Ok(None),
+    }
+}
+
+/// Returns the path of a subvolume relative to the top-level subvolume
+///
+/// The top-level subvolume has an empty path; nested subvolumes are joined
+/// with `/`, e.g. `@/var`.
+pub fn subvolume_path(fs: &BtrfsFilesystem, id: u64) -> Result<String> {
+    // Collected innermost first
+    let mut components = Vec::new();
+    let mut current = id;
+
+    while current != objectid::FS_TREE {
+        let (parent, backref) =
+            read_root_backref(fs, current)?.ok_or(BtrfsError::SubvolumeNotFound(current))?;
+
+        components.push(backref.name);
+        components.extend(dir_components(fs, parent, backref.dirid)?);
+        current = parent;
+    }
+
+    components.reverse();
+    Ok(components.join("/"))
+}
+
+/// Collects directory names from `ino` up to the tree's root directory,
+/// innermost first
+fn dir_components(fs: &BtrfsFilesystem, tree_id: u64, mut ino: u64) -> Result<Vec<String>> {
+    let root = read_root_item(fs, tree_id)?;
+    let tree = BtrfsTree::new(fs, root.bytenr, root.level);
+
+    let mut names = Vec::new();
+    while ino != root.root_dirid {
+        let min_key = BtrfsKey::new(ino, item_type::INODE_REF, 0);
+        let max_key = BtrfsKey::new(ino, item_type::INODE_REF, u64::MAX);
+
+        let (item, data) = tree
+            .search_range(&min_key, &max_key)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| BtrfsError::NotFound(format!("inode ref for {}", ino)))?;
+
+        let parent = item.key.offset;
+        if parent == ino {
+            break;
+        }
+
+        names.push(InodeRef::from_bytes(&data)?.name);
+        ino = parent;
+    }
+
+    Ok(names)
+}
+
+/// Translates a path inside a subvolume to a path from the top level
+///
+/// Both `/` and `\` are accepted as separators in `rel_path`; the result
+/// always starts with `/`.
+pub fn relative_to_absolute(
+    fs: &BtrfsFilesystem,
+    subvol_id: u64,
+    rel_path: &str,
+) -> Result<String> {
+    let base = subvolume_path(fs, subvol_id)?;
+    Ok(join_paths(&base, rel_path))
+}
+
+/// Joins a subvolume path and a path relative to it
+fn join_paths(base: &str, rel_path: &str) -> String {
+    let components: Vec<&str> = base
+        .split('/')
+        .chain(rel_path.split(['/', '\\']))
+        .filter(|c| !c.is_empty())
+        .collect();
+
+    format!("/{}", components.join("/"))
+}
+
+/// Builds a subvolume description from its ROOT_ITEM and back reference
+fn subvolume_from_root(fs: &BtrfsFilesystem, id: u64, root: RootItem) -> Result<Subvolume> {
+    let (parent_id, name) = match read_root_backref(fs, id)? {
+        Some((parent, backref)) => (parent, backref.name),
+        None => (0, String::new()),
+    };
+
+    Ok(Subvolume {
+        id,
+        parent_id,
+        generation: root.generation,
+        // Transaction the subvolume or snapshot was created in
+        parent_generation: root.otransid,
+        flags: root.flags,
+        uuid: root.uuid,
+        parent_uuid: root.parent_uuid,
+        received_uuid: root.received_uuid,
+        otime: root.otime,
+        stime: root.stime,
+        rtime: root.rtime,
+        name,
+        path: subvolume_path(fs, id)?,
+        root_bytenr: root.bytenr,
+        root_level: root.level,
+    })
+}
+
+/// Lists the snapshots of a subvolume
+///
+/// A snapshot records the UUID of its source as `parent_uuid`, so this
+/// returns every subvolume whose `parent_uuid` matches `subvol_id`'s UUID.
+pub fn snapshots_of(fs: &BtrfsFilesystem, subvol_id: u64) -> Result<Vec<Subvolume>> {
+    let target = read_root_item(fs, subvol_id)?;
+
+    // Subvolumes created by old kernels have no UUID; don't match every
+    // other UUID-less root against it
+    if target.uuid == [0u8; 16] {
+        return Ok(Vec::new());
+    }
+
+    let tree = BtrfsTree::new(fs, fs.superblock().root(), fs.superblock().root_level());
+
+    let min_key = BtrfsKey::new(objectid::FIRST_FREE, item_type::ROOT_ITEM, 0);
+    let max_key = BtrfsKey::new(objectid::LAST_FREE, item_type::ROOT_ITEM, u64::MAX);
+
+    let mut snapshots = Vec::new();
+    for (item, data) in tree.search_range(&min_key, &max_key)? {
+        if item.key.item_type != item_type::ROOT_ITEM || item.key.objectid == subvol_id {
+            continue;
+        }
+
+        let root = RootItem::from_bytes(&data)?;
+        if root.parent_uuid == target.uuid {
+            snapshots.push(subvolume_from_root(fs, item.key.objectid, root)?);
+        }
+    }
+
+    Ok(snapshots)
+}
+
+/// Lists all subvolumes in the filesystem, including snapshots
+///
+/// The top-level FS tree comes first, followed by every subvolume with a
+/// ROOT_ITEM in the root tree. Subvolumes without a back reference have
+/// been deleted and are waiting to be cleaned up, so they are skipped.
 pub fn list_subvolumes(fs: &BtrfsFilesystem) -> Result<Vec<Subvolume>> {
-    let mut subvolumes = Vec::new();
+    let mut subvolumes = vec![get_subvolume(fs, objectid::FS_TREE)?];
+
+    let tree = BtrfsTree::new(fs, fs.superblock().root(), fs.superblock().root_level());
 
-    // The root tree contains ROOT_ITEMs for all subvolumes
-    // For now, return the default subvolume
-    let default_subvol = get_subvolume(fs, objectid::FS_TREE)?;
-    subvolumes.push(default_subvol);
+    let min_key = BtrfsKey::new(objectid::FIRST_FREE, item_type::ROOT_ITEM, 0);
+    let max_key = BtrfsKey::new(objectid::LAST_FREE, item_type::ROOT_ITEM, u64::MAX);
+
+    let mut roots: Vec<(u64, RootItem)> = Vec::new();
+    for (item, data) in tree.search_range(&min_key, &max_key)? {
+        if item.key.item_type != item_type::ROOT_ITEM {
+            continue;
+        }
+
+        // Like read_root_item, keep the last ROOT_ITEM of each object ID
+        let root = RootItem::from_bytes(&data)?;
+        match roots.last_mut() {
+            Some((id, last)) if *id == item.key.objectid => *last = root,
+            _ => roots.push((item.key.objectid, root)),
+        }
+    }
 
-    // TODO: Iterate root tree for all ROOT_ITEMs with objectid >= FIRST_FREE
+    for (id, root) in roots {
+        if read_root_backref(fs, id)?.is_none() {
+            continue;
+        }
+        subvolumes.push(subvolume_from_root(fs, id, root)?);
+    }
 
     Ok(subvolumes)
 }
@@ -267,19 +507,256 @@ pub fn get_subvolume(fs: &BtrfsFilesystem, id: u64) -> Result<Subvolume> {
         });
     }
 
-    // TODO: Look up ROOT_ITEM in root tree
-    Err(BtrfsError::SubvolumeNotFound(id))
+    let root = read_root_item(fs, id)?;
+    subvolume_from_root(fs, id, root)
+}
+
+/// Returns the ID of the subvolume mounted when none is specified
+///
+/// `btrfs subvolume set-default` records it as the `default` entry of the
+/// root tree's directory and sets the DEFAULT_SUBVOL incompat flag;
+/// without them, the FS tree is the default.
+pub fn default_subvolume_id(fs: &BtrfsFilesystem) -> Result<u64> {
+    if !fs.superblock().has_incompat(incompat::DEFAULT_SUBVOL) {
+        return Ok(objectid::FS_TREE);
+    }
+
+    let tree = BtrfsTree::new(fs, fs.superblock().root(), fs.superblock().root_level());
+    let dir = fs.superblock().root_dir_objectid();
+
+    let key = BtrfsKey::new(dir, item_type::DIR_ITEM, btrfs_name_hash("default"));
+    match tree.search(&key)? {
+        Some((_, data)) => Ok(DirEntry::from_bytes(&data)?.ino),
+        None => Ok(objectid::FS_TREE),
+    }
 }
 
-/// Creates a snapshot of a subvolume
+/// Size of a ROOT_ITEM written by current kernels
+const ROOT_ITEM_SIZE: usize = 439;
+
+/// Directory entry type of a directory, which a subvolume appears as
+const FT_DIR: u8 = 2;
+
+/// Creates a snapshot of subvolume `source_id` named `name`, in the
+/// directory holding the source (the top level for the FS tree itself)
+///
+/// The snapshot shares every block with the source: the source's root node
+/// is copied and everything it points at gains a reference. Fails with
+/// [`BtrfsError::ReadOnly`] on a read-only mount, and with
+/// [`BtrfsError::UnsupportedFeature`] where the directory gaining the entry
+/// shares blocks with an earlier snapshot, since those would have to be
+/// unshared first.
 pub fn create_snapshot(
-    _fs: &BtrfsFilesystem,
-    _source_id: u64,
-    _name: &str,
-    _readonly: bool,
+    fs: &mut BtrfsFilesystem,
+    source_id: u64,
+    name: &str,
+    readonly: bool,
 ) -> Result<Subvolume> {
-    // TODO: Implement snapshot creation
-    Err(BtrfsError::ReadOnly)
+    check_name(name)?;
+    if source_id != objectid::FS_TREE
+        && !(objectid::FIRST_FREE..=objectid::LAST_FREE).contains(&source_id)
+    {
+        return Err(BtrfsError::SubvolumeNotFound(source_id));
+    }
+
+    let (parent, dirid) = match read_root_backref(fs, source_id)? {
+        Some((parent, backref)) => (parent, backref.dirid),
+        None if source_id == objectid::FS_TREE => (source_id, objectid::FIRST_FREE),
+        None => return Err(BtrfsError::SubvolumeNotFound(source_id)),
+    };
+
+    let tree = BtrfsTree::new(fs, fs.superblock().root(), fs.superblock().root_level());
+    let (source_key, source_item) = tree
+        .search_range(
+            &BtrfsKey::new(source_id, item_type::ROOT_ITEM, 0),
+            &BtrfsKey::new(source_id, item_type::ROOT_ITEM, u64::MAX),
+        )?
+        .pop()
+        .map(|(item, data)| (item.key, data))
+        .ok_or(BtrfsError::SubvolumeNotFound(source_id))?;
+    let source = RootItem::from_bytes(&source_item)?;
+    let id = fs.next_free_objectid(objectid::ROOT_TREE)?;
+
+    let mut txn = Transaction::start(fs)?;
+    let transid = txn.generation();
+    let now = now();
+    let (bytenr, level) = txn.copy_root(source.bytenr, id)?;
+
+    // The snapshot's ROOT_ITEM starts as a copy of the source's
+    let mut item = source_item;
+    item.resize(ROOT_ITEM_SIZE, 0);
+    LittleEndian::write_u64(&mut item[160..168], transid); // generation
+    LittleEndian::write_u64(&mut item[176..184], bytenr);
+    LittleEndian::write_u64(&mut item[200..208], transid); // last_snapshot
+    let flags = match readonly {
+        true => source.flags | subvol_flags::RDONLY,
+        false => source.flags & !subvol_flags::RDONLY,
+    };
+    LittleEndian::write_u64(&mut item[208..216], flags);
+    LittleEndian::write_u32(&mut item[216..220], 1); // refs
+    item[220..238].fill(0); // drop_progress and drop_level
+    item[238] = level;
+    LittleEndian::write_u64(&mut item[239..247], transid); // generation_v2
+    item[247..263].copy_from_slice(uuid::Uuid::new_v4().as_bytes());
+    item[263..279].copy_from_slice(&source.uuid); // parent_uuid
+    if !readonly {
+        // Only a read-only snapshot can stand in for a received subvolume
+        item[279..295].fill(0); // received_uuid
+        item[311..327].fill(0); // stransid and rtransid
+        item[351..375].fill(0); // stime and rtime
+    }
+    LittleEndian::write_u64(&mut item[303..311], transid); // otransid
+    item[339..351].copy_from_slice(&now); // otime
+
+    txn.insert(
+        objectid::ROOT_TREE,
+        BtrfsKey::new(id, item_type::ROOT_ITEM, transid),
+        item,
+    )?;
+    txn.update(objectid::ROOT_TREE, &source_key, |data| {
+        LittleEndian::write_u64(&mut data[200..208], transid); // last_snapshot
+        Ok(())
+    })?;
+
+    let index = link_subvolume(&mut txn, parent, dirid, id, name, &now)?;
+    let root_ref = root_ref_bytes(dirid, index, name);
+    txn.insert(
+        objectid::ROOT_TREE,
+        BtrfsKey::new(parent, item_type::ROOT_REF, id),
+        root_ref.clone(),
+    )?;
+    txn.insert(
+        objectid::ROOT_TREE,
+        BtrfsKey::new(id, item_type::ROOT_BACKREF, parent),
+        root_ref,
+    )?;
+
+    txn.commit()?;
+    fs.refresh_superblock()?;
+    get_subvolume(fs, id)
+}
+
+/// Fails unless `name` can be a directory entry
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || name.len() > MAX_NAME_LEN
+        || name == "."
+        || name == ".."
+        || name.contains(['/', '\0'])
+    {
+        return Err(BtrfsError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid subvolume name {:?}", name),
+        )));
+    }
+    Ok(())
+}
+
+/// Adds the directory entry for subvolume `id` as `name` in directory
+/// `dirid` of tree `tree_id`, returning its directory index
+fn link_subvolume(
+    txn: &mut Transaction<'_>,
+    tree_id: u64,
+    dirid: u64,
+    id: u64,
+    name: &str,
+    now: &[u8; 12],
+) -> Result<u64> {
+    let transid = txn.generation();
+    let hash = btrfs_name_hash(name);
+    let item_key = BtrfsKey::new(dirid, item_type::DIR_ITEM, hash);
+
+    // Names sharing a hash share the DIR_ITEM
+    let existing = txn.get(tree_id, &item_key)?;
+    let names = existing.as_deref().map(dir_item_names);
+    if names.is_some_and(|mut names| names.any(|n| n == name.as_bytes())) {
+        return Err(BtrfsError::Io(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} already exists", name),
+        )));
+    }
+
+    let last_index = txn.last_in(
+        tree_id,
+        &BtrfsKey::new(dirid, item_type::DIR_INDEX, 0),
+        &BtrfsKey::new(dirid, item_type::DIR_INDEX, u64::MAX),
+    )?;
+    // Indexes 0 and 1 stand for "." and ".."
+    let index = last_index.map_or(2, |(key, _)| (key.offset + 1).max(2));
+
+    let location = BtrfsKey::new(id, item_type::ROOT_ITEM, u64::MAX);
+    let mut entry = Vec::with_capacity(30 + name.len());
+    entry.extend_from_slice(&location.to_bytes());
+    entry.extend_from_slice(&transid.to_le_bytes());
+    entry.extend_from_slice(&0u16.to_le_bytes()); // data_len
+    entry.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    entry.push(FT_DIR);
+    entry.extend_from_slice(name.as_bytes());
+
+    match existing {
+        Some(_) => txn.update(tree_id, &item_key, |data| {
+            data.extend_from_slice(&entry);
+            Ok(())
+        })?,
+        None => txn.insert(tree_id, item_key, entry.clone())?,
+    }
+    txn.insert(
+        tree_id,
+        BtrfsKey::new(dirid, item_type::DIR_INDEX, index),
+        entry,
+    )?;
+
+    // Directory size counts each name once per DIR_ITEM and DIR_INDEX
+    let inode_key = BtrfsKey::new(dirid, item_type::INODE_ITEM, 0);
+    txn.update(tree_id, &inode_key, |data| {
+        if data.len() < 160 {
+            return Err(BtrfsError::Corrupt("Inode item too small".to_string()));
+        }
+        let size = LittleEndian::read_u64(&data[16..24]) + 2 * name.len() as u64;
+        let sequence = LittleEndian::read_u64(&data[72..80]) + 1;
+        LittleEndian::write_u64(&mut data[8..16], transid);
+        LittleEndian::write_u64(&mut data[16..24], size);
+        LittleEndian::write_u64(&mut data[72..80], sequence);
+        data[124..136].copy_from_slice(now); // ctime
+        data[136..148].copy_from_slice(now); // mtime
+        Ok(())
+    })?;
+
+    Ok(index)
+}
+
+/// Iterates over the names packed into a DIR_ITEM
+fn dir_item_names(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = data;
+    std::iter::from_fn(move || {
+        let header = rest.get(..30)?;
+        let data_len = LittleEndian::read_u16(&header[25..27]) as usize;
+        let name_len = LittleEndian::read_u16(&header[27..29]) as usize;
+        let name = rest.get(30..30 + name_len)?;
+        rest = rest.get(30 + name_len + data_len..).unwrap_or(&[]);
+        Some(name)
+    })
+}
+
+/// Serializes a ROOT_REF or ROOT_BACKREF
+fn root_ref_bytes(dirid: u64, sequence: u64, name: &str) -> Vec<u8> {
+    let mut data = Vec::with_capacity(18 + name.len());
+    data.extend_from_slice(&dirid.to_le_bytes());
+    data.extend_from_slice(&sequence.to_le_bytes());
+    data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    data.extend_from_slice(name.as_bytes());
+    data
+}
+
+/// Returns the current time as an on-disk timespec
+fn now() -> [u8; 12] {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut timespec = [0u8; 12];
+    LittleEndian::write_u64(&mut timespec[0..8], since_epoch.as_secs());
+    LittleEndian::write_u32(&mut timespec[8..12], since_epoch.subsec_nanos());
+    timespec
 }
 
 /// Deletes a subvolume
@@ -291,6 +768,9 @@ pub fn delete_subvolume(_fs: &BtrfsFilesystem, _id: u64) -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::superblock::compat_ro;
+    use crate::test_utils::{check_extents, dir_item, extent_data, extent_item, ImageBuilder};
+    use std::io::ErrorKind;
 
     #[test]
     fn test_subvol_flags() {
@@ -474,4 +954,363 @@ mod tests {
         assert_eq!(cloned.name, subvol.name);
         assert_eq!(cloned.uuid, subvol.uuid);
     }
+
+    fn nested_subvolumes() -> BtrfsFilesystem {
+        let root = objectid::FIRST_FREE;
+        ImageBuilder::new()
+            .root_dir(objectid::FS_TREE)
+            .subvolume(256, objectid::FS_TREE, root, "@")
+            .subvolume(257, 256, root, "var")
+            .dir(256, root, 257, "srv")
+            .subvolume(258, 256, 257, "data")
+            .open()
+    }
+
+    #[test]
+    fn test_snapshots_of() {
+        let root = objectid::FIRST_FREE;
+        let fs = ImageBuilder::new()
+            .root_dir(objectid::FS_TREE)
+            .subvolume(256, objectid::FS_TREE, root, "@")
+            .subvolume(257, objectid::FS_TREE, root, "@-snap1")
+            .subvolume(258, objectid::FS_TREE, root, "@-snap2")
+            .subvolume(259, objectid::FS_TREE, root, "@home")
+            .subvolume(260, objectid::FS_TREE, root, "@home-snap")
+            .root_uuid(256, [1; 16], [0; 16])
+            .root_uuid(257, [2; 16], [1; 16])
+            .root_uuid(258, [3; 16], [1; 16])
+            .root_uuid(259, [4; 16], [0; 16])
+            .root_uuid(260, [5; 16], [4; 16])
+            .open();
+
+        let snapshots = snapshots_of(&fs, 256).unwrap();
+        let ids: Vec<u64> = snapshots.iter().map(|s| s.id).collect();
+        assert_eq!(ids, vec![257, 258]);
+        assert_eq!(snapshots[0].name, "@-snap1");
+        assert_eq!(snapshots[1].path, "@-snap2");
+        assert_eq!(snapshots[1].parent_id, objectid::FS_TREE);
+        assert_eq!(snapshots[1].uuid, [3; 16]);
+        assert_eq!(snapshots[1].parent_uuid, [1; 16]);
+
+        let ids: Vec<u64> = snapshots_of(&fs, 259).unwrap().iter().map(|s| s.id).collect();
+        assert_eq!(ids, vec![260]);
+        assert!(snapshots_of(&fs, 258).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_list_subvolumes() {
+        let root = objectid::FIRST_FREE;
+        let mut builder = ImageBuilder::new();
+        builder
+            .root_dir(objectid::FS_TREE)
+            .subvolume(256, objectid::FS_TREE, root, "@")
+            .subvolume(257, 256, root, "var")
+            .subvolume(258, objectid::FS_TREE, root, "@-snap")
+            .root_uuid(256, [1; 16], [0; 16])
+            .root_uuid(258, [2; 16], [1; 16])
+            // A deleted subvolume keeps its ROOT_ITEM until cleaned up
+            .root_dir(259);
+        let fs = builder.open();
+
+        let subvolumes = list_subvolumes(&fs).unwrap();
+        let ids: Vec<u64> = subvolumes.iter().map(|s| s.id).collect();
+        assert_eq!(ids, vec![objectid::FS_TREE, 256, 257, 258]);
+
+        let paths: Vec<&str> = subvolumes.iter().map(|s| s.path.as_str()).collect();
+        assert_eq!(paths, vec!["/", "@", "@/var", "@-snap"]);
+        assert_eq!(subvolumes[2].parent_id, 256);
+
+        let snapshots: Vec<u64> = subvolumes
+            .iter()
+            .filter(|s| s.is_snapshot())
+            .map(|s| s.id)
+            .collect();
+        assert_eq!(snapshots, vec![258]);
+    }
+
+    #[test]
+    fn test_get_subvolume() {
+        let root = objectid::FIRST_FREE;
+        let fs = ImageBuilder::new()
+            .root_dir(objectid::FS_TREE)
+            .subvolume(256, objectid::FS_TREE, root, "@")
+            .subvolume(257, 256, root, "var")
+            .root_uuid(257, [7; 16], [0; 16])
+            .open();
+
+        let var = get_subvolume(&fs, 257).unwrap();
+        assert_eq!(var.name, "var");
+        assert_eq!(var.path, "@/var");
+        assert_eq!(var.parent_id, 256);
+        assert_eq!(var.uuid, [7; 16]);
+        assert_eq!(var.root_bytenr, read_root_item(&fs, 257).unwrap().bytenr);
+        assert!(!var.is_snapshot());
+
+        assert!(matches!(
+            get_subvolume(&fs, 999),
+            Err(BtrfsError::SubvolumeNotFound(999))
+        ));
+    }
+
+    #[test]
+    fn test_default_subvolume() {
+        let root = objectid::FIRST_FREE;
+        let mut builder = ImageBuilder::new();
+        builder
+            .root_dir(objectid::FS_TREE)
+            .subvolume(256, objectid::FS_TREE, root, "@");
+        let fs = builder.open();
+        assert_eq!(fs.default_subvolume().unwrap().id, objectid::FS_TREE);
+
+        // `btrfs subvolume set-default 256`
+        let dir = 6;
+        let location = BtrfsKey::new(256, item_type::ROOT_ITEM, u64::MAX);
+        builder.insert(
+            objectid::ROOT_TREE,
+            BtrfsKey::new(dir, item_type::DIR_ITEM, btrfs_name_hash("default")),
+            dir_item(&location, 2, "default"),
+        );
+
+        // The entry only counts once DEFAULT_SUBVOL is set
+        let fs = builder.open();
+        assert_eq!(default_subvolume_id(&fs).unwrap(), objectid::FS_TREE);
+        builder.incompat(incompat::DEFAULT_SUBVOL);
+        let default = builder.open().default_subvolume().unwrap();
+        assert_eq!((default.id, default.name.as_str()), (256, "@"));
+    }
+
+    #[test]
+    fn test_snapshots_of_without_uuid() {
+        let fs = nested_subvolumes();
+        assert!(snapshots_of(&fs, 256).unwrap().is_empty());
+        assert!(matches!(
+            snapshots_of(&fs, 999),
+            Err(BtrfsError::SubvolumeNotFound(999))
+        ));
+    }
+
+    #[test]
+    fn test_root_ref_from_bytes() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&256u64.to_le_bytes());
+        data.extend_from_slice(&3u64.to_le_bytes());
+        data.extend_from_slice(&3u16.to_le_bytes());
+        data.extend_from_slice(b"var");
+
+        let root_ref = RootRef::from_bytes(&data).unwrap();
+        assert_eq!(root_ref.dirid, 256);
+        assert_eq!(root_ref.sequence, 3);
+        assert_eq!(root_ref.name, "var");
+
+        assert!(RootRef::from_bytes(&data[..20]).is_err());
+    }
+
+    #[test]
+    fn test_subvolume_path() {
+        let fs = nested_subvolumes();
+        assert_eq!(subvolume_path(&fs, objectid::FS_TREE).unwrap(), "");
+        assert_eq!(subvolume_path(&fs, 256).unwrap(), "@");
+        assert_eq!(subvolume_path(&fs, 257).unwrap(), "@/var");
+        assert_eq!(subvolume_path(&fs, 258).unwrap(), "@/srv/data");
+    }
+
+    #[test]
+    fn test_subvolume_relative_to_absolute() {
+        let fs = nested_subvolumes();
+
+        assert_eq!(
+            fs.subvolume_relative_to_absolute(257, "log/messages").unwrap(),
+            "/@/var/log/messages"
+        );
+        assert_eq!(
+            fs.subvolume_relative_to_absolute(257, "\\log\\messages").unwrap(),
+            "/@/var/log/messages"
+        );
+        assert_eq!(fs.subvolume_relative_to_absolute(257, "/").unwrap(), "/@/var");
+        assert_eq!(
+            fs.subvolume_relative_to_absolute(objectid::FS_TREE, "etc").unwrap(),
+            "/etc"
+        );
+    }
+
+    #[test]
+    fn test_subvolume_relative_to_absolute_unknown() {
+        let fs = nested_subvolumes();
+        let result = fs.subvolume_relative_to_absolute(999, "x");
+        assert!(matches!(result, Err(BtrfsError::SubvolumeNotFound(999))));
+    }
+
+    const DATA_AT: u64 = 0x300000;
+
+    /// A writable image with subvolume `@` holding one file with a data
+    /// extent
+    fn writable_image() -> ImageBuilder {
+        let root = objectid::FIRST_FREE;
+        let mut builder = ImageBuilder::new();
+        builder
+            .incompat(incompat::MIXED_BACKREF | incompat::SKINNY_METADATA)
+            .account_extents()
+            .root_dir(objectid::FS_TREE)
+            .subvolume(256, objectid::FS_TREE, root, "@")
+            .root_uuid(256, [1; 16], [0; 16])
+            .file(256, root, 257, "file", 4096)
+            .insert(
+                256,
+                BtrfsKey::new(257, item_type::EXTENT_DATA, 0),
+                extent_data(DATA_AT, 4096),
+            )
+            .insert(
+                objectid::EXTENT_TREE,
+                BtrfsKey::new(DATA_AT, item_type::EXTENT_ITEM, 4096),
+                extent_item(1),
+            )
+            .data(DATA_AT, &[0x5A; 4096]);
+        builder
+    }
+
+    #[test]
+    fn test_create_snapshot() {
+        let device = writable_image().device();
+        let mut fs = BtrfsFilesystem::open(device.clone(), false).unwrap();
+        let generation = fs.generation();
+
+        let snapshot = fs.create_snapshot(256, "snap", true).unwrap();
+        assert_eq!(snapshot.id, 257);
+        assert_eq!(snapshot.path, "snap");
+        assert_eq!(snapshot.parent_id, objectid::FS_TREE);
+        assert_eq!(snapshot.parent_uuid, [1; 16]);
+        assert_eq!(snapshot.flags & subvol_flags::RDONLY, subvol_flags::RDONLY);
+        assert_eq!(fs.generation(), generation + 1);
+
+        let fs = BtrfsFilesystem::open(device, true).unwrap();
+        check_extents(&fs);
+        assert!(fs.check(objectid::FS_TREE).unwrap().is_clean());
+        let ids: Vec<u64> = fs.snapshots_of(256).unwrap().iter().map(|s| s.id).collect();
+        assert_eq!(ids, vec![257]);
+
+        let root = read_root_item(&fs, 257).unwrap();
+        assert_eq!(root.generation, generation + 1);
+        assert_eq!(
+            read_root_item(&fs, 256).unwrap().last_snapshot,
+            generation + 1
+        );
+
+        // The snapshot sees the source's file and its data
+        let key = BtrfsKey::new(257, item_type::EXTENT_DATA, 0);
+        assert_eq!(
+            fs.get_item(257u64, key).unwrap(),
+            Some(extent_data(DATA_AT, 4096))
+        );
+    }
+
+    #[test]
+    fn test_create_snapshot_writable() {
+        let device = writable_image().device();
+        let mut fs = BtrfsFilesystem::open(device.clone(), false).unwrap();
+        fs.create_snapshot(256, "a", false).unwrap();
+        let b = fs.create_snapshot(256, "b", false).unwrap();
+        assert_eq!(b.flags & subvol_flags::RDONLY, 0);
+
+        // Snapshotting the top level links the snapshot into itself
+        let top = fs.create_snapshot(objectid::FS_TREE, "top", false).unwrap();
+        assert_eq!(top.parent_id, objectid::FS_TREE);
+
+        let fs = BtrfsFilesystem::open(device, true).unwrap();
+        check_extents(&fs);
+        assert!(fs.check(objectid::FS_TREE).unwrap().is_clean());
+        let paths: Vec<String> = list_subvolumes(&fs)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.path)
+            .collect();
+        assert_eq!(paths, vec!["/", "@", "a", "b", "top"]);
+    }
+
+    #[test]
+    fn test_create_snapshot_errors() {
+        let builder = writable_image();
+        let mut fs = BtrfsFilesystem::open(builder.device(), true).unwrap();
+        assert!(matches!(
+            fs.create_snapshot(256, "snap", true),
+            Err(BtrfsError::ReadOnly)
+        ));
+
+        let mut fs = BtrfsFilesystem::open(builder.device(), false).unwrap();
+        let generation = fs.generation();
+        let kind = |result: Result<Subvolume>| match result {
+            Err(BtrfsError::Io(e)) => e.kind(),
+            other => panic!("unexpected {:?}", other.map(|s| s.id)),
+        };
+        assert_eq!(
+            kind(fs.create_snapshot(256, "@", true)),
+            ErrorKind::AlreadyExists
+        );
+        assert_eq!(
+            kind(fs.create_snapshot(256, "a/b", true)),
+            ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            kind(fs.create_snapshot(256, "..", true)),
+            ErrorKind::InvalidInput
+        );
+        assert!(matches!(
+            fs.create_snapshot(999, "snap", true),
+            Err(BtrfsError::SubvolumeNotFound(999))
+        ));
+        assert_eq!(fs.generation(), generation);
+    }
+
+    #[test]
+    fn test_create_snapshot_shared_parent() {
+        let root = objectid::FIRST_FREE;
+        let mut builder = ImageBuilder::new();
+        builder
+            .node_size(4096)
+            .incompat(incompat::MIXED_BACKREF)
+            .compat_ro(compat_ro::FREE_SPACE_TREE | compat_ro::FREE_SPACE_TREE_VALID)
+            .account_extents()
+            .root_dir(objectid::FS_TREE)
+            .subvolume(256, objectid::FS_TREE, root, "@")
+            .subvolume(300, 256, root, "nested");
+        for ino in 1000..1200 {
+            builder.file(256, root, ino, &format!("file{}", ino), 0);
+        }
+        let device = builder.device();
+        let mut fs = BtrfsFilesystem::open(device.clone(), false).unwrap();
+
+        for i in 0..20 {
+            fs.create_snapshot(256, &format!("@-{}", i), true).unwrap();
+        }
+        check_extents(&fs);
+        assert_eq!(list_subvolumes(&fs).unwrap().len(), 23);
+
+        // Linking into `@` would rewrite leaves its snapshots share
+        let generation = fs.generation();
+        assert!(matches!(
+            fs.create_snapshot(300, "nested-snap", true),
+            Err(BtrfsError::UnsupportedFeature(_))
+        ));
+        assert_eq!(fs.generation(), generation);
+
+        let fs = BtrfsFilesystem::open(device, true).unwrap();
+        check_extents(&fs);
+        assert!(fs.check(256).unwrap().is_clean());
+        assert_eq!(fs.get_subvolume(320).unwrap().path, "@-19");
+    }
+
+    #[test]
+    fn test_create_snapshot_free_space_bitmaps() {
+        let mut builder = writable_image();
+        builder
+            .compat_ro(compat_ro::FREE_SPACE_TREE | compat_ro::FREE_SPACE_TREE_VALID)
+            .free_space_bitmaps();
+        let device = builder.device();
+        let mut fs = BtrfsFilesystem::open(device.clone(), false).unwrap();
+        check_extents(&fs);
+
+        for i in 0..5 {
+            fs.create_snapshot(256, &format!("@-{}", i), true).unwrap();
+        }
+        check_extents(&BtrfsFilesystem::open(device, true).unwrap());
+    }
 }
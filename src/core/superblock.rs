@@ -3,10 +3,13 @@
 //! The superblock is located at offset 0x10000 (64 KiB) with mirrors at
 //! 0x4000000 (64 MiB) and 0x4000000000 (256 GiB).
 
-use super::{BtrfsError, Result, BTRFS_MAGIC, SUPERBLOCK_OFFSET};
+use super::{
+    BtrfsError, Checksum, Result, BTRFS_MAGIC, MAX_NODE_SIZE, MIN_SECTOR_SIZE,
+    SUPERBLOCK_MIRROR1_OFFSET, SUPERBLOCK_MIRROR2_OFFSET, SUPERBLOCK_OFFSET,
+};
 use crate::blockdev::BlockDevice;
-use byteorder::{ByteOrder, LittleEndian};
-use zerocopy::{FromBytes, Immutable, KnownLayout};
+use std::borrow::Cow;
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
 
 /// Size of the superblock structure
 pub const SUPERBLOCK_SIZE: usize = 0x1000;
@@ -17,7 +20,7 @@ pub const CSUM_TYPE_CRC32C: u16 = 0;
 /// Superblock structure
 ///
 /// This is the on-disk format of the BTRFS superblock.
-#[derive(Debug, Clone, Copy, FromBytes, KnownLayout, Immutable)]
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, KnownLayout, Immutable)]
 #[repr(C, packed)]
 pub struct SuperblockRaw {
     /// Checksum of everything from offset 0x20 to 0x1000
@@ -106,6 +109,136 @@ impl Superblock {
         Self::parse(&buf)
     }
 
+    /// Reads the newest valid superblock among the primary and its mirrors
+    ///
+    /// Mirrors beyond the end of the device are skipped. As in the kernel's
+    /// `btrfs_validate_super`, a copy whose `bytenr` is not the offset it
+    /// was read from is invalid, and a mirror must belong to the same
+    /// filesystem as the primary (or, with the primary invalid, as the
+    /// first valid mirror), so stale copies left by an earlier filesystem
+    /// are ignored. Returns the superblock together with the offset it was
+    /// read from, and fails with the primary's error only if no copy is
+    /// valid.
+    pub fn read_with_mirrors(device: &dyn BlockDevice) -> Result<(Self, u64)> {
+        let primary = Self::read_copy(device, SUPERBLOCK_OFFSET);
+        let mut best = primary
+            .as_ref()
+            .ok()
+            .map(|sb| (sb.clone(), SUPERBLOCK_OFFSET));
+        let mut fsid = best.as_ref().map(|(sb, _)| sb.raw.fsid);
+
+        for offset in [SUPERBLOCK_MIRROR1_OFFSET, SUPERBLOCK_MIRROR2_OFFSET] {
+            if offset + SUPERBLOCK_SIZE as u64 > device.size() {
+                continue;
+            }
+            let Ok(sb) = Self::read_copy(device, offset) else {
+                continue;
+            };
+            if *fsid.get_or_insert(sb.raw.fsid) != sb.raw.fsid {
+                continue;
+            }
+            if best
+                .as_ref()
+                .is_none_or(|(b, _)| sb.generation() > b.generation())
+            {
+                best = Some((sb, offset));
+            }
+        }
+
+        // Without a valid copy, primary holds the error to report
+        match best {
+            Some(found) => Ok(found),
+            None => primary.map(|sb| (sb, SUPERBLOCK_OFFSET)),
+        }
+    }
+
+    /// Reads the superblock copy at `offset`, checking that it records
+    /// that offset as its own
+    fn read_copy(device: &dyn BlockDevice, offset: u64) -> Result<Self> {
+        let mut buf = [0u8; SUPERBLOCK_SIZE];
+        device.read_at(offset, &mut buf)?;
+        let sb = Self::parse(&buf)?;
+        let bytenr = sb.raw.bytenr;
+        if bytenr != offset {
+            return Err(BtrfsError::Corrupt(format!(
+                "superblock at {:#x} records bytenr {:#x}",
+                offset, bytenr
+            )));
+        }
+        Ok(sb)
+    }
+
+    /// Writes this superblock to the primary location and every mirror that
+    /// fits on the device
+    ///
+    /// Each copy records its own offset in `bytenr` and gets a freshly
+    /// computed checksum. The mirrors are written and flushed before the
+    /// primary, so a crash part way leaves either the old primary or a
+    /// complete set of new copies, and the newest valid copy wins on mount.
+    pub fn write_all(&self, device: &dyn BlockDevice) -> Result<()> {
+        if device.is_read_only() {
+            return Err(BtrfsError::ReadOnly);
+        }
+
+        for offset in [SUPERBLOCK_MIRROR2_OFFSET, SUPERBLOCK_MIRROR1_OFFSET] {
+            if offset + SUPERBLOCK_SIZE as u64 <= device.size() {
+                device.write_at(offset, &self.to_bytes(offset)?)?;
+            }
+        }
+        device.flush_device()?;
+
+        device.write_at(SUPERBLOCK_OFFSET, &self.to_bytes(SUPERBLOCK_OFFSET)?)?;
+        device.flush_device()?;
+        Ok(())
+    }
+
+    /// Serializes the superblock for the copy at `bytenr`, with its checksum
+    pub fn to_bytes(&self, bytenr: u64) -> Result<Vec<u8>> {
+        let mut copy = self.clone();
+        copy.raw.bytenr = bytenr;
+        Ok(copy.with_checksum().to_vec())
+    }
+
+    /// Serializes the superblock with a freshly computed checksum, ready to
+    /// write
+    ///
+    /// The checksum covers bytes `0x20..` and uses the superblock's own
+    /// algorithm.
+    pub fn with_checksum(&self) -> [u8; SUPERBLOCK_SIZE] {
+        let mut raw = self.raw;
+        raw.csum = [0; 32];
+
+        let mut data = [0u8; SUPERBLOCK_SIZE];
+        data.copy_from_slice(raw.as_bytes());
+        // Parsing verified the checksum, so the algorithm is supported
+        let checksum = self
+            .checksum_type()
+            .expect("superblock checksum type was verified on parse");
+        let csum = checksum
+            .compute(&data[0x20..])
+            .expect("superblock checksum type was verified on parse")
+            .to_le_bytes();
+        let size = checksum.size().min(csum.len());
+        data[..size].copy_from_slice(&csum[..size]);
+        data
+    }
+
+    /// Sets the generation, as a commit does before writing the superblock
+    pub fn set_generation(&mut self, generation: u64) {
+        self.raw.generation = generation;
+    }
+
+    /// Points the superblock at a new root tree root, as a commit does
+    pub fn set_root(&mut self, root: u64, level: u8) {
+        self.raw.root = root;
+        self.raw.root_level = level;
+    }
+
+    /// Sets the bytes allocated to extents, as a commit does
+    pub fn set_bytes_used(&mut self, bytes_used: u64) {
+        self.raw.bytes_used = bytes_used;
+    }
+
     /// Parses a superblock from raw bytes
     pub fn parse(data: &[u8]) -> Result<Self> {
         if data.len() < SUPERBLOCK_SIZE {
@@ -128,10 +261,37 @@ impl Superblock {
 
         // Verify checksum
         superblock.verify_checksum(data)?;
+        superblock.validate_sizes()?;
 
         Ok(superblock)
     }
 
+    /// Checks that the sector and node sizes are powers of two the kernel
+    /// accepts, so node buffers are never sized from a corrupt field
+    ///
+    /// Sectors are 4 KiB to 64 KiB; nodes are at least a sector and at most
+    /// 64 KiB.
+    fn validate_sizes(&self) -> Result<()> {
+        let sector_size = self.sector_size();
+        if !sector_size.is_power_of_two()
+            || !(MIN_SECTOR_SIZE..=MAX_NODE_SIZE).contains(&sector_size)
+        {
+            return Err(BtrfsError::Corrupt(format!(
+                "Invalid sector size {}",
+                sector_size
+            )));
+        }
+
+        let node_size = self.node_size();
+        if !node_size.is_power_of_two() || !(sector_size..=MAX_NODE_SIZE).contains(&node_size) {
+            return Err(BtrfsError::Corrupt(format!(
+                "Invalid node size {} for sector size {}",
+                node_size, sector_size
+            )));
+        }
+        Ok(())
+    }
+
     /// Parses a superblock and verifies its checksum
     pub fn parse_and_verify(data: &[u8]) -> Result<Self> {
         Self::parse(data)
@@ -140,24 +300,15 @@ impl Superblock {
     /// Verifies the superblock checksum
     fn verify_checksum(&self, data: &[u8]) -> Result<()> {
         // Copy packed struct fields to avoid unaligned reference
-        let csum_type = { self.raw.csum_type };
         let csum = { self.raw.csum };
-        
-        if csum_type != CSUM_TYPE_CRC32C {
-            return Err(BtrfsError::UnsupportedFeature(format!(
-                "Unsupported checksum type: {}",
-                csum_type
-            )));
-        }
-
-        let expected = LittleEndian::read_u32(&csum[..4]);
-        let actual = super::checksum::crc32c(&data[0x20..SUPERBLOCK_SIZE]);
 
-        if expected != actual {
-            return Err(BtrfsError::ChecksumMismatch { expected, actual });
-        }
+        self.checksum_type()?
+            .verify(&data[0x20..SUPERBLOCK_SIZE], &csum)
+    }
 
-        Ok(())
+    /// Returns the checksum algorithm used for metadata and data
+    pub fn checksum_type(&self) -> Result<Checksum> {
+        Checksum::from_type(self.raw.csum_type)
     }
 
     /// Returns the filesystem UUID
@@ -165,11 +316,16 @@ impl Superblock {
         uuid::Uuid::from_bytes(self.raw.fsid)
     }
 
-    /// Returns the filesystem label
-    pub fn label(&self) -> &str {
+    /// Returns the filesystem label, replacing invalid UTF-8 sequences
+    pub fn label(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.label_bytes())
+    }
+
+    /// Returns the raw label bytes, up to the first NUL
+    pub fn label_bytes(&self) -> &[u8] {
         let label = &self.raw.label;
         let end = label.iter().position(|&b| b == 0).unwrap_or(label.len());
-        std::str::from_utf8(&label[..end]).unwrap_or("")
+        &label[..end]
     }
 
     /// Returns the generation number
@@ -247,6 +403,17 @@ impl Superblock {
         &self.raw.sys_chunk_array[..self.raw.sys_chunk_array_size as usize]
     }
 
+    /// Returns the ID of the device this superblock was read from
+    pub fn devid(&self) -> u64 {
+        u64::from_le_bytes(self.raw.dev_item[..8].try_into().unwrap())
+    }
+
+    /// Returns the size of the device this superblock was read from, as
+    /// recorded in its device item
+    pub fn dev_total_bytes(&self) -> u64 {
+        u64::from_le_bytes(self.raw.dev_item[8..16].try_into().unwrap())
+    }
+
     /// Returns the compatible feature flags
     pub fn compat_flags(&self) -> u64 {
         self.raw.compat_flags
@@ -262,6 +429,25 @@ impl Superblock {
         self.raw.incompat_flags
     }
 
+    /// Returns true if the given incompatible feature flag is set
+    pub fn has_incompat(&self, flag: u64) -> bool {
+        self.raw.incompat_flags & flag != 0
+    }
+
+    /// Returns true if the given read-only compatible feature flag is set
+    pub fn has_compat_ro(&self, flag: u64) -> bool {
+        self.raw.compat_ro_flags & flag != 0
+    }
+
+    /// Returns true if the free space tree exists and is up to date
+    ///
+    /// A kernel without free space tree support can mount the filesystem
+    /// read-write and leave the tree stale; it then clears the VALID flag.
+    pub fn free_space_tree_valid(&self) -> bool {
+        let flags = compat_ro::FREE_SPACE_TREE | compat_ro::FREE_SPACE_TREE_VALID;
+        self.raw.compat_ro_flags & flags == flags
+    }
+
     /// Returns the checksum type
     pub fn csum_type(&self) -> u16 {
         self.raw.csum_type
@@ -291,9 +477,144 @@ pub mod incompat {
     pub const EXTENT_TREE_V2: u64 = 1 << 13;
 }
 
+/// Read-only compatible feature flags
+pub mod compat_ro {
+    pub const FREE_SPACE_TREE: u64 = 1 << 0;
+    pub const FREE_SPACE_TREE_VALID: u64 = 1 << 1;
+    pub const VERITY: u64 = 1 << 2;
+    pub const BLOCK_GROUP_TREE: u64 = 1 << 3;
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_utils::{ImageBuilder, MemDevice};
+    use std::sync::Arc;
+
+    fn mirrored_device(generations: [Option<u64>; 2]) -> Arc<MemDevice> {
+        let mut image = vec![0u8; (SUPERBLOCK_MIRROR1_OFFSET + 0x10000) as usize];
+        for (offset, generation) in [SUPERBLOCK_OFFSET, SUPERBLOCK_MIRROR1_OFFSET]
+            .into_iter()
+            .zip(generations)
+        {
+            if let Some(generation) = generation {
+                let sb = ImageBuilder::new()
+                    .generation(generation)
+                    .superblock_bytes();
+                let sb = Superblock::parse(&sb).unwrap().to_bytes(offset).unwrap();
+                image[offset as usize..offset as usize + sb.len()].copy_from_slice(&sb);
+            }
+        }
+        Arc::new(MemDevice::new(image))
+    }
+
+    #[test]
+    fn test_write_all_updates_mirrors() {
+        let device = mirrored_device([Some(7), Some(7)]);
+        let (mut sb, _) = Superblock::read_with_mirrors(device.as_ref()).unwrap();
+
+        sb.set_generation(8);
+        sb.write_all(device.as_ref()).unwrap();
+
+        for offset in [SUPERBLOCK_OFFSET, SUPERBLOCK_MIRROR1_OFFSET] {
+            let mut buf = [0u8; SUPERBLOCK_SIZE];
+            device.read_at(offset, &mut buf).unwrap();
+            let copy = Superblock::parse(&buf).unwrap();
+            assert_eq!(copy.generation(), 8);
+            assert_eq!({ copy.raw.bytenr }, offset);
+        }
+
+        // Mirror 2 lies past the end of this device and is skipped
+        assert!(device.size() < SUPERBLOCK_MIRROR2_OFFSET);
+
+        let read_only = MemDevice::read_only(vec![0u8; device.size() as usize]);
+        assert!(matches!(
+            sb.write_all(&read_only),
+            Err(BtrfsError::ReadOnly)
+        ));
+    }
+
+    #[test]
+    fn test_with_checksum_parses() {
+        let device = mirrored_device([Some(7), Some(7)]);
+        let mut sb = Superblock::read(device.as_ref()).unwrap();
+        sb.set_generation(9);
+
+        let mut image = sb.with_checksum();
+        let copy = Superblock::parse(&image).unwrap();
+        assert_eq!(copy.generation(), 9);
+        assert_eq!(copy.label(), sb.label());
+
+        image[0x100] ^= 0xFF;
+        assert!(Superblock::parse(&image).is_err());
+    }
+
+    #[test]
+    fn test_read_with_mirrors_primary() {
+        let device = mirrored_device([Some(7), Some(7)]);
+        let (sb, offset) = Superblock::read_with_mirrors(device.as_ref()).unwrap();
+        assert_eq!(offset, SUPERBLOCK_OFFSET);
+        assert_eq!(sb.generation(), 7);
+    }
+
+    #[test]
+    fn test_read_with_mirrors_corrupt_primary() {
+        let device = mirrored_device([Some(7), Some(6)]);
+        // Flip a byte covered by the checksum
+        device.write_at(SUPERBLOCK_OFFSET + 0x100, &[0xAA]).unwrap();
+        assert!(Superblock::read(device.as_ref()).is_err());
+
+        let (sb, offset) = Superblock::read_with_mirrors(device.as_ref()).unwrap();
+        assert_eq!(offset, SUPERBLOCK_MIRROR1_OFFSET);
+        assert_eq!(sb.generation(), 6);
+    }
+
+    #[test]
+    fn test_read_with_mirrors_newest_generation() {
+        let device = mirrored_device([Some(7), Some(8)]);
+        let (sb, offset) = Superblock::read_with_mirrors(device.as_ref()).unwrap();
+        assert_eq!(offset, SUPERBLOCK_MIRROR1_OFFSET);
+        assert_eq!(sb.generation(), 8);
+    }
+
+    #[test]
+    fn test_read_with_mirrors_all_invalid() {
+        let device = mirrored_device([None, None]);
+        assert!(matches!(
+            Superblock::read_with_mirrors(device.as_ref()),
+            Err(BtrfsError::InvalidMagic)
+        ));
+
+        // Mirrors past the end of a small device are not read
+        let small = MemDevice::new(vec![0u8; 0x20000]);
+        assert!(Superblock::read_with_mirrors(&small).is_err());
+    }
+
+    #[test]
+    fn test_read_with_mirrors_rejects_foreign_copies() {
+        // A newer mirror recording the wrong offset is not a valid copy
+        let device = mirrored_device([Some(7), None]);
+        let sb = ImageBuilder::new().generation(9).superblock_bytes();
+        device.write_at(SUPERBLOCK_MIRROR1_OFFSET, &sb).unwrap();
+        let (sb, offset) = Superblock::read_with_mirrors(device.as_ref()).unwrap();
+        assert_eq!((offset, sb.generation()), (SUPERBLOCK_OFFSET, 7));
+
+        // Nor is a newer mirror left behind by another filesystem
+        let mut other = Superblock::parse(&sb.with_checksum()).unwrap();
+        other.raw.fsid = [0x17; 16];
+        other.set_generation(9);
+        let bytes = other.to_bytes(SUPERBLOCK_MIRROR1_OFFSET).unwrap();
+        device.write_at(SUPERBLOCK_MIRROR1_OFFSET, &bytes).unwrap();
+        let (sb, offset) = Superblock::read_with_mirrors(device.as_ref()).unwrap();
+        assert_eq!((offset, sb.generation()), (SUPERBLOCK_OFFSET, 7));
+
+        // A primary at the wrong offset is rejected like a corrupt one
+        let device = mirrored_device([Some(7), Some(6)]);
+        let moved = sb.to_bytes(SUPERBLOCK_MIRROR1_OFFSET).unwrap();
+        device.write_at(SUPERBLOCK_OFFSET, &moved).unwrap();
+        let (sb, offset) = Superblock::read_with_mirrors(device.as_ref()).unwrap();
+        assert_eq!((offset, sb.generation()), (SUPERBLOCK_MIRROR1_OFFSET, 6));
+    }
 
     #[test]
     fn test_superblock_size() {
@@ -389,19 +710,66 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_superblock_invalid_sizes() {
+        fn with_sizes(sector_size: u32, node_size: u32) -> Result<Superblock> {
+            let mut data = create_mock_superblock_data();
+            data[0x90..0x94].copy_from_slice(&sector_size.to_le_bytes());
+            data[0x94..0x98].copy_from_slice(&node_size.to_le_bytes());
+            let csum = crate::core::checksum::crc32c(&data[0x20..]);
+            data[0..4].copy_from_slice(&csum.to_le_bytes());
+            Superblock::parse(&data)
+        }
+
+        for (sector_size, node_size) in [(4096, 4096), (4096, 65536), (65536, 65536)] {
+            let sb = with_sizes(sector_size, node_size).unwrap();
+            assert_eq!((sb.sector_size(), sb.node_size()), (sector_size, node_size));
+        }
+
+        for (sector_size, node_size) in [
+            (4096, 0),
+            (4096, 12288),
+            (4096, 131072),
+            (4096, 2048),
+            (8192, 4096),
+            (0, 16384),
+            (512, 16384),
+            (6144, 16384),
+            (131072, 131072),
+        ] {
+            assert!(
+                matches!(
+                    with_sizes(sector_size, node_size),
+                    Err(BtrfsError::Corrupt(_))
+                ),
+                "sector size {} node size {}",
+                sector_size,
+                node_size
+            );
+        }
+    }
+
     #[test]
     fn test_superblock_label() {
-        let mut data = create_mock_superblock_data();
-        // Set label at proper offset
-        let label = b"TestVolume";
-        // Label is at offset after dev_item, which is quite deep
-        // For simplicity, we'll test with empty label
-        
-        // Recalculate checksum
-        let csum = crate::core::checksum::crc32c(&data[0x20..]);
-        data[0..4].copy_from_slice(&csum.to_le_bytes());
-        
-        // This would require a valid parse which needs proper checksum
-        // For now, just verify the label function exists
+        fn with_label(label: &[u8]) -> Superblock {
+            let mut data = create_mock_superblock_data();
+            data[0x12b..0x12b + label.len()].copy_from_slice(label);
+            let csum = crate::core::checksum::crc32c(&data[0x20..]);
+            data[0..4].copy_from_slice(&csum.to_le_bytes());
+            Superblock::parse(&data).unwrap()
+        }
+
+        let sb = with_label(b"TestVolume");
+        assert_eq!(sb.label(), "TestVolume");
+        assert_eq!(sb.label_bytes(), b"TestVolume");
+
+        let sb = with_label(b"");
+        assert_eq!(sb.label(), "");
+        assert!(sb.label_bytes().is_empty());
+
+        // Latin-1 "café" is not UTF-8
+        let sb = with_label(b"caf\xe9");
+        assert_eq!(sb.label_bytes(), b"caf\xe9");
+        assert_eq!(sb.label(), "caf\u{FFFD}");
     }
 }
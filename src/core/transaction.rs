@@ -0,0 +1,1159 @@
+//! Copy-on-write metadata transactions
+//!
+//! A transaction changes tree items by writing every node on the path from
+//! the root to the affected leaf to a newly allocated block, leaving the
+//! committed trees untouched until the superblock is written. A node is
+//! copied once per transaction; later changes rewrite the copy in place.
+//!
+//! On commit, the extent tree, block group usage and free space tree are
+//! brought up to date for every block allocated or freed. That rewrites
+//! more nodes, so it repeats until nothing is left to account for, then the
+//! nodes are written and the superblock is pointed at the new root tree.
+//!
+//! Only what snapshot creation needs is supported. Rewriting a block that
+//! is shared with a snapshot is refused, as are filesystems with quotas, a
+//! tree log waiting to be replayed, or more than one device.
+
+use super::{
+    chunk::chunk_type,
+    extent::{extent_flags, ExtentItem, FREE_SPACE_USING_BITMAPS},
+    item_type, objectid, subvolume,
+    superblock::{compat_ro, incompat, SUPERBLOCK_SIZE},
+    tree::{BtrfsKey, NodeHeader, TreeNode, ITEM_SIZE, KEY_PTR_SIZE, NODE_HEADER_SIZE},
+    BtrfsError, BtrfsFilesystem, Result, Superblock, SUPERBLOCK_MIRROR1_OFFSET,
+    SUPERBLOCK_MIRROR2_OFFSET, SUPERBLOCK_OFFSET,
+};
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Bytes at the start of every device that are never allocated
+const DEVICE_RESERVED_BYTES: u64 = 1024 * 1024;
+
+/// A new reference to an existing extent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackRef {
+    /// From a node of tree `root`
+    Tree { root: u64 },
+    /// From a file extent item of inode `ino` in tree `root`, whose file
+    /// offset less its offset into the extent is `offset`
+    Data { root: u64, ino: u64, offset: u64 },
+}
+
+/// An extent tree change not yet applied
+#[derive(Debug, Clone, Copy)]
+enum ExtentOp {
+    /// A tree block was allocated for tree `owner`
+    Alloc { bytenr: u64, owner: u64, level: u8 },
+    /// A tree block is no longer referenced
+    Free { bytenr: u64 },
+    /// An existing extent gained a reference
+    AddRef { bytenr: u64, backref: BackRef },
+}
+
+/// Items of a leaf or key pointers of an internal node, being rewritten
+enum Entries {
+    Leaf(Vec<(BtrfsKey, Vec<u8>)>),
+    Internal(Vec<(BtrfsKey, u64, u64)>),
+}
+
+/// A block group that tree blocks can be allocated from
+struct MetadataGroup {
+    start: u64,
+    length: u64,
+    /// Allocated ranges, start to length, read when first needed
+    used: Option<BTreeMap<u64, u64>>,
+}
+
+/// A set of tree changes committed together
+pub struct Transaction<'a> {
+    fs: &'a BtrfsFilesystem,
+    generation: u64,
+    node_size: u64,
+    /// Nodes written in this transaction, by logical address
+    dirty: BTreeMap<u64, TreeNode>,
+    /// Root node and level of every tree changed so far
+    roots: BTreeMap<u64, (u64, u8)>,
+    /// Extent tree changes waiting to be applied
+    pending: Vec<ExtentOp>,
+    /// Committed blocks that gained a reference, so must not be replaced
+    shared: BTreeSet<u64>,
+    groups: Vec<MetadataGroup>,
+    /// Change in bytes allocated to extents
+    bytes_used: i64,
+}
+
+impl<'a> Transaction<'a> {
+    /// Starts a transaction on a writable filesystem
+    ///
+    /// Fails with [`BtrfsError::ReadOnly`] on a read-only mount or device,
+    /// and with [`BtrfsError::UnsupportedFeature`] if the filesystem uses
+    /// something a commit would have to maintain but doesn't.
+    pub fn start(fs: &'a BtrfsFilesystem) -> Result<Self> {
+        if fs.is_read_only() || fs.device().is_read_only() {
+            return Err(BtrfsError::ReadOnly);
+        }
+
+        let sb = fs.superblock();
+        let unsupported = |what: &str| {
+            Err(BtrfsError::UnsupportedFeature(format!(
+                "writing to a filesystem with {}",
+                what
+            )))
+        };
+        if sb.log_root() != 0 {
+            return unsupported("a tree log waiting to be replayed");
+        }
+        if sb.num_devices() != 1 {
+            return unsupported("more than one device");
+        }
+        if sb.has_incompat(incompat::ZONED | incompat::EXTENT_TREE_V2) {
+            return unsupported("a zoned or extent tree v2 layout");
+        }
+        if !sb.has_incompat(incompat::MIXED_BACKREF) {
+            return unsupported("old-style back references");
+        }
+        if sb.has_compat_ro(compat_ro::FREE_SPACE_TREE) && !sb.free_space_tree_valid() {
+            return unsupported("a stale free space tree");
+        }
+        if subvolume::read_root_item(fs, objectid::QUOTA_TREE).is_ok() {
+            return unsupported("quotas enabled");
+        }
+
+        let mut groups = Vec::new();
+        for chunk in fs.chunk_tree().chunks().values() {
+            if chunk.type_flags & chunk_type::METADATA == 0 {
+                continue;
+            }
+            if chunk.is_raid56() {
+                return unsupported("RAID5/6 metadata");
+            }
+            groups.push(MetadataGroup {
+                start: chunk.logical,
+                length: chunk.size,
+                used: None,
+            });
+        }
+
+        Ok(Self {
+            fs,
+            generation: sb.generation() + 1,
+            node_size: sb.node_size() as u64,
+            dirty: BTreeMap::new(),
+            roots: BTreeMap::new(),
+            pending: Vec::new(),
+            shared: BTreeSet::new(),
+            groups,
+            bytes_used: 0,
+        })
+    }
+
+    /// Returns the generation the transaction commits as
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Returns the node at `bytenr` as of this transaction
+    fn node(&self, bytenr: u64) -> Result<TreeNode> {
+        match self.dirty.get(&bytenr) {
+            Some(node) => Ok(node.clone()),
+            None => self.fs.node_at(bytenr),
+        }
+    }
+
+    /// Returns the root node and level of a tree as of this transaction
+    fn root(&self, tree_id: u64) -> Result<(u64, u8)> {
+        if let Some(&root) = self.roots.get(&tree_id) {
+            return Ok(root);
+        }
+
+        let sb = self.fs.superblock();
+        match tree_id {
+            objectid::ROOT_TREE => Ok((sb.root(), sb.root_level())),
+            objectid::CHUNK_TREE => Err(BtrfsError::UnsupportedFeature(
+                "changing the chunk tree".to_string(),
+            )),
+            // Trees not changed yet have the ROOT_ITEM they were committed with
+            _ => {
+                let root = subvolume::read_root_item(self.fs, tree_id)?;
+                Ok((root.bytenr, root.level))
+            }
+        }
+    }
+
+    /// Returns the data of the item with exactly `key`
+    pub fn get(&self, tree_id: u64, key: &BtrfsKey) -> Result<Option<Vec<u8>>> {
+        Ok(self.first_in(tree_id, key, key)?.map(|(_, data)| data))
+    }
+
+    /// Returns the first item with a key in `min..=max`
+    pub fn first_in(
+        &self,
+        tree_id: u64,
+        min: &BtrfsKey,
+        max: &BtrfsKey,
+    ) -> Result<Option<(BtrfsKey, Vec<u8>)>> {
+        Ok(self.scan(tree_id, min, max, false, 1)?.pop())
+    }
+
+    /// Returns the last item with a key in `min..=max`
+    pub fn last_in(
+        &self,
+        tree_id: u64,
+        min: &BtrfsKey,
+        max: &BtrfsKey,
+    ) -> Result<Option<(BtrfsKey, Vec<u8>)>> {
+        Ok(self.scan(tree_id, min, max, true, 1)?.pop())
+    }
+
+    /// Returns every item with a key in `min..=max`, in key order
+    pub fn range(
+        &self,
+        tree_id: u64,
+        min: &BtrfsKey,
+        max: &BtrfsKey,
+    ) -> Result<Vec<(BtrfsKey, Vec<u8>)>> {
+        self.scan(tree_id, min, max, false, usize::MAX)
+    }
+
+    /// Collects up to `limit` items with keys in `min..=max`, last first
+    /// if `rev` is set
+    fn scan(
+        &self,
+        tree_id: u64,
+        min: &BtrfsKey,
+        max: &BtrfsKey,
+        rev: bool,
+        limit: usize,
+    ) -> Result<Vec<(BtrfsKey, Vec<u8>)>> {
+        let (root, _) = self.root(tree_id)?;
+        let mut out = Vec::new();
+        self.scan_node(root, min, max, rev, limit, &mut out)?;
+        Ok(out)
+    }
+
+    fn scan_node(
+        &self,
+        bytenr: u64,
+        min: &BtrfsKey,
+        max: &BtrfsKey,
+        rev: bool,
+        limit: usize,
+        out: &mut Vec<(BtrfsKey, Vec<u8>)>,
+    ) -> Result<()> {
+        let node = self.node(bytenr)?;
+
+        if node.is_leaf() {
+            let mut items = node.items()?;
+            items.retain(|item| item.key >= *min && item.key <= *max);
+            if rev {
+                items.reverse();
+            }
+            for item in items.iter().take(limit - out.len()) {
+                out.push((item.key, node.item_data(item).to_vec()));
+            }
+            return Ok(());
+        }
+
+        // Child i holds the keys from its own key up to the next child's
+        let ptrs = node.key_ptrs()?;
+        let mut children: Vec<u64> = (0..ptrs.len())
+            .filter(|&i| {
+                let starts_after = i > 0 && ptrs[i].key > *max;
+                let ends_before = ptrs.get(i + 1).is_some_and(|next| next.key <= *min);
+                !starts_after && !ends_before
+            })
+            .map(|i| ptrs[i].blockptr)
+            .collect();
+        if rev {
+            children.reverse();
+        }
+
+        for child in children {
+            if out.len() >= limit {
+                break;
+            }
+            self.scan_node(child, min, max, rev, limit, out)?;
+        }
+        Ok(())
+    }
+
+    /// Inserts an item, failing if one with the same key exists
+    pub fn insert(&mut self, tree_id: u64, key: BtrfsKey, data: Vec<u8>) -> Result<()> {
+        self.modify(tree_id, &key, |items| {
+            match items.binary_search_by(|(k, _)| k.cmp(&key)) {
+                Ok(_) => Err(BtrfsError::Corrupt(format!(
+                    "Item {} already exists in tree {}",
+                    key, tree_id
+                ))),
+                Err(pos) => {
+                    items.insert(pos, (key, data));
+                    Ok(())
+                }
+            }
+        })
+    }
+
+    /// Changes the data of an existing item
+    pub fn update<F>(&mut self, tree_id: u64, key: &BtrfsKey, change: F) -> Result<()>
+    where
+        F: FnOnce(&mut Vec<u8>) -> Result<()>,
+    {
+        self.modify(tree_id, key, |items| {
+            match items.binary_search_by(|(k, _)| k.cmp(key)) {
+                Ok(pos) => change(&mut items[pos].1),
+                Err(_) => Err(BtrfsError::NotFound(format!(
+                    "Item {} in tree {}",
+                    key, tree_id
+                ))),
+            }
+        })
+    }
+
+    /// Deletes an existing item
+    pub fn delete(&mut self, tree_id: u64, key: &BtrfsKey) -> Result<()> {
+        self.modify(tree_id, key, |items| {
+            match items.binary_search_by(|(k, _)| k.cmp(key)) {
+                Ok(pos) => {
+                    items.remove(pos);
+                    Ok(())
+                }
+                Err(_) => Err(BtrfsError::NotFound(format!(
+                    "Item {} in tree {}",
+                    key, tree_id
+                ))),
+            }
+        })
+    }
+
+    /// Runs `change` on the items of the leaf where `key` belongs, then
+    /// writes that leaf and every node above it to new blocks
+    ///
+    /// Leaves and nodes that outgrow a block are split, and the tree grows
+    /// a level when its root splits.
+    fn modify<R, F>(&mut self, tree_id: u64, key: &BtrfsKey, change: F) -> Result<R>
+    where
+        F: FnOnce(&mut Vec<(BtrfsKey, Vec<u8>)>) -> Result<R>,
+    {
+        let (root, root_level) = self.root(tree_id)?;
+
+        // The nodes passed on the way down and the slot taken in each
+        let mut path = Vec::new();
+        let mut bytenr = root;
+        let leaf = loop {
+            let node = self.node(bytenr)?;
+            if node.is_leaf() {
+                break node;
+            }
+            let ptrs = node.key_ptrs()?;
+            let slot = ptrs.iter().rposition(|ptr| ptr.key <= *key).unwrap_or(0);
+            let child = ptrs.get(slot).map(|ptr| ptr.blockptr).ok_or_else(|| {
+                BtrfsError::Corrupt(format!("Internal node at {} is empty", bytenr))
+            })?;
+            path.push((bytenr, node, slot));
+            bytenr = child;
+        };
+
+        let mut items = Vec::with_capacity(leaf.item_count());
+        for item in leaf.items()? {
+            items.push((item.key, leaf.item_data(&item).to_vec()));
+        }
+        let result = change(&mut items)?;
+
+        let template = leaf.header;
+        let mut children =
+            self.write_nodes(tree_id, Some(bytenr), &template, 0, Entries::Leaf(items))?;
+        for (bytenr, node, slot) in path.into_iter().rev() {
+            let mut ptrs: Vec<_> = node
+                .key_ptrs()?
+                .iter()
+                .map(|ptr| (ptr.key, ptr.blockptr, ptr.generation))
+                .collect();
+            let generation = self.generation;
+            ptrs.splice(
+                slot..=slot,
+                children
+                    .iter()
+                    .map(|&(key, child)| (key, child, generation)),
+            );
+            let entries = Entries::Internal(ptrs);
+            children = self.write_nodes(
+                tree_id,
+                Some(bytenr),
+                &node.header,
+                node.header.level,
+                entries,
+            )?;
+        }
+
+        let mut level = root_level;
+        while children.len() > 1 {
+            level += 1;
+            let ptrs = children
+                .iter()
+                .map(|&(key, child)| (key, child, self.generation))
+                .collect();
+            children =
+                self.write_nodes(tree_id, None, &template, level, Entries::Internal(ptrs))?;
+        }
+
+        let root = match children.first() {
+            Some(&(_, bytenr)) => (bytenr, level),
+            // Every item is gone; the tree is left with an empty leaf
+            None => {
+                let empty = Entries::Leaf(Vec::new());
+                let bytenr = self.alloc(tree_id, 0)?;
+                self.fill_node(bytenr, tree_id, &template, 0, &empty, 0..0)?;
+                (bytenr, 0)
+            }
+        };
+        self.roots.insert(tree_id, root);
+        Ok(result)
+    }
+
+    /// Writes `entries` into as many nodes at `level` as they need, the
+    /// first replacing `old`, and returns the first key and address of each
+    fn write_nodes(
+        &mut self,
+        tree_id: u64,
+        old: Option<u64>,
+        template: &NodeHeader,
+        level: u8,
+        entries: Entries,
+    ) -> Result<Vec<(BtrfsKey, u64)>> {
+        let capacity = self.node_size as usize - NODE_HEADER_SIZE;
+
+        let mut groups = Vec::new();
+        match &entries {
+            Entries::Leaf(items) => {
+                let (mut start, mut used) = (0, 0);
+                for (i, (_, data)) in items.iter().enumerate() {
+                    let needed = ITEM_SIZE + data.len();
+                    if used + needed > capacity && i > start {
+                        groups.push(start..i);
+                        (start, used) = (i, 0);
+                    }
+                    used += needed;
+                }
+                if start < items.len() {
+                    groups.push(start..items.len());
+                }
+            }
+            Entries::Internal(ptrs) => {
+                let per_node = capacity / KEY_PTR_SIZE;
+                for start in (0..ptrs.len()).step_by(per_node) {
+                    groups.push(start..ptrs.len().min(start + per_node));
+                }
+            }
+        }
+
+        if groups.is_empty() {
+            if let Some(old) = old {
+                self.release(tree_id, old)?;
+            }
+            return Ok(Vec::new());
+        }
+
+        let mut written = Vec::with_capacity(groups.len());
+        for (i, range) in groups.into_iter().enumerate() {
+            let bytenr = match old {
+                Some(old) if i == 0 => self.cow(tree_id, old, level)?,
+                _ => self.alloc(tree_id, level)?,
+            };
+            let first = self.fill_node(bytenr, tree_id, template, level, &entries, range)?;
+            written.push((first, bytenr));
+        }
+        Ok(written)
+    }
+
+    /// Builds the node at `bytenr` from `entries[range]`, returning its
+    /// first key
+    fn fill_node(
+        &mut self,
+        bytenr: u64,
+        tree_id: u64,
+        template: &NodeHeader,
+        level: u8,
+        entries: &Entries,
+        range: std::ops::Range<usize>,
+    ) -> Result<BtrfsKey> {
+        let (node_size, generation) = (self.node_size as u32, self.generation);
+        let (fsid, chunk_tree_uuid) = (template.fsid, template.chunk_tree_uuid);
+
+        let mut node = match entries {
+            Entries::Leaf(items) => {
+                let mut node =
+                    TreeNode::new_leaf(node_size, tree_id, generation, fsid, chunk_tree_uuid);
+                for (key, data) in &items[range.clone()] {
+                    node.push_item(*key, data)?;
+                }
+                node
+            }
+            Entries::Internal(ptrs) => {
+                let mut node = TreeNode::new_internal(
+                    node_size,
+                    level,
+                    tree_id,
+                    generation,
+                    fsid,
+                    chunk_tree_uuid,
+                );
+                for &(key, child, child_generation) in &ptrs[range.clone()] {
+                    node.push_key_ptr(key, child, child_generation)?;
+                }
+                node
+            }
+        };
+        node.header.bytenr = bytenr;
+
+        let first = match entries {
+            Entries::Leaf(items) => items.get(range.start).map(|(key, _)| *key),
+            Entries::Internal(ptrs) => ptrs.get(range.start).map(|(key, _, _)| *key),
+        };
+        self.dirty.insert(bytenr, node);
+        Ok(first.unwrap_or(BtrfsKey::min()))
+    }
+
+    /// Returns the block to write a new version of `old` to: `old` itself
+    /// if this transaction wrote it, otherwise a new one, freeing `old`
+    fn cow(&mut self, tree_id: u64, old: u64, level: u8) -> Result<u64> {
+        if self.dirty.contains_key(&old) {
+            return Ok(old);
+        }
+        self.check_exclusive(old)?;
+        self.pending.push(ExtentOp::Free { bytenr: old });
+        self.alloc(tree_id, level)
+    }
+
+    /// Drops a block that no longer holds anything
+    fn release(&mut self, tree_id: u64, old: u64) -> Result<()> {
+        if self.dirty.remove(&old).is_some() {
+            // Never recorded in the extent tree if its allocation is pending
+            let pending = self
+                .pending
+                .iter()
+                .position(|op| matches!(op, ExtentOp::Alloc { bytenr, .. } if *bytenr == old));
+            match pending {
+                Some(pos) => {
+                    self.pending.remove(pos);
+                }
+                None => self.pending.push(ExtentOp::Free { bytenr: old }),
+            }
+            return Ok(());
+        }
+
+        self.check_exclusive(old)?;
+        tracing::debug!("Freeing tree block {} of tree {}", old, tree_id);
+        self.pending.push(ExtentOp::Free { bytenr: old });
+        Ok(())
+    }
+
+    /// Fails unless the committed block at `bytenr` is referenced once, so
+    /// it can be replaced without changing what any other tree sees
+    fn check_exclusive(&self, bytenr: u64) -> Result<()> {
+        let (key, data) = self.extent_item(bytenr)?.ok_or_else(|| {
+            BtrfsError::Corrupt(format!("Tree block {} has no extent item", bytenr))
+        })?;
+        let extent = ExtentItem::from_bytes(&key, &data)?;
+
+        if self.shared.contains(&bytenr)
+            || extent.refs != 1
+            || extent.flags & extent_flags::FULL_BACKREF != 0
+        {
+            return Err(BtrfsError::UnsupportedFeature(format!(
+                "rewriting tree block {} shared with a snapshot",
+                bytenr
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns the EXTENT_ITEM or METADATA_ITEM of the extent at `bytenr`
+    fn extent_item(&self, bytenr: u64) -> Result<Option<(BtrfsKey, Vec<u8>)>> {
+        self.first_in(
+            objectid::EXTENT_TREE,
+            &BtrfsKey::new(bytenr, item_type::EXTENT_ITEM, 0),
+            &BtrfsKey::new(bytenr, item_type::METADATA_ITEM, u64::MAX),
+        )
+    }
+
+    /// Allocates a tree block for tree `owner`
+    fn alloc(&mut self, owner: u64, level: u8) -> Result<u64> {
+        let bytenr = self.find_free()?;
+        self.pending.push(ExtentOp::Alloc {
+            bytenr,
+            owner,
+            level,
+        });
+        Ok(bytenr)
+    }
+
+    /// Finds and reserves a free, node-aligned block in a metadata block
+    /// group
+    ///
+    /// Blocks freed in this transaction stay reserved: the committed trees
+    /// still point at them until the superblock is written.
+    fn find_free(&mut self) -> Result<u64> {
+        let node_size = self.node_size;
+
+        for i in 0..self.groups.len() {
+            if self.groups[i].used.is_none() {
+                let used = self.committed_extents(self.groups[i].start, self.groups[i].length)?;
+                self.groups[i].used = Some(used);
+            }
+
+            let group = &self.groups[i];
+            let end = group.start + group.length;
+            let used = group.used.as_ref().expect("loaded above");
+            let mut candidate = group.start.next_multiple_of(node_size);
+            let mut found = None;
+
+            while candidate + node_size <= end {
+                let overlap = used
+                    .range(..candidate + node_size)
+                    .next_back()
+                    .filter(|(start, len)| *start + *len > candidate);
+                if let Some((&start, &len)) = overlap {
+                    candidate = (start + len).next_multiple_of(node_size);
+                } else if self.overlaps_reserved(candidate)? {
+                    candidate += node_size;
+                } else {
+                    found = Some(candidate);
+                    break;
+                }
+            }
+
+            if let Some(bytenr) = found {
+                let used = self.groups[i].used.as_mut().expect("loaded above");
+                used.insert(bytenr, node_size);
+                return Ok(bytenr);
+            }
+        }
+
+        Err(BtrfsError::NoSpace)
+    }
+
+    /// Returns the extents in `start..start + length` as committed
+    fn committed_extents(&self, start: u64, length: u64) -> Result<BTreeMap<u64, u64>> {
+        let tree = self.fs.tree(objectid::EXTENT_TREE)?;
+        let min = BtrfsKey::new(start, item_type::EXTENT_ITEM, 0);
+        let max = BtrfsKey::new(start + length - 1, item_type::METADATA_ITEM, u64::MAX);
+
+        let mut used = BTreeMap::new();
+        for (item, _) in tree.search_range(&min, &max)? {
+            let len = match item.key.item_type {
+                item_type::EXTENT_ITEM => item.key.offset,
+                item_type::METADATA_ITEM => self.node_size,
+                _ => continue,
+            };
+            used.insert(item.key.objectid, len);
+        }
+        Ok(used)
+    }
+
+    /// Returns true if a copy of a block at `logical` would overlap the
+    /// start of a device or a superblock copy
+    fn overlaps_reserved(&self, logical: u64) -> Result<bool> {
+        for (_, physical) in self.fs.logical_to_physical_mapped(logical)? {
+            let end = physical + self.node_size;
+            if physical < DEVICE_RESERVED_BYTES {
+                return Ok(true);
+            }
+            for offset in [
+                SUPERBLOCK_OFFSET,
+                SUPERBLOCK_MIRROR1_OFFSET,
+                SUPERBLOCK_MIRROR2_OFFSET,
+            ] {
+                if physical < offset + SUPERBLOCK_SIZE as u64 && offset < end {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Makes tree `root_id` a copy of the tree rooted at `bytenr`
+    ///
+    /// Only the root node is copied; everything it points at gains a
+    /// reference from the new tree and is shared from then on.
+    pub fn copy_root(&mut self, bytenr: u64, root_id: u64) -> Result<(u64, u8)> {
+        let mut node = self.node(bytenr)?;
+        let level = node.header.level;
+        let copy = self.alloc(root_id, level)?;
+
+        if node.is_leaf() {
+            for item in node.items()? {
+                if item.key.item_type != item_type::EXTENT_DATA {
+                    continue;
+                }
+                if let Some((disk_bytenr, extent_offset)) = file_extent_ref(node.item_data(&item)) {
+                    let backref = BackRef::Data {
+                        root: root_id,
+                        ino: item.key.objectid,
+                        offset: item.key.offset.wrapping_sub(extent_offset),
+                    };
+                    self.add_ref(disk_bytenr, backref);
+                }
+            }
+        } else {
+            for ptr in node.key_ptrs()? {
+                self.add_ref(ptr.blockptr, BackRef::Tree { root: root_id });
+            }
+        }
+
+        node.header.owner = root_id;
+        node.header.generation = self.generation;
+        node.header.flags = [0; 7];
+        self.dirty.insert(copy, node);
+        self.roots.insert(root_id, (copy, level));
+        Ok((copy, level))
+    }
+
+    /// Records a new reference to the extent at `bytenr`
+    pub fn add_ref(&mut self, bytenr: u64, backref: BackRef) {
+        self.shared.insert(bytenr);
+        self.pending.push(ExtentOp::AddRef { bytenr, backref });
+    }
+
+    /// Applies one extent tree change
+    fn apply(&mut self, op: ExtentOp) -> Result<()> {
+        match op {
+            ExtentOp::Alloc {
+                bytenr,
+                owner,
+                level,
+            } => {
+                let (key, data) = self.tree_block_item(bytenr, owner, level);
+                self.insert(objectid::EXTENT_TREE, key, data)?;
+                self.account(bytenr, true)
+            }
+            ExtentOp::Free { bytenr } => {
+                let items = self.range(
+                    objectid::EXTENT_TREE,
+                    &BtrfsKey::new(bytenr, item_type::EXTENT_ITEM, 0),
+                    &BtrfsKey::new(bytenr, item_type::SHARED_DATA_REF, u64::MAX),
+                )?;
+                if items.is_empty() {
+                    return Err(BtrfsError::Corrupt(format!(
+                        "Freed tree block {} has no extent item",
+                        bytenr
+                    )));
+                }
+                for (key, _) in items {
+                    self.delete(objectid::EXTENT_TREE, &key)?;
+                }
+                self.account(bytenr, false)
+            }
+            ExtentOp::AddRef { bytenr, backref } => self.apply_add_ref(bytenr, backref),
+        }
+    }
+
+    /// Builds the extent item of a new tree block, with an inline
+    /// reference from its owner
+    fn tree_block_item(&self, bytenr: u64, owner: u64, level: u8) -> (BtrfsKey, Vec<u8>) {
+        let skinny = self.fs.superblock().has_incompat(incompat::SKINNY_METADATA);
+
+        let mut data = Vec::with_capacity(51);
+        data.extend_from_slice(&1u64.to_le_bytes()); // refs
+        data.extend_from_slice(&self.generation.to_le_bytes());
+        data.extend_from_slice(&extent_flags::TREE_BLOCK.to_le_bytes());
+
+        let key = if skinny {
+            BtrfsKey::new(bytenr, item_type::METADATA_ITEM, level as u64)
+        } else {
+            // tree_block_info: the node's first key and level
+            let first = self
+                .dirty
+                .get(&bytenr)
+                .and_then(|node| match node.is_leaf() {
+                    true => node.item_at(0).ok().map(|item| item.key),
+                    false => node.key_ptrs().ok()?.first().map(|ptr| ptr.key),
+                })
+                .unwrap_or(BtrfsKey::min());
+            data.extend_from_slice(&first.to_bytes());
+            data.push(level);
+            BtrfsKey::new(bytenr, item_type::EXTENT_ITEM, self.node_size)
+        };
+
+        data.push(item_type::TREE_BLOCK_REF);
+        data.extend_from_slice(&owner.to_le_bytes());
+        (key, data)
+    }
+
+    /// Adds a reference to an extent's count and its keyed back reference
+    fn apply_add_ref(&mut self, bytenr: u64, backref: BackRef) -> Result<()> {
+        let (key, _) = self.extent_item(bytenr)?.ok_or_else(|| {
+            BtrfsError::Corrupt(format!("Referenced extent {} has no extent item", bytenr))
+        })?;
+        self.update(objectid::EXTENT_TREE, &key, |data| {
+            let refs = read_u64(data, 0, "Extent item")?;
+            LittleEndian::write_u64(&mut data[0..8], refs + 1);
+            Ok(())
+        })?;
+
+        match backref {
+            BackRef::Tree { root } => {
+                let key = BtrfsKey::new(bytenr, item_type::TREE_BLOCK_REF, root);
+                self.insert(objectid::EXTENT_TREE, key, Vec::new())
+            }
+            BackRef::Data { root, ino, offset } => {
+                let hash = extent_data_ref_hash(root, ino, offset);
+                let mut key = BtrfsKey::new(bytenr, item_type::EXTENT_DATA_REF, hash);
+
+                // Colliding hashes take the next free offset
+                loop {
+                    let Some(data) = self.get(objectid::EXTENT_TREE, &key)? else {
+                        let mut data = Vec::with_capacity(28);
+                        for value in [root, ino, offset] {
+                            data.extend_from_slice(&value.to_le_bytes());
+                        }
+                        data.extend_from_slice(&1u32.to_le_bytes());
+                        return self.insert(objectid::EXTENT_TREE, key, data);
+                    };
+
+                    let same = data.len() >= 28
+                        && [0, 8, 16]
+                            .iter()
+                            .zip([root, ino, offset])
+                            .all(|(&at, value)| LittleEndian::read_u64(&data[at..]) == value);
+                    if same {
+                        return self.update(objectid::EXTENT_TREE, &key, |data| {
+                            let count = LittleEndian::read_u32(&data[24..28]);
+                            LittleEndian::write_u32(&mut data[24..28], count + 1);
+                            Ok(())
+                        });
+                    }
+                    key.offset = key.offset.wrapping_add(1);
+                }
+            }
+        }
+    }
+
+    /// Updates block group usage, the filesystem total and the free space
+    /// tree for a tree block being allocated or freed
+    fn account(&mut self, bytenr: u64, allocated: bool) -> Result<()> {
+        let (start, length) = self
+            .fs
+            .chunk_tree()
+            .chunk_at(bytenr)
+            .map(|chunk| (chunk.logical, chunk.size))
+            .ok_or_else(|| {
+                BtrfsError::NotFound(format!("Logical address {} not in any chunk", bytenr))
+            })?;
+        let node_size = self.node_size;
+
+        let sb = self.fs.superblock();
+        let group_tree = match sb.has_compat_ro(compat_ro::BLOCK_GROUP_TREE) {
+            true => objectid::BLOCK_GROUP_TREE,
+            false => objectid::EXTENT_TREE,
+        };
+        let key = BtrfsKey::new(start, item_type::BLOCK_GROUP_ITEM, length);
+        self.update(group_tree, &key, |data| {
+            let used = read_u64(data, 0, "Block group item")?;
+            let used = match allocated {
+                true => used + node_size,
+                false => used.saturating_sub(node_size),
+            };
+            LittleEndian::write_u64(&mut data[0..8], used);
+            Ok(())
+        })?;
+
+        self.bytes_used += match allocated {
+            true => node_size as i64,
+            false => -(node_size as i64),
+        };
+
+        if sb.has_compat_ro(compat_ro::FREE_SPACE_TREE) {
+            self.update_free_space((start, length), bytenr, !allocated)?;
+        }
+        Ok(())
+    }
+
+    /// Marks a node-sized range of a block group free or used in the free
+    /// space tree
+    fn update_free_space(&mut self, group: (u64, u64), start: u64, free: bool) -> Result<()> {
+        let tree = objectid::FREE_SPACE_TREE;
+        let (group_start, group_end) = (group.0, group.0 + group.1);
+        let end = start + self.node_size;
+
+        let info_key = BtrfsKey::new(group.0, item_type::FREE_SPACE_INFO, group.1);
+        let info = self.get(tree, &info_key)?.ok_or_else(|| {
+            BtrfsError::Corrupt(format!("Block group {} has no free space info", group.0))
+        })?;
+        if info.len() < 8 {
+            return Err(BtrfsError::Corrupt("Free space info too small".to_string()));
+        }
+        let bitmaps = LittleEndian::read_u32(&info[4..8]) & FREE_SPACE_USING_BITMAPS != 0;
+
+        // Whether the range joins free space on either side decides how the
+        // number of free extents changes
+        let sector = self.fs.superblock().sector_size() as u64;
+        let before = start > group_start && self.is_free(group, start - sector, bitmaps)?;
+        let after = end < group_end && self.is_free(group, end, bitmaps)?;
+        let delta: i64 = match (before, after, free) {
+            (true, true, false) | (false, false, true) => 1,
+            (true, true, true) | (false, false, false) => -1,
+            _ => 0,
+        };
+
+        if bitmaps {
+            self.set_bitmap_range(group, start, end, free)?;
+        } else if free {
+            self.add_free_extent(group, start, end, before, after)?;
+        } else {
+            self.remove_free_extent(group, start, end)?;
+        }
+
+        self.update(tree, &info_key, |data| {
+            let count = LittleEndian::read_u32(&data[0..4]) as i64 + delta;
+            LittleEndian::write_u32(&mut data[0..4], count.max(0) as u32);
+            Ok(())
+        })
+    }
+
+    /// Returns the free space item of `item_type` in `group` that starts
+    /// at or before `pos`
+    fn free_space_item(
+        &self,
+        group: (u64, u64),
+        item_type: u8,
+        pos: u64,
+    ) -> Result<Option<(BtrfsKey, Vec<u8>)>> {
+        let found = self.last_in(
+            objectid::FREE_SPACE_TREE,
+            &BtrfsKey::new(group.0, item_type, 0),
+            &BtrfsKey::new(pos, item_type, u64::MAX),
+        )?;
+        Ok(found.filter(|(key, _)| key.item_type == item_type))
+    }
+
+    /// Returns true if the sector at `pos` is free in the free space tree
+    fn is_free(&self, group: (u64, u64), pos: u64, bitmaps: bool) -> Result<bool> {
+        if !bitmaps {
+            let extent = self.free_space_item(group, item_type::FREE_SPACE_EXTENT, pos)?;
+            return Ok(extent.is_some_and(|(key, _)| pos < key.objectid + key.offset));
+        }
+
+        let sector = self.fs.superblock().sector_size() as u64;
+        match self.free_space_item(group, item_type::FREE_SPACE_BITMAP, pos)? {
+            Some((key, data)) if pos < key.objectid + key.offset => {
+                let bit = ((pos - key.objectid) / sector) as usize;
+                Ok(data
+                    .get(bit / 8)
+                    .is_some_and(|byte| byte & (1 << (bit % 8)) != 0))
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Sets (free) or clears the bitmap bits of every sector in `start..end`
+    fn set_bitmap_range(
+        &mut self,
+        group: (u64, u64),
+        start: u64,
+        end: u64,
+        free: bool,
+    ) -> Result<()> {
+        let sector = self.fs.superblock().sector_size() as u64;
+        let mut pos = start;
+        while pos < end {
+            let (key, _) = self
+                .free_space_item(group, item_type::FREE_SPACE_BITMAP, pos)?
+                .filter(|(key, _)| pos < key.objectid + key.offset)
+                .ok_or_else(|| {
+                    BtrfsError::Corrupt(format!("No free space bitmap covers {}", pos))
+                })?;
+
+            let bitmap_end = end.min(key.objectid + key.offset);
+            let first = ((pos - key.objectid) / sector) as usize;
+            let last = ((bitmap_end - key.objectid) / sector) as usize;
+            self.update(objectid::FREE_SPACE_TREE, &key, |data| {
+                if data.len() < last.div_ceil(8) {
+                    return Err(BtrfsError::Corrupt(format!(
+                        "Free space bitmap {} too small",
+                        key
+                    )));
+                }
+                for bit in first..last {
+                    match free {
+                        true => data[bit / 8] |= 1 << (bit % 8),
+                        false => data[bit / 8] &= !(1 << (bit % 8)),
+                    }
+                }
+                Ok(())
+            })?;
+            pos = bitmap_end;
+        }
+        Ok(())
+    }
+
+    /// Records `start..end` as free, merging it with the free extents it
+    /// touches
+    fn add_free_extent(
+        &mut self,
+        group: (u64, u64),
+        start: u64,
+        end: u64,
+        before: bool,
+        after: bool,
+    ) -> Result<()> {
+        let tree = objectid::FREE_SPACE_TREE;
+        let (mut new_start, mut new_end) = (start, end);
+
+        let prev = match before {
+            true => self.free_space_item(group, item_type::FREE_SPACE_EXTENT, start - 1)?,
+            false => None,
+        };
+        if let Some((key, _)) = prev {
+            new_start = key.objectid;
+            self.delete(tree, &key)?;
+        }
+        let next = match after {
+            true => self.free_space_item(group, item_type::FREE_SPACE_EXTENT, end)?,
+            false => None,
+        };
+        if let Some((key, _)) = next {
+            new_end = key.objectid + key.offset;
+            self.delete(tree, &key)?;
+        }
+
+        let key = BtrfsKey::new(new_start, item_type::FREE_SPACE_EXTENT, new_end - new_start);
+        self.insert(tree, key, Vec::new())
+    }
+
+    /// Removes `start..end` from the free extent that holds it
+    fn remove_free_extent(&mut self, group: (u64, u64), start: u64, end: u64) -> Result<()> {
+        let tree = objectid::FREE_SPACE_TREE;
+        let (key, _) = self
+            .free_space_item(group, item_type::FREE_SPACE_EXTENT, start)?
+            .filter(|(key, _)| end <= key.objectid + key.offset)
+            .ok_or_else(|| {
+                BtrfsError::Corrupt(format!("Allocated block {} is not free space", start))
+            })?;
+
+        self.delete(tree, &key)?;
+        let extent_end = key.objectid + key.offset;
+        if key.objectid < start {
+            let left = BtrfsKey::new(
+                key.objectid,
+                item_type::FREE_SPACE_EXTENT,
+                start - key.objectid,
+            );
+            self.insert(tree, left, Vec::new())?;
+        }
+        if end < extent_end {
+            let right = BtrfsKey::new(end, item_type::FREE_SPACE_EXTENT, extent_end - end);
+            self.insert(tree, right, Vec::new())?;
+        }
+        Ok(())
+    }
+
+    /// Points the ROOT_ITEM of every changed tree at its new root,
+    /// returning false if they all already were
+    fn update_root_items(&mut self) -> Result<bool> {
+        let roots: Vec<_> = self
+            .roots
+            .iter()
+            .filter(|(id, _)| **id != objectid::ROOT_TREE)
+            .map(|(&id, &root)| (id, root))
+            .collect();
+
+        let mut changed = false;
+        for (id, (bytenr, level)) in roots {
+            let (key, data) = self
+                .last_in(
+                    objectid::ROOT_TREE,
+                    &BtrfsKey::new(id, item_type::ROOT_ITEM, 0),
+                    &BtrfsKey::new(id, item_type::ROOT_ITEM, u64::MAX),
+                )?
+                .ok_or(BtrfsError::SubvolumeNotFound(id))?;
+            if read_u64(&data, 176, "Root item")? == bytenr && data[238] == level {
+                continue;
+            }
+
+            let generation = self.generation;
+            self.update(objectid::ROOT_TREE, &key, |data| {
+                LittleEndian::write_u64(&mut data[160..168], generation);
+                LittleEndian::write_u64(&mut data[176..184], bytenr);
+                data[238] = level;
+                if data.len() >= 247 {
+                    LittleEndian::write_u64(&mut data[239..247], generation);
+                }
+                Ok(())
+            })?;
+            changed = true;
+        }
+        Ok(changed)
+    }
+
+    /// Brings the extent tree up to date, writes every node and then the
+    /// superblock, returning the superblock written
+    ///
+    /// Nodes are written and flushed before the superblock, so a crash
+    /// leaves the previous commit intact.
+    pub fn commit(mut self) -> Result<Superblock> {
+        loop {
+            if !self.pending.is_empty() {
+                for op in std::mem::take(&mut self.pending) {
+                    self.apply(op)?;
+                }
+                continue;
+            }
+            if !self.update_root_items()? {
+                break;
+            }
+        }
+
+        // Checksum everything before writing anything
+        let csum = self.fs.checksum();
+        for (&bytenr, node) in self.dirty.iter_mut() {
+            node.seal(bytenr, csum)?;
+        }
+        for (&bytenr, node) in &self.dirty {
+            self.fs.write_logical(bytenr, node.data())?;
+        }
+        self.fs.device().flush_device()?;
+
+        let mut superblock = self.fs.superblock().clone();
+        let (root, root_level) = self.root(objectid::ROOT_TREE)?;
+        superblock.set_root(root, root_level);
+        superblock.set_generation(self.generation);
+        superblock.set_bytes_used(
+            superblock
+                .bytes_used()
+                .saturating_add_signed(self.bytes_used),
+        );
+        superblock.write_all(self.fs.device().as_ref())?;
+        Ok(superblock)
+    }
+}
+
+/// Returns the disk address of a regular or preallocated file extent and
+/// the offset into it the item starts at; inline extents and holes have
+/// none
+fn file_extent_ref(data: &[u8]) -> Option<(u64, u64)> {
+    // Inline extents end after the type byte at offset 20
+    if data.len() < 53 || data[20] == 0 {
+        return None;
+    }
+    let disk_bytenr = LittleEndian::read_u64(&data[21..29]);
+    let offset = LittleEndian::read_u64(&data[37..45]);
+    (disk_bytenr != 0).then_some((disk_bytenr, offset))
+}
+
+/// Hashes a data back reference into the offset of its EXTENT_DATA_REF key
+///
+/// This is the kernel's CRC32c without the final inversion, which
+/// `crc32c_append` applies.
+pub fn extent_data_ref_hash(root: u64, ino: u64, offset: u64) -> u64 {
+    let high = !crc32c::crc32c_append(0, &root.to_le_bytes());
+    let low = crc32c::crc32c_append(0, &ino.to_le_bytes());
+    let low = !crc32c::crc32c_append(low, &offset.to_le_bytes());
+    ((high as u64) << 31) ^ low as u64
+}
+
+/// Reads a little-endian u64 at `at`, failing if `data` is too short
+fn read_u64(data: &[u8], at: usize, what: &str) -> Result<u64> {
+    data.get(at..at + 8)
+        .map(LittleEndian::read_u64)
+        .ok_or_else(|| BtrfsError::Corrupt(format!("{} too small", what)))
+}
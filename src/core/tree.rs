@@ -3,9 +3,11 @@
 //! BTRFS uses copy-on-write B-trees for all on-disk data structures.
 //! All parsing functions are optimized with inline hints for hot paths.
 
-use super::{checksum, BtrfsError, BtrfsFilesystem, Result};
+use super::{checksum, item_type, BtrfsError, BtrfsFilesystem, Checksum, Result};
 use byteorder::{ByteOrder, LittleEndian};
-use zerocopy::{FromBytes, Immutable, KnownLayout};
+use std::cmp::Ordering;
+use std::fmt;
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
 
 /// Size of a node header
 pub const NODE_HEADER_SIZE: usize = 0x65;
@@ -19,6 +21,9 @@ pub const ITEM_SIZE: usize = 0x19;
 /// Size of a key structure
 pub const KEY_SIZE: usize = 0x11;
 
+/// Header flag set on every node written to disk
+pub const HEADER_FLAG_WRITTEN: u8 = 1 << 0;
+
 /// Tree types in BTRFS
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TreeType {
@@ -49,9 +54,34 @@ impl TreeType {
             8 => Self::Quota,
             9 => Self::Uuid,
             10 => Self::FreeSpace,
+            11 => Self::BlockGroup,
             _ => Self::Unknown(objectid),
         }
     }
+
+    /// Returns the tree's object ID
+    #[inline]
+    pub const fn objectid(self) -> u64 {
+        match self {
+            Self::Root => 1,
+            Self::Extent => 2,
+            Self::Chunk => 3,
+            Self::Dev => 4,
+            Self::Fs => 5,
+            Self::Csum => 7,
+            Self::Quota => 8,
+            Self::Uuid => 9,
+            Self::FreeSpace => 10,
+            Self::BlockGroup => 11,
+            Self::Unknown(objectid) => objectid,
+        }
+    }
+}
+
+impl From<TreeType> for u64 {
+    fn from(tree: TreeType) -> Self {
+        tree.objectid()
+    }
 }
 
 /// A BTRFS key used for B-tree lookups
@@ -91,6 +121,20 @@ impl BtrfsKey {
         })
     }
 
+    /// Serializes the key in its on-disk layout
+    pub fn to_bytes(&self) -> [u8; KEY_SIZE] {
+        let mut buf = [0u8; KEY_SIZE];
+        LittleEndian::write_u64(&mut buf[0..8], self.objectid);
+        buf[8] = self.item_type;
+        LittleEndian::write_u64(&mut buf[9..17], self.offset);
+        buf
+    }
+
+    /// Writes the key to the start of `buf`
+    pub fn write_to(&self, buf: &mut [u8]) -> Result<()> {
+        write_prefix(buf, &self.to_bytes(), "Key")
+    }
+
     /// Parses a key from bytes without bounds checking (caller must ensure data is valid)
     /// 
     /// # Safety
@@ -115,11 +159,65 @@ impl BtrfsKey {
     pub const fn max() -> Self {
         Self::new(u64::MAX, u8::MAX, u64::MAX)
     }
+
+    /// Describes what the offset field holds for this key's item type
+    pub fn offset_meaning(&self) -> &'static str {
+        match self.item_type {
+            item_type::INODE_ITEM => "unused",
+            item_type::INODE_REF => "parent dir",
+            item_type::INODE_EXTREF => "name hash",
+            item_type::XATTR_ITEM => "name hash",
+            item_type::ORPHAN_ITEM => "inode",
+            item_type::DIR_LOG_ITEM | item_type::DIR_LOG_INDEX => "range start",
+            item_type::DIR_ITEM => "name hash",
+            item_type::DIR_INDEX => "dir index",
+            item_type::EXTENT_DATA => "file offset",
+            item_type::EXTENT_CSUM => "logical address",
+            item_type::ROOT_ITEM => "snapshot transid",
+            item_type::ROOT_BACKREF => "parent tree",
+            item_type::ROOT_REF => "child tree",
+            item_type::EXTENT_ITEM => "length",
+            item_type::METADATA_ITEM => "level",
+            item_type::TREE_BLOCK_REF => "root",
+            item_type::EXTENT_DATA_REF => "ref hash",
+            item_type::SHARED_BLOCK_REF | item_type::SHARED_DATA_REF => "parent block",
+            item_type::BLOCK_GROUP_ITEM => "length",
+            item_type::DEV_EXTENT => "physical offset",
+            item_type::DEV_ITEM => "device id",
+            item_type::CHUNK_ITEM => "logical address",
+            _ => "offset",
+        }
+    }
+}
+
+impl fmt::Display for BtrfsKey {
+    /// Formats as `(objectid TYPE offset)` with the offset annotated, e.g.
+    /// `(257 INODE_REF 256 [parent dir])`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (objectid, item_type, offset) = (self.objectid, self.item_type, self.offset);
+
+        write!(f, "({} ", objectid)?;
+        match item_type::name(item_type) {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "UNKNOWN.{}", item_type)?,
+        }
+
+        // Hashes are easier to compare in hex
+        match item_type {
+            item_type::DIR_ITEM
+            | item_type::XATTR_ITEM
+            | item_type::INODE_EXTREF
+            | item_type::EXTENT_DATA_REF => write!(f, " {:#x}", offset)?,
+            _ => write!(f, " {}", offset)?,
+        }
+
+        write!(f, " [{}])", self.offset_meaning())
+    }
 }
 
 /// Node header structure
-#[derive(Debug, Clone, Copy, FromBytes, KnownLayout, Immutable)]
-#[repr(C)]
+#[derive(Debug, Clone, Copy, FromBytes, IntoBytes, KnownLayout, Immutable)]
+#[repr(C, packed)]
 pub struct NodeHeader {
     /// Checksum
     pub csum: [u8; 32],
@@ -155,6 +253,18 @@ impl NodeHeader {
             .map_err(|_| BtrfsError::Corrupt("Failed to parse node header".to_string()))
     }
 
+    /// Serializes the header in its on-disk layout
+    pub fn to_bytes(&self) -> [u8; NODE_HEADER_SIZE] {
+        let mut buf = [0u8; NODE_HEADER_SIZE];
+        buf.copy_from_slice(self.as_bytes());
+        buf
+    }
+
+    /// Writes the header to the start of `buf`
+    pub fn write_to(&self, buf: &mut [u8]) -> Result<()> {
+        write_prefix(buf, self.as_bytes(), "Node header")
+    }
+
     /// Returns true if this is a leaf node
     pub fn is_leaf(&self) -> bool {
         self.level == 0
@@ -185,6 +295,20 @@ impl KeyPtr {
             generation: LittleEndian::read_u64(&data[KEY_SIZE + 8..KEY_SIZE + 16]),
         })
     }
+
+    /// Serializes the key pointer in its on-disk layout
+    pub fn to_bytes(&self) -> [u8; KEY_PTR_SIZE] {
+        let mut buf = [0u8; KEY_PTR_SIZE];
+        buf[0..KEY_SIZE].copy_from_slice(&self.key.to_bytes());
+        LittleEndian::write_u64(&mut buf[KEY_SIZE..KEY_SIZE + 8], self.blockptr);
+        LittleEndian::write_u64(&mut buf[KEY_SIZE + 8..KEY_SIZE + 16], self.generation);
+        buf
+    }
+
+    /// Writes the key pointer to the start of `buf`
+    pub fn write_to(&self, buf: &mut [u8]) -> Result<()> {
+        write_prefix(buf, &self.to_bytes(), "Key pointer")
+    }
 }
 
 /// Item in leaf nodes
@@ -211,10 +335,45 @@ impl Item {
             size: LittleEndian::read_u32(&data[KEY_SIZE + 4..KEY_SIZE + 8]),
         })
     }
+
+    /// Serializes the item header in its on-disk layout
+    pub fn to_bytes(&self) -> [u8; ITEM_SIZE] {
+        let mut buf = [0u8; ITEM_SIZE];
+        buf[0..KEY_SIZE].copy_from_slice(&self.key.to_bytes());
+        LittleEndian::write_u32(&mut buf[KEY_SIZE..KEY_SIZE + 4], self.offset);
+        LittleEndian::write_u32(&mut buf[KEY_SIZE + 4..KEY_SIZE + 8], self.size);
+        buf
+    }
+
+    /// Writes the item header to the start of `buf`
+    pub fn write_to(&self, buf: &mut [u8]) -> Result<()> {
+        write_prefix(buf, &self.to_bytes(), "Item")
+    }
+}
+
+/// Copies `bytes` to the start of `buf`, failing if `buf` is too small
+///
+/// A short buffer is the caller's mistake, not damage on disk, so it is
+/// reported as an invalid argument rather than corruption.
+fn write_prefix(buf: &mut [u8], bytes: &[u8], what: &str) -> Result<()> {
+    if buf.len() < bytes.len() {
+        return Err(BtrfsError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "{} needs {} bytes, buffer has {}",
+                what,
+                bytes.len(),
+                buf.len()
+            ),
+        )));
+    }
+
+    buf[..bytes.len()].copy_from_slice(bytes);
+    Ok(())
 }
 
 /// A parsed BTRFS tree node
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TreeNode {
     /// Node header
     pub header: NodeHeader,
@@ -223,16 +382,226 @@ pub struct TreeNode {
 }
 
 impl TreeNode {
-    /// Parses a tree node from raw data
-    pub fn parse(data: Vec<u8>) -> Result<Self> {
+    /// Parses a tree node from raw data, verifying it with `csum`
+    pub fn parse(data: Vec<u8>, csum: Checksum) -> Result<Self> {
         // Verify checksum
-        checksum::verify_node_checksum(&data)?;
+        checksum::verify_node_checksum(&data, csum)?;
 
         let header = NodeHeader::from_bytes(&data)?;
+        Self::check_bounds(&header, &data)?;
 
         Ok(Self { header, data })
     }
 
+    /// Fails unless every item header or key pointer, and every item's
+    /// data, lies inside the node
+    fn check_bounds(header: &NodeHeader, data: &[u8]) -> Result<()> {
+        let nritems = header.nritems as usize;
+        let entry_size = if header.is_leaf() {
+            ITEM_SIZE
+        } else {
+            KEY_PTR_SIZE
+        };
+        let entries_end = nritems
+            .checked_mul(entry_size)
+            .and_then(|len| len.checked_add(NODE_HEADER_SIZE));
+        if entries_end.is_none_or(|end| end > data.len()) {
+            return Err(BtrfsError::Corrupt(format!(
+                "Node at {} claims {} items, more than fit",
+                { header.bytenr },
+                nritems
+            )));
+        }
+
+        if header.is_leaf() {
+            for i in 0..nritems {
+                let item = Item::from_bytes(&data[NODE_HEADER_SIZE + i * ITEM_SIZE..])?;
+                let end = NODE_HEADER_SIZE
+                    .checked_add(item.offset as usize)
+                    .and_then(|start| start.checked_add(item.size as usize));
+                if end.is_none_or(|end| end > data.len()) {
+                    return Err(BtrfsError::Corrupt(format!(
+                        "Item {} of node at {} has data past the end of the node",
+                        item.key,
+                        { header.bytenr }
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates an empty leaf of `node_size` bytes
+    ///
+    /// The header is filled in except for the checksum and `bytenr`, which
+    /// are set when the node is written.
+    pub fn new_leaf(
+        node_size: u32,
+        owner: u64,
+        generation: u64,
+        fsid: [u8; 16],
+        chunk_tree_uuid: [u8; 16],
+    ) -> Self {
+        Self::new_node(node_size, 0, owner, generation, fsid, chunk_tree_uuid)
+    }
+
+    /// Creates an empty internal node at `level` (at least 1)
+    pub fn new_internal(
+        node_size: u32,
+        level: u8,
+        owner: u64,
+        generation: u64,
+        fsid: [u8; 16],
+        chunk_tree_uuid: [u8; 16],
+    ) -> Self {
+        Self::new_node(
+            node_size,
+            level.max(1),
+            owner,
+            generation,
+            fsid,
+            chunk_tree_uuid,
+        )
+    }
+
+    fn new_node(
+        node_size: u32,
+        level: u8,
+        owner: u64,
+        generation: u64,
+        fsid: [u8; 16],
+        chunk_tree_uuid: [u8; 16],
+    ) -> Self {
+        let header = NodeHeader {
+            csum: [0; 32],
+            fsid,
+            bytenr: 0,
+            flags: [0; 7],
+            // Mixed backrefs, as every node written by a current kernel
+            backref_rev: 1,
+            chunk_tree_uuid,
+            generation,
+            owner,
+            nritems: 0,
+            level,
+        };
+
+        let mut data = vec![0u8; node_size as usize];
+        data[..NODE_HEADER_SIZE].copy_from_slice(header.as_bytes());
+        Self { header, data }
+    }
+
+    /// Makes the node ready to write at `bytenr`: records the address,
+    /// marks the node written and stores its checksum
+    pub fn seal(&mut self, bytenr: u64, csum: Checksum) -> Result<()> {
+        self.header.bytenr = bytenr;
+        self.header.flags[0] |= HEADER_FLAG_WRITTEN;
+        self.header.csum = [0; 32];
+        self.header.write_to(&mut self.data)?;
+
+        let value = csum.compute(&self.data[0x20..])?.to_le_bytes();
+        let size = csum.size().min(value.len());
+        self.header.csum[..size].copy_from_slice(&value[..size]);
+        self.header.write_to(&mut self.data)
+    }
+
+    /// Returns the bytes left for another item header and its data in a
+    /// leaf, or for key pointers in an internal node
+    pub fn free_space(&self) -> usize {
+        let used = if self.is_leaf() {
+            let data_start = match self.item_count() {
+                0 => self.data.len(),
+                n => self.item_at(n - 1).map_or(self.data.len(), |item| {
+                    NODE_HEADER_SIZE + item.offset as usize
+                }),
+            };
+            self.item_count() * ITEM_SIZE + (self.data.len() - data_start)
+        } else {
+            self.item_count() * KEY_PTR_SIZE
+        };
+        self.data.len().saturating_sub(NODE_HEADER_SIZE + used)
+    }
+
+    /// Appends an item to a leaf
+    ///
+    /// Keys must be pushed in ascending order. The data is packed against
+    /// the end of the node, below the previous item's. Fails with
+    /// [`BtrfsError::NoSpace`] if the item doesn't fit.
+    pub fn push_item(&mut self, key: BtrfsKey, data: &[u8]) -> Result<()> {
+        if !self.is_leaf() {
+            return Err(BtrfsError::Corrupt(
+                "Cannot push an item into an internal node".to_string(),
+            ));
+        }
+        self.check_ascending(key)?;
+        if ITEM_SIZE + data.len() > self.free_space() {
+            return Err(BtrfsError::NoSpace);
+        }
+
+        let n = self.item_count();
+        let data_end = match n {
+            0 => self.data.len(),
+            _ => NODE_HEADER_SIZE + self.item_at(n - 1)?.offset as usize,
+        };
+        let data_start = data_end - data.len();
+        self.data[data_start..data_end].copy_from_slice(data);
+
+        let item = Item {
+            key,
+            offset: (data_start - NODE_HEADER_SIZE) as u32,
+            size: data.len() as u32,
+        };
+        item.write_to(&mut self.data[NODE_HEADER_SIZE + n * ITEM_SIZE..])?;
+        self.set_nritems(n + 1)
+    }
+
+    /// Appends a key pointer to an internal node
+    ///
+    /// Keys must be pushed in ascending order. Fails with
+    /// [`BtrfsError::NoSpace`] if the node is full.
+    pub fn push_key_ptr(&mut self, key: BtrfsKey, blockptr: u64, generation: u64) -> Result<()> {
+        if self.is_leaf() {
+            return Err(BtrfsError::Corrupt(
+                "Cannot push a key pointer into a leaf".to_string(),
+            ));
+        }
+        self.check_ascending(key)?;
+        if KEY_PTR_SIZE > self.free_space() {
+            return Err(BtrfsError::NoSpace);
+        }
+
+        let n = self.item_count();
+        let ptr = KeyPtr {
+            key,
+            blockptr,
+            generation,
+        };
+        ptr.write_to(&mut self.data[NODE_HEADER_SIZE + n * KEY_PTR_SIZE..])?;
+        self.set_nritems(n + 1)
+    }
+
+    /// Fails unless `key` sorts after the node's last key
+    fn check_ascending(&self, key: BtrfsKey) -> Result<()> {
+        let last = match self.item_count() {
+            0 => return Ok(()),
+            n if self.is_leaf() => self.item_at(n - 1)?.key,
+            n => KeyPtr::from_bytes(&self.data[NODE_HEADER_SIZE + (n - 1) * KEY_PTR_SIZE..])?.key,
+        };
+        if key <= last {
+            return Err(BtrfsError::Corrupt(format!(
+                "Key {} pushed after {}",
+                key, last
+            )));
+        }
+        Ok(())
+    }
+
+    /// Updates the item count in both the header and the node bytes
+    fn set_nritems(&mut self, count: usize) -> Result<()> {
+        self.header.nritems = count as u32;
+        self.header.write_to(&mut self.data)
+    }
+
     /// Returns true if this is a leaf node
     pub fn is_leaf(&self) -> bool {
         self.header.is_leaf()
@@ -283,10 +652,80 @@ impl TreeNode {
         Ok(items)
     }
 
+    /// Returns the number of items (leaf) or key pointers (internal node)
+    #[inline]
+    pub fn item_count(&self) -> usize {
+        self.header.nritems as usize
+    }
+
+    /// Parses a single leaf item by index without materializing all items
+    #[inline]
+    pub fn item_at(&self, index: usize) -> Result<Item> {
+        if !self.is_leaf() {
+            return Err(BtrfsError::Corrupt(
+                "Cannot get items from internal node".to_string(),
+            ));
+        }
+
+        if index >= self.item_count() {
+            return Err(BtrfsError::Corrupt(format!(
+                "Item index {} out of range ({} items)",
+                index,
+                self.item_count()
+            )));
+        }
+
+        Item::from_bytes(&self.data[NODE_HEADER_SIZE + index * ITEM_SIZE..])
+    }
+
+    /// Binary searches a leaf for an item with exactly `key`
+    pub fn find_item(&self, key: &BtrfsKey) -> Result<Option<Item>> {
+        let mut lo = 0;
+        let mut hi = self.item_count();
+
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let item = self.item_at(mid)?;
+
+            match item.key.cmp(key) {
+                Ordering::Less => lo = mid + 1,
+                Ordering::Greater => hi = mid,
+                Ordering::Equal => return Ok(Some(item)),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Binary searches a leaf for the item with the largest key not
+    /// greater than `key`
+    pub fn find_floor_item(&self, key: &BtrfsKey) -> Result<Option<Item>> {
+        let mut lo = 0;
+        let mut hi = self.item_count();
+
+        // Invariant: items before lo are <= key, items from hi on are > key
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.item_at(mid)?.key <= *key {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+
+        match lo {
+            0 => Ok(None),
+            _ => self.item_at(lo - 1).map(Some),
+        }
+    }
+
     /// Gets item data for a leaf node item
+    ///
+    /// `item` must come from this node; [`parse`](Self::parse) checks that
+    /// every item's data lies inside the node.
     pub fn item_data(&self, item: &Item) -> &[u8] {
-        let start = NODE_HEADER_SIZE + item.offset as usize;
-        let end = start + item.size as usize;
+        let start = NODE_HEADER_SIZE.saturating_add(item.offset as usize);
+        let end = start.saturating_add(item.size as usize);
         &self.data[start..end]
     }
 
@@ -315,8 +754,7 @@ impl<'a> BtrfsTree<'a> {
 
     /// Reads a node at the given logical address
     pub fn read_node(&self, logical: u64) -> Result<TreeNode> {
-        let data = self.fs.read_node(logical)?;
-        TreeNode::parse(data)
+        self.fs.node_at(logical)
     }
 
     /// Searches for a key in the tree
@@ -329,15 +767,14 @@ impl<'a> BtrfsTree<'a> {
         let node = self.read_node(logical)?;
 
         if node.is_leaf() {
-            // Search in leaf node
-            let items = node.items()?;
-            for item in items {
-                if item.key == *key {
+            // Items are sorted, so binary search by index
+            match node.find_item(key)? {
+                Some(item) => {
                     let data = node.item_data(&item).to_vec();
-                    return Ok(Some((item, data)));
+                    Ok(Some((item, data)))
                 }
+                None => Ok(None),
             }
-            Ok(None)
         } else {
             // Search in internal node
             let ptrs = node.key_ptrs()?;
@@ -355,14 +792,57 @@ impl<'a> BtrfsTree<'a> {
         }
     }
 
+    /// Finds the item with the largest key not greater than `key`
+    ///
+    /// This is the read side of the kernel's `btrfs_search_slot`. Useful
+    /// where an item covers a range starting at its key, such as a file
+    /// extent containing a given file offset. Returns `None` if `key`
+    /// precedes every item.
+    pub fn search_slot(&self, key: &BtrfsKey) -> Result<Option<(Item, Vec<u8>)>> {
+        let mut node = self.read_node(self.root_logical)?;
+
+        // The floor lives under the last child whose first key is <= key;
+        // if key precedes every child, the first leaf has no floor either
+        while !node.is_leaf() {
+            let ptrs = node.key_ptrs()?;
+            let child = ptrs
+                .iter()
+                .take_while(|ptr| ptr.key <= *key)
+                .last()
+                .or(ptrs.first())
+                .ok_or_else(|| BtrfsError::Corrupt("Empty internal node".to_string()))?;
+            node = self.read_node(child.blockptr)?;
+        }
+
+        Ok(node.find_floor_item(key)?.map(|item| {
+            let data = node.item_data(&item).to_vec();
+            (item, data)
+        }))
+    }
+
     /// Searches for items in a range
     pub fn search_range(
         &self,
         min_key: &BtrfsKey,
         max_key: &BtrfsKey,
+    ) -> Result<Vec<(Item, Vec<u8>)>> {
+        self.search_range_limited(min_key, max_key, usize::MAX)
+    }
+
+    /// Searches for at most `limit` items in a range, in key order
+    ///
+    /// Large ranges can be paged through by restarting just past the last
+    /// key returned.
+    pub fn search_range_limited(
+        &self,
+        min_key: &BtrfsKey,
+        max_key: &BtrfsKey,
+        limit: usize,
     ) -> Result<Vec<(Item, Vec<u8>)>> {
         let mut results = Vec::new();
-        self.search_range_from(self.root_logical, min_key, max_key, &mut results)?;
+        if limit > 0 {
+            self.search_range_from(self.root_logical, min_key, max_key, limit, &mut results)?;
+        }
         Ok(results)
     }
 
@@ -372,6 +852,7 @@ impl<'a> BtrfsTree<'a> {
         logical: u64,
         min_key: &BtrfsKey,
         max_key: &BtrfsKey,
+        limit: usize,
         results: &mut Vec<(Item, Vec<u8>)>,
     ) -> Result<()> {
         let node = self.read_node(logical)?;
@@ -379,18 +860,30 @@ impl<'a> BtrfsTree<'a> {
         if node.is_leaf() {
             let items = node.items()?;
             for item in items {
-                if item.key >= *min_key && item.key <= *max_key {
+                if results.len() >= limit || item.key > *max_key {
+                    break;
+                }
+                if item.key >= *min_key {
                     let data = node.item_data(&item).to_vec();
                     results.push((item, data));
                 }
             }
         } else {
             let ptrs = node.key_ptrs()?;
-            for ptr in ptrs {
-                // Check if this subtree might contain items in range
-                if ptr.key <= *max_key {
-                    self.search_range_from(ptr.blockptr, min_key, max_key, results)?;
+            for (i, ptr) in ptrs.iter().enumerate() {
+                // A child's key is the smallest key in its subtree, so once it
+                // exceeds max_key no later child can hold anything in range.
+                // A child whose key equals max_key must still be visited.
+                if results.len() >= limit || ptr.key > *max_key {
+                    break;
                 }
+
+                // Skip subtrees that end before the range starts
+                if ptrs.get(i + 1).is_some_and(|next| next.key <= *min_key) {
+                    continue;
+                }
+
+                self.search_range_from(ptr.blockptr, min_key, max_key, limit, results)?;
             }
         }
 
@@ -399,7 +892,15 @@ impl<'a> BtrfsTree<'a> {
 
     /// Iterates over all items in the tree
     pub fn iter(&'a self) -> TreeIterator<'a> {
-        TreeIterator::new(self)
+        TreeIterator::new(self, false)
+    }
+
+    /// Iterates over all items in the tree in descending key order
+    ///
+    /// Finds the last items of a tree, such as its highest objectid,
+    /// without reading the leaves before them.
+    pub fn iter_rev(&'a self) -> TreeIterator<'a> {
+        TreeIterator::new(self, true)
     }
 }
 
@@ -408,14 +909,16 @@ pub struct TreeIterator<'a> {
     tree: &'a BtrfsTree<'a>,
     stack: Vec<(TreeNode, usize)>,
     initialized: bool,
+    reverse: bool,
 }
 
 impl<'a> TreeIterator<'a> {
-    fn new(tree: &'a BtrfsTree<'a>) -> Self {
+    fn new(tree: &'a BtrfsTree<'a>, reverse: bool) -> Self {
         Self {
             tree,
             stack: Vec::new(),
             initialized: false,
+            reverse,
         }
     }
 
@@ -424,24 +927,64 @@ impl<'a> TreeIterator<'a> {
             return Ok(());
         }
 
-        // Descend to leftmost leaf
-        let mut logical = self.tree.root_logical;
+        self.initialized = true;
+        self.descend(self.tree.root_logical)
+    }
+
+    /// Pushes the path from `logical` down to its leftmost leaf, or its
+    /// rightmost one when iterating in reverse
+    ///
+    /// Internal nodes on the stack hold the index of the child currently
+    /// being visited. Leaves hold the index of the next item going forward,
+    /// or one past it going backward.
+    fn descend(&mut self, mut logical: u64) -> Result<()> {
         loop {
             let node = self.tree.read_node(logical)?;
             if node.is_leaf() {
-                self.stack.push((node, 0));
-                break;
+                let start = if self.reverse { node.item_count() } else { 0 };
+                self.stack.push((node, start));
+                return Ok(());
+            }
+
+            let ptrs = node.key_ptrs()?;
+            let idx = if self.reverse {
+                ptrs.len().saturating_sub(1)
             } else {
-                let ptrs = node.key_ptrs()?;
-                if ptrs.is_empty() {
-                    break;
-                }
-                logical = ptrs[0].blockptr;
-                self.stack.push((node, 0));
+                0
+            };
+            self.stack.push((node, idx));
+            match ptrs.get(idx) {
+                Some(ptr) => logical = ptr.blockptr,
+                None => return Ok(()),
             }
         }
+    }
 
-        self.initialized = true;
+    /// Moves to the first leaf of the neighbouring subtree once a leaf is
+    /// done
+    fn advance(&mut self) -> Result<()> {
+        while let Some((node, idx)) = self.stack.last_mut() {
+            if node.is_leaf() {
+                self.stack.pop();
+                continue;
+            }
+
+            let next = if self.reverse {
+                idx.checked_sub(1)
+            } else {
+                Some(*idx + 1)
+            };
+            let ptrs = node.key_ptrs()?;
+            match next.and_then(|i| Some((i, ptrs.get(i)?.blockptr))) {
+                Some((i, blockptr)) => {
+                    *idx = i;
+                    return self.descend(blockptr);
+                }
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
         Ok(())
     }
 }
@@ -451,32 +994,39 @@ impl<'a> Iterator for TreeIterator<'a> {
 
     fn next(&mut self) -> Option<Self::Item> {
         if let Err(e) = self.initialize() {
+            self.stack.clear();
             return Some(Err(e));
         }
 
         loop {
+            let reverse = self.reverse;
             let (node, idx) = self.stack.last_mut()?;
 
             if node.is_leaf() {
-                let items = match node.items() {
-                    Ok(items) => items,
-                    Err(e) => return Some(Err(e)),
+                let slot = if reverse {
+                    idx.checked_sub(1)
+                } else {
+                    Some(*idx).filter(|&i| i < node.item_count())
                 };
 
-                if *idx < items.len() {
-                    let item = items[*idx];
+                if let Some(i) = slot {
+                    let item = match node.item_at(i) {
+                        Ok(item) => item,
+                        Err(e) => {
+                            self.stack.clear();
+                            return Some(Err(e));
+                        }
+                    };
                     let data = node.item_data(&item).to_vec();
-                    *idx += 1;
+                    *idx = if reverse { i } else { i + 1 };
                     return Some(Ok((item, data)));
-                } else {
-                    // Move to next node
-                    self.stack.pop();
-                    // TODO: Implement sibling traversal
-                    return None;
                 }
-            } else {
-                // Should not happen after initialization
-                self.stack.pop();
+            }
+
+            // Leaf exhausted or an internal node with no children
+            if let Err(e) = self.advance() {
+                self.stack.clear();
+                return Some(Err(e));
             }
         }
     }
@@ -485,6 +1035,7 @@ impl<'a> Iterator for TreeIterator<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_utils::ImageBuilder;
 
     #[test]
     fn test_tree_type_from_objectid() {
@@ -501,6 +1052,15 @@ mod tests {
         assert_eq!(TreeType::from_objectid(256), TreeType::Unknown(256));
     }
 
+    #[test]
+    fn test_tree_type_objectid_round_trip() {
+        for id in 0..=12u64 {
+            assert_eq!(TreeType::from_objectid(id).objectid(), id);
+        }
+        assert_eq!(u64::from(TreeType::Csum), 7);
+        assert_eq!(u64::from(TreeType::Unknown(257)), 257);
+    }
+
     #[test]
     fn test_btrfs_key_new() {
         let key = BtrfsKey::new(256, 0x01, 0);
@@ -575,6 +1135,38 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_btrfs_key_offset_meaning() {
+        assert_eq!(
+            BtrfsKey::new(257, 0x6C, 4096).offset_meaning(),
+            "file offset"
+        );
+        assert_eq!(BtrfsKey::new(256, 0x54, 0).offset_meaning(), "name hash");
+        assert_eq!(BtrfsKey::new(257, 0x0C, 256).offset_meaning(), "parent dir");
+        assert_eq!(BtrfsKey::new(256, 0x60, 2).offset_meaning(), "dir index");
+        assert_eq!(BtrfsKey::new(256, 0x02, 0).offset_meaning(), "offset");
+    }
+
+    #[test]
+    fn test_btrfs_key_display() {
+        assert_eq!(
+            BtrfsKey::new(257, 0x0C, 256).to_string(),
+            "(257 INODE_REF 256 [parent dir])"
+        );
+        assert_eq!(
+            BtrfsKey::new(257, 0x6C, 8192).to_string(),
+            "(257 EXTENT_DATA 8192 [file offset])"
+        );
+        assert_eq!(
+            BtrfsKey::new(256, 0x54, 0xdeadbeef).to_string(),
+            "(256 DIR_ITEM 0xdeadbeef [name hash])"
+        );
+        assert_eq!(
+            BtrfsKey::new(1, 0x02, 7).to_string(),
+            "(1 UNKNOWN.2 7 [offset])"
+        );
+    }
+
     #[test]
     fn test_key_ptr_from_bytes() {
         let mut data = vec![0u8; KEY_PTR_SIZE];
@@ -633,6 +1225,112 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_on_disk_structs_round_trip() {
+        let data: Vec<u8> = (0..NODE_HEADER_SIZE).map(|i| (i * 37 + 11) as u8).collect();
+
+        let header = NodeHeader::from_bytes(&data).unwrap();
+        assert_eq!(header.to_bytes()[..], data[..]);
+        let key = BtrfsKey::from_bytes(&data).unwrap();
+        assert_eq!(key.to_bytes()[..], data[..KEY_SIZE]);
+        let ptr = KeyPtr::from_bytes(&data).unwrap();
+        assert_eq!(ptr.to_bytes()[..], data[..KEY_PTR_SIZE]);
+        let item = Item::from_bytes(&data).unwrap();
+        assert_eq!(item.to_bytes()[..], data[..ITEM_SIZE]);
+
+        // A real leaf header and its first item header
+        let (_, node) = leaf_with_items(3);
+        let raw = node.data();
+        assert_eq!(node.header.to_bytes()[..], raw[..NODE_HEADER_SIZE]);
+
+        let item = node.item_at(0).unwrap();
+        let mut buf = vec![0u8; ITEM_SIZE + 4];
+        item.write_to(&mut buf).unwrap();
+        assert_eq!(
+            buf[..ITEM_SIZE],
+            raw[NODE_HEADER_SIZE..NODE_HEADER_SIZE + ITEM_SIZE]
+        );
+        assert_eq!(Item::from_bytes(&buf).unwrap().size, item.size);
+
+        let mut small = [0u8; KEY_SIZE];
+        assert!(ptr.write_to(&mut small).is_err());
+        match header.write_to(&mut small) {
+            Err(BtrfsError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput),
+            other => panic!("expected an invalid argument error, got {:?}", other),
+        }
+        key.write_to(&mut small).unwrap();
+        assert_eq!(small[..], data[..KEY_SIZE]);
+    }
+
+    #[test]
+    fn test_new_leaf_push_items() {
+        let mut leaf = TreeNode::new_leaf(4096, 5, 7, [1; 16], [2; 16]);
+        assert!(leaf.is_leaf());
+        assert_eq!(leaf.data().len(), 4096);
+        assert_eq!(leaf.free_space(), 4096 - NODE_HEADER_SIZE);
+
+        for i in 0..3u64 {
+            leaf.push_item(BtrfsKey::new(256 + i, 0x01, 0), &[i as u8; 10])
+                .unwrap();
+        }
+        assert_eq!(
+            leaf.free_space(),
+            4096 - NODE_HEADER_SIZE - 3 * (ITEM_SIZE + 10)
+        );
+
+        // Reads back through the parsing path
+        let header = NodeHeader::from_bytes(leaf.data()).unwrap();
+        assert_eq!(
+            ({ header.owner }, { header.generation }, { header.nritems }),
+            (5, 7, 3)
+        );
+        assert_eq!(header.fsid, [1; 16]);
+        let items = leaf.items().unwrap();
+        assert_eq!(items.len(), 3);
+        for (i, item) in items.iter().enumerate() {
+            assert_eq!({ item.key.objectid }, 256 + i as u64);
+            assert_eq!(leaf.item_data(item), &[i as u8; 10]);
+        }
+        assert_eq!(items[0].offset as usize, 4096 - NODE_HEADER_SIZE - 10);
+
+        // Out of order keys and internal-node operations are refused
+        assert!(leaf.push_item(BtrfsKey::new(256, 0x01, 0), &[]).is_err());
+        assert!(leaf
+            .push_key_ptr(BtrfsKey::new(300, 0x01, 0), 0x1000, 7)
+            .is_err());
+
+        // Fill the leaf
+        let big = vec![0u8; leaf.free_space() - ITEM_SIZE];
+        leaf.push_item(BtrfsKey::new(300, 0x01, 0), &big).unwrap();
+        assert_eq!(leaf.free_space(), 0);
+        assert!(matches!(
+            leaf.push_item(BtrfsKey::new(301, 0x01, 0), &[]),
+            Err(BtrfsError::NoSpace)
+        ));
+    }
+
+    #[test]
+    fn test_new_internal_push_key_ptrs() {
+        let mut node = TreeNode::new_internal(4096, 1, 5, 7, [1; 16], [2; 16]);
+        assert!(!node.is_leaf());
+
+        let capacity = (4096 - NODE_HEADER_SIZE) / KEY_PTR_SIZE;
+        for i in 0..capacity as u64 {
+            node.push_key_ptr(BtrfsKey::new(256 + i, 0x01, 0), 0x100000 + i * 4096, 7)
+                .unwrap();
+        }
+        assert!(matches!(
+            node.push_key_ptr(BtrfsKey::new(u64::MAX, 0x01, 0), 0, 7),
+            Err(BtrfsError::NoSpace)
+        ));
+
+        let ptrs = node.key_ptrs().unwrap();
+        assert_eq!(ptrs.len(), capacity);
+        assert_eq!(ptrs[3].blockptr, 0x100000 + 3 * 4096);
+        assert_eq!({ ptrs[3].key.objectid }, 259);
+        assert!(node.push_item(BtrfsKey::new(1, 1, 0), &[]).is_err());
+    }
+
     #[test]
     fn test_constants() {
         assert_eq!(NODE_HEADER_SIZE, 0x65);
@@ -640,4 +1338,242 @@ mod tests {
         assert_eq!(ITEM_SIZE, 0x19);
         assert_eq!(KEY_SIZE, 0x11);
     }
+
+    fn leaf_with_items(count: u64) -> (Vec<BtrfsKey>, TreeNode) {
+        let keys: Vec<BtrfsKey> = (0..count)
+            .map(|i| BtrfsKey::new(1000 + i / 2, 0x6C, (i % 2) * 4096))
+            .collect();
+        let payloads: Vec<[u8; 8]> = (0..count).map(|i| i.to_le_bytes()).collect();
+        let items: Vec<(BtrfsKey, &[u8])> = keys
+            .iter()
+            .zip(&payloads)
+            .map(|(k, p)| (*k, p.as_slice()))
+            .collect();
+
+        let data = ImageBuilder::new().leaf_bytes(0x100000, 5, &items);
+        (keys, TreeNode::parse(data, Checksum::Crc32c).unwrap())
+    }
+
+    #[test]
+    fn test_parse_rejects_out_of_bounds_items() {
+        let items: Vec<(BtrfsKey, &[u8])> = vec![(BtrfsKey::new(256, 0x01, 0), b"data")];
+        let data = ImageBuilder::new().leaf_bytes(0x100000, 5, &items);
+        let reparse = |mut data: Vec<u8>| {
+            let csum = Checksum::Crc32c.compute(&data[0x20..]).unwrap() as u32;
+            data[..4].copy_from_slice(&csum.to_le_bytes());
+            TreeNode::parse(data, Checksum::Crc32c)
+        };
+        assert!(reparse(data.clone()).is_ok());
+
+        // More items than the node can hold
+        let mut nritems = data.clone();
+        nritems[0x60..0x64].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(reparse(nritems), Err(BtrfsError::Corrupt(_))));
+
+        // Item data offset and size near u32::MAX
+        let item = NODE_HEADER_SIZE + KEY_SIZE;
+        let mut offset = data.clone();
+        offset[item..item + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(reparse(offset), Err(BtrfsError::Corrupt(_))));
+        let mut size = data;
+        size[item + 4..item + 8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(reparse(size), Err(BtrfsError::Corrupt(_))));
+    }
+
+    #[test]
+    fn test_item_at_matches_items() {
+        let (_, node) = leaf_with_items(400);
+        let items = node.items().unwrap();
+
+        assert_eq!(node.item_count(), 400);
+        assert_eq!(items.len(), node.item_count());
+        for (i, item) in items.iter().enumerate() {
+            let indexed = node.item_at(i).unwrap();
+            assert_eq!(indexed.key, item.key);
+            assert_eq!(indexed.offset, item.offset);
+            assert_eq!(indexed.size, item.size);
+        }
+
+        assert!(node.item_at(400).is_err());
+    }
+
+    #[test]
+    fn test_find_item_matches_linear_scan() {
+        let (keys, node) = leaf_with_items(400);
+        let items = node.items().unwrap();
+
+        for key in &keys {
+            let found = node.find_item(key).unwrap().unwrap();
+            let linear = items.iter().find(|item| item.key == *key).unwrap();
+            assert_eq!(found.key, linear.key);
+            assert_eq!(node.item_data(&found), node.item_data(linear));
+        }
+
+        // Keys before, between and after the stored ones
+        assert!(node.find_item(&BtrfsKey::min()).unwrap().is_none());
+        assert!(node.find_item(&BtrfsKey::new(1000, 0x6C, 1)).unwrap().is_none());
+        assert!(node.find_item(&BtrfsKey::max()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_find_item_empty_leaf() {
+        let (_, node) = leaf_with_items(0);
+        assert_eq!(node.item_count(), 0);
+        assert!(node.find_item(&BtrfsKey::min()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_tree_search_multi_level() {
+        let mut builder = ImageBuilder::new();
+        for i in 0..1500u64 {
+            builder.insert(5, BtrfsKey::new(1000 + i, 0x6C, 0), i.to_le_bytes().to_vec());
+        }
+        let fs = builder.open();
+        let tree = fs.tree(5).unwrap();
+        assert!(!tree.read_node(tree.root_logical).unwrap().is_leaf());
+
+        for i in [0u64, 1, 499, 500, 1234, 1499] {
+            let (item, data) = tree.search(&BtrfsKey::new(1000 + i, 0x6C, 0)).unwrap().unwrap();
+            assert_eq!({ item.key.objectid }, 1000 + i);
+            assert_eq!(data, i.to_le_bytes());
+        }
+        assert!(tree.search(&BtrfsKey::new(999, 0x6C, 0)).unwrap().is_none());
+        assert!(tree.search(&BtrfsKey::new(2500, 0x6C, 0)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_search_range_on_separator_keys() {
+        let mut builder = ImageBuilder::new();
+        for i in 0..1500u64 {
+            builder.insert(5, BtrfsKey::new(1000 + i, 0x6C, 0), i.to_le_bytes().to_vec());
+        }
+        let fs = builder.open();
+        let tree = fs.tree(5).unwrap();
+        let ptrs = tree.read_node(tree.root_logical).unwrap().key_ptrs().unwrap();
+        assert!(ptrs.len() > 1);
+
+        for ptr in &ptrs[1..] {
+            let separator = ptr.key;
+            let first = BtrfsKey::new(1000, 0x6C, 0);
+            let last = BtrfsKey::new(2499, 0x6C, 0);
+
+            // Range ending exactly on the separator includes it
+            let upto = tree.search_range(&first, &separator).unwrap();
+            assert_eq!(upto.len() as u64, separator.objectid - 1000 + 1);
+            assert_eq!({ upto.last().unwrap().0.key }, separator);
+
+            // Range starting exactly on the separator includes it
+            let from = tree.search_range(&separator, &last).unwrap();
+            assert_eq!(from.len() as u64, 2500 - separator.objectid);
+            assert_eq!({ from[0].0.key }, separator);
+
+            // Single-key range on the separator
+            let exact = tree.search_range(&separator, &separator).unwrap();
+            assert_eq!(exact.len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_search_slot() {
+        let mut builder = ImageBuilder::new();
+        for i in 0..1500u64 {
+            builder.insert(5, BtrfsKey::new(1000 + 2 * i, 0x6C, 0), i.to_le_bytes().to_vec());
+        }
+        let fs = builder.open();
+        let tree = fs.tree(5).unwrap();
+        assert!(tree.read_node(tree.root_logical).unwrap().key_ptrs().unwrap().len() > 1);
+
+        let floor = |objectid| {
+            tree.search_slot(&BtrfsKey::new(objectid, 0x6C, 0))
+                .unwrap()
+                .map(|(item, _)| item.key.objectid)
+        };
+        // Before the first key
+        assert_eq!(floor(999), None);
+        // Exact matches
+        assert_eq!(floor(1000), Some(1000));
+        assert_eq!(floor(3998), Some(3998));
+        // Between keys, and past the last one
+        assert_eq!(floor(1001), Some(1000));
+        assert_eq!(floor(2001), Some(2000));
+        assert_eq!(floor(u64::MAX), Some(3998));
+
+        // Keys between leaves land on the last item of the earlier leaf
+        let ptrs = tree.read_node(tree.root_logical).unwrap().key_ptrs().unwrap();
+        for ptr in &ptrs[1..] {
+            let separator = { ptr.key.objectid };
+            assert_eq!(floor(separator - 1), Some(separator - 2));
+            assert_eq!(floor(separator), Some(separator));
+        }
+    }
+
+    #[test]
+    fn test_iter_visits_every_leaf() {
+        // Small nodes give a three-level tree with a modest item count
+        let mut builder = ImageBuilder::new();
+        builder.node_size(4096);
+        for i in 0..20_000u64 {
+            builder.insert(5, BtrfsKey::new(1000 + i, 0x6C, 0), i.to_le_bytes().to_vec());
+        }
+        let fs = builder.open();
+        let tree = fs.tree(5).unwrap();
+        assert_eq!(tree.read_node(tree.root_logical).unwrap().header.level, 2);
+
+        let mut count = 0u64;
+        for (i, result) in tree.iter().enumerate() {
+            let (item, data) = result.unwrap();
+            assert_eq!({ item.key.objectid }, 1000 + i as u64);
+            assert_eq!(data, (i as u64).to_le_bytes());
+            count += 1;
+        }
+        assert_eq!(count, 20_000);
+    }
+
+    #[test]
+    fn test_iter_single_leaf() {
+        let mut builder = ImageBuilder::new();
+        for i in 0..3u64 {
+            builder.insert(5, BtrfsKey::new(256 + i, 0x01, 0), vec![i as u8]);
+        }
+        let fs = builder.open();
+        let tree = fs.tree(5).unwrap();
+
+        let keys: Vec<u64> = tree.iter().map(|r| r.unwrap().0.key.objectid).collect();
+        assert_eq!(keys, vec![256, 257, 258]);
+    }
+
+    #[test]
+    fn test_iter_rev_visits_every_leaf() {
+        let mut builder = ImageBuilder::new();
+        builder.node_size(4096);
+        for i in 0..20_000u64 {
+            builder.insert(5, BtrfsKey::new(1000 + i, 0x6C, 0), i.to_le_bytes().to_vec());
+        }
+        let fs = builder.open();
+        let tree = fs.tree(5).unwrap();
+        assert_eq!(tree.read_node(tree.root_logical).unwrap().header.level, 2);
+
+        let mut count = 0u64;
+        for (i, result) in tree.iter_rev().enumerate() {
+            let (item, data) = result.unwrap();
+            let expected = 19_999 - i as u64;
+            assert_eq!({ item.key.objectid }, 1000 + expected);
+            assert_eq!(data, expected.to_le_bytes());
+            count += 1;
+        }
+        assert_eq!(count, 20_000);
+    }
+
+    #[test]
+    fn test_iter_rev_single_leaf() {
+        let mut builder = ImageBuilder::new();
+        for i in 0..3u64 {
+            builder.insert(5, BtrfsKey::new(256 + i, 0x01, 0), vec![i as u8]);
+        }
+        let fs = builder.open();
+        let tree = fs.tree(5).unwrap();
+
+        let keys: Vec<u64> = tree.iter_rev().map(|r| r.unwrap().0.key.objectid).collect();
+        assert_eq!(keys, vec![258, 257, 256]);
+    }
 }
@@ -7,8 +7,14 @@ use std::ffi::{CStr, CString};
 use std::os::raw::{c_char, c_int, c_void};
 use std::ptr;
 
-use crate::core::{BtrfsFilesystem, Subvolume};
+use crate::core::{
+    inode::DirEntry, objectid, BtrfsError, BtrfsFilesystem, Inode, InodeType, Result, Subvolume,
+};
 use crate::blockdev;
+use crate::fuse::{
+    operations::{self, PathResolver},
+    FileReader,
+};
 
 /// Library version
 pub const LIB_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -23,9 +29,53 @@ pub const BTRFS_ERR_UNSUPPORTED: c_int = -5;
 pub const BTRFS_ERR_PERMISSION: c_int = -6;
 pub const BTRFS_ERR_UNKNOWN: c_int = -99;
 
+/// Returned by `btrfs_readdir` once every entry has been read
+pub const BTRFS_DIR_END: c_int = 1;
+
+/// Directory entry types, as stored in BTRFS directory items
+pub const BTRFS_FT_UNKNOWN: u8 = 0;
+pub const BTRFS_FT_REG_FILE: u8 = 1;
+pub const BTRFS_FT_DIR: u8 = 2;
+pub const BTRFS_FT_CHRDEV: u8 = 3;
+pub const BTRFS_FT_BLKDEV: u8 = 4;
+pub const BTRFS_FT_FIFO: u8 = 5;
+pub const BTRFS_FT_SOCK: u8 = 6;
+pub const BTRFS_FT_SYMLINK: u8 = 7;
+
 /// Opaque handle to a BTRFS filesystem
 pub struct BtrfsHandle {
     fs: BtrfsFilesystem,
+    /// Resolves and caches paths from the top-level subvolume
+    resolver: PathResolver,
+}
+
+impl BtrfsHandle {
+    fn new(fs: BtrfsFilesystem) -> Self {
+        Self {
+            fs,
+            resolver: PathResolver::new().with_cache(),
+        }
+    }
+
+    /// Resolves `path` from the top-level subvolume, entering nested ones
+    fn resolve(&self, path: &str) -> Result<(u64, u64, Inode)> {
+        self.resolver.resolve(&self.fs, objectid::FS_TREE, path)
+    }
+}
+
+/// Maps an error to the closest error code
+fn error_code(e: &BtrfsError) -> c_int {
+    match e {
+        BtrfsError::Io(_) | BtrfsError::BlockDevice(_) => BTRFS_ERR_IO,
+        BtrfsError::Corrupt(_) | BtrfsError::InvalidMagic => BTRFS_ERR_CORRUPT,
+        BtrfsError::ChecksumMismatch { .. } => BTRFS_ERR_CORRUPT,
+        BtrfsError::UnsupportedFeature(_) | BtrfsError::UnsupportedCompression(_) => {
+            BTRFS_ERR_UNSUPPORTED
+        }
+        BtrfsError::NotFound(_) | BtrfsError::SubvolumeNotFound(_) => BTRFS_ERR_NOT_FOUND,
+        BtrfsError::NotADirectory | BtrfsError::NotAFile => BTRFS_ERR_INVALID_ARG,
+        _ => BTRFS_ERR_UNKNOWN,
+    }
 }
 
 /// Get the library version
@@ -85,18 +135,10 @@ pub unsafe extern "C" fn btrfs_open(
 
     let fs = match BtrfsFilesystem::open(std::sync::Arc::from(device), read_only != 0) {
         Ok(fs) => fs,
-        Err(e) => {
-            return match e {
-                crate::core::BtrfsError::Io(_) => BTRFS_ERR_IO,
-                crate::core::BtrfsError::Corrupt(_) => BTRFS_ERR_CORRUPT,
-                crate::core::BtrfsError::InvalidMagic => BTRFS_ERR_CORRUPT,
-                crate::core::BtrfsError::UnsupportedFeature(_) => BTRFS_ERR_UNSUPPORTED,
-                _ => BTRFS_ERR_UNKNOWN,
-            };
-        }
+        Err(e) => return error_code(&e),
     };
 
-    let handle = Box::new(BtrfsHandle { fs });
+    let handle = Box::new(BtrfsHandle::new(fs));
     *handle_out = Box::into_raw(handle);
 
     BTRFS_OK
@@ -160,9 +202,7 @@ pub unsafe extern "C" fn btrfs_get_label(
     }
 
     let handle = &*handle;
-    let label = handle.fs.label();
-    
-    let bytes = label.as_bytes();
+    let bytes = handle.fs.label_bytes();
     let copy_len = std::cmp::min(bytes.len(), label_len - 1);
     
     ptr::copy_nonoverlapping(bytes.as_ptr(), label_out as *mut u8, copy_len);
@@ -268,6 +308,254 @@ pub unsafe extern "C" fn btrfs_get_subvolume(
     BTRFS_OK
 }
 
+/// File metadata structure for FFI
+#[repr(C)]
+pub struct BtrfsStat {
+    /// Subvolume holding the file
+    pub subvol_id: u64,
+    /// Inode number within the subvolume
+    pub ino: u64,
+    pub size: u64,
+    /// File type and permission bits, as in `st_mode`
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Last modification, in seconds and nanoseconds since the Unix epoch
+    pub mtime_sec: i64,
+    pub mtime_nsec: u32,
+}
+
+/// Reads a null-terminated UTF-8 path argument
+unsafe fn path_arg<'a>(path: *const c_char) -> Option<&'a str> {
+    if path.is_null() {
+        return None;
+    }
+    unsafe { CStr::from_ptr(path) }.to_str().ok()
+}
+
+/// Get the metadata of the file or directory at `path`
+///
+/// Paths are relative to the top-level subvolume and separated by `/` or
+/// `\`; nested subvolumes are entered, so `@home/user` is a directory in
+/// subvolume `@home`.
+///
+/// # Safety
+/// - `handle` must be a valid handle
+/// - `path` must be a valid null-terminated UTF-8 string
+/// - `stat_out` must be a valid pointer
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn btrfs_stat(
+    handle: *const BtrfsHandle,
+    path: *const c_char,
+    stat_out: *mut BtrfsStat,
+) -> c_int {
+    let path = unsafe { path_arg(path) };
+    let (Some(path), false, false) = (path, handle.is_null(), stat_out.is_null()) else {
+        return BTRFS_ERR_INVALID_ARG;
+    };
+    let handle = unsafe { &*handle };
+
+    let (subvol_id, inode) = match handle.resolve(path) {
+        Ok((tree_id, _, inode)) => (tree_id, inode),
+        Err(e) => return error_code(&e),
+    };
+
+    unsafe {
+        *stat_out = BtrfsStat {
+            subvol_id,
+            ino: inode.ino,
+            size: inode.size,
+            mode: inode.mode,
+            nlink: inode.nlink,
+            uid: inode.uid,
+            gid: inode.gid,
+            mtime_sec: inode.mtime.sec,
+            mtime_nsec: inode.mtime.nsec,
+        };
+    }
+    BTRFS_OK
+}
+
+/// Read up to `len` bytes of the file at `path`, starting at `offset`
+///
+/// Paths are resolved as in [`btrfs_stat`]. Fewer than `len` bytes are
+/// read only at the end of the file; reading at or past the end reads
+/// nothing. Data checksums are verified.
+///
+/// # Safety
+/// - `handle` must be a valid handle
+/// - `path` must be a valid null-terminated UTF-8 string
+/// - `buf` must point to at least `len` writable bytes
+/// - `bytes_read_out` must be a valid pointer
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn btrfs_read_file(
+    handle: *const BtrfsHandle,
+    path: *const c_char,
+    offset: u64,
+    buf: *mut u8,
+    len: usize,
+    bytes_read_out: *mut usize,
+) -> c_int {
+    let path = unsafe { path_arg(path) };
+    let invalid = handle.is_null() || (buf.is_null() && len > 0) || bytes_read_out.is_null();
+    let (Some(path), false) = (path, invalid) else {
+        return BTRFS_ERR_INVALID_ARG;
+    };
+    let handle = unsafe { &*handle };
+    let buf: &mut [u8] = match len {
+        0 => &mut [],
+        _ => unsafe { std::slice::from_raw_parts_mut(buf, len) },
+    };
+
+    let read = handle.resolve(path).and_then(|(tree_id, ino, _)| {
+        let mut reader = FileReader::open(&handle.fs, tree_id, ino)?;
+        reader.set_position(offset);
+
+        let mut filled = 0;
+        while filled < buf.len() {
+            match reader.read_chunk(&mut buf[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        Ok(filled)
+    });
+    match read {
+        Ok(n) => {
+            unsafe { *bytes_read_out = n };
+            BTRFS_OK
+        }
+        Err(e) => error_code(&e),
+    }
+}
+
+/// Directory entry structure for FFI
+#[repr(C)]
+pub struct BtrfsDirEntry {
+    pub name: [c_char; 256],
+    /// Subvolume holding the entry's inode; differs from the directory's
+    /// for a nested subvolume
+    pub subvol_id: u64,
+    /// Inode number within `subvol_id`
+    pub ino: u64,
+    /// One of the `BTRFS_FT_*` types
+    pub entry_type: u8,
+}
+
+/// Open directory listing returned by `btrfs_opendir`
+///
+/// The entries are read when the directory is opened, so the listing
+/// stays valid after its filesystem handle is closed.
+pub struct BtrfsDirHandle {
+    /// Tree holding the directory
+    tree_id: u64,
+    entries: std::vec::IntoIter<DirEntry>,
+}
+
+/// Open the directory at `path` for reading with `btrfs_readdir`
+///
+/// Paths are resolved as in [`btrfs_stat`].
+///
+/// # Safety
+/// - `handle` must be a valid handle
+/// - `path` must be a valid null-terminated UTF-8 string
+/// - `dir_handle_out` must be a valid pointer
+/// - The returned directory handle must be freed with `btrfs_closedir`
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn btrfs_opendir(
+    handle: *const BtrfsHandle,
+    path: *const c_char,
+    dir_handle_out: *mut *mut BtrfsDirHandle,
+) -> c_int {
+    let path = unsafe { path_arg(path) };
+    let (Some(path), false, false) = (path, handle.is_null(), dir_handle_out.is_null()) else {
+        return BTRFS_ERR_INVALID_ARG;
+    };
+    let handle = unsafe { &*handle };
+
+    let listing = handle.resolve(path).and_then(|(tree_id, ino, inode)| {
+        if !inode.is_dir() {
+            return Err(BtrfsError::NotADirectory);
+        }
+        let entries = operations::read_dir(&handle.fs, tree_id, ino)?;
+        Ok((tree_id, entries))
+    });
+    let (tree_id, entries) = match listing {
+        Ok(listing) => listing,
+        Err(e) => return error_code(&e),
+    };
+
+    let dir = Box::new(BtrfsDirHandle {
+        tree_id,
+        entries: entries.into_iter(),
+    });
+    unsafe { *dir_handle_out = Box::into_raw(dir) };
+    BTRFS_OK
+}
+
+/// Read the next entry of an open directory
+///
+/// Returns `BTRFS_OK` with `entry_out` filled in, or `BTRFS_DIR_END` once
+/// every entry has been read. `.` and `..` are not listed.
+///
+/// # Safety
+/// - `dir_handle` must be a valid directory handle
+/// - `entry_out` must be a valid pointer
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn btrfs_readdir(
+    dir_handle: *mut BtrfsDirHandle,
+    entry_out: *mut BtrfsDirEntry,
+) -> c_int {
+    if dir_handle.is_null() || entry_out.is_null() {
+        return BTRFS_ERR_INVALID_ARG;
+    }
+    let dir = unsafe { &mut *dir_handle };
+
+    let Some(entry) = dir.entries.next() else {
+        return BTRFS_DIR_END;
+    };
+    let (subvol_id, ino) = entry.target(dir.tree_id);
+    let entry_type = match entry.entry_type {
+        InodeType::Unknown => BTRFS_FT_UNKNOWN,
+        InodeType::File => BTRFS_FT_REG_FILE,
+        InodeType::Directory => BTRFS_FT_DIR,
+        InodeType::CharDevice => BTRFS_FT_CHRDEV,
+        InodeType::BlockDevice => BTRFS_FT_BLKDEV,
+        InodeType::Fifo => BTRFS_FT_FIFO,
+        InodeType::Socket => BTRFS_FT_SOCK,
+        InodeType::Symlink => BTRFS_FT_SYMLINK,
+    };
+
+    let out = unsafe { &mut *entry_out };
+    let name_bytes = entry.name.as_bytes();
+    let name_len = std::cmp::min(name_bytes.len(), out.name.len() - 1);
+    unsafe {
+        ptr::copy_nonoverlapping(name_bytes.as_ptr(), out.name.as_mut_ptr() as *mut u8, name_len);
+    }
+    out.name[name_len] = 0;
+    out.subvol_id = subvol_id;
+    out.ino = ino;
+    out.entry_type = entry_type;
+
+    BTRFS_OK
+}
+
+/// Close a directory handle
+///
+/// # Safety
+/// - `dir_handle` must be a valid handle returned by `btrfs_opendir`
+/// - The handle must not be used after this call
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn btrfs_closedir(dir_handle: *mut BtrfsDirHandle) -> c_int {
+    if dir_handle.is_null() {
+        return BTRFS_ERR_INVALID_ARG;
+    }
+
+    drop(unsafe { Box::from_raw(dir_handle) });
+    BTRFS_OK
+}
+
 /// Get the last error message
 /// 
 /// # Safety
@@ -284,6 +572,8 @@ pub extern "C" fn btrfs_last_error() -> *const c_char {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::{item_type, tree::BtrfsKey};
+    use crate::test_utils::{inline_extent, ImageBuilder};
 
     #[test]
     fn test_version() {
@@ -300,6 +590,134 @@ mod tests {
         assert!(major >= 0);
     }
 
+    /// A file in the top level and one in subvolume `@`
+    fn handle() -> BtrfsHandle {
+        let root = objectid::FIRST_FREE;
+        let fs = ImageBuilder::new()
+            .root_dir(objectid::FS_TREE)
+            .file(objectid::FS_TREE, root, 257, "hello.txt", 11)
+            .insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(257, item_type::EXTENT_DATA, 0),
+                inline_extent(b"hello world"),
+            )
+            .subvolume(256, objectid::FS_TREE, root, "@")
+            .dir(256, root, 257, "docs")
+            .file(256, 257, 258, "empty", 0)
+            .open();
+        BtrfsHandle::new(fs)
+    }
+
+    #[test]
+    fn test_read_file() {
+        let handle = handle();
+        let path = CString::new("hello.txt").unwrap();
+        let mut buf = [0u8; 16];
+        let mut read = 0;
+
+        let code = unsafe {
+            btrfs_read_file(&handle, path.as_ptr(), 6, buf.as_mut_ptr(), 16, &mut read)
+        };
+        assert_eq!(code, BTRFS_OK);
+        assert_eq!(&buf[..read], b"world");
+
+        let code = unsafe {
+            btrfs_read_file(&handle, path.as_ptr(), 20, buf.as_mut_ptr(), 16, &mut read)
+        };
+        assert_eq!((code, read), (BTRFS_OK, 0));
+
+        let missing = CString::new("@/docs/missing").unwrap();
+        let code = unsafe {
+            btrfs_read_file(&handle, missing.as_ptr(), 0, buf.as_mut_ptr(), 16, &mut read)
+        };
+        assert_eq!(code, BTRFS_ERR_NOT_FOUND);
+
+        let dir = CString::new("@/docs").unwrap();
+        let code = unsafe {
+            btrfs_read_file(&handle, dir.as_ptr(), 0, buf.as_mut_ptr(), 16, &mut read)
+        };
+        assert_eq!(code, BTRFS_ERR_INVALID_ARG);
+    }
+
+    #[test]
+    fn test_stat() {
+        let handle = handle();
+        let mut stat = std::mem::MaybeUninit::<BtrfsStat>::uninit();
+
+        let path = CString::new("hello.txt").unwrap();
+        let code = unsafe { btrfs_stat(&handle, path.as_ptr(), stat.as_mut_ptr()) };
+        assert_eq!(code, BTRFS_OK);
+        let stat = unsafe { stat.assume_init() };
+        assert_eq!((stat.subvol_id, stat.ino), (objectid::FS_TREE, 257));
+        assert_eq!(stat.size, 11);
+        assert_eq!(stat.mode & 0o170000, 0o100000);
+
+        let mut stat = std::mem::MaybeUninit::<BtrfsStat>::uninit();
+        let path = CString::new("@\\docs").unwrap();
+        let code = unsafe { btrfs_stat(&handle, path.as_ptr(), stat.as_mut_ptr()) };
+        assert_eq!(code, BTRFS_OK);
+        let stat = unsafe { stat.assume_init() };
+        assert_eq!((stat.subvol_id, stat.ino), (256, 257));
+        assert_eq!(stat.mode & 0o170000, 0o040000);
+
+        let code = unsafe { btrfs_stat(&handle, ptr::null(), ptr::null_mut()) };
+        assert_eq!(code, BTRFS_ERR_INVALID_ARG);
+    }
+
+    /// Lists the directory at `path` through the FFI
+    fn list(handle: &BtrfsHandle, path: &str) -> Vec<(String, u64, u64, u8)> {
+        let path = CString::new(path).unwrap();
+        let mut dir = ptr::null_mut();
+        assert_eq!(unsafe { btrfs_opendir(handle, path.as_ptr(), &mut dir) }, BTRFS_OK);
+
+        let mut entries = Vec::new();
+        let mut entry = std::mem::MaybeUninit::<BtrfsDirEntry>::uninit();
+        while unsafe { btrfs_readdir(dir, entry.as_mut_ptr()) } == BTRFS_OK {
+            let entry = unsafe { entry.assume_init_ref() };
+            let name = unsafe { CStr::from_ptr(entry.name.as_ptr()) };
+            let name = name.to_str().unwrap().to_string();
+            entries.push((name, entry.subvol_id, entry.ino, entry.entry_type));
+        }
+        assert_eq!(unsafe { btrfs_readdir(dir, entry.as_mut_ptr()) }, BTRFS_DIR_END);
+        assert_eq!(unsafe { btrfs_closedir(dir) }, BTRFS_OK);
+        entries
+    }
+
+    #[test]
+    fn test_readdir() {
+        let handle = handle();
+        assert_eq!(
+            list(&handle, "/"),
+            vec![
+                ("hello.txt".to_string(), objectid::FS_TREE, 257, BTRFS_FT_REG_FILE),
+                ("@".to_string(), 256, objectid::FIRST_FREE, BTRFS_FT_DIR),
+            ]
+        );
+        assert_eq!(
+            list(&handle, "@/docs"),
+            vec![("empty".to_string(), 256, 258, BTRFS_FT_REG_FILE)]
+        );
+    }
+
+    #[test]
+    fn test_opendir_errors() {
+        let handle = handle();
+        let mut dir = ptr::null_mut();
+        for (path, code) in [
+            ("hello.txt", BTRFS_ERR_INVALID_ARG),
+            ("missing", BTRFS_ERR_NOT_FOUND),
+        ] {
+            let path = CString::new(path).unwrap();
+            assert_eq!(unsafe { btrfs_opendir(&handle, path.as_ptr(), &mut dir) }, code);
+        }
+        assert!(dir.is_null());
+
+        unsafe {
+            assert_eq!(btrfs_readdir(ptr::null_mut(), ptr::null_mut()), BTRFS_ERR_INVALID_ARG);
+            assert_eq!(btrfs_closedir(ptr::null_mut()), BTRFS_ERR_INVALID_ARG);
+        }
+    }
+
     #[test]
     fn test_null_handle() {
         unsafe {
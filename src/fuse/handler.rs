@@ -1,9 +1,13 @@
 //! Dokan FileSystemHandler implementation for BTRFS
+//!
+//! Translates Dokan callbacks into [`HandlerCore`] operations and maps the
+//! results back to Windows types.
 
-use crate::core::{BtrfsFilesystem, Inode, InodeType};
-use parking_lot::RwLock;
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicU64, Ordering};
+use super::handler_core::HandlerCore;
+use super::mount::MountOptions;
+#[cfg(windows)]
+use super::operations;
+use crate::core::{BtrfsError, BtrfsFilesystem, MAX_NAME_LEN};
 use std::sync::Arc;
 
 #[cfg(windows)]
@@ -12,68 +16,48 @@ use dokan::{
     MountFlags, OperationError, OperationInfo, VolumeInfo,
 };
 
+#[cfg(windows)]
+use widestring::U16CString;
 #[cfg(windows)]
 use windows::Win32::Foundation::NTSTATUS;
-
-/// Context for an open file
-#[derive(Debug)]
-pub struct FileContext {
-    /// Inode number
-    pub ino: u64,
-    /// Tree ID (subvolume)
-    pub tree_id: u64,
-    /// Is directory
-    pub is_dir: bool,
-    /// Current read position
-    pub position: AtomicU64,
-}
+#[cfg(windows)]
+use windows::Win32::Security::PSECURITY_DESCRIPTOR;
 
 /// BTRFS Dokan handler
 pub struct BtrfsHandler {
-    /// The filesystem
-    fs: Arc<BtrfsFilesystem>,
+    /// Platform-independent handler logic
+    core: HandlerCore,
     /// Read-only mode
     read_only: bool,
-    /// Open file handles
-    handles: RwLock<HashMap<u64, Arc<FileContext>>>,
-    /// Next handle ID
-    next_handle: AtomicU64,
 }
 
 impl BtrfsHandler {
     /// Creates a new handler
-    pub fn new(fs: Arc<BtrfsFilesystem>, read_only: bool) -> Self {
+    pub fn new(fs: Arc<BtrfsFilesystem>, options: MountOptions) -> Self {
         Self {
-            fs,
-            read_only,
-            handles: RwLock::new(HashMap::new()),
-            next_handle: AtomicU64::new(1),
+            read_only: options.read_only,
+            core: HandlerCore::new(fs, options),
         }
     }
+}
 
-    /// Allocates a new handle
-    fn alloc_handle(&self, ctx: FileContext) -> u64 {
-        let handle = self.next_handle.fetch_add(1, Ordering::SeqCst);
-        self.handles.write().insert(handle, Arc::new(ctx));
-        handle
-    }
-
-    /// Gets a handle context
-    fn get_handle(&self, handle: u64) -> Option<Arc<FileContext>> {
-        self.handles.read().get(&handle).cloned()
-    }
-
-    /// Releases a handle
-    fn release_handle(&self, handle: u64) {
-        self.handles.write().remove(&handle);
-    }
-
-    /// Converts a path to an inode
-    fn path_to_inode(&self, _path: &str) -> Option<(u64, u64)> {
-        // TODO: Implement path resolution
-        // Returns (tree_id, ino)
-        None
-    }
+/// Maps a filesystem error to the NTSTATUS reported to Windows
+#[cfg(windows)]
+fn nt_status(err: &BtrfsError) -> OperationError {
+    let status = match err {
+        BtrfsError::NotFound(_) | BtrfsError::InvalidInode(_) | BtrfsError::SubvolumeNotFound(_) => {
+            0xC0000034u32 // STATUS_OBJECT_NAME_NOT_FOUND
+        }
+        BtrfsError::NotADirectory => 0xC0000103u32, // STATUS_NOT_A_DIRECTORY
+        BtrfsError::NotAFile => 0xC00000BAu32,      // STATUS_FILE_IS_A_DIRECTORY
+        BtrfsError::NotASymlink => 0xC0000275u32,   // STATUS_NOT_A_REPARSE_POINT
+        BtrfsError::ReadOnly => 0xC00000A2u32,      // STATUS_MEDIA_WRITE_PROTECTED
+        BtrfsError::ChecksumMismatch { .. } | BtrfsError::Corrupt(_) => {
+            0xC0000032u32 // STATUS_DISK_CORRUPT_ERROR
+        }
+        _ => 0xC0000001u32, // STATUS_UNSUCCESSFUL
+    };
+    OperationError::NtStatus(NTSTATUS(status as i32))
 }
 
 #[cfg(windows)]
@@ -93,29 +77,14 @@ impl FileSystemHandler for BtrfsHandler {
     ) -> std::result::Result<CreateFileInfo<Self::Context>, OperationError> {
         let path = file_name.path().to_string_lossy();
 
-        // Check if read-only and write access requested
-        if self.read_only {
-            // Allow read-only access
-        }
-
-        // Root directory
-        if path == "\\" {
-            let ctx = FileContext {
-                ino: 256, // Root inode
-                tree_id: 5, // FS_TREE
-                is_dir: true,
-                position: AtomicU64::new(0),
-            };
-            let handle = self.alloc_handle(ctx);
-            return Ok(CreateFileInfo {
-                context: handle,
-                is_dir: true,
-                new_file_created: false,
-            });
-        }
+        let handle = self.core.open(&path).map_err(|e| nt_status(&e))?;
+        let is_dir = self.core.context(handle).is_some_and(|ctx| ctx.is_dir);
 
-        // TODO: Implement full path resolution
-        Err(OperationError::NtStatus(NTSTATUS(0xC0000034u32 as i32))) // STATUS_OBJECT_NAME_NOT_FOUND
+        Ok(CreateFileInfo {
+            context: handle,
+            is_dir,
+            new_file_created: false,
+        })
     }
 
     fn close_file(
@@ -124,7 +93,7 @@ impl FileSystemHandler for BtrfsHandler {
         _info: &dokan::OperationInfo<'_, '_, Self>,
         context: &Self::Context,
     ) {
-        self.release_handle(*context);
+        self.core.close(*context);
     }
 
     fn read_file(
@@ -135,12 +104,16 @@ impl FileSystemHandler for BtrfsHandler {
         _info: &dokan::OperationInfo<'_, '_, Self>,
         context: &Self::Context,
     ) -> std::result::Result<u32, OperationError> {
-        let _ctx = self
-            .get_handle(*context)
+        let ctx = self
+            .core
+            .context(*context)
             .ok_or(OperationError::NtStatus(NTSTATUS(0xC0000008u32 as i32)))?; // STATUS_INVALID_HANDLE
 
-        // TODO: Implement file reading
-        Ok(0)
+        let n = self
+            .core
+            .read(&ctx, offset.max(0) as u64, buffer)
+            .map_err(|e| nt_status(&e))?;
+        Ok(n as u32)
     }
 
     fn write_file(
@@ -175,33 +148,87 @@ impl FileSystemHandler for BtrfsHandler {
         context: &Self::Context,
     ) -> std::result::Result<FileInfo, OperationError> {
         let ctx = self
-            .get_handle(*context)
+            .core
+            .context(*context)
             .ok_or(OperationError::NtStatus(NTSTATUS(0xC0000008u32 as i32)))?;
 
-        // TODO: Get actual file info from inode
+        let stat = self.core.stat(&ctx).map_err(|e| nt_status(&e))?;
+
         Ok(FileInfo {
-            attributes: if ctx.is_dir {
-                0x10 // FILE_ATTRIBUTE_DIRECTORY
-            } else {
-                0x80 // FILE_ATTRIBUTE_NORMAL
-            },
-            creation_time: std::time::UNIX_EPOCH,
-            last_access_time: std::time::UNIX_EPOCH,
-            last_write_time: std::time::UNIX_EPOCH,
-            file_size: 0,
-            number_of_links: 1,
-            file_index: ctx.ino,
+            attributes: stat.attributes,
+            creation_time: stat.creation_time,
+            last_access_time: stat.last_access_time,
+            last_write_time: stat.last_write_time,
+            file_size: stat.file_size,
+            number_of_links: stat.number_of_links,
+            file_index: stat.file_index,
         })
     }
 
+    fn get_file_security(
+        &self,
+        _file_name: &dokan::OperationInfo<'_, '_, Self>,
+        security_information: u32,
+        security_descriptor: PSECURITY_DESCRIPTOR,
+        buffer_length: u32,
+        _info: &dokan::OperationInfo<'_, '_, Self>,
+        context: &Self::Context,
+    ) -> std::result::Result<u32, OperationError> {
+        let ctx = self
+            .core
+            .context(*context)
+            .ok_or(OperationError::NtStatus(NTSTATUS(0xC0000008u32 as i32)))?;
+
+        // Ownership overrides are already applied to the stat
+        let stat = self.core.stat(&ctx).map_err(|e| nt_status(&e))?;
+        let descriptor = operations::security_descriptor(stat.uid, stat.gid, security_information);
+
+        // Dokan retries with a larger buffer when given the needed length
+        if descriptor.len() <= buffer_length as usize {
+            // SAFETY: Dokan provides a writable buffer of `buffer_length` bytes
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    descriptor.as_ptr(),
+                    security_descriptor.0 as *mut u8,
+                    descriptor.len(),
+                );
+            }
+        }
+        Ok(descriptor.len() as u32)
+    }
+
     fn find_files(
         &self,
         _file_name: &dokan::OperationInfo<'_, '_, Self>,
         mut fill_find_data: impl FnMut(&FindData) -> std::result::Result<(), dokan::FillDataError>,
         _info: &dokan::OperationInfo<'_, '_, Self>,
-        _context: &Self::Context,
+        context: &Self::Context,
     ) -> std::result::Result<(), OperationError> {
-        // TODO: List directory contents
+        let ctx = self
+            .core
+            .context(*context)
+            .ok_or(OperationError::NtStatus(NTSTATUS(0xC0000008u32 as i32)))?;
+
+        // Entries are read in bounded batches and handed to Dokan as we go
+        let entries = self.core.find(&ctx).map_err(|e| nt_status(&e))?;
+
+        for entry in entries {
+            let entry = entry.map_err(|e| nt_status(&e))?;
+
+            let find_data = FindData {
+                attributes: entry.stat.attributes,
+                creation_time: entry.stat.creation_time,
+                last_access_time: entry.stat.last_access_time,
+                last_write_time: entry.stat.last_write_time,
+                file_size: entry.stat.file_size,
+                file_name: U16CString::from_str(&entry.name)
+                    .map_err(|_| OperationError::NtStatus(NTSTATUS(0xC0000033u32 as i32)))?, // STATUS_OBJECT_NAME_INVALID
+            };
+
+            fill_find_data(&find_data)
+                .map_err(|_| OperationError::NtStatus(NTSTATUS(0x80000005u32 as i32)))?; // STATUS_BUFFER_OVERFLOW
+        }
+
         Ok(())
     }
 
@@ -301,9 +328,8 @@ impl FileSystemHandler for BtrfsHandler {
         &self,
         _info: &dokan::OperationInfo<'_, '_, Self>,
     ) -> std::result::Result<DiskSpaceInfo, OperationError> {
-        let total = self.fs.total_bytes();
-        let used = self.fs.bytes_used();
-        let free = total.saturating_sub(used);
+        let total = self.core.filesystem().total_bytes();
+        let free = self.core.free_bytes();
 
         Ok(DiskSpaceInfo {
             byte_count: total,
@@ -317,10 +343,10 @@ impl FileSystemHandler for BtrfsHandler {
         _info: &dokan::OperationInfo<'_, '_, Self>,
     ) -> std::result::Result<VolumeInfo, OperationError> {
         Ok(VolumeInfo {
-            name: self.fs.label().to_string(),
+            name: self.core.filesystem().label().to_string(),
             serial_number: 0x42545246, // "BTRF"
-            max_component_length: 255,
-            fs_flags: 0x0000001F, // Case sensitive, unicode, etc.
+            max_component_length: MAX_NAME_LEN as u32,
+            fs_flags: self.core.fs_flags(),
             fs_name: String::from("BTRFS"),
         })
     }
@@ -330,18 +356,12 @@ impl FileSystemHandler for BtrfsHandler {
         _mount_point: &dokan::OperationInfo<'_, '_, Self>,
         _info: &dokan::OperationInfo<'_, '_, Self>,
     ) -> std::result::Result<(), OperationError> {
-        tracing::info!("BTRFS volume mounted");
+        self.core.log_mounted();
         Ok(())
     }
 
     fn unmounted(&self, _info: &dokan::OperationInfo<'_, '_, Self>) -> std::result::Result<(), OperationError> {
-        tracing::info!("BTRFS volume unmounted");
+        self.core.log_unmounted();
         Ok(())
     }
 }
-
-// Non-windows stub
-#[cfg(not(windows))]
-impl BtrfsHandler {
-    // Stub methods for non-Windows platforms
-}
@@ -0,0 +1,819 @@
+//! Platform-independent filesystem handler logic
+//!
+//! Path resolution, stat, directory listing and reads are implemented here
+//! against `BtrfsFilesystem` without any Dokan types, so they can be tested
+//! on every platform. The Windows handler translates Dokan calls into these.
+
+use super::mount::MountOptions;
+use super::operations::{self, fs_flag, PathResolver};
+use super::reader::FileReader;
+use crate::core::extent::ExtentTree;
+use crate::core::inode::{ExtentData, TimeSpec};
+use crate::core::{objectid, subvolume, BtrfsError, BtrfsFilesystem, Inode, Result};
+use parking_lot::{Mutex, RwLock};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Context for an open file
+#[derive(Debug)]
+pub struct FileContext {
+    /// Inode number
+    pub ino: u64,
+    /// Tree ID (subvolume)
+    pub tree_id: u64,
+    /// Is directory
+    pub is_dir: bool,
+    /// Current read position
+    pub position: AtomicU64,
+}
+
+/// File metadata in the shape Windows expects
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileStat {
+    /// Windows file attributes
+    pub attributes: u32,
+    /// Creation time (BTRFS otime)
+    pub creation_time: SystemTime,
+    /// Last access time
+    pub last_access_time: SystemTime,
+    /// Last write time
+    pub last_write_time: SystemTime,
+    /// File size in bytes (0 for directories)
+    pub file_size: u64,
+    /// Number of hard links
+    pub number_of_links: u32,
+    /// Inode number
+    pub file_index: u64,
+    /// Owner user ID, after any `force_uid` override
+    pub uid: u32,
+    /// Owner group ID, after any `force_gid` override
+    pub gid: u32,
+}
+
+/// A directory entry reported when listing a directory
+#[derive(Debug, Clone)]
+pub struct FindEntry {
+    /// Entry name
+    pub name: String,
+    /// Metadata of the entry's inode
+    pub stat: FileStat,
+}
+
+/// Handler state and operations shared by all platforms
+pub struct HandlerCore {
+    /// The filesystem
+    fs: Arc<BtrfsFilesystem>,
+    /// Mount options
+    options: MountOptions,
+    /// Open file handles
+    handles: RwLock<HashMap<u64, Arc<FileContext>>>,
+    /// Next handle ID
+    next_handle: AtomicU64,
+    /// Resolves and caches paths, ignoring case if mounted so
+    resolver: PathResolver,
+    /// Tree ID of the subvolume mounted as the root
+    root_tree: u64,
+    /// Data blocks returned as zeros under `tolerate_errors`
+    tolerated_errors: AtomicU64,
+    /// Last free byte count, with the generation it was computed at
+    free_cache: Mutex<Option<(u64, u64)>>,
+}
+
+impl HandlerCore {
+    /// Creates a new handler core
+    ///
+    /// Without a `subvolume_id`, the filesystem's default subvolume is
+    /// mounted, falling back to the FS tree if it can't be read.
+    pub fn new(fs: Arc<BtrfsFilesystem>, options: MountOptions) -> Self {
+        let root_tree = options.subvolume_id.unwrap_or_else(|| {
+            subvolume::default_subvolume_id(&fs).unwrap_or_else(|e| {
+                tracing::warn!(
+                    "Cannot read the default subvolume, using the FS tree: {}",
+                    e
+                );
+                objectid::FS_TREE
+            })
+        });
+
+        let mut resolver = PathResolver::new().with_cache();
+        if options.case_insensitive {
+            resolver = resolver.with_case_insensitive(options.dir_batch_size);
+        }
+
+        Self {
+            fs,
+            options,
+            handles: RwLock::new(HashMap::new()),
+            next_handle: AtomicU64::new(1),
+            resolver,
+            root_tree,
+            tolerated_errors: AtomicU64::new(0),
+            free_cache: Mutex::new(None),
+        }
+    }
+
+    /// Returns the filesystem
+    pub fn filesystem(&self) -> &Arc<BtrfsFilesystem> {
+        &self.fs
+    }
+
+    /// Returns the mount options
+    pub fn options(&self) -> &MountOptions {
+        &self.options
+    }
+
+    /// Returns true if mounted read-only
+    pub fn is_read_only(&self) -> bool {
+        self.options.read_only
+    }
+
+    /// Tree ID of the subvolume mounted as the root
+    pub fn root_tree(&self) -> u64 {
+        self.root_tree
+    }
+
+    /// Number of data blocks returned as zeros since mounting
+    pub fn tolerated_errors(&self) -> u64 {
+        self.tolerated_errors.load(Ordering::Relaxed)
+    }
+
+    /// Filesystem flags to report for the volume
+    ///
+    /// Names are case sensitive unless mounted with `case_insensitive`.
+    pub fn fs_flags(&self) -> u32 {
+        let mut flags = fs_flag::CASE_PRESERVED_NAMES
+            | fs_flag::UNICODE_ON_DISK
+            | fs_flag::PERSISTENT_ACLS
+            | fs_flag::FILE_COMPRESSION;
+        if !self.options.case_insensitive {
+            flags |= fs_flag::CASE_SENSITIVE_SEARCH;
+        }
+        flags
+    }
+
+    /// Free bytes to report for the volume
+    ///
+    /// This is the free space inside block groups, as
+    /// [`ExtentTree::free_space`] finds it, plus the space each device's
+    /// DEV_ITEM has not allocated to chunks; if either can't be read, the
+    /// superblock's size less its used bytes. Explorer asks for this
+    /// constantly, so it is only recomputed when the generation changes.
+    pub fn free_bytes(&self) -> u64 {
+        let generation = self.fs.generation();
+        if let Some((cached, free)) = *self.free_cache.lock()
+            && cached == generation
+        {
+            return free;
+        }
+
+        let free = ExtentTree::new(&self.fs).free_space().and_then(|free| {
+            let unallocated: u64 = self
+                .fs
+                .device_usage()?
+                .iter()
+                .map(|device| device.unallocated)
+                .sum();
+            Ok(free.free_bytes + unallocated)
+        });
+        let free = free.unwrap_or_else(|e| {
+            tracing::warn!("Cannot read the free space, using the superblock: {}", e);
+            self.fs.total_bytes().saturating_sub(self.fs.bytes_used())
+        });
+        *self.free_cache.lock() = Some((generation, free));
+        free
+    }
+
+    /// Reads the on-disk data of a regular extent
+    ///
+    /// With `tolerate_errors`, unreadable blocks come back as zeros and are
+    /// counted in [`Self::tolerated_errors`]; otherwise they fail the read.
+    pub fn read_extent(&self, inode: &Inode, extent: &ExtentData) -> Result<Vec<u8>> {
+        if !self.options.tolerate_errors {
+            return operations::read_extent_verified(&self.fs, inode, extent);
+        }
+
+        let (data, bad_blocks) = operations::read_extent_tolerant(&self.fs, inode, extent)?;
+        self.tolerated_errors
+            .fetch_add(bad_blocks, Ordering::Relaxed);
+        Ok(data)
+    }
+
+    /// Logs that the volume was mounted, with fields identifying it
+    pub fn log_mounted(&self) {
+        tracing::info!(
+            name: "mounted",
+            mount_point = %self.options.mount_point(),
+            subvolume_id = self.root_tree(),
+            read_only = self.is_read_only(),
+            label = %self.fs.label(),
+            uuid = %self.fs.uuid(),
+            "BTRFS volume mounted"
+        );
+    }
+
+    /// Logs that the volume was unmounted
+    pub fn log_unmounted(&self) {
+        tracing::info!(
+            name: "unmounted",
+            mount_point = %self.options.mount_point(),
+            subvolume_id = self.root_tree(),
+            uuid = %self.fs.uuid(),
+            tolerated_errors = self.tolerated_errors(),
+            "BTRFS volume unmounted"
+        );
+    }
+
+    /// Resolves a path relative to the mount root
+    ///
+    /// Paths are resolved by [`PathResolver`]: both `\` and `/` separate
+    /// components and nested subvolumes are entered. Successful lookups are
+    /// cached, so reopening a path skips the tree walk.
+    pub fn resolve(&self, path: &str) -> Result<FileContext> {
+        let (tree_id, ino, inode) = self.resolver.resolve(&self.fs, self.root_tree(), path)?;
+        Ok(FileContext {
+            ino,
+            tree_id,
+            is_dir: inode.is_dir(),
+            position: AtomicU64::new(0),
+        })
+    }
+
+    /// Resolves `path` and allocates a handle for it
+    pub fn open(&self, path: &str) -> Result<u64> {
+        let ctx = self.resolve(path)?;
+        let handle = self.next_handle.fetch_add(1, Ordering::SeqCst);
+        self.handles.write().insert(handle, Arc::new(ctx));
+        Ok(handle)
+    }
+
+    /// Gets a handle context
+    pub fn context(&self, handle: u64) -> Option<Arc<FileContext>> {
+        self.handles.read().get(&handle).cloned()
+    }
+
+    /// Releases a handle
+    pub fn close(&self, handle: u64) {
+        self.handles.write().remove(&handle);
+    }
+
+    /// Returns the metadata of an open file
+    pub fn stat(&self, ctx: &FileContext) -> Result<FileStat> {
+        let inode = operations::read_inode(&self.fs, ctx.tree_id, ctx.ino)?;
+        Ok(self.file_stat(&inode, operations::file_attributes(&inode)))
+    }
+
+    /// Reads from an open file at `offset`, returning the bytes read
+    ///
+    /// Reads past the end of the file return 0. Large reads are streamed
+    /// through a [`FileReader`] in bounded chunks.
+    pub fn read(&self, ctx: &FileContext, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if ctx.is_dir {
+            return Err(BtrfsError::NotAFile);
+        }
+
+        let mut reader = FileReader::open(&self.fs, ctx.tree_id, ctx.ino)?
+            .with_extent_reader(|inode, extent| self.read_extent(inode, extent));
+        reader.set_position(offset);
+
+        let mut filled = 0;
+        while filled < buf.len() {
+            match reader.read_chunk(&mut buf[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        Ok(filled)
+    }
+
+    /// Lists an open directory
+    ///
+    /// Entries are read lazily in batches of `dir_batch_size`. Every
+    /// directory but the mount root starts with `.` and `..`, as on NTFS.
+    /// An entry whose inode can't be read is skipped and logged rather than
+    /// ending the listing.
+    ///
+    /// The order is stable: the dot entries, then the rest in DIR_INDEX
+    /// order, which is the order the names were created in. Listing an
+    /// unchanged directory again, with any batch size, gives the same order.
+    pub fn find<'a>(
+        &'a self,
+        ctx: &FileContext,
+    ) -> Result<impl Iterator<Item = Result<FindEntry>> + 'a> {
+        if !ctx.is_dir {
+            return Err(BtrfsError::NotADirectory);
+        }
+
+        let tree_id = ctx.tree_id;
+        let mut dots = Vec::new();
+        if (tree_id, ctx.ino) != (self.root_tree(), objectid::FIRST_FREE) {
+            // A directory's only INODE_REF points at its parent
+            let parent = operations::get_inode_refs(&self.fs, tree_id, ctx.ino)?
+                .first()
+                .map(|(parent, _)| *parent)
+                .unwrap_or(ctx.ino);
+            for (name, ino) in [(".", ctx.ino), ("..", parent)] {
+                let inode = operations::read_inode(&self.fs, tree_id, ino)?;
+                dots.push(Ok(FindEntry {
+                    name: name.to_string(),
+                    stat: self.file_stat(&inode, operations::file_attributes(&inode)),
+                }));
+            }
+        }
+
+        let entries =
+            operations::dir_iter(&self.fs, tree_id, ctx.ino, self.options.dir_batch_size)?;
+
+        let entries = entries.filter_map(move |entry| {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e)),
+            };
+            let attributes =
+                operations::entry_attributes(&entry, self.options.subvolumes_as_reparse);
+            let (child_tree, child_ino) = entry.target(tree_id);
+
+            match operations::read_inode(&self.fs, child_tree, child_ino) {
+                Ok(inode) => Some(Ok(FindEntry {
+                    stat: self.file_stat(&inode, attributes),
+                    name: entry.name,
+                })),
+                Err(e) => {
+                    tracing::warn!(
+                        "Skipping {:?}: cannot read inode {} in tree {}: {}",
+                        entry.name,
+                        child_ino,
+                        child_tree,
+                        e
+                    );
+                    None
+                }
+            }
+        });
+
+        Ok(dots.into_iter().chain(entries))
+    }
+
+    /// Builds the Windows view of an inode, applying ownership overrides
+    fn file_stat(&self, inode: &Inode, attributes: u32) -> FileStat {
+        FileStat {
+            attributes,
+            creation_time: system_time(&inode.otime),
+            last_access_time: system_time(&inode.atime),
+            last_write_time: system_time(&inode.mtime),
+            file_size: if inode.is_dir() { 0 } else { inode.size },
+            number_of_links: inode.nlink,
+            file_index: inode.ino,
+            uid: self.options.force_uid.unwrap_or(inode.uid),
+            gid: self.options.force_gid.unwrap_or(inode.gid),
+        }
+    }
+}
+
+/// Converts an on-disk timestamp to `SystemTime`
+fn system_time(ts: &TimeSpec) -> SystemTime {
+    let nsec = Duration::from_nanos(ts.nsec as u64);
+    if ts.sec >= 0 {
+        UNIX_EPOCH + Duration::from_secs(ts.sec as u64) + nsec
+    } else {
+        UNIX_EPOCH - Duration::from_secs(ts.sec.unsigned_abs()) + nsec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{item_type, BtrfsKey};
+    use crate::fuse::mount::BtrfsMount;
+    use crate::fuse::operations::file_attribute;
+    use crate::test_utils::{inline_extent, ImageBuilder};
+
+    const HELLO: &[u8] = b"Hello from BTRFS!";
+
+    fn fixture() -> Arc<BtrfsFilesystem> {
+        Arc::new(fixture_builder().open())
+    }
+
+    fn fixture_builder() -> ImageBuilder {
+        let root = objectid::FIRST_FREE;
+        let mut builder = ImageBuilder::new();
+        builder
+            .root_dir(objectid::FS_TREE)
+            .dir(objectid::FS_TREE, root, 257, "docs")
+            .file(objectid::FS_TREE, 257, 258, "hello.txt", HELLO.len() as u64)
+            .insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(258, item_type::EXTENT_DATA, 0),
+                inline_extent(HELLO),
+            )
+            .subvolume(300, objectid::FS_TREE, root, "@home")
+            .file(300, root, 257, "notes.txt", 0);
+        builder
+    }
+
+    fn core() -> HandlerCore {
+        HandlerCore::new(fixture(), MountOptions::default())
+    }
+
+    #[test]
+    fn test_resolve() {
+        let core = core();
+
+        let root = core.resolve("\\").unwrap();
+        assert_eq!((root.tree_id, root.ino, root.is_dir), (5, 256, true));
+
+        let file = core.resolve("\\docs\\hello.txt").unwrap();
+        assert_eq!((file.tree_id, file.ino, file.is_dir), (5, 258, false));
+
+        // Crossing into a nested subvolume switches trees
+        let notes = core.resolve("/@home/notes.txt").unwrap();
+        assert_eq!((notes.tree_id, notes.ino), (300, 257));
+
+        assert!(matches!(
+            core.resolve("\\docs\\missing"),
+            Err(BtrfsError::NotFound(_))
+        ));
+        assert!(matches!(
+            core.resolve("\\docs\\hello.txt\\x"),
+            Err(BtrfsError::NotADirectory)
+        ));
+    }
+
+    #[test]
+    fn test_open_read_close_cycle() {
+        let core = core();
+
+        let handle = core.open("\\docs\\hello.txt").unwrap();
+        let ctx = core.context(handle).unwrap();
+
+        let mut buf = [0u8; 64];
+        let n = core.read(&ctx, 0, &mut buf).unwrap();
+        assert_eq!(&buf[..n], HELLO);
+
+        // Reads are clamped to the file size
+        let mut short = [0u8; 5];
+        assert_eq!(core.read(&ctx, 0, &mut short).unwrap(), 5);
+        assert_eq!(&short, b"Hello");
+
+        // Reads honor the offset, and the final read is partial
+        let tail = HELLO.len() as u64 - 3;
+        assert_eq!(core.read(&ctx, tail, &mut short).unwrap(), 3);
+        assert_eq!(&short[..3], &HELLO[HELLO.len() - 3..]);
+        assert_eq!(core.read(&ctx, 1000, &mut buf).unwrap(), 0);
+
+        core.close(handle);
+        assert!(core.context(handle).is_none());
+
+        let dir = core.resolve("\\docs").unwrap();
+        assert!(matches!(
+            core.read(&dir, 0, &mut buf),
+            Err(BtrfsError::NotAFile)
+        ));
+    }
+
+    #[test]
+    fn test_mount_read_unmount() {
+        let mut mount = BtrfsMount::mount(fixture(), MountOptions::default()).unwrap();
+        let core = HandlerCore::new(mount.filesystem().clone(), mount.options().clone());
+
+        let handle = core.open("\\docs\\hello.txt").unwrap();
+        let mut buf = [0u8; 64];
+        let n = core.read(&core.context(handle).unwrap(), 0, &mut buf).unwrap();
+        assert_eq!(&buf[..n], HELLO);
+        core.close(handle);
+
+        mount.unmount().unwrap();
+        assert!(!mount.is_mounted());
+    }
+
+    #[test]
+    fn test_stat() {
+        let core = core();
+
+        let file = core.stat(&core.resolve("\\docs\\hello.txt").unwrap()).unwrap();
+        assert_eq!(file.attributes, file_attribute::NORMAL);
+        assert_eq!(file.file_size, HELLO.len() as u64);
+        assert_eq!(file.number_of_links, 1);
+        assert_eq!(file.file_index, 258);
+
+        let dir = core.stat(&core.resolve("\\docs").unwrap()).unwrap();
+        assert_eq!(dir.attributes, file_attribute::DIRECTORY);
+        assert_eq!(dir.file_size, 0);
+    }
+
+    #[test]
+    fn test_find() {
+        let core = core();
+
+        let root = core.resolve("\\").unwrap();
+        let entries: Vec<FindEntry> = core.find(&root).unwrap().map(|e| e.unwrap()).collect();
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["docs", "@home"]);
+        assert_eq!(entries[1].stat.attributes, file_attribute::DIRECTORY);
+        assert_eq!(entries[1].stat.file_index, 256);
+
+        // Only directories below the mount root list `.` and `..`
+        let docs = core.resolve("\\docs").unwrap();
+        let entries: Vec<FindEntry> = core.find(&docs).unwrap().map(|e| e.unwrap()).collect();
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec![".", "..", "hello.txt"]);
+        assert_eq!(entries[0].stat.file_index, 257);
+        assert_eq!(entries[1].stat.file_index, 256);
+        assert_eq!(entries[1].stat.attributes, file_attribute::DIRECTORY);
+        assert_eq!(entries[2].stat.file_size, HELLO.len() as u64);
+
+        let file = core.resolve("\\docs\\hello.txt").unwrap();
+        assert!(matches!(core.find(&file), Err(BtrfsError::NotADirectory)));
+    }
+
+    #[test]
+    fn test_find_order_is_stable() {
+        let root = objectid::FIRST_FREE;
+        let names = ["zeta", "alpha", "mid", "Beta", "omega"];
+        let mut builder = ImageBuilder::new();
+        builder
+            .root_dir(objectid::FS_TREE)
+            .dir(objectid::FS_TREE, root, 257, "dir");
+        for (i, name) in names.iter().enumerate() {
+            builder.file(objectid::FS_TREE, 257, 258 + i as u64, name, 0);
+        }
+        let options = MountOptions {
+            dir_batch_size: 2,
+            ..MountOptions::default()
+        };
+        let core = HandlerCore::new(Arc::new(builder.open()), options);
+
+        let dir = core.resolve("\\dir").unwrap();
+        let list =
+            || -> Vec<String> { core.find(&dir).unwrap().map(|e| e.unwrap().name).collect() };
+
+        // Creation order, not sorted by name, and the same every time
+        let first = list();
+        assert_eq!(first, [".", "..", "zeta", "alpha", "mid", "Beta", "omega"]);
+        assert_eq!(list(), first);
+    }
+
+    #[test]
+    fn test_find_skips_unreadable_entries() {
+        use crate::test_utils::dir_item;
+
+        // A dangling entry whose inode does not exist
+        let root = objectid::FIRST_FREE;
+        let dangling = BtrfsKey::new(999, item_type::INODE_ITEM, 0);
+        let mut builder = ImageBuilder::new();
+        builder
+            .root_dir(objectid::FS_TREE)
+            .file(objectid::FS_TREE, root, 257, "a.txt", 1)
+            .insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(root, item_type::DIR_INDEX, 3),
+                dir_item(&dangling, 1, "broken"),
+            )
+            .file(objectid::FS_TREE, root, 258, "c.txt", 3);
+        let core = HandlerCore::new(Arc::new(builder.open()), MountOptions::default());
+
+        let root = core.resolve("\\").unwrap();
+        let names: Vec<String> = core
+            .find(&root)
+            .unwrap()
+            .map(|e| e.unwrap().name)
+            .collect();
+        assert_eq!(names, vec!["a.txt", "c.txt"]);
+    }
+
+    #[test]
+    fn test_mounted_subvolume_root() {
+        let options = MountOptions {
+            subvolume_id: Some(300),
+            ..Default::default()
+        };
+        let core = HandlerCore::new(fixture(), options);
+
+        let notes = core.resolve("\\notes.txt").unwrap();
+        assert_eq!((notes.tree_id, notes.ino), (300, 257));
+        assert!(core.resolve("\\docs").is_err());
+    }
+
+    #[test]
+    fn test_mounts_default_subvolume() {
+        use crate::core::superblock::incompat;
+        use crate::core::inode::btrfs_name_hash;
+        use crate::test_utils::dir_item;
+
+        // `btrfs subvolume set-default 300`
+        let mut builder = fixture_builder();
+        let location = BtrfsKey::new(300, item_type::ROOT_ITEM, u64::MAX);
+        builder.incompat(incompat::DEFAULT_SUBVOL).insert(
+            objectid::ROOT_TREE,
+            BtrfsKey::new(6, item_type::DIR_ITEM, btrfs_name_hash("default")),
+            dir_item(&location, 2, "default"),
+        );
+        let fs = Arc::new(builder.open());
+
+        let core = HandlerCore::new(fs.clone(), MountOptions::default());
+        assert_eq!(core.root_tree(), 300);
+        assert!(core.resolve("\\notes.txt").is_ok());
+
+        // An explicit subvolume still wins
+        let options = MountOptions {
+            subvolume_id: Some(objectid::FS_TREE),
+            ..Default::default()
+        };
+        let core = HandlerCore::new(fs, options);
+        assert!(core.resolve("\\docs\\hello.txt").is_ok());
+    }
+
+    #[test]
+    fn test_stat_forced_ownership() {
+        let fs = fixture();
+        let ctx = HandlerCore::new(fs.clone(), MountOptions::default())
+            .resolve("\\docs\\hello.txt")
+            .unwrap();
+
+        // The fixture's inodes are owned by root
+        let stat = core().stat(&ctx).unwrap();
+        assert_eq!((stat.uid, stat.gid), (0, 0));
+
+        let options = MountOptions {
+            force_uid: Some(1000),
+            force_gid: Some(100),
+            ..Default::default()
+        };
+        let core = HandlerCore::new(fs.clone(), options);
+        let stat = core.stat(&ctx).unwrap();
+        assert_eq!((stat.uid, stat.gid), (1000, 100));
+
+        let root = core.resolve("\\").unwrap();
+        for entry in core.find(&root).unwrap() {
+            let stat = entry.unwrap().stat;
+            assert_eq!((stat.uid, stat.gid), (1000, 100));
+        }
+
+        // Overrides are independent
+        let options = MountOptions {
+            force_gid: Some(100),
+            ..Default::default()
+        };
+        let stat = HandlerCore::new(fs, options).stat(&ctx).unwrap();
+        assert_eq!((stat.uid, stat.gid), (0, 100));
+    }
+
+    #[test]
+    fn test_system_time() {
+        let ts = TimeSpec {
+            sec: 1_700_000_000,
+            nsec: 500,
+        };
+        assert_eq!(
+            system_time(&ts),
+            UNIX_EPOCH + Duration::new(1_700_000_000, 500)
+        );
+
+        let before_epoch = TimeSpec { sec: -10, nsec: 0 };
+        assert_eq!(
+            system_time(&before_epoch),
+            UNIX_EPOCH - Duration::from_secs(10)
+        );
+    }
+
+    #[test]
+    fn test_mounted_event_fields() {
+        use parking_lot::Mutex;
+        use std::io::Write;
+
+        #[derive(Clone, Default)]
+        struct Captured(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Captured {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let options = MountOptions {
+            drive_letter: 'B',
+            read_only: true,
+            subvolume_id: Some(300),
+            ..Default::default()
+        };
+        let core = HandlerCore::new(fixture(), options);
+
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || core.log_mounted());
+
+        let log = String::from_utf8(captured.0.lock().clone()).unwrap();
+        assert!(log.contains("BTRFS volume mounted"));
+        assert!(log.contains("mount_point=B:"));
+        assert!(log.contains("subvolume_id=300"));
+        assert!(log.contains("read_only=true"));
+        assert!(log.contains(&format!("uuid={}", core.filesystem().uuid())));
+    }
+
+    #[test]
+    fn test_tolerate_errors() {
+        use crate::core::checksum;
+        use crate::test_utils::extent_data;
+
+        // Two blocks of data; the second is corrupt on disk
+        let blocks = [[0x11u8; 4096], [0x22u8; 4096]];
+        let mut on_disk = blocks.concat();
+        on_disk[4096] ^= 0xFF;
+        let csums: Vec<u8> = blocks
+            .iter()
+            .flat_map(|block| checksum::crc32c(block).to_le_bytes())
+            .collect();
+
+        let mut builder = fixture_builder();
+        builder.data(0x300000, &on_disk).insert(
+            objectid::CSUM_TREE,
+            BtrfsKey::new(objectid::EXTENT_CSUM, item_type::EXTENT_CSUM, 0x300000),
+            csums,
+        );
+        let fs = Arc::new(builder.open());
+        let inode = operations::read_inode(&fs, objectid::FS_TREE, 258).unwrap();
+        let extent = ExtentData::from_bytes(&extent_data(0x300000, 8192)).unwrap();
+
+        let strict = HandlerCore::new(fs.clone(), MountOptions::default());
+        assert!(strict.read_extent(&inode, &extent).is_err());
+
+        let options = MountOptions {
+            tolerate_errors: true,
+            ..Default::default()
+        };
+        let tolerant = HandlerCore::new(fs, options);
+        let data = tolerant.read_extent(&inode, &extent).unwrap();
+        assert_eq!(&data[..4096], &blocks[0]);
+        assert!(data[4096..].iter().all(|&b| b == 0));
+
+        tolerant.read_extent(&inode, &extent).unwrap();
+        assert_eq!(tolerant.tolerated_errors(), 2);
+        assert_eq!(strict.tolerated_errors(), 0);
+    }
+
+    #[test]
+    fn test_free_bytes() {
+        use crate::test_utils::{block_group_item, dev_extent, dev_item};
+
+        // A 1 MiB block group, and 2 MiB of the 64 MiB device allocated
+        // to chunks, e.g. as DUP
+        let mut builder = fixture_builder();
+        builder
+            .insert(
+                objectid::EXTENT_TREE,
+                BtrfsKey::new(0x100000, item_type::BLOCK_GROUP_ITEM, 0x100000),
+                block_group_item(0x4000, 0x1),
+            )
+            .insert(
+                objectid::CHUNK_TREE,
+                BtrfsKey::new(objectid::DEV_ITEMS, item_type::DEV_ITEM, 1),
+                dev_item(1, 64 << 20, 2 << 20),
+            )
+            .insert(
+                objectid::DEV_TREE,
+                BtrfsKey::new(1, item_type::DEV_EXTENT, 0x100000),
+                dev_extent(0x100000, 2 << 20),
+            );
+        let fs = Arc::new(builder.open());
+
+        // Free space inside the block group plus what no chunk uses
+        let inside = ExtentTree::new(&fs).free_space().unwrap().free_bytes;
+        let free = inside + (62 << 20);
+        let core = HandlerCore::new(fs.clone(), MountOptions::default());
+        assert_eq!(core.free_bytes(), free);
+        assert_eq!(*core.free_cache.lock(), Some((fs.generation(), free)));
+        assert_eq!(core.free_bytes(), free);
+    }
+
+    #[test]
+    fn test_case_insensitive() {
+        let sensitive = core();
+        assert!(matches!(
+            sensitive.resolve("\\DOCS\\Hello.TXT"),
+            Err(BtrfsError::NotFound(_))
+        ));
+        assert_ne!(sensitive.fs_flags() & fs_flag::CASE_SENSITIVE_SEARCH, 0);
+
+        let options = MountOptions {
+            case_insensitive: true,
+            ..Default::default()
+        };
+        let insensitive = HandlerCore::new(fixture(), options);
+        let exact = insensitive.resolve("\\docs\\hello.txt").unwrap();
+        let folded = insensitive.resolve("\\DOCS\\Hello.TXT").unwrap();
+        assert_eq!((folded.tree_id, folded.ino), (exact.tree_id, exact.ino));
+        assert!(insensitive.resolve("\\docs\\hello.md").is_err());
+        assert_eq!(insensitive.fs_flags() & fs_flag::CASE_SENSITIVE_SEARCH, 0);
+        assert_ne!(insensitive.fs_flags() & fs_flag::CASE_PRESERVED_NAMES, 0);
+    }
+}
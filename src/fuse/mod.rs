@@ -4,9 +4,12 @@
 
 #[cfg(windows)]
 pub mod handler;
+pub mod handler_core;
 pub mod mount;
 pub mod operations;
 
+pub use crate::core::reader::{self, read_file_stream, CancelToken, FileReader, StreamChunk};
 #[cfg(windows)]
 pub use handler::BtrfsHandler;
+pub use handler_core::{FileContext, FileStat, FindEntry, HandlerCore};
 pub use mount::{BtrfsMount, MountOptions};
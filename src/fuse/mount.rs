@@ -2,6 +2,7 @@
 
 #[cfg(windows)]
 use super::handler::BtrfsHandler;
+use super::operations::DEFAULT_DIR_BATCH_SIZE;
 use crate::core::{BtrfsError, BtrfsFilesystem, Result};
 use std::sync::Arc;
 
@@ -25,6 +26,19 @@ pub struct MountOptions {
     pub volume_name: String,
     /// Filesystem name
     pub filesystem_name: String,
+    /// Maximum directory entries held in memory while listing a directory
+    pub dir_batch_size: usize,
+    /// Report nested subvolumes as reparse points instead of plain directories
+    pub subvolumes_as_reparse: bool,
+    /// Report every file as owned by this user ID (like FUSE's `uid=`)
+    pub force_uid: Option<u32>,
+    /// Report every file as owned by this group ID (like FUSE's `gid=`)
+    pub force_gid: Option<u32>,
+    /// Return zeros for data blocks that can't be read or fail their
+    /// checksum on every copy, instead of failing the read
+    pub tolerate_errors: bool,
+    /// Match path components ignoring case, as Windows applications expect
+    pub case_insensitive: bool,
 }
 
 impl Default for MountOptions {
@@ -37,16 +51,31 @@ impl Default for MountOptions {
             thread_count: 0,
             volume_name: String::from("BTRFS Volume"),
             filesystem_name: String::from("BTRFS"),
+            dir_batch_size: DEFAULT_DIR_BATCH_SIZE,
+            subvolumes_as_reparse: false,
+            force_uid: None,
+            force_gid: None,
+            tolerate_errors: false,
+            case_insensitive: false,
         }
     }
 }
 
+impl MountOptions {
+    /// Returns the mount point, e.g. `Z:`
+    pub fn mount_point(&self) -> String {
+        format!("{}:", self.drive_letter)
+    }
+}
+
 /// A mounted BTRFS filesystem
 pub struct BtrfsMount {
     /// The filesystem
     fs: Arc<BtrfsFilesystem>,
     /// Mount point (drive letter)
     mount_point: String,
+    /// Options the filesystem was mounted with
+    options: MountOptions,
     /// Whether mounted
     mounted: bool,
 }
@@ -55,8 +84,16 @@ impl BtrfsMount {
     /// Mounts a BTRFS filesystem
     #[cfg(windows)]
     pub fn mount(fs: Arc<BtrfsFilesystem>, options: MountOptions) -> Result<Self> {
-        let mount_point = format!("{}:", options.drive_letter);
-        let handler = BtrfsHandler::new(fs.clone(), options.read_only);
+        let mount_point = options.mount_point();
+        tracing::info!(
+            name: "mount_started",
+            mount_point = %mount_point,
+            subvolume_id = ?options.subvolume_id,
+            read_only = options.read_only,
+            uuid = %fs.uuid(),
+            "Mounting BTRFS volume"
+        );
+        let handler = BtrfsHandler::new(fs.clone(), options.clone());
 
         let mut flags = MountFlags::empty();
         if options.debug {
@@ -80,31 +117,56 @@ impl BtrfsMount {
 
         // Start mount in a separate thread
         let handler_arc = Arc::new(handler);
+        let failed_mount_point = mount_point.clone();
         let handle = std::thread::spawn(move || {
             if let Err(e) = drive.mount(&*handler_arc) {
-                tracing::error!("Mount error: {:?}", e);
+                tracing::error!(
+                    name: "mount_failed",
+                    mount_point = %failed_mount_point,
+                    error = ?e,
+                    "BTRFS mount failed"
+                );
             }
         });
 
         Ok(Self {
             fs,
             mount_point,
+            options,
             mounted: true,
         })
     }
 
     #[cfg(not(windows))]
     pub fn mount(fs: Arc<BtrfsFilesystem>, options: MountOptions) -> Result<Self> {
-        let mount_point = format!("{}:", options.drive_letter);
+        let mount_point = options.mount_point();
         tracing::warn!("Dokan mount not available on this platform");
 
         Ok(Self {
             fs,
             mount_point,
+            options,
             mounted: false,
         })
     }
 
+    /// Mounts several subvolumes of one filesystem, each at its own drive
+    ///
+    /// All mounts share `fs`, and with it the device handle and node cache;
+    /// the filesystem only takes `&self` and is `Sync`, so the Dokan threads
+    /// of every mount can read it concurrently. The subvolume ID given with
+    /// each set of options overrides `options.subvolume_id`. If any mount
+    /// fails, those already made are unmounted.
+    pub fn mount_subvolumes(
+        fs: Arc<BtrfsFilesystem>,
+        mounts: Vec<(u64, MountOptions)>,
+    ) -> Result<Vec<Self>> {
+        subvolume_mount_options(&fs, mounts)?
+            .into_iter()
+            .map(|options| Self::mount(fs.clone(), options))
+            .collect()
+    }
+
     /// Unmounts the filesystem
     #[cfg(windows)]
     pub fn unmount(&mut self) -> Result<()> {
@@ -121,6 +183,57 @@ impl BtrfsMount {
         Ok(())
     }
 
+    /// Prepares the volume to be unplugged: unmounts, flushes and ejects
+    ///
+    /// The drive is unmounted first, so nothing can write to the device
+    /// while its buffers are flushed. The device's media is ejected only
+    /// once the flush has succeeded.
+    pub fn eject(&mut self) -> Result<()> {
+        self.eject_with(Self::unmount)
+    }
+
+    /// Runs the eject sequence with `unmount` taking down the mount
+    fn eject_with(&mut self, unmount: impl FnOnce(&mut Self) -> Result<()>) -> Result<()> {
+        unmount(self)?;
+        self.fs.device().flush_device()?;
+        self.fs.device().eject()?;
+
+        tracing::info!(
+            name: "volume_ejected",
+            mount_point = %self.mount_point,
+            "BTRFS volume ejected"
+        );
+        Ok(())
+    }
+
+    /// Remounts at the same mount point with a different access mode
+    ///
+    /// `fs` must be opened on the same source with the new mode. All other
+    /// options, including the subvolume, are kept. If the new mount fails,
+    /// the previous mount is restored.
+    pub fn remount(&mut self, fs: Arc<BtrfsFilesystem>, read_only: bool) -> Result<()> {
+        if !read_only && (fs.is_read_only() || fs.device().is_read_only()) {
+            return Err(BtrfsError::ReadOnly);
+        }
+
+        let mut options = self.options.clone();
+        options.read_only = read_only;
+
+        self.unmount()?;
+        match Self::mount(fs, options) {
+            Ok(mount) => {
+                *self = mount;
+                Ok(())
+            }
+            Err(e) => {
+                if let Ok(previous) = Self::mount(self.fs.clone(), self.options.clone()) {
+                    *self = previous;
+                }
+                Err(e)
+            }
+        }
+    }
+
     /// Returns the mount point
     pub fn mount_point(&self) -> &str {
         &self.mount_point
@@ -135,6 +248,11 @@ impl BtrfsMount {
     pub fn filesystem(&self) -> &Arc<BtrfsFilesystem> {
         &self.fs
     }
+
+    /// Returns the options the filesystem was mounted with
+    pub fn options(&self) -> &MountOptions {
+        &self.options
+    }
 }
 
 impl Drop for BtrfsMount {
@@ -143,6 +261,32 @@ impl Drop for BtrfsMount {
     }
 }
 
+/// Checks each subvolume exists and pairs its ID with its mount options
+fn subvolume_mount_options(
+    fs: &BtrfsFilesystem,
+    mounts: Vec<(u64, MountOptions)>,
+) -> Result<Vec<MountOptions>> {
+    let mut drive_letters = Vec::with_capacity(mounts.len());
+    let mut resolved = Vec::with_capacity(mounts.len());
+
+    for (subvol_id, mut options) in mounts {
+        let letter = options.drive_letter.to_ascii_uppercase();
+        if drive_letters.contains(&letter) {
+            return Err(BtrfsError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("drive {} is used by more than one subvolume", letter),
+            )));
+        }
+        drive_letters.push(letter);
+
+        fs.get_subvolume(subvol_id)?;
+        options.subvolume_id = Some(subvol_id);
+        resolved.push(options);
+    }
+
+    Ok(resolved)
+}
+
 /// Lists active Dokan mount points
 #[cfg(windows)]
 pub fn list_mount_points() -> Vec<String> {
@@ -156,3 +300,193 @@ pub fn list_mount_points() -> Vec<String> {
 pub fn list_mount_points() -> Vec<String> {
     Vec::new()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdev::BlockDevice;
+    use crate::core::objectid;
+    use crate::test_utils::{ImageBuilder, MemDevice};
+
+    fn open(
+        builder: &ImageBuilder,
+        device_read_only: bool,
+        read_only: bool,
+    ) -> Arc<BtrfsFilesystem> {
+        let image = builder.build();
+        let device = if device_read_only {
+            MemDevice::read_only(image)
+        } else {
+            MemDevice::new(image)
+        };
+        Arc::new(BtrfsFilesystem::open(Arc::new(device), read_only).unwrap())
+    }
+
+    /// Records the order of flushes and ejects
+    struct EjectProbe {
+        inner: MemDevice,
+        fail_flush: bool,
+        events: std::sync::Mutex<Vec<&'static str>>,
+    }
+
+    impl BlockDevice for EjectProbe {
+        fn size(&self) -> u64 {
+            self.inner.size()
+        }
+
+        fn sector_size(&self) -> u32 {
+            self.inner.sector_size()
+        }
+
+        fn is_read_only(&self) -> bool {
+            self.inner.is_read_only()
+        }
+
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> crate::blockdev::Result<usize> {
+            self.inner.read_at(offset, buf)
+        }
+
+        fn write_at(&self, offset: u64, buf: &[u8]) -> crate::blockdev::Result<usize> {
+            self.inner.write_at(offset, buf)
+        }
+
+        fn flush_device(&self) -> crate::blockdev::Result<()> {
+            self.events.lock().unwrap().push("flush");
+            if self.fail_flush {
+                return Err(crate::blockdev::BlockDeviceError::ReadOnly);
+            }
+            Ok(())
+        }
+
+        fn eject(&self) -> crate::blockdev::Result<()> {
+            self.events.lock().unwrap().push("eject");
+            Ok(())
+        }
+    }
+
+    fn probe_mount(fail_flush: bool) -> (BtrfsMount, Arc<EjectProbe>) {
+        let mut builder = ImageBuilder::new();
+        builder.root_dir(objectid::FS_TREE);
+        let probe = Arc::new(EjectProbe {
+            inner: MemDevice::new(builder.build()),
+            fail_flush,
+            events: Default::default(),
+        });
+        let fs = BtrfsFilesystem::open(probe.clone(), false).unwrap();
+
+        // Stands in for a live Dokan mount, which needs Windows
+        let mount = BtrfsMount {
+            fs: Arc::new(fs),
+            mount_point: "E:".to_string(),
+            options: MountOptions::default(),
+            mounted: true,
+        };
+        (mount, probe)
+    }
+
+    #[test]
+    fn test_eject_unmounts_then_flushes() {
+        let unmount = |mount: &mut BtrfsMount, probe: &EjectProbe| {
+            probe.events.lock().unwrap().push("unmount");
+            mount.unmount()
+        };
+
+        let (mut mount, probe) = probe_mount(false);
+        mount.eject_with(|m| unmount(m, &probe)).unwrap();
+        assert!(!mount.is_mounted());
+        assert_eq!(*probe.events.lock().unwrap(), ["unmount", "flush", "eject"]);
+
+        // A failed flush stops before ejecting
+        let (mut mount, probe) = probe_mount(true);
+        assert!(mount.eject_with(|m| unmount(m, &probe)).is_err());
+        assert!(!mount.is_mounted());
+        assert_eq!(*probe.events.lock().unwrap(), ["unmount", "flush"]);
+    }
+
+    #[test]
+    fn test_remount_preserves_options() {
+        let mut builder = ImageBuilder::new();
+        builder.root_dir(objectid::FS_TREE);
+
+        let options = MountOptions {
+            drive_letter: 'Q',
+            read_only: true,
+            subvolume_id: Some(257),
+            ..Default::default()
+        };
+        let mut mount = BtrfsMount::mount(open(&builder, false, true), options).unwrap();
+
+        mount.remount(open(&builder, false, false), false).unwrap();
+        assert_eq!(mount.mount_point(), "Q:");
+        assert!(!mount.options().read_only);
+        assert_eq!(mount.options().subvolume_id, Some(257));
+        assert!(!mount.filesystem().is_read_only());
+
+        mount.remount(open(&builder, false, true), true).unwrap();
+        assert!(mount.options().read_only);
+        assert!(mount.filesystem().is_read_only());
+    }
+
+    fn with_home() -> ImageBuilder {
+        let mut builder = ImageBuilder::new();
+        builder
+            .root_dir(objectid::FS_TREE)
+            .subvolume(256, objectid::FS_TREE, objectid::FIRST_FREE, "@")
+            .subvolume(257, objectid::FS_TREE, objectid::FIRST_FREE, "@home");
+        builder
+    }
+
+    fn at(drive_letter: char) -> MountOptions {
+        MountOptions {
+            drive_letter,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_mount_subvolumes() {
+        let fs = open(&with_home(), false, true);
+
+        let mounts =
+            BtrfsMount::mount_subvolumes(fs.clone(), vec![(256, at('Z')), (257, at('Y'))]).unwrap();
+        let mounted: Vec<_> = mounts
+            .iter()
+            .map(|m| (m.mount_point(), m.options().subvolume_id))
+            .collect();
+        assert_eq!(mounted, [("Z:", Some(256)), ("Y:", Some(257))]);
+        assert!(mounts.iter().all(|m| Arc::ptr_eq(m.filesystem(), &fs)));
+    }
+
+    #[test]
+    fn test_mount_subvolumes_rejects_bad_requests() {
+        let fs = open(&with_home(), false, true);
+
+        let missing =
+            BtrfsMount::mount_subvolumes(fs.clone(), vec![(256, at('Z')), (300, at('Y'))]);
+        assert!(matches!(missing, Err(BtrfsError::SubvolumeNotFound(300))));
+
+        let same_drive = BtrfsMount::mount_subvolumes(fs, vec![(256, at('Z')), (257, at('z'))]);
+        assert!(matches!(same_drive, Err(BtrfsError::Io(_))));
+    }
+
+    #[test]
+    fn test_remount_read_write_on_read_only_device() {
+        let mut builder = ImageBuilder::new();
+        builder.root_dir(objectid::FS_TREE);
+
+        let options = MountOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        let mut mount = BtrfsMount::mount(open(&builder, true, true), options).unwrap();
+        let fs = open(&builder, true, false);
+
+        assert!(matches!(
+            mount.remount(fs, false),
+            Err(BtrfsError::ReadOnly)
+        ));
+        // The existing mount is left untouched
+        assert!(mount.options().read_only);
+        assert!(mount.filesystem().is_read_only());
+    }
+}
@@ -4,267 +4,884 @@
 //! to BTRFS tree operations.
 
 use crate::core::{
-    inode::{DirEntry, ExtentData, Inode, InodeRef},
+    inode::{
+        btrfs_name_hash, DirEntry, ExtentData, Inode, InodeExtRef, InodeFlags, InodeRef, InodeType,
+        Xattr,
+    },
     item_type,
-    tree::{BtrfsKey, BtrfsTree},
+    superblock::incompat,
+    tree::BtrfsKey,
     BtrfsError, BtrfsFilesystem, Result,
 };
 
-/// Reads an inode from the filesystem
-pub fn read_inode(fs: &BtrfsFilesystem, tree_id: u64, ino: u64) -> Result<Inode> {
-    let root_addr = fs.superblock().root();
-    let root_level = fs.superblock().root_level();
-
-    // TODO: Get tree root from root tree
-    let tree = BtrfsTree::new(fs, root_addr, root_level);
-
-    let key = BtrfsKey::new(ino, item_type::INODE_ITEM, 0);
+pub use crate::core::file::{
+    lookup_data_csums, read_extent_tolerant, read_extent_unverified, read_extent_verified,
+    read_file_data, read_file_data_with, read_file_extents, read_symlink, verify_data, DataPolicy,
+};
+pub use crate::core::path::{
+    dir_iter, lookup, lookup_case_insensitive, parse_path_components, read_dir, read_inode,
+    resolve_path, DirIter, PathResolver, DEFAULT_DIR_BATCH_SIZE,
+};
 
-    match tree.search(&key)? {
-        Some((_, data)) => Inode::from_bytes(ino, &data),
-        None => Err(BtrfsError::InvalidInode(ino)),
-    }
+/// Windows file attribute bits reported for BTRFS inodes
+pub mod file_attribute {
+    pub const READONLY: u32 = 0x1;
+    pub const DIRECTORY: u32 = 0x10;
+    pub const NORMAL: u32 = 0x80;
+    pub const REPARSE_POINT: u32 = 0x400;
+    pub const COMPRESSED: u32 = 0x800;
 }
 
-/// Reads directory entries
-pub fn read_dir(fs: &BtrfsFilesystem, tree_id: u64, ino: u64) -> Result<Vec<DirEntry>> {
-    let root_addr = fs.superblock().root();
-    let root_level = fs.superblock().root_level();
-
-    let tree = BtrfsTree::new(fs, root_addr, root_level);
+/// Windows filesystem flag bits reported for the volume
+pub mod fs_flag {
+    pub const CASE_SENSITIVE_SEARCH: u32 = 0x1;
+    pub const CASE_PRESERVED_NAMES: u32 = 0x2;
+    pub const UNICODE_ON_DISK: u32 = 0x4;
+    pub const PERSISTENT_ACLS: u32 = 0x8;
+    pub const FILE_COMPRESSION: u32 = 0x10;
+}
 
-    let min_key = BtrfsKey::new(ino, item_type::DIR_INDEX, 0);
-    let max_key = BtrfsKey::new(ino, item_type::DIR_INDEX, u64::MAX);
+/// Maps an inode's type and flags to Windows file attributes
+///
+/// IMMUTABLE and READONLY inodes are shown read-only and COMPRESS inodes
+/// compressed, so per-file BTRFS attributes are visible in Explorer.
+/// Symlinks are reparse points.
+pub fn file_attributes(inode: &Inode) -> u32 {
+    let flags = inode.inode_flags();
+    let mut attributes = 0;
+
+    if inode.is_dir() {
+        attributes |= file_attribute::DIRECTORY;
+    }
+    if inode.is_symlink() {
+        attributes |= file_attribute::REPARSE_POINT;
+    }
+    if flags.intersects(InodeFlags::IMMUTABLE | InodeFlags::READONLY) {
+        attributes |= file_attribute::READONLY;
+    }
+    if flags.contains(InodeFlags::COMPRESS) {
+        attributes |= file_attribute::COMPRESSED;
+    }
 
-    let items = tree.search_range(&min_key, &max_key)?;
+    if attributes == 0 {
+        file_attribute::NORMAL
+    } else {
+        attributes
+    }
+}
 
-    let mut entries = Vec::new();
-    for (_, data) in items {
-        if let Ok(entry) = DirEntry::from_bytes(&data) {
-            entries.push(entry);
-        }
+/// Maps a directory entry to the Windows attributes reported when listing
+///
+/// With `subvolumes_as_reparse`, entries that cross into another subvolume
+/// are flagged as reparse points so tools can tell them apart.
+pub fn entry_attributes(entry: &DirEntry, subvolumes_as_reparse: bool) -> u32 {
+    let mut attributes = if entry.entry_type.is_dir() {
+        file_attribute::DIRECTORY
+    } else {
+        file_attribute::NORMAL
+    };
+
+    if entry.entry_type == InodeType::Symlink || (subvolumes_as_reparse && entry.is_subvolume()) {
+        attributes |= file_attribute::REPARSE_POINT;
     }
 
-    Ok(entries)
+    attributes
 }
 
-/// Looks up a name in a directory
-pub fn lookup(fs: &BtrfsFilesystem, tree_id: u64, dir_ino: u64, name: &str) -> Result<DirEntry> {
-    // Hash the name for DIR_ITEM lookup
-    let name_hash = btrfs_name_hash(name);
+/// Windows SECURITY_INFORMATION bits naming the parts of a security
+/// descriptor a caller asks for
+pub mod security_info {
+    pub const OWNER: u32 = 0x1;
+    pub const GROUP: u32 = 0x2;
+    pub const DACL: u32 = 0x4;
+}
 
-    let root_addr = fs.superblock().root();
-    let root_level = fs.superblock().root_level();
+/// Access mask granting every file right
+const FILE_ALL_ACCESS: u32 = 0x001F_01FF;
 
-    let tree = BtrfsTree::new(fs, root_addr, root_level);
+/// Builds a binary SID from its identifier authority and sub-authorities
+fn sid(authority: u8, sub_authorities: &[u32]) -> Vec<u8> {
+    let mut sid = vec![1, sub_authorities.len() as u8, 0, 0, 0, 0, 0, authority];
+    for sub_authority in sub_authorities {
+        sid.extend(sub_authority.to_le_bytes());
+    }
+    sid
+}
 
-    let key = BtrfsKey::new(dir_ino, item_type::DIR_ITEM, name_hash);
+/// Builds the self-relative security descriptor reported for a file
+///
+/// The owner and group are the Unix user and group SIDs Samba and NFS use,
+/// S-1-22-1-`uid` and S-1-22-2-`gid`, so `force_uid` and `force_gid` show
+/// up as the file's owner in Windows. The DACL grants Everyone full access;
+/// writes are refused by the mount itself. Only the parts named in
+/// `requested`, a set of [`security_info`] bits, are included.
+pub fn security_descriptor(uid: u32, gid: u32, requested: u32) -> Vec<u8> {
+    const HEADER_SIZE: usize = 20;
+    const SE_DACL_PRESENT: u16 = 0x0004;
+    const SE_SELF_RELATIVE: u16 = 0x8000;
+
+    let mut control = SE_SELF_RELATIVE;
+    // Offsets of the owner, group, SACL and DACL; 0 when absent
+    let mut offsets = [0u32; 4];
+    let mut body = Vec::new();
+
+    if requested & security_info::OWNER != 0 {
+        offsets[0] = (HEADER_SIZE + body.len()) as u32;
+        body.extend(sid(22, &[1, uid]));
+    }
+    if requested & security_info::GROUP != 0 {
+        offsets[1] = (HEADER_SIZE + body.len()) as u32;
+        body.extend(sid(22, &[2, gid]));
+    }
+    if requested & security_info::DACL != 0 {
+        offsets[3] = (HEADER_SIZE + body.len()) as u32;
+        control |= SE_DACL_PRESENT;
+
+        let everyone = sid(1, &[0]);
+        let ace_size = 8 + everyone.len() as u16;
+        // ACL header: revision, size and ACE count
+        body.extend([2, 0]);
+        body.extend((8 + ace_size).to_le_bytes());
+        body.extend([1, 0, 0, 0]);
+        // ACCESS_ALLOWED_ACE, inherited by files and subdirectories
+        body.extend([0, 0x3]);
+        body.extend(ace_size.to_le_bytes());
+        body.extend(FILE_ALL_ACCESS.to_le_bytes());
+        body.extend(everyone);
+    }
 
-    match tree.search(&key)? {
-        Some((_, data)) => {
-            // DIR_ITEM may contain multiple entries with same hash
-            // Parse and find the one with matching name
-            let entry = DirEntry::from_bytes(&data)?;
-            if entry.name == name {
-                Ok(entry)
-            } else {
-                Err(BtrfsError::NotFound(name.to_string()))
-            }
-        }
-        None => Err(BtrfsError::NotFound(name.to_string())),
+    let mut descriptor = vec![1, 0];
+    descriptor.extend(control.to_le_bytes());
+    for offset in offsets {
+        descriptor.extend(offset.to_le_bytes());
     }
+    descriptor.extend(body);
+    descriptor
 }
 
-/// Reads file extent data
-pub fn read_file_extents(fs: &BtrfsFilesystem, tree_id: u64, ino: u64) -> Result<Vec<ExtentData>> {
-    let root_addr = fs.superblock().root();
-    let root_level = fs.superblock().root_level();
+/// Lists a directory, including the `.` and `..` entries
+///
+/// The parent is taken from the directory's first INODE_REF; a directory
+/// without one (the subvolume root) is its own parent.
+pub fn list_dir(fs: &BtrfsFilesystem, tree_id: u64, ino: u64) -> Result<Vec<DirEntry>> {
+    let parent = get_inode_refs(fs, tree_id, ino)?
+        .first()
+        .map(|(parent, _)| *parent)
+        .unwrap_or(ino);
+
+    let mut entries = vec![dot_entry(".", ino), dot_entry("..", parent)];
+    entries.extend(read_dir(fs, tree_id, ino)?);
+    Ok(entries)
+}
 
-    let tree = BtrfsTree::new(fs, root_addr, root_level);
+/// Builds a synthetic `.` or `..` entry
+fn dot_entry(name: &str, ino: u64) -> DirEntry {
+    DirEntry {
+        ino,
+        child_tree: 0,
+        entry_type: InodeType::Directory,
+        name: name.to_string(),
+    }
+}
 
+/// Returns the `(start, len)` byte ranges of a file that are holes
+///
+/// Like `SEEK_HOLE`, this covers sparse extents, gaps between extents left
+/// by NO_HOLES, and everything past the last extent up to the inode size.
+/// Adjacent holes are merged.
+pub fn file_holes(fs: &BtrfsFilesystem, tree_id: u64, ino: u64) -> Result<Vec<(u64, u64)>> {
+    let size = read_inode(fs, tree_id, ino)?.size;
+    let tree = fs.tree(tree_id)?;
     let min_key = BtrfsKey::new(ino, item_type::EXTENT_DATA, 0);
     let max_key = BtrfsKey::new(ino, item_type::EXTENT_DATA, u64::MAX);
 
-    let items = tree.search_range(&min_key, &max_key)?;
+    let mut holes: Vec<(u64, u64)> = Vec::new();
+    let mut add_hole = |start: u64, end: u64| {
+        let end = end.min(size);
+        if start >= end {
+            return;
+        }
+        match holes.last_mut() {
+            Some((last, len)) if *last + *len == start => *len += end - start,
+            _ => holes.push((start, end - start)),
+        }
+    };
+
+    let mut pos = 0;
+    for (item, data) in tree.search_range(&min_key, &max_key)? {
+        let extent = ExtentData::from_bytes(&data)?;
+        let start = item.key.offset;
+        let len = if extent.is_inline() {
+            extent.ram_bytes
+        } else {
+            extent.num_bytes.unwrap_or(0)
+        };
+        let end = start.saturating_add(len);
+
+        add_hole(pos, start);
+        if extent.is_sparse() {
+            add_hole(start, end);
+        }
+        pos = pos.max(end);
+    }
+    add_hole(pos, size);
+
+    Ok(holes)
+}
+
+/// Returns the first offset at or after `from` that holds data
+///
+/// Like `SEEK_DATA`, returns None if `from` is at or past the end of the
+/// file or only holes follow it.
+pub fn seek_data(fs: &BtrfsFilesystem, tree_id: u64, ino: u64, from: u64) -> Result<Option<u64>> {
+    let size = read_inode(fs, tree_id, ino)?.size;
+    if from >= size {
+        return Ok(None);
+    }
 
-    let mut extents = Vec::new();
-    for (_, data) in items {
-        if let Ok(extent) = ExtentData::from_bytes(&data) {
-            extents.push(extent);
+    let mut pos = from;
+    for (start, len) in file_holes(fs, tree_id, ino)? {
+        if start <= pos && pos < start + len {
+            pos = start + len;
         }
     }
+    Ok(Some(pos).filter(|&pos| pos < size))
+}
 
-    Ok(extents)
+/// Returns the first offset at or after `from` that is in a hole
+///
+/// Like `SEEK_HOLE`, the end of the file counts as a hole, and None is
+/// returned only if `from` is at or past it.
+pub fn seek_hole(fs: &BtrfsFilesystem, tree_id: u64, ino: u64, from: u64) -> Result<Option<u64>> {
+    let size = read_inode(fs, tree_id, ino)?.size;
+    if from >= size {
+        return Ok(None);
+    }
+
+    let hole = file_holes(fs, tree_id, ino)?
+        .into_iter()
+        .find(|&(start, len)| start + len > from)
+        .map_or(size, |(start, _)| start.max(from));
+    Ok(Some(hole))
+}
+
+/// A run of a file's bytes on one device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhysicalRange {
+    /// Offset in the file of the first byte
+    ///
+    /// Compressed extents only decompress whole, so every range of one
+    /// reports the file offset of the extent.
+    pub file_offset: u64,
+    /// Length in bytes on disk
+    pub len: u64,
+    /// Logical address of the first byte
+    pub logical: u64,
+    /// Device holding this copy
+    pub devid: u64,
+    /// Byte offset on the device
+    pub physical: u64,
+    /// Whether the bytes are compressed
+    pub compressed: bool,
 }
 
-/// Reads file data at an offset
-pub fn read_file_data(
+/// Returns where the data of a file is stored on disk, in file order
+///
+/// Each copy of a mirrored extent gets its own range, and extents on
+/// striped chunks are split where they cross onto another stripe. Holes
+/// and inline extents, which live in the tree itself, have no ranges.
+/// Preallocated extents are included, as they occupy disk space.
+pub fn file_physical_map(
     fs: &BtrfsFilesystem,
     tree_id: u64,
     ino: u64,
-    offset: u64,
-    size: usize,
-) -> Result<Vec<u8>> {
-    let extents = read_file_extents(fs, tree_id, ino)?;
-
-    let mut result = vec![0u8; size];
-    let mut bytes_read = 0;
-
-    for extent in extents {
-        // TODO: Handle extent reading with decompression
-        if extent.is_inline() {
-            if let Some(data) = &extent.inline_data {
-                let copy_size = std::cmp::min(data.len(), size - bytes_read);
-                result[bytes_read..bytes_read + copy_size].copy_from_slice(&data[..copy_size]);
-                bytes_read += copy_size;
+) -> Result<Vec<PhysicalRange>> {
+    let tree = fs.tree(tree_id)?;
+    let min_key = BtrfsKey::new(ino, item_type::EXTENT_DATA, 0);
+    let max_key = BtrfsKey::new(ino, item_type::EXTENT_DATA, u64::MAX);
+
+    let mut ranges = Vec::new();
+    for (item, data) in tree.search_range(&min_key, &max_key)? {
+        let extent = ExtentData::from_bytes(&data)?;
+        if extent.is_inline() || extent.disk_bytenr.unwrap_or(0) == 0 {
+            continue;
+        }
+
+        let compressed = extent.compression != 0;
+        let (start, len) = if compressed {
+            (extent.disk_bytenr.unwrap_or(0), extent.disk_num_bytes.unwrap_or(0))
+        } else {
+            (
+                extent.disk_bytenr.unwrap_or(0) + extent.offset.unwrap_or(0),
+                extent.num_bytes.unwrap_or(0),
+            )
+        };
+
+        let mut done = 0;
+        while done < len {
+            let logical = start + done;
+            let copies = fs.logical_to_physical_mapped(logical)?;
+            let run = fs.chunk_tree().contiguous_run(logical)?.min(len - done);
+            let file_offset = if compressed {
+                item.key.offset
+            } else {
+                item.key.offset + done
+            };
+            for (devid, physical) in copies {
+                ranges.push(PhysicalRange {
+                    file_offset,
+                    len: run,
+                    logical,
+                    devid,
+                    physical,
+                    compressed,
+                });
             }
+            done += run;
         }
     }
 
-    result.truncate(bytes_read);
-    Ok(result)
+    Ok(ranges)
 }
 
-/// Gets inode references (hard links)
+/// Gets inode references (hard links) as `(parent_ino, ref)` pairs
+///
+/// INODE_REF items come first, then, with the EXTENDED_IREF feature, the
+/// INODE_EXTREF items holding links that overflowed them.
 pub fn get_inode_refs(
     fs: &BtrfsFilesystem,
     tree_id: u64,
     ino: u64,
 ) -> Result<Vec<(u64, InodeRef)>> {
-    let root_addr = fs.superblock().root();
-    let root_level = fs.superblock().root_level();
-
-    let tree = BtrfsTree::new(fs, root_addr, root_level);
+    let tree = fs.tree(tree_id)?;
 
+    let last_type = if fs.superblock().has_incompat(incompat::EXTENDED_IREF) {
+        item_type::INODE_EXTREF
+    } else {
+        item_type::INODE_REF
+    };
     let min_key = BtrfsKey::new(ino, item_type::INODE_REF, 0);
-    let max_key = BtrfsKey::new(ino, item_type::INODE_REF, u64::MAX);
+    let max_key = BtrfsKey::new(ino, last_type, u64::MAX);
 
     let items = tree.search_range(&min_key, &max_key)?;
 
     let mut refs = Vec::new();
     for (item, data) in items {
-        if let Ok(iref) = InodeRef::from_bytes(&data) {
-            refs.push((item.key.offset, iref)); // offset is parent dir ino
+        // A malformed link loses only itself and the ones after it in the item
+        let warn = |err: Option<BtrfsError>| {
+            if let Some(err) = err {
+                tracing::warn!(
+                    "Skipping the rest of {:?} of inode {}: {}",
+                    item.key,
+                    ino,
+                    err
+                );
+            }
+        };
+        if item.key.item_type == item_type::INODE_REF {
+            let (irefs, err) = InodeRef::parse_partial(&data);
+            warn(err);
+            // offset is parent dir ino
+            for iref in irefs {
+                refs.push((item.key.offset, iref));
+            }
+        } else {
+            let (extrefs, err) = InodeExtRef::parse_partial(&data);
+            warn(err);
+            for extref in extrefs {
+                let iref = InodeRef {
+                    index: extref.index,
+                    name_len: extref.name_len,
+                    name: extref.name,
+                };
+                refs.push((extref.parent, iref));
+            }
         }
     }
 
     Ok(refs)
 }
 
-/// BTRFS name hash function (CRC32c based)
-pub fn btrfs_name_hash(name: &str) -> u64 {
-    let crc = crc32c::crc32c(name.as_bytes());
-    crc as u64
+/// Gets the value of extended attribute `name`, or None if it isn't set
+pub fn get_xattr(
+    fs: &BtrfsFilesystem,
+    tree_id: u64,
+    ino: u64,
+    name: &str,
+) -> Result<Option<Vec<u8>>> {
+    let key = BtrfsKey::new(ino, item_type::XATTR_ITEM, btrfs_name_hash(name));
+
+    match fs.tree(tree_id)?.search(&key)? {
+        Some((_, data)) => Ok(Xattr::all_from_bytes(&data)?
+            .into_iter()
+            .find(|xattr| xattr.name == name)
+            .map(|xattr| xattr.value)),
+        None => Ok(None),
+    }
 }
 
-/// Resolves a path to an inode
-pub fn resolve_path(fs: &BtrfsFilesystem, tree_id: u64, path: &str) -> Result<(u64, Inode)> {
-    let components: Vec<&str> = path
-        .split(['/', '\\'])
-        .filter(|s| !s.is_empty())
-        .collect();
+/// Lists the extended attributes of an inode
+pub fn list_xattrs(fs: &BtrfsFilesystem, tree_id: u64, ino: u64) -> Result<Vec<Xattr>> {
+    let tree = fs.tree(tree_id)?;
 
-    // Start from root inode (256)
-    let mut current_ino = 256u64;
+    let min_key = BtrfsKey::new(ino, item_type::XATTR_ITEM, 0);
+    let max_key = BtrfsKey::new(ino, item_type::XATTR_ITEM, u64::MAX);
 
-    for component in components {
-        // Look up component in current directory
-        let entry = lookup(fs, tree_id, current_ino, component)?;
-        current_ino = entry.ino;
+    let mut xattrs = Vec::new();
+    for (_, data) in tree.search_range(&min_key, &max_key)? {
+        xattrs.extend(Xattr::all_from_bytes(&data)?);
     }
 
-    let inode = read_inode(fs, tree_id, current_ino)?;
-    Ok((current_ino, inode))
-}
-
-/// Parses path components from a path string
-pub fn parse_path_components(path: &str) -> Vec<&str> {
-    path.split(['/', '\\'])
-        .filter(|s| !s.is_empty())
-        .collect()
+    Ok(xattrs)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::{chunk::chunk_type, objectid};
+    use crate::test_utils::{inode_with, ImageBuilder};
+
+    /// An empty directory `empty` next to `full`, and a zero-byte file
+    /// `zero` whose stray extent item points outside every chunk
+    fn degenerate_fs() -> BtrfsFilesystem {
+        use crate::test_utils::extent_data;
+
+        let root = objectid::FIRST_FREE;
+        ImageBuilder::new()
+            .root_dir(objectid::FS_TREE)
+            .dir(objectid::FS_TREE, root, 257, "empty")
+            .file(objectid::FS_TREE, root, 258, "zero", 0)
+            .insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(258, item_type::EXTENT_DATA, 0),
+                extent_data(0x7000_0000, 4096),
+            )
+            .dir(objectid::FS_TREE, root, 259, "full")
+            .file(objectid::FS_TREE, 259, 260, "a.txt", 0)
+            .open()
+    }
 
     #[test]
-    fn test_btrfs_name_hash() {
-        // Hash should be deterministic
-        let hash1 = btrfs_name_hash("test.txt");
-        let hash2 = btrfs_name_hash("test.txt");
-        assert_eq!(hash1, hash2);
+    fn test_read_zero_length_skips_tree() {
+        // No trees at all: any traversal would fail
+        let fs = ImageBuilder::new().open();
+        let data = read_file_data(&fs, objectid::FS_TREE, 258, 0, 0, true).unwrap();
+        assert!(data.is_empty());
+    }
 
-        // Different names should have different hashes (usually)
-        let hash_a = btrfs_name_hash("file_a.txt");
-        let hash_b = btrfs_name_hash("file_b.txt");
-        assert_ne!(hash_a, hash_b);
+    #[test]
+    fn test_read_zero_byte_file() {
+        let fs = degenerate_fs();
+        let data = read_file_data(&fs, objectid::FS_TREE, 258, 0, 4096, true).unwrap();
+        assert!(data.is_empty());
+
+        // The read reaches the inode: a missing one fails
+        assert!(read_file_data(&fs, objectid::FS_TREE, 999, 0, 4096, true).is_err());
+    }
+
+    #[test]
+    fn test_file_holes() {
+        use crate::test_utils::extent_data;
+
+        // Data at 0..4K and 12K..16K around a sparse extent and a NO_HOLES
+        // gap, then an unallocated tail up to the 32K size
+        let root = objectid::FIRST_FREE;
+        let extent = |offset| BtrfsKey::new(257, item_type::EXTENT_DATA, offset);
+        let fs = ImageBuilder::new()
+            .root_dir(objectid::FS_TREE)
+            .file(objectid::FS_TREE, root, 257, "sparse", 0x8000)
+            .insert(objectid::FS_TREE, extent(0), extent_data(0x300000, 0x1000))
+            .insert(objectid::FS_TREE, extent(0x1000), extent_data(0, 0x1000))
+            .insert(objectid::FS_TREE, extent(0x3000), extent_data(0x301000, 0x1000))
+            .file(objectid::FS_TREE, root, 258, "dense", 0x1000)
+            .insert(
+                objectid::FS_TREE,
+                BtrfsKey::new(258, item_type::EXTENT_DATA, 0),
+                extent_data(0x302000, 0x1000),
+            )
+            .open();
+
+        assert_eq!(
+            file_holes(&fs, objectid::FS_TREE, 257).unwrap(),
+            vec![(0x1000, 0x2000), (0x4000, 0x4000)]
+        );
+        assert!(file_holes(&fs, objectid::FS_TREE, 258).unwrap().is_empty());
     }
 
     #[test]
-    fn test_btrfs_name_hash_empty() {
-        let hash = btrfs_name_hash("");
-        assert_eq!(hash, 0); // CRC32c of empty data is 0
+    fn test_seek_data_and_hole() {
+        use crate::test_utils::extent_data;
+
+        // "gappy" starts with a hole: hole 0..8K, data 8K..12K, hole to 16K.
+        // "dense" starts with data: data 0..4K, hole 4K..8K, data 8K..12K.
+        let root = objectid::FIRST_FREE;
+        let key = |ino, offset| BtrfsKey::new(ino, item_type::EXTENT_DATA, offset);
+        let fs = ImageBuilder::new()
+            .root_dir(objectid::FS_TREE)
+            .file(objectid::FS_TREE, root, 257, "gappy", 0x4000)
+            .insert(objectid::FS_TREE, key(257, 0x2000), extent_data(0x300000, 0x1000))
+            .file(objectid::FS_TREE, root, 258, "dense", 0x3000)
+            .insert(objectid::FS_TREE, key(258, 0), extent_data(0x301000, 0x1000))
+            .insert(objectid::FS_TREE, key(258, 0x2000), extent_data(0x302000, 0x1000))
+            .open();
+        let data = |ino, from| seek_data(&fs, objectid::FS_TREE, ino, from).unwrap();
+        let hole = |ino, from| seek_hole(&fs, objectid::FS_TREE, ino, from).unwrap();
+
+        assert_eq!(data(257, 0), Some(0x2000));
+        assert_eq!(data(257, 0x2800), Some(0x2800));
+        assert_eq!(data(257, 0x3000), None);
+        assert_eq!(hole(257, 0), Some(0));
+        assert_eq!(hole(257, 0x2000), Some(0x3000));
+        assert_eq!(hole(257, 0x4000), None);
+
+        assert_eq!(data(258, 0), Some(0));
+        assert_eq!(data(258, 0x1000), Some(0x2000));
+        assert_eq!(hole(258, 0), Some(0x1000));
+        // The end of the file is an implicit hole
+        assert_eq!(hole(258, 0x2000), Some(0x3000));
+        assert_eq!(data(258, 0x3000), None);
     }
 
     #[test]
-    fn test_btrfs_name_hash_special_chars() {
-        let hash1 = btrfs_name_hash("file with spaces.txt");
-        let hash2 = btrfs_name_hash("file-with-dashes.txt");
-        assert_ne!(hash1, hash2);
+    fn test_xattrs() {
+        use crate::test_utils::xattr_item;
+
+        // Two names sharing one item, as if their hashes collided
+        let label = b"system_u:object_r:etc_t:s0";
+        let mut packed = xattr_item("user.other", b"1");
+        packed.extend(xattr_item("security.selinux", label));
+        let key = |name| BtrfsKey::new(257, item_type::XATTR_ITEM, btrfs_name_hash(name));
+
+        let root = objectid::FIRST_FREE;
+        let fs = ImageBuilder::new()
+            .root_dir(objectid::FS_TREE)
+            .file(objectid::FS_TREE, root, 257, "f", 0)
+            .insert(
+                objectid::FS_TREE,
+                key("user.comment"),
+                xattr_item("user.comment", b"hi"),
+            )
+            .insert(objectid::FS_TREE, key("security.selinux"), packed)
+            .open();
+        let get = |name| get_xattr(&fs, objectid::FS_TREE, 257, name).unwrap();
+
+        assert_eq!(get("user.comment"), Some(b"hi".to_vec()));
+        assert_eq!(get("security.selinux"), Some(label.to_vec()));
+        assert_eq!(get("user.missing"), None);
+
+        let list = |ino| list_xattrs(&fs, objectid::FS_TREE, ino).unwrap();
+        let mut names: Vec<String> = list(257).into_iter().map(|x| x.name).collect();
+        names.sort();
+        assert_eq!(names, ["security.selinux", "user.comment", "user.other"]);
+        assert!(list(root).is_empty());
     }
 
     #[test]
-    fn test_btrfs_name_hash_unicode() {
-        let hash = btrfs_name_hash("файл.txt"); // Russian word for "file"
-        assert_ne!(hash, 0);
+    fn test_list_empty_dir() {
+        let fs = degenerate_fs();
+        let entries = list_dir(&fs, objectid::FS_TREE, 257).unwrap();
+
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec![".", ".."]);
+        assert_eq!(entries[0].ino, 257);
+        // The parent comes from the directory's INODE_REF in the FS tree
+        assert_eq!(entries[1].ino, objectid::FIRST_FREE);
+        assert!(entries.iter().all(|e| e.entry_type == InodeType::Directory));
+
+        // A sibling read the same way does list its entry
+        let names: Vec<String> = list_dir(&fs, objectid::FS_TREE, 259)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names, [".", "..", "a.txt"]);
     }
 
     #[test]
-    fn test_parse_path_components_unix() {
-        let components = parse_path_components("/home/user/file.txt");
-        assert_eq!(components, vec!["home", "user", "file.txt"]);
+    fn test_operations_read_subvolume_tree() {
+        use crate::test_utils::inline_extent;
+
+        // The FS tree and subvolume 300 both have an inode 257, with
+        // different names and sizes
+        let root = objectid::FIRST_FREE;
+        let fs = ImageBuilder::new()
+            .root_dir(objectid::FS_TREE)
+            .file(objectid::FS_TREE, root, 257, "top.txt", 1)
+            .subvolume(300, objectid::FS_TREE, root, "@home")
+            .file(300, root, 257, "inner.txt", 5)
+            .insert(
+                300,
+                BtrfsKey::new(257, item_type::EXTENT_DATA, 0),
+                inline_extent(b"hello"),
+            )
+            .open();
+
+        assert_eq!(read_inode(&fs, 300, 257).unwrap().size, 5);
+
+        let names: Vec<String> = read_dir(&fs, 300, root)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names, ["inner.txt"]);
+
+        assert_eq!(lookup(&fs, 300, root, "inner.txt").unwrap().ino, 257);
+        assert!(matches!(
+            lookup(&fs, 300, root, "top.txt"),
+            Err(BtrfsError::NotFound(_))
+        ));
+
+        let extents = read_file_extents(&fs, 300, 257).unwrap();
+        assert_eq!(extents.len(), 1);
+        assert_eq!(extents[0].ram_bytes, 5);
+
+        let refs = get_inode_refs(&fs, 300, 257).unwrap();
+        assert_eq!(refs.len(), 1);
+        assert_eq!((refs[0].0, refs[0].1.name.as_str()), (root, "inner.txt"));
     }
 
     #[test]
-    fn test_parse_path_components_windows() {
-        let components = parse_path_components("C:\\Users\\user\\file.txt");
-        assert_eq!(components, vec!["C:", "Users", "user", "file.txt"]);
+    fn test_read_symlink() {
+        let root = objectid::FIRST_FREE;
+        let fs = ImageBuilder::new()
+            .root_dir(objectid::FS_TREE)
+            .file(objectid::FS_TREE, root, 257, "target.txt", 0)
+            .symlink(objectid::FS_TREE, root, 258, "link", "../etc/target.txt")
+            .open();
+
+        assert_eq!(
+            read_symlink(&fs, objectid::FS_TREE, 258).unwrap(),
+            "../etc/target.txt"
+        );
+        assert!(matches!(
+            read_symlink(&fs, objectid::FS_TREE, 257),
+            Err(BtrfsError::NotASymlink)
+        ));
+
+        let entry = lookup(&fs, objectid::FS_TREE, root, "link").unwrap();
+        assert_eq!(entry.entry_type, InodeType::Symlink);
+        assert_eq!(
+            entry_attributes(&entry, false),
+            file_attribute::NORMAL | file_attribute::REPARSE_POINT
+        );
     }
 
     #[test]
-    fn test_parse_path_components_mixed() {
-        let components = parse_path_components("/path/to\\mixed/separators");
-        assert_eq!(components, vec!["path", "to", "mixed", "separators"]);
+    fn test_security_descriptor() {
+        let all = security_info::OWNER | security_info::GROUP | security_info::DACL;
+        let sd = security_descriptor(1000, 100, all);
+        let offsets = |sd: &[u8]| -> Vec<usize> {
+            sd[4..20]
+                .chunks(4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+                .collect()
+        };
+        let offset = |i: usize| offsets(&sd)[i];
+
+        assert_eq!(sd[0], 1);
+        assert_eq!(u16::from_le_bytes([sd[2], sd[3]]), 0x8004);
+        // S-1-22-1-1000 and S-1-22-2-100
+        assert_eq!(
+            sd[offset(0)..offset(0) + 16],
+            [1, 2, 0, 0, 0, 0, 0, 22, 1, 0, 0, 0, 0xE8, 0x03, 0, 0]
+        );
+        assert_eq!(
+            sd[offset(1)..offset(1) + 16],
+            [1, 2, 0, 0, 0, 0, 0, 22, 2, 0, 0, 0, 100, 0, 0, 0]
+        );
+        assert_eq!(offset(2), 0);
+        // One ACE granting S-1-1-0 full access
+        let dacl = &sd[offset(3)..];
+        assert_eq!(dacl.len(), 28);
+        assert_eq!(u16::from_le_bytes([dacl[2], dacl[3]]), 28);
+        assert_eq!(
+            u32::from_le_bytes(dacl[12..16].try_into().unwrap()),
+            FILE_ALL_ACCESS
+        );
+        assert_eq!(dacl[16..], [1, 1, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0]);
+
+        // Only the requested parts are included
+        let sd = security_descriptor(1000, 100, security_info::GROUP);
+        assert_eq!(sd.len(), 36);
+        assert_eq!(u16::from_le_bytes([sd[2], sd[3]]), 0x8000);
+        assert_eq!(offsets(&sd), [0, 20, 0, 0]);
     }
 
     #[test]
-    fn test_parse_path_components_empty() {
-        let components = parse_path_components("");
-        assert!(components.is_empty());
+    fn test_file_attributes() {
+        let inode = inode_with(0o100644, InodeFlags::empty());
+        assert_eq!(file_attributes(&inode), file_attribute::NORMAL);
+
+        let inode = inode_with(0o040755, InodeFlags::empty());
+        assert_eq!(file_attributes(&inode), file_attribute::DIRECTORY);
+
+        let inode = inode_with(0o100644, InodeFlags::IMMUTABLE | InodeFlags::COMPRESS);
+        assert_eq!(
+            file_attributes(&inode),
+            file_attribute::READONLY | file_attribute::COMPRESSED
+        );
+
+        // Flags without a Windows equivalent don't change the attributes
+        let inode = inode_with(0o100644, InodeFlags::NODATACOW | InodeFlags::NODATASUM);
+        assert_eq!(file_attributes(&inode), file_attribute::NORMAL);
+
+        let inode = inode_with(0o120777, InodeFlags::empty());
+        assert_eq!(file_attributes(&inode), file_attribute::REPARSE_POINT);
     }
 
     #[test]
-    fn test_parse_path_components_root() {
-        let components = parse_path_components("/");
-        assert!(components.is_empty());
+    fn test_file_physical_map() {
+        use crate::test_utils::{extent_data, inline_extent};
+
+        // 0..8K: the last two of three blocks at 0x300000, identity mapped;
+        // 8K..12K: a hole; 12K..16K: in a chunk at 0x10000000 stored at
+        // 0x340000
+        let mut first = extent_data(0x300000, 3 * 4096);
+        first[37..45].copy_from_slice(&4096u64.to_le_bytes()); // offset
+        first[45..53].copy_from_slice(&8192u64.to_le_bytes()); // num_bytes
+        let extent_key = |ino, offset| BtrfsKey::new(ino, item_type::EXTENT_DATA, offset);
+
+        let root = objectid::FIRST_FREE;
+        let tree = objectid::FS_TREE;
+        let fs = ImageBuilder::new()
+            .chunk(0x10000000, 0x100000, 0x340000)
+            .root_dir(tree)
+            .file(tree, root, 257, "two", 4 * 4096)
+            .insert(tree, extent_key(257, 0), first)
+            .insert(tree, extent_key(257, 8192), extent_data(0, 4096))
+            .insert(tree, extent_key(257, 12288), extent_data(0x10000000, 4096))
+            .file(tree, root, 258, "small", 5)
+            .insert(tree, extent_key(258, 0), inline_extent(b"small"))
+            .open();
+
+        let range = |file_offset, len, logical, physical| PhysicalRange {
+            file_offset,
+            len,
+            logical,
+            devid: 1,
+            physical,
+            compressed: false,
+        };
+        assert_eq!(
+            file_physical_map(&fs, tree, 257).unwrap(),
+            vec![
+                range(0, 8192, 0x301000, 0x301000),
+                range(12288, 4096, 0x10000000, 0x340000),
+            ]
+        );
+        assert!(file_physical_map(&fs, tree, 258).unwrap().is_empty());
     }
 
     #[test]
-    fn test_parse_path_components_trailing_slash() {
-        let components = parse_path_components("/path/to/dir/");
-        assert_eq!(components, vec!["path", "to", "dir"]);
+    fn test_file_physical_map_striped() {
+        use crate::test_utils::extent_data;
+
+        // RAID0 over two stripes of 64K; 96K from 32K into the chunk is the
+        // second half of the first element and all of the second
+        let tree = objectid::FS_TREE;
+        let fs = ImageBuilder::new()
+            .mirrored_chunk(0x10000000, 0x100000, chunk_type::RAID0, &[0x200000, 0x300000])
+            .root_dir(tree)
+            .file(tree, objectid::FIRST_FREE, 257, "striped", 0x18000)
+            .insert(
+                tree,
+                BtrfsKey::new(257, item_type::EXTENT_DATA, 0),
+                extent_data(0x10008000, 0x18000),
+            )
+            .open();
+
+        let map: Vec<(u64, u64, u64)> = file_physical_map(&fs, tree, 257)
+            .unwrap()
+            .iter()
+            .map(|range| (range.file_offset, range.len, range.physical))
+            .collect();
+        assert_eq!(
+            map,
+            vec![(0, 0x8000, 0x208000), (0x8000, 0x10000, 0x300000)]
+        );
     }
 
     #[test]
-    fn test_parse_path_components_double_slash() {
-        let components = parse_path_components("/path//to///dir");
-        assert_eq!(components, vec!["path", "to", "dir"]);
+    fn test_get_inode_refs_with_extrefs() {
+        use crate::core::superblock::incompat;
+
+        let mut extref = Vec::new();
+        extref.extend_from_slice(&257u64.to_le_bytes()); // parent
+        extref.extend_from_slice(&9u64.to_le_bytes()); // index
+        extref.extend_from_slice(&5u16.to_le_bytes());
+        extref.extend_from_slice(b"alias");
+
+        let root = objectid::FIRST_FREE;
+        let fs_with = |flags, extref: &[u8]| {
+            ImageBuilder::new()
+                .incompat(flags)
+                .root_dir(objectid::FS_TREE)
+                .dir(objectid::FS_TREE, root, 257, "sub")
+                .file(objectid::FS_TREE, root, 258, "file", 0)
+                .insert(
+                    objectid::FS_TREE,
+                    BtrfsKey::new(258, item_type::INODE_EXTREF, 0x1234),
+                    extref.to_vec(),
+                )
+                .open()
+        };
+        let links = |fs: &BtrfsFilesystem| -> Vec<(u64, String)> {
+            get_inode_refs(fs, objectid::FS_TREE, 258)
+                .unwrap()
+                .into_iter()
+                .map(|(parent, iref)| (parent, iref.name))
+                .collect()
+        };
+
+        let fs = fs_with(incompat::EXTENDED_IREF, &extref);
+        assert_eq!(
+            links(&fs),
+            [(root, "file".to_string()), (257, "alias".to_string())]
+        );
+
+        // Without the feature there are no extrefs to look for
+        let fs = fs_with(0, &extref);
+        assert_eq!(links(&fs), [(root, "file".to_string())]);
+
+        // A truncated link at the end of the item leaves the one before it
+        let mut broken = extref.clone();
+        broken.extend_from_slice(&257u64.to_le_bytes());
+        broken.extend_from_slice(&10u64.to_le_bytes());
+        broken.extend_from_slice(&5u16.to_le_bytes());
+        broken.extend_from_slice(b"al");
+        let fs = fs_with(incompat::EXTENDED_IREF, &broken);
+        assert_eq!(
+            links(&fs),
+            [(root, "file".to_string()), (257, "alias".to_string())]
+        );
     }
 
     #[test]
-    fn test_parse_path_components_single_name() {
-        let components = parse_path_components("filename.txt");
-        assert_eq!(components, vec!["filename.txt"]);
+    fn test_entry_attributes_subvolume() {
+        let root = objectid::FIRST_FREE;
+        let fs = ImageBuilder::new()
+            .root_dir(objectid::FS_TREE)
+            .dir(objectid::FS_TREE, root, 257, "home")
+            .subvolume(258, objectid::FS_TREE, root, "@")
+            .open();
+
+        let entries: Vec<DirEntry> = dir_iter(&fs, objectid::FS_TREE, root, 16)
+            .unwrap()
+            .map(|e| e.unwrap())
+            .collect();
+        let home = entries.iter().find(|e| e.name == "home").unwrap();
+        let subvol = entries.iter().find(|e| e.name == "@").unwrap();
+
+        assert!(!home.is_subvolume());
+        assert!(subvol.is_subvolume());
+        assert_eq!(subvol.ino, 258);
+
+        // Plain directories are unaffected by the option
+        assert_eq!(entry_attributes(home, true), file_attribute::DIRECTORY);
+        assert_eq!(entry_attributes(subvol, false), file_attribute::DIRECTORY);
+        assert_eq!(
+            entry_attributes(subvol, true),
+            file_attribute::DIRECTORY | file_attribute::REPARSE_POINT
+        );
     }
 }
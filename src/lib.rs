@@ -27,6 +27,9 @@ pub mod ffi;
 pub mod fuse;
 pub mod updater;
 
+#[cfg(test)]
+pub(crate) mod test_utils;
+
 pub use blockdev::{BlockDevice, BlockDeviceError};
 pub use core::{
     BtrfsError, BtrfsFilesystem, BtrfsKey, CompressionType, Inode, InodeType, Subvolume,
@@ -2,6 +2,7 @@
 //!
 //! Command-line interface for mounting BTRFS volumes on Windows.
 
+use btrf_mount_windows::core::objectid;
 use btrf_mount_windows::{blockdev, BtrfsFilesystem, BtrfsMount, MountOptions};
 use std::sync::Arc;
 
@@ -16,19 +17,31 @@ fn main() {
 
     let args: Vec<String> = std::env::args().collect();
 
+    if args.get(1).map(String::as_str) == Some("frag") {
+        if args.len() < 4 {
+            eprintln!("Usage: {} frag <source> <path>", args[0]);
+            std::process::exit(1);
+        }
+        frag(&args[2], &args[3]);
+        return;
+    }
+
     if args.len() < 3 {
         eprintln!("BTRFS Mount Windows v{}", btrf_mount_windows::VERSION);
         eprintln!();
         eprintln!("Usage: {} <source> <drive_letter>", args[0]);
+        eprintln!("       {} frag <source> <path>", args[0]);
         eprintln!();
         eprintln!("Arguments:");
         eprintln!("  source       Path to BTRFS image file or physical drive");
         eprintln!("               (e.g., ./disk.img or \\\\.\\PhysicalDrive1)");
         eprintln!("  drive_letter Drive letter to mount (e.g., Z:)");
+        eprintln!("  path         File to report extent fragmentation for");
         eprintln!();
         eprintln!("Examples:");
         eprintln!("  {} ./btrfs.img Z:", args[0]);
         eprintln!("  {} \\\\.\\PhysicalDrive1 Y:", args[0]);
+        eprintln!("  {} frag ./btrfs.img /home/user/video.mkv", args[0]);
         std::process::exit(1);
     }
 
@@ -37,23 +50,7 @@ fn main() {
 
     tracing::info!("Mounting {} to {}", source, drive_letter);
 
-    // Open block device
-    let device = match blockdev::open(source, false) {
-        Ok(d) => Arc::from(d),
-        Err(e) => {
-            eprintln!("Failed to open device: {}", e);
-            std::process::exit(1);
-        }
-    };
-
-    // Open BTRFS filesystem
-    let fs = match BtrfsFilesystem::open(device, false) {
-        Ok(fs) => Arc::new(fs),
-        Err(e) => {
-            eprintln!("Failed to open BTRFS filesystem: {}", e);
-            std::process::exit(1);
-        }
-    };
+    let fs = open_filesystem(source, false);
 
     tracing::info!("Filesystem label: {}", fs.label());
     tracing::info!("Filesystem UUID: {}", fs.uuid());
@@ -81,3 +78,44 @@ fn main() {
         }
     }
 }
+
+/// Opens the BTRFS filesystem at `source`, exiting on failure
+fn open_filesystem(source: &str, read_only: bool) -> Arc<BtrfsFilesystem> {
+    // Open block device
+    let device = match blockdev::open(source, read_only) {
+        Ok(d) => Arc::from(d),
+        Err(e) => {
+            eprintln!("Failed to open device: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Open BTRFS filesystem
+    match BtrfsFilesystem::open(device, read_only) {
+        Ok(fs) => Arc::new(fs),
+        Err(e) => {
+            eprintln!("Failed to open BTRFS filesystem: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Prints a fragmentation report for a file in the top-level subvolume
+fn frag(source: &str, path: &str) {
+    let fs = open_filesystem(source, true);
+
+    let report = match fs.fragmentation_report(objectid::FS_TREE, path) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Failed to analyze {}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+
+    println!("{}", path);
+    println!("  Inode:          {}", report.ino);
+    println!("  Extents:        {}", report.extent_count);
+    println!("  Data bytes:     {}", report.total_bytes);
+    println!("  Average extent: {} bytes", report.average_extent_size());
+    println!("  Gaps:           {}", report.gaps);
+}
@@ -0,0 +1,1100 @@
+//! In-memory fixtures shared by unit tests
+//!
+//! Provides a memory-backed block device and a builder that lays out a
+//! minimal single-device BTRFS image, so filesystem-level code can be
+//! exercised without a real disk or image file.
+
+use crate::blockdev::{self, BlockDevice, BlockDeviceError};
+use crate::core::{
+    device::DEV_ITEM_SIZE,
+    inode::{btrfs_name_hash, Inode, InodeFlags},
+    item_type, objectid,
+    superblock::{compat_ro, incompat},
+    tree::{BtrfsKey, ITEM_SIZE, KEY_PTR_SIZE, KEY_SIZE, NODE_HEADER_SIZE},
+    BtrfsFilesystem, Checksum, BTRFS_MAGIC, DEFAULT_NODE_SIZE, DEFAULT_SECTOR_SIZE,
+    SUPERBLOCK_OFFSET,
+};
+use parking_lot::RwLock;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Default size of a test image (4 MiB)
+pub const TEST_IMAGE_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Logical address where tree nodes are allocated from (1 MiB)
+const NODE_ALLOC_START: u64 = 0x100000;
+
+/// Filesystem UUID written into the superblock and node headers
+pub const TEST_FSID: [u8; 16] = [0x42; 16];
+
+/// File type bits of an inode mode
+pub const S_IFMT: u32 = 0o170000;
+/// Directory file type
+pub const S_IFDIR: u32 = 0o040000;
+/// Regular file type
+pub const S_IFREG: u32 = 0o100000;
+/// Symbolic link file type
+pub const S_IFLNK: u32 = 0o120000;
+
+/// A block device backed by a byte vector
+pub struct MemDevice {
+    data: RwLock<Vec<u8>>,
+    read_only: bool,
+}
+
+impl MemDevice {
+    /// Creates a writable device holding `data`
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            data: RwLock::new(data),
+            read_only: false,
+        }
+    }
+
+    /// Creates a device holding `data` that rejects writes
+    pub fn read_only(data: Vec<u8>) -> Self {
+        Self {
+            data: RwLock::new(data),
+            read_only: true,
+        }
+    }
+}
+
+impl BlockDevice for MemDevice {
+    fn size(&self) -> u64 {
+        self.data.read().len() as u64
+    }
+
+    fn sector_size(&self) -> u32 {
+        512
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> blockdev::Result<usize> {
+        let data = self.data.read();
+        let size = data.len() as u64;
+        if offset >= size {
+            return Err(BlockDeviceError::InvalidOffset { offset, size });
+        }
+
+        let n = std::cmp::min(buf.len() as u64, size - offset) as usize;
+        let start = offset as usize;
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        Ok(n)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> blockdev::Result<usize> {
+        if self.read_only {
+            return Err(BlockDeviceError::ReadOnly);
+        }
+
+        let mut data = self.data.write();
+        let size = data.len() as u64;
+        if offset >= size {
+            return Err(BlockDeviceError::InvalidOffset { offset, size });
+        }
+
+        let n = std::cmp::min(buf.len() as u64, size - offset) as usize;
+        let start = offset as usize;
+        data[start..start + n].copy_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush_device(&self) -> blockdev::Result<()> {
+        Ok(())
+    }
+}
+
+/// Builder for a minimal single-device BTRFS image
+///
+/// The image contains a single chunk that maps logical addresses 1:1 onto
+/// the device, described by the superblock's bootstrap `sys_chunk_array`.
+#[derive(Debug, Clone)]
+pub struct ImageBuilder {
+    size: u64,
+    generation: u64,
+    node_size: u32,
+    sector_size: u32,
+    incompat_flags: u64,
+    compat_ro_flags: u64,
+    csum: Checksum,
+    /// UUID and parent UUID recorded in generated ROOT_ITEMs
+    root_uuids: BTreeMap<u64, ([u8; 16], [u8; 16])>,
+    /// Items per tree, keyed by tree object ID
+    trees: BTreeMap<u64, BTreeMap<BtrfsKey, Vec<u8>>>,
+    /// Raw data written at fixed logical addresses
+    data: BTreeMap<u64, Vec<u8>>,
+    /// Whether to describe every tree block in the extent tree
+    account_extents: bool,
+    /// Whether the free space tree uses bitmaps instead of extents
+    free_space_bitmaps: bool,
+}
+
+impl Default for ImageBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ImageBuilder {
+    /// Creates a builder with default geometry
+    pub fn new() -> Self {
+        Self {
+            size: TEST_IMAGE_SIZE,
+            generation: 1,
+            node_size: DEFAULT_NODE_SIZE,
+            sector_size: DEFAULT_SECTOR_SIZE,
+            incompat_flags: 0,
+            compat_ro_flags: 0,
+            csum: Checksum::Crc32c,
+            root_uuids: BTreeMap::new(),
+            trees: BTreeMap::new(),
+            data: BTreeMap::new(),
+            account_extents: false,
+            free_space_bitmaps: false,
+        }
+    }
+
+    /// Sets the superblock generation
+    pub fn generation(&mut self, generation: u64) -> &mut Self {
+        self.generation = generation;
+        self
+    }
+
+    /// Sets the superblock's incompatible feature flags
+    pub fn incompat(&mut self, flags: u64) -> &mut Self {
+        self.incompat_flags = flags;
+        self
+    }
+
+    /// Sets the superblock's read-only compatible feature flags
+    pub fn compat_ro(&mut self, flags: u64) -> &mut Self {
+        self.compat_ro_flags = flags;
+        self
+    }
+
+    /// Sets the tree node size
+    ///
+    /// Smaller nodes give deeper trees for the same number of items.
+    pub fn node_size(&mut self, node_size: u32) -> &mut Self {
+        self.node_size = node_size;
+        self
+    }
+
+    /// Sets the checksum algorithm for the superblock and tree nodes
+    pub fn checksum(&mut self, csum: Checksum) -> &mut Self {
+        self.csum = csum;
+        self
+    }
+
+    /// Fills in the extent tree as a writable filesystem needs it: an extent
+    /// item for every tree block, a block group item for the identity-mapped
+    /// chunk and, with the FREE_SPACE_TREE flag set, a free space tree
+    ///
+    /// Data extents must be given their EXTENT_ITEMs by the test.
+    pub fn account_extents(&mut self) -> &mut Self {
+        self.account_extents = true;
+        self
+    }
+
+    /// Has [`account_extents`](Self::account_extents) record free space
+    /// as bitmaps
+    pub fn free_space_bitmaps(&mut self) -> &mut Self {
+        self.free_space_bitmaps = true;
+        self
+    }
+
+    /// Inserts an item into a tree, creating the tree if needed
+    ///
+    /// A ROOT_ITEM is generated automatically for every tree other than the
+    /// root tree when the image is built.
+    pub fn insert(&mut self, tree_id: u64, key: BtrfsKey, data: Vec<u8>) -> &mut Self {
+        self.trees.entry(tree_id).or_default().insert(key, data);
+        self
+    }
+
+    /// Adds a chunk mapping `size` bytes at `logical` to `physical`
+    ///
+    /// Only the bootstrap chunk is in the superblock; this one is found by
+    /// reading the chunk tree.
+    pub fn chunk(&mut self, logical: u64, size: u64, physical: u64) -> &mut Self {
+        let item = chunk_item(size, physical, self.sector_size, 0x1); // DATA
+        self.insert(
+            objectid::CHUNK_TREE,
+            BtrfsKey::new(objectid::FIRST_CHUNK_TREE, item_type::CHUNK_ITEM, logical),
+            item,
+        )
+    }
+
+    /// Adds a data chunk of `type_flags` (e.g. RAID1) with one copy at each
+    /// of `physicals`
+    pub fn mirrored_chunk(
+        &mut self,
+        logical: u64,
+        size: u64,
+        type_flags: u64,
+        physicals: &[u64],
+    ) -> &mut Self {
+        let item = striped_chunk_item(size, physicals, self.sector_size, 0x1 | type_flags);
+        self.insert(
+            objectid::CHUNK_TREE,
+            BtrfsKey::new(objectid::FIRST_CHUNK_TREE, item_type::CHUNK_ITEM, logical),
+            item,
+        )
+    }
+
+    /// Writes raw file data at `logical`
+    ///
+    /// Tree nodes are allocated from 1 MiB upward, so data should be placed
+    /// well above that.
+    pub fn data(&mut self, logical: u64, bytes: &[u8]) -> &mut Self {
+        self.data.insert(logical, bytes.to_vec());
+        self
+    }
+
+    /// Adds the root directory (inode 256) of a filesystem tree
+    pub fn root_dir(&mut self, tree_id: u64) -> &mut Self {
+        let ino = objectid::FIRST_FREE;
+        self.insert(
+            tree_id,
+            BtrfsKey::new(ino, item_type::INODE_ITEM, 0),
+            inode_item(S_IFDIR | 0o755, 0),
+        );
+        self.insert(
+            tree_id,
+            BtrfsKey::new(ino, item_type::INODE_REF, ino),
+            inode_ref(0, ".."),
+        )
+    }
+
+    /// Adds an empty directory `name` under `parent`
+    pub fn dir(&mut self, tree_id: u64, parent: u64, ino: u64, name: &str) -> &mut Self {
+        self.entry(tree_id, parent, ino, name, inode_item(S_IFDIR | 0o755, 0))
+    }
+
+    /// Adds a regular file `name` of `size` bytes under `parent`
+    pub fn file(
+        &mut self,
+        tree_id: u64,
+        parent: u64,
+        ino: u64,
+        name: &str,
+        size: u64,
+    ) -> &mut Self {
+        self.entry(
+            tree_id,
+            parent,
+            ino,
+            name,
+            inode_item(S_IFREG | 0o644, size),
+        )
+    }
+
+    /// Adds a symlink `name` under `parent` pointing at `target`
+    pub fn symlink(
+        &mut self,
+        tree_id: u64,
+        parent: u64,
+        ino: u64,
+        name: &str,
+        target: &str,
+    ) -> &mut Self {
+        let inode = inode_item(S_IFLNK | 0o777, target.len() as u64);
+        self.entry(tree_id, parent, ino, name, inode).insert(
+            tree_id,
+            BtrfsKey::new(ino, item_type::EXTENT_DATA, 0),
+            inline_extent(target.as_bytes()),
+        )
+    }
+
+    /// Adds an inode plus the INODE_REF, DIR_ITEM and DIR_INDEX linking it
+    /// into `parent`
+    pub fn entry(
+        &mut self,
+        tree_id: u64,
+        parent: u64,
+        ino: u64,
+        name: &str,
+        inode: Vec<u8>,
+    ) -> &mut Self {
+        let mode = u32::from_le_bytes(inode[52..56].try_into().unwrap());
+        let dir_type = match mode & S_IFMT {
+            S_IFDIR => 2,
+            S_IFLNK => 7,
+            _ => 1,
+        };
+
+        self.insert(tree_id, BtrfsKey::new(ino, item_type::INODE_ITEM, 0), inode);
+        let location = BtrfsKey::new(ino, item_type::INODE_ITEM, 0);
+        let index = self.link(tree_id, parent, location, dir_type, name);
+        self.insert(
+            tree_id,
+            BtrfsKey::new(ino, item_type::INODE_REF, parent),
+            inode_ref(index, name),
+        )
+    }
+
+    /// Creates subvolume `id` and links it as `name` in directory `dirid` of
+    /// tree `parent`, with matching ROOT_REF and ROOT_BACKREF items
+    pub fn subvolume(&mut self, id: u64, parent: u64, dirid: u64, name: &str) -> &mut Self {
+        self.root_dir(id);
+
+        let location = BtrfsKey::new(id, item_type::ROOT_ITEM, u64::MAX);
+        let index = self.link(parent, dirid, location, 2, name);
+
+        let root_ref = root_ref(dirid, index, name);
+        self.insert(
+            objectid::ROOT_TREE,
+            BtrfsKey::new(parent, item_type::ROOT_REF, id),
+            root_ref.clone(),
+        );
+        self.insert(
+            objectid::ROOT_TREE,
+            BtrfsKey::new(id, item_type::ROOT_BACKREF, parent),
+            root_ref,
+        )
+    }
+
+    /// Sets the UUID and parent UUID written into tree `id`'s ROOT_ITEM
+    pub fn root_uuid(&mut self, id: u64, uuid: [u8; 16], parent_uuid: [u8; 16]) -> &mut Self {
+        self.root_uuids.insert(id, (uuid, parent_uuid));
+        self
+    }
+
+    /// Inserts the DIR_ITEM and DIR_INDEX for `name` in `dir`, returning
+    /// the directory index used
+    fn link(
+        &mut self,
+        tree_id: u64,
+        dir: u64,
+        location: BtrfsKey,
+        dir_type: u8,
+        name: &str,
+    ) -> u64 {
+        let index = 2 + self
+            .trees
+            .get(&tree_id)
+            .map(|items| {
+                items
+                    .keys()
+                    .filter(|k| k.objectid == dir && k.item_type == item_type::DIR_INDEX)
+                    .count() as u64
+            })
+            .unwrap_or(0);
+        let hash = btrfs_name_hash(name);
+
+        self.insert(
+            tree_id,
+            BtrfsKey::new(dir, item_type::DIR_ITEM, hash),
+            dir_item(&location, dir_type, name),
+        );
+        self.insert(
+            tree_id,
+            BtrfsKey::new(dir, item_type::DIR_INDEX, index),
+            dir_item(&location, dir_type, name),
+        );
+        index
+    }
+
+    /// Serializes the image
+    pub fn build(&self) -> Vec<u8> {
+        if !self.account_extents {
+            return self.build_with_nodes().0;
+        }
+
+        // Describing the tree blocks adds items, which can add tree blocks,
+        // so repeat until the layout stops changing
+        let mut accounting = BTreeMap::new();
+        loop {
+            let mut round = self.clone();
+            round.account_extents = false;
+            for ((tree_id, key), data) in &accounting {
+                round.insert(*tree_id, *key, Vec::clone(data));
+            }
+
+            let (image, nodes) = round.build_with_nodes();
+            let next = self.accounting_items(&nodes);
+            if next == accounting {
+                return image;
+            }
+            accounting = next;
+        }
+    }
+
+    /// Builds the extent, block group and free space items describing
+    /// `nodes` and the test's data extents
+    fn accounting_items(&self, nodes: &[(u64, u64, u8)]) -> BTreeMap<(u64, BtrfsKey), Vec<u8>> {
+        let skinny = self.incompat_flags & incompat::SKINNY_METADATA != 0;
+        let node_size = self.node_size as u64;
+        let mut items = BTreeMap::new();
+        let mut used: BTreeMap<u64, u64> = BTreeMap::new();
+
+        for &(bytenr, owner, level) in nodes {
+            let mut data = vec![0u8; 24];
+            data[0..8].copy_from_slice(&1u64.to_le_bytes()); // refs
+            data[8..16].copy_from_slice(&self.generation.to_le_bytes());
+            data[16..24].copy_from_slice(&2u64.to_le_bytes()); // TREE_BLOCK
+            let key = if skinny {
+                BtrfsKey::new(bytenr, item_type::METADATA_ITEM, level as u64)
+            } else {
+                data.extend_from_slice(&[0u8; KEY_SIZE]);
+                data.push(level);
+                BtrfsKey::new(bytenr, item_type::EXTENT_ITEM, node_size)
+            };
+            data.push(item_type::TREE_BLOCK_REF);
+            data.extend_from_slice(&owner.to_le_bytes());
+            items.insert((objectid::EXTENT_TREE, key), data);
+            used.insert(bytenr, node_size);
+        }
+
+        let extents = self.trees.get(&objectid::EXTENT_TREE);
+        for key in extents.into_iter().flat_map(|items| items.keys()) {
+            if key.item_type == item_type::EXTENT_ITEM {
+                used.insert(key.objectid, key.offset);
+            }
+        }
+
+        let total: u64 = used.values().sum();
+        items.insert(
+            (
+                objectid::EXTENT_TREE,
+                BtrfsKey::new(0, item_type::BLOCK_GROUP_ITEM, self.size),
+            ),
+            block_group_item(total, 0x7),
+        );
+
+        if self.compat_ro_flags & compat_ro::FREE_SPACE_TREE != 0 {
+            let mut free = Vec::new();
+            let mut cursor = NODE_ALLOC_START;
+            for (&start, &len) in used.range(NODE_ALLOC_START..) {
+                if start > cursor {
+                    free.push((cursor, start - cursor));
+                }
+                cursor = cursor.max(start + len);
+            }
+            if cursor < self.size {
+                free.push((cursor, self.size - cursor));
+            }
+
+            let mut info = vec![0u8; 8];
+            info[0..4].copy_from_slice(&(free.len() as u32).to_le_bytes());
+            info[4..8].copy_from_slice(&(self.free_space_bitmaps as u32).to_le_bytes());
+            let tree = objectid::FREE_SPACE_TREE;
+            items.insert(
+                (
+                    tree,
+                    BtrfsKey::new(0, item_type::FREE_SPACE_INFO, self.size),
+                ),
+                info,
+            );
+
+            if self.free_space_bitmaps {
+                // One bit per sector, in a single bitmap covering the group
+                let sector = self.sector_size as u64;
+                let mut bitmap = vec![0u8; (self.size / sector).div_ceil(8) as usize];
+                for (start, len) in free {
+                    for bit in (start / sector)..((start + len) / sector) {
+                        bitmap[bit as usize / 8] |= 1 << (bit % 8);
+                    }
+                }
+                let key = BtrfsKey::new(0, item_type::FREE_SPACE_BITMAP, self.size);
+                items.insert((tree, key), bitmap);
+            } else {
+                for (start, len) in free {
+                    let key = BtrfsKey::new(start, item_type::FREE_SPACE_EXTENT, len);
+                    items.insert((tree, key), Vec::new());
+                }
+            }
+        }
+        items
+    }
+
+    /// Serializes the image, returning it with the address, owner and level
+    /// of every tree block written
+    fn build_with_nodes(&self) -> (Vec<u8>, Vec<(u64, u64, u8)>) {
+        let mut image = vec![0u8; self.size as usize];
+        let mut nodes = Vec::new();
+
+        let mut cursor = NODE_ALLOC_START;
+        let mut root_items = self
+            .trees
+            .get(&objectid::ROOT_TREE)
+            .cloned()
+            .unwrap_or_default();
+
+        // The chunk tree is found through the superblock, not a ROOT_ITEM
+        let mut chunk_items = self
+            .trees
+            .get(&objectid::CHUNK_TREE)
+            .cloned()
+            .unwrap_or_default();
+        let (key, item) = self.bootstrap_chunk();
+        chunk_items.insert(key, item);
+        let chunk_root = self.write_tree(
+            &mut image,
+            &mut cursor,
+            &mut nodes,
+            objectid::CHUNK_TREE,
+            &chunk_items,
+        );
+
+        for (&tree_id, items) in &self.trees {
+            if tree_id == objectid::ROOT_TREE || tree_id == objectid::CHUNK_TREE {
+                continue;
+            }
+            let (bytenr, level) =
+                self.write_tree(&mut image, &mut cursor, &mut nodes, tree_id, items);
+            let item = root_items
+                .entry(BtrfsKey::new(tree_id, item_type::ROOT_ITEM, 0))
+                .or_insert_with(|| root_item(bytenr, level, self.generation));
+            if let Some((uuid, parent_uuid)) = self.root_uuids.get(&tree_id) {
+                item[247..263].copy_from_slice(uuid);
+                item[263..279].copy_from_slice(parent_uuid);
+            }
+        }
+
+        let root = if root_items.is_empty() {
+            None
+        } else {
+            Some(self.write_tree(
+                &mut image,
+                &mut cursor,
+                &mut nodes,
+                objectid::ROOT_TREE,
+                &root_items,
+            ))
+        };
+
+        for (&logical, bytes) in &self.data {
+            let start = logical as usize;
+            image[start..start + bytes.len()].copy_from_slice(bytes);
+        }
+
+        let superblock = self.superblock_with_roots(root, chunk_root);
+        let start = SUPERBLOCK_OFFSET as usize;
+        image[start..start + superblock.len()].copy_from_slice(&superblock);
+        (image, nodes)
+    }
+
+    /// Builds the image into a memory device
+    pub fn device(&self) -> Arc<MemDevice> {
+        Arc::new(MemDevice::new(self.build()))
+    }
+
+    /// Builds the image and opens it as a filesystem
+    pub fn open(&self) -> BtrfsFilesystem {
+        BtrfsFilesystem::open(self.device(), false).expect("failed to open test image")
+    }
+
+    /// Serializes the 4 KiB superblock exactly as `build` writes it
+    pub fn superblock_bytes(&self) -> Vec<u8> {
+        let image = self.build();
+        let start = SUPERBLOCK_OFFSET as usize;
+        image[start..start + 0x1000].to_vec()
+    }
+
+    /// Builds another device of a multi-device filesystem: an empty image
+    /// whose superblock carries `devid` and `fsid`
+    pub fn member_image(&self, devid: u64, fsid: [u8; 16]) -> Vec<u8> {
+        let mut sb = self.superblock_bytes();
+        sb[0x20..0x30].copy_from_slice(&fsid);
+        sb[0xc9..0xd1].copy_from_slice(&devid.to_le_bytes());
+        self.write_csum(&mut sb);
+
+        let mut image = vec![0u8; self.size as usize];
+        let start = SUPERBLOCK_OFFSET as usize;
+        image[start..start + sb.len()].copy_from_slice(&sb);
+        image
+    }
+
+    /// Serializes the superblock with a valid checksum
+    fn superblock_with_roots(&self, root: Option<(u64, u8)>, chunk_root: (u64, u8)) -> Vec<u8> {
+        let mut sb = vec![0u8; 0x1000];
+        let (root, root_level) = root.unwrap_or((0, 0));
+        let (chunk_root, chunk_root_level) = chunk_root;
+
+        sb[0x20..0x30].copy_from_slice(&TEST_FSID);
+        sb[0x30..0x38].copy_from_slice(&SUPERBLOCK_OFFSET.to_le_bytes()); // bytenr
+        sb[0x40..0x48].copy_from_slice(&BTRFS_MAGIC);
+        sb[0x48..0x50].copy_from_slice(&self.generation.to_le_bytes());
+        sb[0x50..0x58].copy_from_slice(&root.to_le_bytes());
+        sb[0x58..0x60].copy_from_slice(&chunk_root.to_le_bytes());
+        sb[0x70..0x78].copy_from_slice(&self.size.to_le_bytes()); // total_bytes
+        sb[0x80..0x88].copy_from_slice(&6u64.to_le_bytes()); // root_dir_objectid
+        sb[0x88..0x90].copy_from_slice(&1u64.to_le_bytes()); // num_devices
+        sb[0x90..0x94].copy_from_slice(&self.sector_size.to_le_bytes());
+        sb[0x94..0x98].copy_from_slice(&self.node_size.to_le_bytes());
+        sb[0x98..0x9c].copy_from_slice(&self.node_size.to_le_bytes()); // leaf_size
+        sb[0x9c..0xa0].copy_from_slice(&self.sector_size.to_le_bytes()); // stripe_size
+        sb[0xb4..0xbc].copy_from_slice(&self.compat_ro_flags.to_le_bytes());
+        sb[0xbc..0xc4].copy_from_slice(&self.incompat_flags.to_le_bytes());
+        sb[0xc4..0xc6].copy_from_slice(&(self.csum as u16).to_le_bytes());
+        sb[0xc6] = root_level;
+        sb[0xc7] = chunk_root_level;
+
+        // dev_item.devid
+        sb[0xc9..0xd1].copy_from_slice(&1u64.to_le_bytes());
+
+        // sys_chunk_array: one chunk mapping the whole device 1:1
+        let (key, item) = self.bootstrap_chunk();
+        let mut chunk = key_bytes(&key).to_vec();
+        chunk.extend_from_slice(&item);
+        sb[0xa0..0xa4].copy_from_slice(&(chunk.len() as u32).to_le_bytes());
+        sb[0x32b..0x32b + chunk.len()].copy_from_slice(&chunk);
+
+        self.write_csum(&mut sb);
+        sb
+    }
+
+    /// Returns the key and CHUNK_ITEM of the identity-mapped chunk
+    fn bootstrap_chunk(&self) -> (BtrfsKey, Vec<u8>) {
+        let key = BtrfsKey::new(objectid::FIRST_CHUNK_TREE, item_type::CHUNK_ITEM, 0);
+        // DATA | SYSTEM | METADATA
+        (key, chunk_item(self.size, 0, self.sector_size, 0x7))
+    }
+
+    /// Writes a tree's items as leaves plus internal levels, recording each
+    /// node in `nodes`
+    ///
+    /// Returns the logical address and level of the tree root.
+    fn write_tree(
+        &self,
+        image: &mut [u8],
+        cursor: &mut u64,
+        nodes: &mut Vec<(u64, u64, u8)>,
+        owner: u64,
+        items: &BTreeMap<BtrfsKey, Vec<u8>>,
+    ) -> (u64, u8) {
+        let node_size = self.node_size as usize;
+        let capacity = node_size - NODE_HEADER_SIZE;
+
+        // Pack items into leaves
+        let mut leaves: Vec<Vec<(BtrfsKey, &[u8])>> = vec![Vec::new()];
+        let mut used = 0;
+        for (key, data) in items {
+            let needed = ITEM_SIZE + data.len();
+            if used + needed > capacity && !leaves.last().unwrap().is_empty() {
+                leaves.push(Vec::new());
+                used = 0;
+            }
+            leaves.last_mut().unwrap().push((*key, data.as_slice()));
+            used += needed;
+        }
+
+        let mut level_ptrs = Vec::new();
+        for leaf in &leaves {
+            let bytenr = self.alloc_node(cursor);
+            let node = self.leaf_bytes(bytenr, owner, leaf);
+            image[bytenr as usize..bytenr as usize + node_size].copy_from_slice(&node);
+            nodes.push((bytenr, owner, 0));
+            let first = leaf.first().map(|(k, _)| *k).unwrap_or(BtrfsKey::min());
+            level_ptrs.push((first, bytenr));
+        }
+
+        // Build internal levels until a single root remains
+        let per_node = capacity / KEY_PTR_SIZE;
+        let mut level = 0u8;
+        while level_ptrs.len() > 1 {
+            level += 1;
+            let mut next = Vec::new();
+            for chunk in level_ptrs.chunks(per_node) {
+                let bytenr = self.alloc_node(cursor);
+                let node = self.internal_bytes(bytenr, owner, level, chunk);
+                image[bytenr as usize..bytenr as usize + node_size].copy_from_slice(&node);
+                nodes.push((bytenr, owner, level));
+                next.push((chunk[0].0, bytenr));
+            }
+            level_ptrs = next;
+        }
+
+        (level_ptrs[0].1, level)
+    }
+
+    fn alloc_node(&self, cursor: &mut u64) -> u64 {
+        let bytenr = *cursor;
+        *cursor += self.node_size as u64;
+        assert!(*cursor <= self.size, "test image too small for its trees");
+        bytenr
+    }
+
+    /// Serializes a leaf node
+    pub fn leaf_bytes(&self, bytenr: u64, owner: u64, items: &[(BtrfsKey, &[u8])]) -> Vec<u8> {
+        let mut node = vec![0u8; self.node_size as usize];
+        self.write_header(&mut node, bytenr, owner, items.len() as u32, 0);
+
+        let mut data_end = self.node_size as usize - NODE_HEADER_SIZE;
+        for (i, (key, data)) in items.iter().enumerate() {
+            data_end -= data.len();
+            let off = NODE_HEADER_SIZE + i * ITEM_SIZE;
+            node[off..off + KEY_SIZE].copy_from_slice(&key_bytes(key));
+            node[off + KEY_SIZE..off + KEY_SIZE + 4]
+                .copy_from_slice(&(data_end as u32).to_le_bytes());
+            node[off + KEY_SIZE + 4..off + ITEM_SIZE]
+                .copy_from_slice(&(data.len() as u32).to_le_bytes());
+            let start = NODE_HEADER_SIZE + data_end;
+            node[start..start + data.len()].copy_from_slice(data);
+        }
+
+        self.write_csum(&mut node);
+        node
+    }
+
+    /// Serializes an internal node
+    fn internal_bytes(
+        &self,
+        bytenr: u64,
+        owner: u64,
+        level: u8,
+        ptrs: &[(BtrfsKey, u64)],
+    ) -> Vec<u8> {
+        let mut node = vec![0u8; self.node_size as usize];
+        self.write_header(&mut node, bytenr, owner, ptrs.len() as u32, level);
+
+        for (i, (key, blockptr)) in ptrs.iter().enumerate() {
+            let off = NODE_HEADER_SIZE + i * KEY_PTR_SIZE;
+            node[off..off + KEY_SIZE].copy_from_slice(&key_bytes(key));
+            node[off + KEY_SIZE..off + KEY_SIZE + 8].copy_from_slice(&blockptr.to_le_bytes());
+            node[off + KEY_SIZE + 8..off + KEY_PTR_SIZE]
+                .copy_from_slice(&self.generation.to_le_bytes());
+        }
+
+        self.write_csum(&mut node);
+        node
+    }
+
+    fn write_header(&self, node: &mut [u8], bytenr: u64, owner: u64, nritems: u32, level: u8) {
+        node[0x20..0x30].copy_from_slice(&TEST_FSID);
+        node[0x30..0x38].copy_from_slice(&bytenr.to_le_bytes());
+        node[0x3f] = 1; // backref_rev
+        node[0x50..0x58].copy_from_slice(&self.generation.to_le_bytes());
+        node[0x58..0x60].copy_from_slice(&owner.to_le_bytes());
+        node[0x60..0x64].copy_from_slice(&nritems.to_le_bytes());
+        node[0x64] = level;
+    }
+
+    /// Computes and stores the checksum of a superblock or tree node
+    fn write_csum(&self, block: &mut [u8]) {
+        let csum = self.csum.compute(&block[0x20..]).unwrap().to_le_bytes();
+        let size = self.csum.size();
+        block[..size].copy_from_slice(&csum[..size]);
+    }
+}
+
+/// Serializes a key in on-disk order
+pub fn key_bytes(key: &BtrfsKey) -> [u8; KEY_SIZE] {
+    let mut out = [0u8; KEY_SIZE];
+    out[0..8].copy_from_slice(&{ key.objectid }.to_le_bytes());
+    out[8] = key.item_type;
+    out[9..17].copy_from_slice(&{ key.offset }.to_le_bytes());
+    out
+}
+
+/// Builds a ROOT_ITEM pointing at a tree root
+pub fn root_item(bytenr: u64, level: u8, generation: u64) -> Vec<u8> {
+    let mut data = vec![0u8; 439];
+    data[52..56].copy_from_slice(&0o40755u32.to_le_bytes()); // inode mode
+    data[160..168].copy_from_slice(&generation.to_le_bytes());
+    data[168..176].copy_from_slice(&256u64.to_le_bytes()); // root_dirid
+    data[176..184].copy_from_slice(&bytenr.to_le_bytes());
+    data[216..220].copy_from_slice(&1u32.to_le_bytes()); // refs
+    data[238] = level;
+    data[239..247].copy_from_slice(&generation.to_le_bytes()); // generation_v2
+    data
+}
+
+/// Builds an INODE_ITEM with the given mode and size
+pub fn inode_item(mode: u32, size: u64) -> Vec<u8> {
+    let mut data = vec![0u8; 160];
+    data[0..8].copy_from_slice(&1u64.to_le_bytes()); // generation
+    data[16..24].copy_from_slice(&size.to_le_bytes());
+    data[40..44].copy_from_slice(&1u32.to_le_bytes()); // nlink
+    data[52..56].copy_from_slice(&mode.to_le_bytes());
+    data
+}
+
+/// Parses an inode with the given mode and flags, for code that takes an
+/// [`Inode`] directly
+pub fn inode_with(mode: u32, flags: InodeFlags) -> Inode {
+    let mut data = vec![0u8; 160];
+    data[52..56].copy_from_slice(&mode.to_le_bytes());
+    data[64..72].copy_from_slice(&flags.bits().to_le_bytes());
+    Inode::from_bytes(257, &data).unwrap()
+}
+
+/// Builds a DIR_ITEM/DIR_INDEX entry pointing at `location`
+pub fn dir_item(location: &BtrfsKey, dir_type: u8, name: &str) -> Vec<u8> {
+    let mut data = Vec::with_capacity(30 + name.len());
+    data.extend_from_slice(&key_bytes(location));
+    data.extend_from_slice(&1u64.to_le_bytes()); // transid
+    data.extend_from_slice(&0u16.to_le_bytes()); // data_len
+    data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    data.push(dir_type);
+    data.extend_from_slice(name.as_bytes());
+    data
+}
+
+/// Builds an XATTR_ITEM holding a single attribute
+pub fn xattr_item(name: &str, value: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(30 + name.len() + value.len());
+    data.extend_from_slice(&[0u8; KEY_SIZE]); // location, unused
+    data.extend_from_slice(&1u64.to_le_bytes()); // transid
+    data.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    data.push(8); // BTRFS_FT_XATTR
+    data.extend_from_slice(name.as_bytes());
+    data.extend_from_slice(value);
+    data
+}
+
+/// Builds an INODE_REF with a single name
+pub fn inode_ref(index: u64, name: &str) -> Vec<u8> {
+    let mut data = Vec::with_capacity(10 + name.len());
+    data.extend_from_slice(&index.to_le_bytes());
+    data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    data.extend_from_slice(name.as_bytes());
+    data
+}
+
+/// Builds a ROOT_REF/ROOT_BACKREF
+pub fn root_ref(dirid: u64, sequence: u64, name: &str) -> Vec<u8> {
+    let mut data = Vec::with_capacity(18 + name.len());
+    data.extend_from_slice(&dirid.to_le_bytes());
+    data.extend_from_slice(&sequence.to_le_bytes());
+    data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    data.extend_from_slice(name.as_bytes());
+    data
+}
+
+/// Builds a regular EXTENT_DATA item covering `num_bytes` at `disk_bytenr`
+///
+/// A `disk_bytenr` of zero describes a hole.
+pub fn extent_data(disk_bytenr: u64, num_bytes: u64) -> Vec<u8> {
+    let mut data = vec![0u8; 53];
+    data[0..8].copy_from_slice(&1u64.to_le_bytes()); // generation
+    data[8..16].copy_from_slice(&num_bytes.to_le_bytes()); // ram_bytes
+    data[20] = 1; // regular
+    data[21..29].copy_from_slice(&disk_bytenr.to_le_bytes());
+    data[29..37].copy_from_slice(&num_bytes.to_le_bytes()); // disk_num_bytes
+    data[45..53].copy_from_slice(&num_bytes.to_le_bytes());
+    data
+}
+
+/// Builds an inline EXTENT_DATA item holding `bytes`
+pub fn inline_extent(bytes: &[u8]) -> Vec<u8> {
+    let mut data = vec![0u8; 21];
+    data[0..8].copy_from_slice(&1u64.to_le_bytes()); // generation
+    data[8..16].copy_from_slice(&(bytes.len() as u64).to_le_bytes()); // ram_bytes
+    data.extend_from_slice(bytes);
+    data
+}
+
+/// Builds a single-stripe CHUNK_ITEM on device 1
+pub fn chunk_item(size: u64, physical: u64, sector_size: u32, type_flags: u64) -> Vec<u8> {
+    striped_chunk_item(size, &[physical], sector_size, type_flags)
+}
+
+/// Builds a CHUNK_ITEM with a stripe on device 1 at each of `physicals`
+pub fn striped_chunk_item(
+    size: u64,
+    physicals: &[u64],
+    sector_size: u32,
+    type_flags: u64,
+) -> Vec<u8> {
+    let mut data = Vec::with_capacity(0x30 + 0x20 * physicals.len());
+    data.extend_from_slice(&size.to_le_bytes());
+    data.extend_from_slice(&2u64.to_le_bytes()); // owner
+    data.extend_from_slice(&0x10000u64.to_le_bytes()); // stripe_len
+    data.extend_from_slice(&type_flags.to_le_bytes());
+    data.extend_from_slice(&sector_size.to_le_bytes()); // io_align
+    data.extend_from_slice(&sector_size.to_le_bytes()); // io_width
+    data.extend_from_slice(&sector_size.to_le_bytes()); // sector_size
+    data.extend_from_slice(&(physicals.len() as u16).to_le_bytes()); // num_stripes
+    data.extend_from_slice(&0u16.to_le_bytes()); // sub_stripes
+
+    for physical in physicals {
+        data.extend_from_slice(&1u64.to_le_bytes()); // devid
+        data.extend_from_slice(&physical.to_le_bytes()); // offset
+        data.extend_from_slice(&[0x24; 16]); // dev_uuid
+    }
+    data
+}
+
+/// Builds a data EXTENT_ITEM with `refs` references and no inline back
+/// references
+pub fn extent_item(refs: u64) -> Vec<u8> {
+    let mut data = vec![0u8; 24];
+    data[0..8].copy_from_slice(&refs.to_le_bytes());
+    data[8..16].copy_from_slice(&1u64.to_le_bytes()); // generation
+    data[16..24].copy_from_slice(&1u64.to_le_bytes()); // EXTENT_FLAG_DATA
+    data
+}
+
+/// Builds a BLOCK_GROUP_ITEM
+pub fn block_group_item(used: u64, flags: u64) -> Vec<u8> {
+    let mut data = vec![0u8; 24];
+    data[0..8].copy_from_slice(&used.to_le_bytes());
+    data[8..16].copy_from_slice(&objectid::FIRST_CHUNK_TREE.to_le_bytes());
+    data[16..24].copy_from_slice(&flags.to_le_bytes());
+    data
+}
+
+/// Builds a DEV_ITEM for device `devid` with `bytes_used` of its
+/// `total_bytes` allocated to chunks
+pub fn dev_item(devid: u64, total_bytes: u64, bytes_used: u64) -> Vec<u8> {
+    let mut data = vec![0u8; DEV_ITEM_SIZE];
+    data[0..8].copy_from_slice(&devid.to_le_bytes());
+    data[8..16].copy_from_slice(&total_bytes.to_le_bytes());
+    data[16..24].copy_from_slice(&bytes_used.to_le_bytes());
+    data[32..36].copy_from_slice(&4096u32.to_le_bytes());
+    data[66..82].copy_from_slice(&[devid as u8; 16]);
+    data
+}
+
+/// Builds a DEV_EXTENT backing `length` bytes of the chunk at
+/// `chunk_offset`
+pub fn dev_extent(chunk_offset: u64, length: u64) -> Vec<u8> {
+    let mut data = vec![0u8; 48];
+    data[0..8].copy_from_slice(&objectid::CHUNK_TREE.to_le_bytes());
+    data[8..16].copy_from_slice(&objectid::FIRST_CHUNK_TREE.to_le_bytes());
+    data[16..24].copy_from_slice(&chunk_offset.to_le_bytes());
+    data[24..32].copy_from_slice(&length.to_le_bytes());
+    data
+}
+
+/// Asserts that the extent tree describes exactly the tree blocks and data
+/// extents in use: every block reachable from the superblock has an extent
+/// item counting its parents, data extents count the file extent items
+/// pointing at them, and the block group's usage and any free space tree
+/// match
+pub fn check_extents(fs: &BtrfsFilesystem) {
+    let sb = fs.superblock();
+    let node_size = fs.node_size() as u64;
+
+    // References to each tree block: one per parent node or ROOT_ITEM
+    let mut refs: BTreeMap<u64, u64> = BTreeMap::new();
+    let mut data_refs: BTreeMap<u64, u64> = BTreeMap::new();
+    let mut roots = vec![(sb.root(), sb.root_level())];
+    for (item, data) in fs
+        .tree(objectid::ROOT_TREE)
+        .unwrap()
+        .iter()
+        .map(Result::unwrap)
+    {
+        if item.key.item_type == item_type::ROOT_ITEM {
+            let root = crate::core::subvolume::RootItem::from_bytes(&data).unwrap();
+            roots.push((root.bytenr, root.level));
+        }
+    }
+    *refs.entry(sb.chunk_root()).or_default() += 1;
+    let mut queue = Vec::new();
+    for (bytenr, _) in roots {
+        *refs.entry(bytenr).or_default() += 1;
+        queue.push(bytenr);
+    }
+    queue.push(sb.chunk_root());
+
+    let mut seen = std::collections::BTreeSet::new();
+    while let Some(bytenr) = queue.pop() {
+        if !seen.insert(bytenr) {
+            continue;
+        }
+        let node = fs.node_at(bytenr).unwrap();
+        assert_eq!({ node.header.bytenr }, bytenr, "node header address");
+        if node.is_leaf() {
+            for item in node.items().unwrap() {
+                let data = node.item_data(&item);
+                if item.key.item_type == item_type::EXTENT_DATA && data.len() >= 53 && data[20] != 0
+                {
+                    let disk_bytenr = u64::from_le_bytes(data[21..29].try_into().unwrap());
+                    if disk_bytenr != 0 {
+                        *data_refs.entry(disk_bytenr).or_default() += 1;
+                    }
+                }
+            }
+        } else {
+            for ptr in node.key_ptrs().unwrap() {
+                *refs.entry(ptr.blockptr).or_default() += 1;
+                queue.push(ptr.blockptr);
+            }
+        }
+    }
+
+    let mut extent_refs = BTreeMap::new();
+    let mut used = BTreeMap::new();
+    let mut group_used = None;
+    let mut free = Vec::new();
+    for (item, data) in fs
+        .tree(objectid::EXTENT_TREE)
+        .unwrap()
+        .iter()
+        .map(Result::unwrap)
+    {
+        let key = item.key;
+        match key.item_type {
+            item_type::EXTENT_ITEM | item_type::METADATA_ITEM => {
+                let len = match key.item_type {
+                    item_type::EXTENT_ITEM => key.offset,
+                    _ => node_size,
+                };
+                let count = u64::from_le_bytes(data[0..8].try_into().unwrap());
+                extent_refs.insert(key.objectid, count);
+                used.insert(key.objectid, len);
+            }
+            item_type::BLOCK_GROUP_ITEM => {
+                group_used = Some(u64::from_le_bytes(data[0..8].try_into().unwrap()));
+            }
+            _ => {}
+        }
+    }
+
+    let mut expected = refs.clone();
+    expected.extend(data_refs);
+    assert_eq!(extent_refs, expected, "extent item reference counts");
+    let total: u64 = used.values().sum();
+    assert_eq!(group_used, Some(total), "block group usage");
+
+    if sb.has_compat_ro(compat_ro::FREE_SPACE_TREE) {
+        let tree = fs.tree(objectid::FREE_SPACE_TREE).unwrap();
+        let mut count = None;
+        for (item, data) in tree.iter().map(Result::unwrap) {
+            match item.key.item_type {
+                item_type::FREE_SPACE_INFO => {
+                    count = Some(u32::from_le_bytes(data[0..4].try_into().unwrap()));
+                }
+                item_type::FREE_SPACE_EXTENT => free.push((item.key.objectid, item.key.offset)),
+                item_type::FREE_SPACE_BITMAP => {
+                    let sector = sb.sector_size() as u64;
+                    let sectors = item.key.offset / sector;
+                    for bit in 0..sectors as usize {
+                        if data[bit / 8] & (1 << (bit % 8)) == 0 {
+                            continue;
+                        }
+                        let start = item.key.objectid + bit as u64 * sector;
+                        match free.last_mut() {
+                            Some((last, len)) if *last + *len == start => *len += sector,
+                            _ => free.push((start, sector)),
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut expected_free = Vec::new();
+        let mut cursor = NODE_ALLOC_START;
+        for (&start, &len) in used.range(NODE_ALLOC_START..) {
+            if start > cursor {
+                expected_free.push((cursor, start - cursor));
+            }
+            cursor = cursor.max(start + len);
+        }
+        if cursor < sb.total_bytes() {
+            expected_free.push((cursor, sb.total_bytes() - cursor));
+        }
+        assert_eq!(free, expected_free, "free space extents");
+        assert_eq!(count, Some(free.len() as u32), "free space extent count");
+    }
+}
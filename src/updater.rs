@@ -62,6 +62,12 @@ pub struct LibraryManifest {
     pub library_path: PathBuf,
     /// SHA256 hash of installed library
     pub sha256: String,
+    /// Version replaced by this install, kept as a backup for rollback
+    #[serde(default)]
+    pub previous_version: Option<String>,
+    /// SHA256 hash of the replaced library
+    #[serde(default)]
+    pub previous_sha256: Option<String>,
 }
 
 /// Error type for updater operations
@@ -79,6 +85,10 @@ pub enum UpdateError {
     PlatformNotSupported,
     #[error("No update available")]
     NoUpdate,
+    #[error("No previous version to roll back to")]
+    NoBackup,
+    #[error("Library failed to load: {0}")]
+    Load(String),
 }
 
 /// Result type for updater operations
@@ -142,6 +152,13 @@ impl LibraryUpdater {
         self.lib_dir.join(Self::library_filename())
     }
 
+    /// Get the path the replaced library is kept at until the next install
+    pub fn backup_path(&self) -> PathBuf {
+        let mut name = Self::library_filename().to_string();
+        name.push_str(".old");
+        self.lib_dir.join(name)
+    }
+
     /// Get the path to the manifest file
     pub fn manifest_path(&self) -> PathBuf {
         self.lib_dir.join("manifest.json")
@@ -208,30 +225,125 @@ impl LibraryUpdater {
         // Create lib directory
         std::fs::create_dir_all(&self.lib_dir)?;
 
-        // Write the library file
+        // Write the new library next to the old one first, so a failed
+        // write leaves the installed library in place
         let lib_path = self.library_path();
-        
-        // On Windows, we may need to rename the old file first
-        #[cfg(windows)]
-        if lib_path.exists() {
-            let backup_path = lib_path.with_extension("dll.old");
-            let _ = std::fs::remove_file(&backup_path);
+        let new_path = self
+            .lib_dir
+            .join(format!("{}.new", Self::library_filename()));
+        std::fs::write(&new_path, data)?;
+        let previous = self.read_manifest().ok().flatten();
+
+        // Keep the old library for rollback. On Windows it can't be
+        // overwritten while loaded, but it can be renamed.
+        let backup_path = self.backup_path();
+        let _ = std::fs::remove_file(&backup_path);
+        let backed_up = lib_path.exists();
+        if backed_up {
             std::fs::rename(&lib_path, &backup_path)?;
         }
 
-        std::fs::write(&lib_path, data)?;
+        if let Err(e) = std::fs::rename(&new_path, &lib_path) {
+            if backed_up {
+                let _ = std::fs::rename(&backup_path, &lib_path);
+            }
+            return Err(e.into());
+        }
 
-        // Write manifest
+        // Write manifest; the previous version is only recorded when its
+        // library was kept, so rollback always has something to restore
+        let previous = previous.filter(|_| backed_up);
         let manifest = LibraryManifest {
             version: version.to_string(),
             installed_date: chrono::Utc::now().to_rfc3339(),
             library_path: lib_path,
             sha256: sha256.to_string(),
+            previous_version: previous.as_ref().map(|m| m.version.clone()),
+            previous_sha256: previous.map(|m| m.sha256),
         };
         self.write_manifest(&manifest)?;
 
         Ok(())
     }
+
+    /// Check that the installed library loads and is the version in its
+    /// manifest
+    ///
+    /// The library is loaded and asked for its version through
+    /// `btrfs_lib_version`, which catches a download that installed cleanly
+    /// but can't run, e.g. one built for another architecture.
+    pub fn verify_installed(&self) -> UpdateResult<()> {
+        use std::ffi::{c_char, CStr};
+
+        let manifest = self
+            .read_manifest()?
+            .ok_or_else(|| UpdateError::Parse("No library manifest".to_string()))?;
+
+        // SAFETY: the library is one of ours, and `btrfs_lib_version`
+        // returns a pointer to a static NUL-terminated string
+        let version = unsafe {
+            let lib = libloading::Library::new(self.library_path())
+                .map_err(|e| UpdateError::Load(e.to_string()))?;
+            let lib_version = lib
+                .get::<unsafe extern "C" fn() -> *const c_char>(b"btrfs_lib_version\0")
+                .map_err(|e| UpdateError::Load(e.to_string()))?;
+            CStr::from_ptr(lib_version()).to_string_lossy().into_owned()
+        };
+
+        if compare_versions(&version, &manifest.version) != 0 {
+            return Err(UpdateError::Load(format!(
+                "library reports version {}, expected {}",
+                version, manifest.version
+            )));
+        }
+        Ok(())
+    }
+
+    /// Restore the library replaced by the last install
+    ///
+    /// The backup is checked against the hash recorded when it was replaced
+    /// before it is moved back, and the manifest is rewritten to describe
+    /// it. Only one previous version is kept, so a second rollback fails
+    /// with [`UpdateError::NoBackup`]. Returns the restored manifest.
+    pub fn rollback(&self) -> UpdateResult<LibraryManifest> {
+        let manifest = self.read_manifest()?.ok_or(UpdateError::NoBackup)?;
+        let (Some(version), Some(sha256)) = (manifest.previous_version, manifest.previous_sha256)
+        else {
+            return Err(UpdateError::NoBackup);
+        };
+
+        let backup_path = self.backup_path();
+        if !backup_path.exists() {
+            return Err(UpdateError::NoBackup);
+        }
+        let data = std::fs::read(&backup_path)?;
+        if !Self::verify_checksum(&data, &sha256) {
+            return Err(UpdateError::ChecksumMismatch);
+        }
+
+        // The failed library may still be loaded on Windows, so move it
+        // aside rather than overwrite it
+        let lib_path = self.library_path();
+        if lib_path.exists() {
+            let failed_path = self
+                .lib_dir
+                .join(format!("{}.failed", Self::library_filename()));
+            let _ = std::fs::remove_file(&failed_path);
+            std::fs::rename(&lib_path, &failed_path)?;
+        }
+        std::fs::rename(&backup_path, &lib_path)?;
+
+        let restored = LibraryManifest {
+            version,
+            installed_date: chrono::Utc::now().to_rfc3339(),
+            library_path: lib_path,
+            sha256,
+            previous_version: None,
+            previous_sha256: None,
+        };
+        self.write_manifest(&restored)?;
+        Ok(restored)
+    }
 }
 
 /// Compare two semantic version strings
@@ -303,4 +415,134 @@ mod tests {
         assert_eq!(update.version, "0.2.0");
         assert!(update.platforms.windows_x64.is_some());
     }
+
+    fn sha256(data: &[u8]) -> String {
+        use sha2::{Digest, Sha256};
+        hex::encode(Sha256::digest(data))
+    }
+
+    #[test]
+    fn test_install_records_previous() {
+        let dir = tempfile::tempdir().unwrap();
+        let updater = LibraryUpdater::new(dir.path().to_path_buf());
+
+        updater
+            .install_library(b"v1", "0.1.0", &sha256(b"v1"))
+            .unwrap();
+        let manifest = updater.read_manifest().unwrap().unwrap();
+        assert_eq!(manifest.previous_version, None);
+
+        // Bytes that pass the checksum still have to load
+        assert!(matches!(
+            updater.verify_installed(),
+            Err(UpdateError::Load(_))
+        ));
+
+        updater
+            .install_library(b"v2", "0.2.0", &sha256(b"v2"))
+            .unwrap();
+        let manifest = updater.read_manifest().unwrap().unwrap();
+        assert_eq!(manifest.version, "0.2.0");
+        assert_eq!(manifest.previous_version.as_deref(), Some("0.1.0"));
+        assert_eq!(manifest.previous_sha256, Some(sha256(b"v1")));
+        assert_eq!(std::fs::read(updater.backup_path()).unwrap(), b"v1");
+
+        // A bad download leaves the installed library alone
+        assert!(matches!(
+            updater.install_library(b"v3", "0.3.0", &sha256(b"v2")),
+            Err(UpdateError::ChecksumMismatch)
+        ));
+        assert_eq!(
+            updater.installed_version().unwrap().as_deref(),
+            Some("0.2.0")
+        );
+    }
+
+    #[test]
+    fn test_rollback() {
+        let dir = tempfile::tempdir().unwrap();
+        let updater = LibraryUpdater::new(dir.path().to_path_buf());
+        assert!(matches!(updater.rollback(), Err(UpdateError::NoBackup)));
+
+        updater
+            .install_library(b"v1", "0.1.0", &sha256(b"v1"))
+            .unwrap();
+        assert!(matches!(updater.rollback(), Err(UpdateError::NoBackup)));
+
+        updater
+            .install_library(b"v2", "0.2.0", &sha256(b"v2"))
+            .unwrap();
+        let restored = updater.rollback().unwrap();
+        assert_eq!(restored.version, "0.1.0");
+        assert_eq!(restored.previous_version, None);
+        assert_eq!(
+            updater.read_manifest().unwrap().unwrap().sha256,
+            sha256(b"v1")
+        );
+        assert_eq!(std::fs::read(updater.library_path()).unwrap(), b"v1");
+        assert!(!updater.backup_path().exists());
+
+        // Only one version back is kept
+        assert!(matches!(updater.rollback(), Err(UpdateError::NoBackup)));
+    }
+
+    #[test]
+    fn test_install_write_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let updater = LibraryUpdater::new(dir.path().to_path_buf());
+        updater
+            .install_library(b"v1", "0.1.0", &sha256(b"v1"))
+            .unwrap();
+
+        // A directory in the way of the new file makes the write fail
+        let new_path = dir
+            .path()
+            .join(format!("{}.new", LibraryUpdater::library_filename()));
+        std::fs::create_dir(&new_path).unwrap();
+        assert!(matches!(
+            updater.install_library(b"v2", "0.2.0", &sha256(b"v2")),
+            Err(UpdateError::Io(_))
+        ));
+        assert_eq!(std::fs::read(updater.library_path()).unwrap(), b"v1");
+        assert_eq!(
+            updater.installed_version().unwrap().as_deref(),
+            Some("0.1.0")
+        );
+    }
+
+    #[test]
+    fn test_rollback_damaged_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let updater = LibraryUpdater::new(dir.path().to_path_buf());
+        updater
+            .install_library(b"v1", "0.1.0", &sha256(b"v1"))
+            .unwrap();
+        updater
+            .install_library(b"v2", "0.2.0", &sha256(b"v2"))
+            .unwrap();
+        std::fs::write(updater.backup_path(), b"junk").unwrap();
+
+        assert!(matches!(
+            updater.rollback(),
+            Err(UpdateError::ChecksumMismatch)
+        ));
+        assert_eq!(std::fs::read(updater.library_path()).unwrap(), b"v2");
+        assert_eq!(
+            updater.installed_version().unwrap().as_deref(),
+            Some("0.2.0")
+        );
+    }
+
+    #[test]
+    fn test_manifest_without_previous() {
+        let json = r#"{
+            "version": "0.1.0",
+            "installed_date": "2024-01-01T00:00:00Z",
+            "library_path": "lib.dll",
+            "sha256": "abc123"
+        }"#;
+        let manifest: LibraryManifest = serde_json::from_str(json).unwrap();
+        assert_eq!(manifest.previous_version, None);
+        assert_eq!(manifest.previous_sha256, None);
+    }
 }